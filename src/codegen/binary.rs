@@ -0,0 +1,640 @@
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, Width};
+use crate::codegen::module::{
+    Composite, Export, Field, Function, HeapType, Module, Signature, StorageType, ValueType,
+};
+use std::collections::HashMap;
+
+const MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+const VERSION: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const TABLE_SECTION: u8 = 4;
+const MEMORY_SECTION: u8 = 5;
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+const ELEMENT_SECTION: u8 = 9;
+const CODE_SECTION: u8 = 10;
+const DATA_SECTION: u8 = 11;
+
+const FUNCTION_TYPE: u8 = 0x60;
+const RECURSIVE_GROUP: u8 = 0x4e;
+const STRUCT_TYPE: u8 = 0x5f;
+const ARRAY_TYPE: u8 = 0x5e;
+const I32: u8 = 0x7f;
+const I8: u8 = 0x78;
+const EQ_REFERENCE: u8 = 0x6d;
+const I31_REFERENCE: u8 = 0x6c;
+// instructions of the garbage collection proposal follow this byte
+const GC_PREFIX: u8 = 0xfb;
+const FUNCTION_REFERENCE: u8 = 0x70;
+const EMPTY_BLOCK: u8 = 0x40;
+const FUNCTION_KIND: u8 = 0x00;
+const MEMORY_KIND: u8 = 0x02;
+const END: u8 = 0x0b;
+
+pub fn write_unsigned(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // stop once the remaining bits are all copies of the sign bit
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, count: usize, contents: Vec<u8>) {
+    let mut section = Vec::new();
+    write_unsigned(&mut section, count as u64);
+    section.extend(contents);
+
+    out.push(id);
+    write_unsigned(out, section.len() as u64);
+    out.extend(section);
+}
+
+fn write_value_type(out: &mut Vec<u8>, value_type: &ValueType) {
+    match value_type {
+        ValueType::I32 => out.push(I32),
+        ValueType::EqRef => out.push(EQ_REFERENCE),
+    }
+}
+
+fn write_field(out: &mut Vec<u8>, field: &Field) {
+    match &field.storage {
+        StorageType::I8 => out.push(I8),
+        StorageType::Value(value_type) => write_value_type(out, value_type),
+    }
+    out.push(field.mutable as u8);
+}
+
+fn write_block_type(out: &mut Vec<u8>, result: &Option<ValueType>) {
+    match result {
+        Some(value_type) => write_value_type(out, value_type),
+        None => out.push(EMPTY_BLOCK),
+    }
+}
+
+/// Writes a garbage collection instruction and its immediates
+fn write_gc(out: &mut Vec<u8>, opcode: u64, immediates: &[u64]) {
+    out.push(GC_PREFIX);
+    write_unsigned(out, opcode);
+    for immediate in immediates {
+        write_unsigned(out, *immediate);
+    }
+}
+
+fn write_memory_access(out: &mut Vec<u8>, width: &Width, offset: u32) {
+    // natural alignment as a power of two
+    let alignment = match width {
+        Width::Word => 2,
+        Width::Byte => 0,
+    };
+    write_unsigned(out, alignment);
+    write_unsigned(out, offset as u64);
+}
+
+/// Encodes a module into the WebAssembly binary format
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut encoder = Encoder {
+        module,
+        types: vec![],
+        functions: module
+            .function_names()
+            .enumerate()
+            .map(|(index, name)| (name.as_str(), index))
+            .collect(),
+        globals: module
+            .globals
+            .iter()
+            .enumerate()
+            .map(|(index, global)| (global.name.as_str(), index))
+            .collect(),
+    };
+    encoder.encode()
+}
+
+struct Encoder<'a> {
+    module: &'a Module,
+    types: Vec<Signature>,
+    functions: HashMap<&'a str, usize>,
+    globals: HashMap<&'a str, usize>,
+}
+
+/// Names that are visible while encoding a function body
+struct Scope<'a> {
+    locals: HashMap<&'a str, usize>,
+    // enclosing labels, innermost last
+    labels: Vec<Option<&'a str>>,
+}
+
+impl<'a> Encoder<'a> {
+    fn heap_type(&self, heap_type: &HeapType) -> i64 {
+        match heap_type {
+            // abstract heap types are negative, in a single byte
+            HeapType::I31 => I31_REFERENCE as i64 - 0x80,
+            HeapType::Defined(name) => self.defined(name) as i64,
+        }
+    }
+
+    fn defined(&self, name: &str) -> u64 {
+        self.module
+            .type_index(name)
+            .expect("reference to a type that is not defined") as u64
+    }
+
+    fn encode(&mut self) -> Vec<u8> {
+        let module = self.module;
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&VERSION);
+
+        // function type indices are needed before the type section can be written
+        let mut imports = Vec::new();
+        for import in &module.imports {
+            write_name(&mut imports, &import.module);
+            write_name(&mut imports, &import.name);
+            imports.push(FUNCTION_KIND);
+            let index = self.type_index(&import.signature);
+            write_unsigned(&mut imports, index);
+        }
+        let mut functions = Vec::new();
+        for function in &module.functions {
+            let index = self.type_index(&function.signature());
+            write_unsigned(&mut functions, index);
+        }
+
+        // call_indirect refers to types too, so bodies are encoded up front
+        let mut code = Vec::new();
+        for function in &module.functions {
+            let body = self.encode_function(function);
+            write_unsigned(&mut code, body.len() as u64);
+            code.extend(body);
+        }
+
+        // the struct and array types form a single group, so types of the
+        // same shape stay distinct
+        let mut types = Vec::new();
+        if !module.types.is_empty() {
+            types.push(RECURSIVE_GROUP);
+            write_unsigned(&mut types, module.types.len() as u64);
+        }
+        for definition in &module.types {
+            match &definition.composite {
+                Composite::Struct(fields) => {
+                    types.push(STRUCT_TYPE);
+                    write_unsigned(&mut types, fields.len() as u64);
+                    for field in fields {
+                        write_field(&mut types, field);
+                    }
+                }
+                Composite::Array(element) => {
+                    types.push(ARRAY_TYPE);
+                    write_field(&mut types, element);
+                }
+            }
+        }
+        for signature in &self.types {
+            types.push(FUNCTION_TYPE);
+            write_unsigned(&mut types, signature.params.len() as u64);
+            for param in &signature.params {
+                write_value_type(&mut types, param);
+            }
+            match &signature.result {
+                Some(result) => {
+                    write_unsigned(&mut types, 1);
+                    write_value_type(&mut types, result);
+                }
+                None => write_unsigned(&mut types, 0),
+            }
+        }
+
+        let type_count = !module.types.is_empty() as usize + self.types.len();
+        write_section(&mut out, TYPE_SECTION, type_count, types);
+        write_section(&mut out, IMPORT_SECTION, module.imports.len(), imports);
+        write_section(
+            &mut out,
+            FUNCTION_SECTION,
+            module.functions.len(),
+            functions,
+        );
+
+        if let Some(table) = &module.table {
+            let mut tables = vec![FUNCTION_REFERENCE, 0x00];
+            write_unsigned(&mut tables, table.len() as u64);
+            write_section(&mut out, TABLE_SECTION, 1, tables);
+        }
+
+        // a single memory with only a minimum size
+        let mut memory = vec![0x00];
+        write_unsigned(&mut memory, module.memory_pages as u64);
+        write_section(&mut out, MEMORY_SECTION, 1, memory);
+
+        let mut globals = Vec::new();
+        for global in &module.globals {
+            write_value_type(&mut globals, &global.value_type);
+            // mutable
+            globals.push(0x01);
+            let mut scope = Scope {
+                locals: HashMap::new(),
+                labels: vec![],
+            };
+            self.encode_instruction(&mut globals, &global.initial, &mut scope);
+            globals.push(END);
+        }
+        write_section(&mut out, GLOBAL_SECTION, module.globals.len(), globals);
+
+        let mut exports = Vec::new();
+        for export in &module.exports {
+            match export {
+                Export::Function(name, function) => {
+                    write_name(&mut exports, name);
+                    exports.push(FUNCTION_KIND);
+                    write_unsigned(&mut exports, self.functions[function.as_str()] as u64);
+                }
+                Export::Memory(name) => {
+                    write_name(&mut exports, name);
+                    exports.extend(vec![MEMORY_KIND, 0x00]);
+                }
+            }
+        }
+        write_section(&mut out, EXPORT_SECTION, module.exports.len(), exports);
+
+        if let Some(table) = &module.table {
+            // one active segment filling the table from index 0
+            let mut elements = vec![0x00, 0x41, 0x00, END];
+            write_unsigned(&mut elements, table.len() as u64);
+            for name in table {
+                write_unsigned(&mut elements, self.functions[name.as_str()] as u64);
+            }
+            write_section(&mut out, ELEMENT_SECTION, 1, elements);
+        }
+
+        write_section(&mut out, CODE_SECTION, module.functions.len(), code);
+
+        let mut data = Vec::new();
+        for segment in &module.data {
+            // active segment in memory 0
+            write_unsigned(&mut data, 0);
+            data.push(0x41);
+            write_signed(&mut data, segment.offset as i64);
+            data.push(END);
+            write_unsigned(&mut data, segment.bytes.len() as u64);
+            data.extend_from_slice(&segment.bytes);
+        }
+        write_section(&mut out, DATA_SECTION, module.data.len(), data);
+
+        out
+    }
+
+    /// Function types follow the struct and array types
+    fn type_index(&mut self, signature: &Signature) -> u64 {
+        let index = match self.types.iter().position(|item| item == signature) {
+            Some(index) => index,
+            None => {
+                self.types.push(signature.clone());
+                self.types.len() - 1
+            }
+        };
+        (self.module.types.len() + index) as u64
+    }
+
+    fn encode_function(&mut self, function: &'a Function) -> Vec<u8> {
+        let mut out = Vec::new();
+        // one entry per local, runs of the same type are not merged
+        write_unsigned(&mut out, function.locals.len() as u64);
+        for local in &function.locals {
+            write_unsigned(&mut out, 1);
+            write_value_type(&mut out, &local.value_type);
+        }
+
+        let mut scope = Scope {
+            locals: function
+                .variables()
+                .enumerate()
+                .map(|(index, local)| (local.name.as_str(), index))
+                .collect(),
+            labels: vec![],
+        };
+        for instruction in &function.body {
+            self.encode_instruction(&mut out, instruction, &mut scope);
+        }
+        out.push(END);
+        out
+    }
+
+    fn encode_body(
+        &mut self,
+        out: &mut Vec<u8>,
+        body: &'a Vec<Instruction>,
+        scope: &mut Scope<'a>,
+    ) {
+        for instruction in body {
+            self.encode_instruction(out, instruction, scope);
+        }
+    }
+
+    fn label_depth(&self, scope: &Scope<'a>, label: &str) -> u64 {
+        let position = scope
+            .labels
+            .iter()
+            .rposition(|item| *item == Some(label))
+            .expect("branch to a label that is not in scope");
+        (scope.labels.len() - 1 - position) as u64
+    }
+
+    fn encode_instruction(
+        &mut self,
+        out: &mut Vec<u8>,
+        instruction: &'a Instruction,
+        scope: &mut Scope<'a>,
+    ) {
+        match instruction {
+            Instruction::Const(value) => {
+                out.push(0x41);
+                write_signed(out, *value as i64);
+            }
+            Instruction::LocalGet(name) => {
+                out.push(0x20);
+                write_unsigned(out, scope.locals[name.as_str()] as u64);
+            }
+            Instruction::LocalSet(name, value) => {
+                self.encode_instruction(out, value, scope);
+                out.push(0x21);
+                write_unsigned(out, scope.locals[name.as_str()] as u64);
+            }
+            Instruction::GlobalGet(name) => {
+                out.push(0x23);
+                write_unsigned(out, self.globals[name.as_str()] as u64);
+            }
+            Instruction::GlobalSet(name, value) => {
+                self.encode_instruction(out, value, scope);
+                out.push(0x24);
+                write_unsigned(out, self.globals[name.as_str()] as u64);
+            }
+            Instruction::Load {
+                width,
+                offset,
+                address,
+            } => {
+                self.encode_instruction(out, address, scope);
+                out.push(match width {
+                    Width::Word => 0x28,
+                    Width::Byte => 0x2d,
+                });
+                write_memory_access(out, width, *offset);
+            }
+            Instruction::Store {
+                width,
+                offset,
+                address,
+                value,
+            } => {
+                self.encode_instruction(out, address, scope);
+                self.encode_instruction(out, value, scope);
+                out.push(match width {
+                    Width::Word => 0x36,
+                    Width::Byte => 0x3a,
+                });
+                write_memory_access(out, width, *offset);
+            }
+            Instruction::Unary(op, value) => {
+                self.encode_instruction(out, value, scope);
+                out.push(match op {
+                    UnaryOp::EqualZero => 0x45,
+                    UnaryOp::CountLeadingZeros => 0x67,
+                    UnaryOp::PopulationCount => 0x69,
+                });
+            }
+            Instruction::Binary(op, left, right) => {
+                self.encode_instruction(out, left, scope);
+                self.encode_instruction(out, right, scope);
+                out.push(match op {
+                    BinaryOp::Add => 0x6a,
+                    BinaryOp::Subtract => 0x6b,
+                    BinaryOp::Multiply => 0x6c,
+                    BinaryOp::DivideSigned => 0x6d,
+                    BinaryOp::DivideUnsigned => 0x6e,
+                    BinaryOp::RemainderSigned => 0x6f,
+                    BinaryOp::RemainderUnsigned => 0x70,
+                    BinaryOp::And => 0x71,
+                    BinaryOp::Or => 0x72,
+                    BinaryOp::Xor => 0x73,
+                    BinaryOp::ShiftLeft => 0x74,
+                    BinaryOp::ShiftRightSigned => 0x75,
+                    BinaryOp::ShiftRightUnsigned => 0x76,
+                    BinaryOp::Equal => 0x46,
+                    BinaryOp::NotEqual => 0x47,
+                    BinaryOp::LessThan => 0x48,
+                    BinaryOp::LessEqual => 0x4c,
+                    BinaryOp::GreaterThan => 0x4a,
+                    BinaryOp::GreaterThanUnsigned => 0x4b,
+                    BinaryOp::GreaterEqual => 0x4e,
+                });
+            }
+            Instruction::Call(name, args) => {
+                self.encode_body(out, args, scope);
+                out.push(0x10);
+                write_unsigned(out, self.functions[name.as_str()] as u64);
+            }
+            Instruction::ReturnCall(name, args) => {
+                self.encode_body(out, args, scope);
+                out.push(0x12);
+                write_unsigned(out, self.functions[name.as_str()] as u64);
+            }
+            Instruction::CallIndirect(signature, args, index) => {
+                self.encode_body(out, args, scope);
+                self.encode_instruction(out, index, scope);
+                out.push(0x11);
+                let type_index = self.type_index(signature);
+                write_unsigned(out, type_index);
+                // table 0
+                out.push(0x00);
+            }
+            Instruction::Drop(value) => {
+                self.encode_instruction(out, value, scope);
+                out.push(0x1a);
+            }
+            // both take the memory index, always 0
+            Instruction::MemorySize => out.extend(&[0x3f, 0x00]),
+            Instruction::MemoryGrow(pages) => {
+                self.encode_instruction(out, pages, scope);
+                out.extend(&[0x40, 0x00]);
+            }
+            Instruction::Block {
+                label,
+                result,
+                body,
+            } => {
+                out.push(0x02);
+                write_block_type(out, result);
+                scope.labels.push(label.as_deref());
+                self.encode_body(out, body, scope);
+                scope.labels.pop();
+                out.push(END);
+            }
+            Instruction::Loop {
+                label,
+                result,
+                body,
+            } => {
+                out.push(0x03);
+                write_block_type(out, result);
+                scope.labels.push(Some(label.as_str()));
+                self.encode_body(out, body, scope);
+                scope.labels.pop();
+                out.push(END);
+            }
+            Instruction::If {
+                result,
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.encode_instruction(out, condition, scope);
+                out.push(0x04);
+                write_block_type(out, result);
+                scope.labels.push(None);
+                self.encode_body(out, then, scope);
+                if !otherwise.is_empty() {
+                    out.push(0x05);
+                    self.encode_body(out, otherwise, scope);
+                }
+                scope.labels.pop();
+                out.push(END);
+            }
+            Instruction::Branch(label) => {
+                out.push(0x0c);
+                write_unsigned(out, self.label_depth(scope, label));
+            }
+            Instruction::BranchIf(label, condition) => {
+                self.encode_instruction(out, condition, scope);
+                out.push(0x0d);
+                write_unsigned(out, self.label_depth(scope, label));
+            }
+            Instruction::RefNull => out.extend(&[0xd0, EQ_REFERENCE]),
+            Instruction::RefIsNull(value) => {
+                self.encode_instruction(out, value, scope);
+                out.push(0xd1);
+            }
+            Instruction::RefEq(left, right) => {
+                self.encode_instruction(out, left, scope);
+                self.encode_instruction(out, right, scope);
+                out.push(0xd3);
+            }
+            Instruction::RefI31(value) => {
+                self.encode_instruction(out, value, scope);
+                write_gc(out, 0x1c, &[]);
+            }
+            Instruction::I31Get(value) => {
+                self.encode_instruction(out, value, scope);
+                write_gc(out, 0x1d, &[]);
+            }
+            Instruction::RefTest(tested, value) => {
+                self.encode_instruction(out, value, scope);
+                write_gc(out, 0x14, &[]);
+                write_signed(out, self.heap_type(tested));
+            }
+            Instruction::RefCast(cast, value) => {
+                self.encode_instruction(out, value, scope);
+                write_gc(out, 0x16, &[]);
+                write_signed(out, self.heap_type(cast));
+            }
+            Instruction::StructNew(name, fields) => {
+                self.encode_body(out, fields, scope);
+                let index = self.defined(name);
+                write_gc(out, 0x00, &[index]);
+            }
+            Instruction::StructGet(name, field, value) => {
+                self.encode_instruction(out, value, scope);
+                let index = self.defined(name);
+                write_gc(out, 0x02, &[index, *field as u64]);
+            }
+            Instruction::ArrayNew {
+                name,
+                value,
+                length,
+            } => {
+                self.encode_instruction(out, value, scope);
+                self.encode_instruction(out, length, scope);
+                let index = self.defined(name);
+                write_gc(out, 0x06, &[index]);
+            }
+            Instruction::ArrayNewFixed(name, elements) => {
+                self.encode_body(out, elements, scope);
+                let index = self.defined(name);
+                write_gc(out, 0x08, &[index, elements.len() as u64]);
+            }
+            Instruction::ArrayGet {
+                name,
+                packed,
+                array,
+                index,
+            } => {
+                self.encode_instruction(out, array, scope);
+                self.encode_instruction(out, index, scope);
+                let opcode = if *packed { 0x0d } else { 0x0b };
+                let type_index = self.defined(name);
+                write_gc(out, opcode, &[type_index]);
+            }
+            Instruction::ArraySet {
+                name,
+                array,
+                index,
+                value,
+            } => {
+                self.encode_instruction(out, array, scope);
+                self.encode_instruction(out, index, scope);
+                self.encode_instruction(out, value, scope);
+                let type_index = self.defined(name);
+                write_gc(out, 0x0e, &[type_index]);
+            }
+            Instruction::ArrayLen(array) => {
+                self.encode_instruction(out, array, scope);
+                write_gc(out, 0x0f, &[]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::binary::{write_signed, write_unsigned};
+
+    #[test]
+    fn encode_leb128() {
+        let mut out = Vec::new();
+        write_unsigned(&mut out, 624485);
+        assert_eq!(out, vec![0xe5, 0x8e, 0x26]);
+
+        let mut out = Vec::new();
+        write_signed(&mut out, -123456);
+        assert_eq!(out, vec![0xc0, 0xbb, 0x78]);
+
+        let mut out = Vec::new();
+        write_signed(&mut out, 64);
+        assert_eq!(out, vec![0xc0, 0x00]);
+    }
+}
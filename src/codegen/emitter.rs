@@ -1,175 +1,3176 @@
-use crate::codegen::instructions::{OpData, Opcodes, SysCalls, Types, WASIImports};
-use crate::frontend::ast::{ConstantLiteral, ListDetails, MainDetails, Node};
-use crate::frontend::scanner::Lexeme;
-use crate::frontend::scanner::Lexeme::StringLiteral;
+use crate::codegen::binary;
+use crate::codegen::environment::Environment;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, WASIImports};
+use crate::codegen::module::{Export, Function, Global, Local, Module, Signature, ValueType};
+use crate::codegen::object;
+use crate::codegen::pool::StringPool;
+use crate::codegen::runtime::{
+    RuntimeFunction, ALLOCATED, COLLECT_AFTER, HEAP, HEAP_START, INVOKE, PAGE_SIZE, ROOTS, SHADOW,
+    SHADOW_STACK_SIZE, THRESHOLD,
+};
+use crate::codegen::shadow::root_locals;
+use crate::codegen::validate::{validate, ValidationError};
+use crate::codegen::value::{self, Tag, FALSE, INTEGER_MAX, INTEGER_MIN, TRUE};
+use crate::codegen::wat;
+use crate::frontend::ast::{
+    Arity, CondClause, ConstantLiteral, FnDetails, FunctionDetails, IfDetails, LetDetails,
+    ListDetails, MainDetails, MapItem, Node, RecurDetails, VariableInformation, WhenDetails,
+};
+use crate::frontend::scanner::{Lexeme, Position};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    InvalidFunctionName,
+    InvalidParameter,
+    InvalidBinding,
+    ArgumentCount(String),
+    // a call to a function that has no arity taking that many arguments
+    ArityMismatch(Position, String, usize),
+    // integers are tagged, which leaves them one bit short of an i32
+    IntegerOutOfRange(i32),
+    UnsupportedForm(String),
+    InvalidModule(ValidationError),
+}
+
+/// How values are represented in the module
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    // tagged i32s, with objects on a heap in linear memory that the module
+    // collects itself
+    Linear,
+    // references from the garbage collection proposal, collected by the
+    // engine
+    WasmGc,
+}
+
+/// What an expression is known to evaluate to at compile time. Parameters
+/// and call results are `Unknown`, and have their tag checked at run time
+/// wherever it matters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Integer,
+    String,
+    Boolean,
+    Nil,
+    Function,
+    Vector,
+    Map,
+    Set,
+    Keyword,
+    Unknown,
+    // the expression jumps elsewhere and never produces a value
+    Never,
+}
+
+impl Kind {
+    /// Whether every value of the kind is truthy, when that is known
+    fn truthiness(self) -> Option<bool> {
+        match self {
+            Kind::Integer
+            | Kind::String
+            | Kind::Function
+            | Kind::Vector
+            | Kind::Map
+            | Kind::Set
+            | Kind::Keyword => Some(true),
+            Kind::Nil => Some(false),
+            Kind::Boolean | Kind::Unknown | Kind::Never => None,
+        }
+    }
+
+    /// The kind of a value that may come from either of two expressions
+    fn join(self, other: Kind) -> Kind {
+        match (self, other) {
+            (Kind::Never, kind) | (kind, Kind::Never) => kind,
+            _ if self == other => self,
+            _ => Kind::Unknown,
+        }
+    }
+}
+
+/// Compiled code together with what it evaluates to
+struct Expression {
+    instruction: Instruction,
+    kind: Kind,
+}
+
+impl Expression {
+    fn new(instruction: Instruction, kind: Kind) -> Self {
+        Expression { instruction, kind }
+    }
+}
+
+/// What a name in scope refers to
+struct Binding {
+    local: String,
+    kind: Kind,
+}
+
+/// A loop or function body that recur jumps back to, and the locals it
+/// rebinds before jumping
+struct Target {
+    label: String,
+    locals: Vec<String>,
+    used: bool,
+}
+
+/// One arity of a function: the wasm function it is compiled to, its number
+/// of fixed parameters and whether it takes the rest as a seq
+#[derive(Clone)]
+struct Overload {
+    function: String,
+    required: usize,
+    variadic: bool,
+}
+
+impl Overload {
+    /// Names the wasm function of every arity. A function with a single fixed
+    /// arity can keep the name it is given.
+    fn of(name: &str, arities: &Vec<Arity>, keep_name: bool) -> Vec<Overload> {
+        arities
+            .iter()
+            .map(|arity| {
+                let function = match (&arity.rest, keep_name && arities.len() == 1) {
+                    (None, true) => name.to_owned(),
+                    (None, false) => format!("{}#{}", name, arity.args.len()),
+                    (Some(_), _) => format!("{}#rest", name),
+                };
+                Overload {
+                    function,
+                    required: arity.args.len(),
+                    variadic: arity.rest.is_some(),
+                }
+            })
+            .collect()
+    }
+
+    /// Picks the arity that a call with `count` arguments runs, preferring a
+    /// fixed arity over the variadic one
+    fn resolve(overloads: &Vec<Overload>, count: usize) -> Option<&Overload> {
+        overloads
+            .iter()
+            .find(|overload| !overload.variadic && overload.required == count)
+            .or_else(|| {
+                overloads
+                    .iter()
+                    .find(|overload| overload.variadic && overload.required <= count)
+            })
+    }
+}
+
+/// What the functions lifted out of a fn need to see its surroundings
+struct Closure<'a> {
+    name: Option<&'a String>,
+    // captured variables with the local holding them where the fn is created
+    captured: &'a Vec<(String, String, Kind)>,
+}
+
+/// State of the function being emitted
+struct Context {
+    parameters: Vec<String>,
+    // let bound locals
+    locals: Vec<Local>,
+    // locals holding raw i32s rather than values, which the collector must
+    // not see
+    raw: Vec<String>,
+    environment: Environment<Binding>,
+    // name of the function being emitted
+    function: String,
+    // innermost last
+    targets: Vec<Target>,
+    // labels handed out so far, to keep them unique
+    labels: Vec<String>,
+}
+
+impl Context {
+    /// A fresh scope chain holding only the parameters
+    fn new(parameters: Vec<String>) -> Self {
+        let mut environment = Environment::new();
+        for name in &parameters {
+            let binding = Binding {
+                local: name.to_owned(),
+                kind: Kind::Unknown,
+            };
+            environment.define(name, binding);
+        }
+        Context {
+            parameters,
+            locals: Vec::new(),
+            raw: Vec::new(),
+            environment,
+            function: String::new(),
+            targets: Vec::new(),
+            labels: Vec::new(),
+        }
+    }
+}
+
+/// Name of the generated function that runs the top level forms
+const START: &str = "_start";
+/// First parameter of every function in the table, the closure record it
+/// was called through
+const CLOSURE: &str = "closure#";
+/// The other parameters of a function in the table: the number of arguments
+/// and the arguments themselves as a seq
+const COUNT: &str = "count#";
+const ARGUMENTS: &str = "arguments#";
+/// Cell of the argument seq a dispatcher has just read
+const SPENT: &str = "spent#";
 
 pub struct Emitter {
     imports: Vec<WASIImports>,
-    data: Vec<OpData>,
+    strings: StringPool,
+    runtime: Vec<RuntimeFunction>,
+    definitions: Vec<Function>,
+    // arities of every function defined at the top level
+    functions: HashMap<String, Vec<Overload>>,
+    // vars created by def, in the order they were first defined
+    globals: Vec<(String, Kind)>,
+    context: Context,
+    // context and body of the start function, built up from the top level forms
+    start: Context,
+    top_level: Vec<Instruction>,
+    // whether the target supports the tail call proposal
+    tail_calls: bool,
+    // functions callable through closures, in table order
+    table: Option<Vec<String>>,
+    // table index of the adapter that lets a defn be used as a value
+    function_values: HashMap<String, usize>,
+    // locals of every function that may hold values, kept on the shadow
+    // stack once the program allocates
+    rooted: HashMap<String, Vec<String>>,
+    // whether every allocation runs a collection
+    gc_stress: bool,
+    backend: Backend,
+    // addresses of the string literals used with wasm-gc, each of which is
+    // turned into an object once at start
+    string_objects: Vec<i32>,
+    // addresses of the names of the keywords used with wasm-gc, which are
+    // turned into objects the same way
+    keyword_objects: Vec<i32>,
 }
 
 impl Emitter {
     pub(crate) fn new() -> Self {
         Emitter {
             imports: Vec::new(),
-            data: Vec::new(),
+            strings: StringPool::new(),
+            runtime: Vec::new(),
+            definitions: Vec::new(),
+            functions: HashMap::new(),
+            globals: Vec::new(),
+            context: Context::new(vec![]),
+            start: Context::new(vec![]),
+            top_level: Vec::new(),
+            tail_calls: false,
+            table: None,
+            function_values: HashMap::new(),
+            rooted: HashMap::new(),
+            gc_stress: false,
+            backend: Backend::Linear,
+            string_objects: Vec::new(),
+            keyword_objects: Vec::new(),
         }
     }
 
-    pub fn emit(&mut self, head: Vec<Node>) -> String {
-        let body = self.build_body(&head);
-        self.get_body_with_header(body)
+    /// Lets calls in tail position use `return_call`
+    pub fn with_tail_calls(mut self, enabled: bool) -> Self {
+        self.tail_calls = enabled;
+        self
     }
 
-    fn get_body_with_header(&mut self, mut body: Vec<String>) -> String {
-        body.insert(0, "(module ".to_owned());
-        body.insert(
-            1,
-            self.imports
-                .iter()
-                .map(|item| return item.to_string())
-                .collect(),
-        );
-        body.insert(2, self.emit_memory_initializer());
-        body.insert(
-            3,
-            self.data
+    /// Collects garbage on every allocation, to shake out values that are
+    /// not rooted
+    pub fn with_gc_stress(mut self, enabled: bool) -> Self {
+        self.gc_stress = enabled;
+        self
+    }
+
+    /// Chooses how values are represented
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Compiles the program into the WebAssembly text format
+    pub fn emit(&mut self, head: Vec<Node>) -> Result<String, CompileError> {
+        let module = self.build_module(&head)?;
+        Ok(wat::print(&module))
+    }
+
+    /// Compiles the program into the WebAssembly binary format
+    pub fn emit_binary(&mut self, head: Vec<Node>) -> Result<Vec<u8>, CompileError> {
+        let module = self.build_module(&head)?;
+        Ok(binary::encode(&module))
+    }
+
+    fn build_module(&mut self, head: &Vec<Node>) -> Result<Module, CompileError> {
+        self.declare_functions(head);
+        for node in head {
+            match node {
+                Node::Function(details) => self.emit_function_definition(details)?,
+                Node::Main(details) => self.emit_main_function(details)?,
+                _ => self.emit_top_level_form(node)?,
+            }
+        }
+        self.emit_start_function();
+        // maps and keywords called as functions go through invoke
+        let mut invoke = None;
+        if self.runtime.contains(&RuntimeFunction::FunctionIndex) {
+            invoke = Some(self.add_to_table(RuntimeFunction::Invoke.name()));
+        }
+        let collected = self.runtime.contains(&RuntimeFunction::Allocate);
+        if collected {
+            self.require_runtime(RuntimeFunction::PushFrame);
+        }
+
+        let strings = &mut self.strings;
+        let backend = self.backend;
+        let mut functions: Vec<Function> = self
+            .runtime
+            .iter()
+            .map(|item| item.definition(strings, backend))
+            .collect();
+        functions.append(&mut self.definitions);
+        let vars: Vec<String> = self
+            .globals
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        if collected {
+            for function in functions.iter_mut() {
+                let rooted = match self.rooted.get(&function.name) {
+                    Some(rooted) => rooted.clone(),
+                    None => match self
+                        .runtime
+                        .iter()
+                        .find(|item| item.name() == function.name)
+                    {
+                        Some(item) if !item.roots().is_empty() => {
+                            item.roots().iter().map(|root| root.to_string()).collect()
+                        }
+                        _ => continue,
+                    },
+                };
+                root_locals(function, &rooted, &vars);
+            }
+        }
+        let mut globals: Vec<Global> = self
+            .globals
+            .iter()
+            .map(|(name, _)| name.to_owned())
+            .chain(
+                self.string_objects
+                    .iter()
+                    .map(|address| string_object(*address)),
+            )
+            .chain(
+                self.keyword_objects
+                    .iter()
+                    .map(|address| keyword_object(*address)),
+            )
+            .map(|name| Global {
+                name,
+                value_type: object::value_type(self.backend),
+                initial: object::nil(self.backend),
+            })
+            .collect();
+        if let Some(index) = invoke {
+            globals.push(Global {
+                name: INVOKE.to_owned(),
+                value_type: ValueType::I32,
+                initial: Instruction::Const(index as i32),
+            });
+        }
+        let mut types = vec![];
+        if self.backend == Backend::WasmGc {
+            types = gc::types();
+            globals.extend(gc::globals());
+        }
+        // the static data is followed by the root slots of the vars, the
+        // shadow stack and the heap. The memory starts out just large enough
+        // to hold them.
+        let mut heap = self.strings.end();
+        if collected {
+            let roots = heap;
+            let shadow = roots + 4 * vars.len() as i32;
+            heap = (shadow + SHADOW_STACK_SIZE + 7) & !7;
+            let threshold = if self.gc_stress { 0 } else { COLLECT_AFTER };
+            let runtime_globals = [
+                (HEAP, heap),
+                (HEAP_START, heap),
+                (ROOTS, roots),
+                (SHADOW, shadow),
+                (ALLOCATED, 0),
+                (THRESHOLD, threshold),
+            ];
+            globals.extend(runtime_globals.iter().map(|(name, initial)| Global {
+                name: name.to_string(),
+                value_type: ValueType::I32,
+                initial: Instruction::Const(*initial),
+            }));
+        }
+        let module = Module {
+            types,
+            imports: self.imports.iter().map(|item| item.import()).collect(),
+            memory_pages: ((heap + PAGE_SIZE - 1) / PAGE_SIZE).max(1) as u32,
+            globals,
+            table: self.table.clone(),
+            functions,
+            exports: vec![
+                Export::Memory("memory".to_owned()),
+                Export::Function("_start".to_owned(), START.to_owned()),
+            ],
+            data: self.strings.segments().clone(),
+        };
+        validate(&module).map_err(CompileError::InvalidModule)?;
+        Ok(module)
+    }
+
+    /// Top level forms are emitted into the start function in source order,
+    /// the same way a script runs. main is only run when the script calls it.
+    fn emit_top_level_form(&mut self, node: &Node) -> Result<(), CompileError> {
+        std::mem::swap(&mut self.context, &mut self.start);
+        let result = match node {
+            Node::Def(details) => self.emit_def(details),
+            _ => self.emit_instructions(node).map(Instruction::drop),
+        };
+        std::mem::swap(&mut self.context, &mut self.start);
+        self.top_level.push(result?);
+        Ok(())
+    }
+
+    /// With wasm-gc, the string literals are turned into objects before the
+    /// top level forms run
+    fn emit_start_function(&mut self) {
+        let context = std::mem::replace(&mut self.start, Context::new(vec![]));
+        self.rooted
+            .insert(START.to_owned(), rooted_locals(&context));
+        let mut body = Vec::new();
+        for address in self.string_objects.clone() {
+            let string = self.call_runtime(
+                RuntimeFunction::NewString,
+                vec![Instruction::Const(address)],
+            );
+            body.push(Instruction::global_set(&string_object(address), string));
+        }
+        for address in self.keyword_objects.clone() {
+            let name = self.call_runtime(
+                RuntimeFunction::NewString,
+                vec![Instruction::Const(address)],
+            );
+            let keyword = Instruction::StructNew(gc::KEYWORD.to_owned(), vec![name]);
+            body.push(Instruction::global_set(&keyword_object(address), keyword));
+        }
+        body.append(&mut self.top_level);
+        self.definitions.push(Function {
+            name: START.to_owned(),
+            params: vec![],
+            result: None,
+            locals: context.locals,
+            body,
+        });
+    }
+
+    /// Record every top level function up front so calls can be resolved
+    /// regardless of the order the functions are defined in
+    fn declare_functions(&mut self, nodes: &Vec<Node>) {
+        for node in nodes {
+            match node {
+                Node::Function(details) => {
+                    if let box Node::Variable(name) = &details.name {
+                        let overloads = Overload::of(name, &details.arities, true);
+                        self.functions.insert(name.to_owned(), overloads);
+                    }
+                }
+                Node::Main(details) => {
+                    let overload = Overload {
+                        function: "main".to_owned(),
+                        required: details.args.len(),
+                        variadic: false,
+                    };
+                    self.functions.insert("main".to_owned(), vec![overload]);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn emit_instructions(&mut self, tree: &Node) -> Result<Instruction, CompileError> {
+        Ok(self.emit_expression(tree)?.instruction)
+    }
+
+    fn emit_expression(&mut self, tree: &Node) -> Result<Expression, CompileError> {
+        let instruction = match tree {
+            Node::Variable(name) => return self.emit_variable(name),
+            Node::Let(details) => return self.emit_let(details),
+            Node::Loop(details) => return self.emit_loop(details),
+            Node::Recur(details) => return self.emit_recur(details),
+            Node::If(details) => return self.emit_if(details),
+            Node::When(details) => return self.emit_when(details),
+            Node::Do(body) => return self.emit_do(body),
+            Node::Cond(clauses) => return self.emit_cond(clauses),
+            Node::List(list) => return self.emit_function_call(list),
+            Node::TailCall(list) => return self.emit_tail_call(list),
+            Node::Fn(details) => return self.emit_fn(details),
+            Node::Null => object::nil(self.backend),
+            Node::Constant(constant) => self.emit_constant(constant)?,
+            Node::Main(_) | Node::Function(_) => {
+                return Err(CompileError::UnsupportedForm(
+                    "nested function definition".to_owned(),
+                ))
+            }
+            // def evaluates to nil, as vars are not values yet
+            Node::Def(details) => {
+                let def = self.emit_def(details)?;
+                self.value_block(vec![def, object::nil(self.backend)])
+            }
+            Node::KeywordLiteral(name) => {
+                let keyword = self.emit_keyword(name);
+                return Ok(Expression::new(keyword, Kind::Keyword));
+            }
+            Node::Keyword(details) => {
+                return Err(CompileError::UnsupportedForm(format!(
+                    "{:?} as a value",
+                    details.token
+                )))
+            }
+            Node::Map(items) => {
+                let map = self.emit_map(items)?;
+                return Ok(Expression::new(map, Kind::Map));
+            }
+            Node::Vector(items) => {
+                let vector = self.emit_vector(items)?;
+                return Ok(Expression::new(vector, Kind::Vector));
+            }
+            Node::Set(items) => {
+                let set = self.emit_set(items)?;
+                return Ok(Expression::new(set, Kind::Set));
+            }
+        };
+        Ok(Expression {
+            instruction,
+            kind: self.kind_of(tree),
+        })
+    }
+
+    fn emit_main_function(&mut self, details: &MainDetails) -> Result<(), CompileError> {
+        let parameters = self.parameter_names(&details.args)?;
+        self.enter_function("main", parameters);
+        let body = self.emit_function_body(details.body.as_ref())?;
+        self.leave_function("main", None, body);
+        Ok(())
+    }
+
+    fn emit_function_definition(&mut self, details: &FunctionDetails) -> Result<(), CompileError> {
+        let name = match &details.name {
+            box Node::Variable(name) => name.to_owned(),
+            _ => return Err(CompileError::InvalidFunctionName),
+        };
+        let overloads = self.functions[&name].clone();
+        for (arity, overload) in details.arities.iter().zip(overloads) {
+            self.emit_arity(&overload.function, arity, None)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles one arity into a function of its own, with the rest bound to
+    /// its last parameter. Functions lifted out of a fn take the closure
+    /// record first and load the captured variables from it.
+    fn emit_arity(
+        &mut self,
+        function: &str,
+        arity: &Arity,
+        closure: Option<Closure>,
+    ) -> Result<(), CompileError> {
+        let mut parameters = self.parameter_names(&arity.args)?;
+        match &arity.rest {
+            Some(box Node::Variable(name)) => parameters.push(name.to_owned()),
+            Some(_) => return Err(CompileError::InvalidParameter),
+            None => {}
+        }
+        let outer = std::mem::replace(&mut self.context, Context::new(vec![]));
+        let mut body = Vec::new();
+        match closure {
+            None => self.enter_function(function, parameters),
+            Some(closure) => {
+                let own = parameters.clone();
+                self.enter_function(function, [vec![CLOSURE.to_owned()], own].concat());
+                // recur rebinds the parameters, the closure stays the same
+                self.context.targets[0].locals.remove(0);
+                for (field, (variable, _, kind)) in closure.captured.iter().enumerate() {
+                    // parameters shadow whatever the fn could capture
+                    if parameters.contains(variable) {
+                        continue;
+                    }
+                    let local = self.declare_local(variable);
+                    let value = match self.backend {
+                        Backend::Linear => {
+                            let offset = 4 * (field as u32 + 2);
+                            Instruction::load_offset(Instruction::get(CLOSURE), offset)
+                        }
+                        Backend::WasmGc => gc::get_element(
+                            gc::get_field(gc::CLOSURE, 1, Instruction::get(CLOSURE)),
+                            Instruction::Const(field as i32),
+                        ),
+                    };
+                    body.push(Instruction::set(&local, value));
+                    let binding = Binding { local, kind: *kind };
+                    self.context.environment.define(variable, binding);
+                }
+                if let Some(name) = closure.name {
+                    let binding = Binding {
+                        local: CLOSURE.to_owned(),
+                        kind: Kind::Function,
+                    };
+                    self.context.environment.define(name, binding);
+                }
+            }
+        }
+        let result = match self.emit_sequence(&arity.body) {
+            Ok((mut instructions, _)) => {
+                body.append(&mut instructions);
+                self.leave_function(function, Some(object::value_type(self.backend)), body);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        };
+        // back to where the function is defined
+        self.context = outer;
+        result
+    }
+
+    /// Starts a function whose body a recur can jump back to
+    fn enter_function(&mut self, name: &str, parameters: Vec<String>) {
+        self.context = Context::new(parameters.clone());
+        self.context.function = name.to_owned();
+        let label = self.fresh_label("recur");
+        self.context.targets.push(Target {
+            label,
+            locals: parameters,
+            used: false,
+        });
+    }
+
+    fn leave_function(&mut self, name: &str, result: Option<ValueType>, body: Vec<Instruction>) {
+        let mut context = std::mem::replace(&mut self.context, Context::new(vec![]));
+        self.rooted.insert(name.to_owned(), rooted_locals(&context));
+        let value_type = object::value_type(self.backend);
+        let body = match context.targets.pop() {
+            Some(target) if target.used => vec![Instruction::Loop {
+                label: target.label,
+                result,
+                body,
+            }],
+            _ => body,
+        };
+        self.definitions.push(Function {
+            name: name.to_owned(),
+            params: context
+                .parameters
                 .iter()
-                .map(|item| return item.to_string())
+                .map(|name| Local {
+                    name: name.to_owned(),
+                    value_type,
+                })
                 .collect(),
+            result,
+            locals: context.locals,
+            body,
+        });
+    }
+
+    fn parameter_names(&self, args: &Vec<Node>) -> Result<Vec<String>, CompileError> {
+        args.iter()
+            .map(|arg| match arg {
+                Node::Variable(name) => Ok(name.to_owned()),
+                _ => Err(CompileError::InvalidParameter),
+            })
+            .collect()
+    }
+
+    fn emit_function_body(&mut self, body: &Vec<Node>) -> Result<Vec<Instruction>, CompileError> {
+        let mut instructions = Vec::new();
+        for expression in body {
+            // main does not return anything
+            instructions.push(Instruction::drop(self.emit_instructions(expression)?));
+        }
+        Ok(instructions)
+    }
+
+    /// Every expression produces a value, only the last one is kept
+    fn emit_sequence(
+        &mut self,
+        body: &Vec<Node>,
+    ) -> Result<(Vec<Instruction>, Kind), CompileError> {
+        let mut instructions = Vec::new();
+        let mut kind = Kind::Nil;
+        let last = body.len().saturating_sub(1);
+        for (index, node) in body.iter().enumerate() {
+            let expression = self.emit_expression(node)?;
+            if index != last {
+                instructions.push(Instruction::drop(expression.instruction));
+            } else {
+                instructions.push(expression.instruction);
+                kind = expression.kind;
+            }
+        }
+        if instructions.is_empty() {
+            // an empty body evaluates to nil
+            instructions.push(object::nil(self.backend));
+        }
+        Ok((instructions, kind))
+    }
+
+    fn emit_variable(&mut self, name: &String) -> Result<Expression, CompileError> {
+        if let Some(binding) = self.context.environment.resolve(name) {
+            return Ok(Expression::new(
+                Instruction::get(&binding.local),
+                binding.kind,
+            ));
+        }
+        // anything not bound locally may be a var
+        match self.globals.iter().find(|(global, _)| global == name) {
+            Some((_, kind)) => Ok(Expression::new(Instruction::global_get(name), *kind)),
+            None if self.functions.contains_key(name) => self.emit_function_value(name),
+            None => Err(CompileError::UndefinedVariable(name.to_owned())),
+        }
+    }
+
+    /// Lifts every arity of the fn into its own wasm function, reached
+    /// through a dispatcher in the table. Evaluates to a closure record
+    /// holding the table index followed by the values of the variables the
+    /// fn captures.
+    fn emit_fn(&mut self, details: &FnDetails) -> Result<Expression, CompileError> {
+        let bodies = details.arities.iter().flat_map(|arity| &arity.body);
+        let captured: Vec<(String, String, Kind)> = free_variables(bodies)
+            .into_iter()
+            .filter(|name| Some(name) != details.name.as_ref())
+            .filter_map(|name| {
+                let binding = self.context.environment.resolve(&name)?;
+                Some((name, binding.local.to_owned(), binding.kind))
+            })
+            .collect();
+        let prefix = details.name.as_deref().unwrap_or("fn");
+        // the slot is taken before the arities, so a fn nested in them gets
+        // a name of its own
+        let name = format!("{}#{}", prefix, self.table.as_ref().map_or(0, Vec::len));
+        let index = self.add_to_table(&name);
+        let overloads = Overload::of(&name, &details.arities, false);
+        for (arity, overload) in details.arities.iter().zip(&overloads) {
+            let closure = Closure {
+                name: details.name.as_ref(),
+                captured: &captured,
+            };
+            self.emit_arity(&overload.function, arity, Some(closure))?;
+        }
+        self.emit_dispatcher(index, prefix, &overloads, true);
+
+        let values = captured
+            .iter()
+            .map(|(_, local, _)| Instruction::get(local))
+            .collect();
+        Ok(Expression::new(
+            self.emit_closure(index, values),
+            Kind::Function,
+        ))
+    }
+
+    /// A defn used as a value is called through a dispatcher in the table,
+    /// created the first time it is needed
+    fn emit_function_value(&mut self, name: &str) -> Result<Expression, CompileError> {
+        let index = match self.function_values.get(name) {
+            Some(index) => *index,
+            None => {
+                let overloads = self.functions[name].clone();
+                let index = self.add_to_table(&format!("{}#fn", name));
+                self.emit_dispatcher(index, name, &overloads, false);
+                self.function_values.insert(name.to_owned(), index);
+                index
+            }
+        };
+        Ok(Expression::new(
+            self.emit_closure(index, vec![]),
+            Kind::Function,
+        ))
+    }
+
+    /// Defines the entry point of a function value, named by the slot of the
+    /// table reserved for it. It takes the closure record, the argument count
+    /// and the arguments as a seq, and calls the arity that accepts that many
+    /// arguments with them unpacked.
+    fn emit_dispatcher(
+        &mut self,
+        index: usize,
+        source: &str,
+        overloads: &Vec<Overload>,
+        closure: bool,
+    ) {
+        let name = self.table.as_ref().map(|table| table[index].to_owned());
+        let name = name.expect("the slot of a dispatcher is reserved first");
+        let most = overloads.iter().map(|overload| overload.required).max();
+        let unpacked: Vec<String> = (0..most.unwrap_or(0))
+            .map(|index| format!("arg{}", index))
+            .collect();
+        let message = format!(
+            "ArityException: Wrong number of args passed to {}\n",
+            source
         );
-        body.append(self.emit_export().as_mut());
-        body.push(")".to_owned());
+        let message = Instruction::Const(self.strings.intern(&message));
+        let mut body = vec![
+            self.call_runtime(RuntimeFunction::Fail, vec![message]),
+            object::nil(self.backend),
+        ];
+        // the fixed arities are tried first, the failure comes last
+        let mut ordered = overloads.clone();
+        ordered.sort_by_key(|overload| overload.variadic);
+        for overload in ordered.iter().rev() {
+            let count = Instruction::Const(overload.required as i32);
+            let op = if overload.variadic {
+                BinaryOp::GreaterEqual
+            } else {
+                BinaryOp::Equal
+            };
+            let test = Instruction::binary(op, Instruction::get(COUNT), count);
+            let mut then = Vec::new();
+            let mut args = Vec::new();
+            if closure {
+                args.push(Instruction::get(CLOSURE));
+            }
+            // the seq was built for this call, so its cells can go once read
+            for local in &unpacked[..overload.required] {
+                let first =
+                    self.call_runtime(RuntimeFunction::First, vec![Instruction::get(ARGUMENTS)]);
+                let next =
+                    self.call_runtime(RuntimeFunction::Next, vec![Instruction::get(ARGUMENTS)]);
+                then.push(Instruction::set(local, first));
+                match self.backend {
+                    Backend::Linear => {
+                        then.push(Instruction::set(SPENT, Instruction::get(ARGUMENTS)));
+                        then.push(Instruction::set(ARGUMENTS, next));
+                        let spent = Instruction::get(SPENT);
+                        then.push(self.call_runtime(RuntimeFunction::Free, vec![spent]));
+                    }
+                    Backend::WasmGc => then.push(Instruction::set(ARGUMENTS, next)),
+                }
+                args.push(Instruction::get(local));
+            }
+            // what is left over is the rest
+            if overload.variadic {
+                args.push(Instruction::get(ARGUMENTS));
+            }
+            then.push(self.call_overload(&overload.function, args));
+            body = vec![object::choose(self.backend, test, then, body)];
+        }
 
-        body.join("\n ")
+        let rooted = [
+            vec![CLOSURE.to_owned(), ARGUMENTS.to_owned()],
+            unpacked.clone(),
+        ]
+        .concat();
+        self.rooted.insert(name.to_owned(), rooted);
+        let value = |name: &str| Local {
+            name: name.to_owned(),
+            value_type: object::value_type(self.backend),
+        };
+        let mut locals: Vec<Local> = unpacked.iter().map(|local| value(local)).collect();
+        if self.backend == Backend::Linear {
+            locals.insert(0, Local::i32(SPENT));
+        }
+        self.definitions.push(Function {
+            name,
+            params: vec![value(CLOSURE), Local::i32(COUNT), value(ARGUMENTS)],
+            result: Some(object::value_type(self.backend)),
+            locals,
+            body,
+        });
     }
 
-    fn build_body(&mut self, nodes: &Vec<Node>) -> Vec<String> {
-        let mut body = Vec::<String>::new();
-        for node in nodes {
-            body.append(self.emit_instructions(node).as_mut())
+    /// Builds a seq holding the values, in order
+    fn emit_seq(&mut self, values: Vec<Instruction>) -> Instruction {
+        let mut seq = object::nil(self.backend);
+        for value in values.into_iter().rev() {
+            seq = self.call_runtime(RuntimeFunction::Cons, vec![value, seq]);
+        }
+        seq
+    }
+
+    /// Fills the tail of a new vector with up to 32 of the items and conjs
+    /// the rest on. The vector is kept in a local while the items run.
+    fn emit_vector(&mut self, items: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let tail = self.declare_local("tail");
+        let vector = self.declare_local("vector");
+        let length = Instruction::Const(items.len().min(32) as i32);
+        let mut body = vec![Instruction::set(
+            &tail,
+            self.call_runtime(RuntimeFunction::NewArray, vec![length.clone()]),
+        )];
+        for (index, item) in items.iter().enumerate().take(32) {
+            let value = self.emit_instructions(item)?;
+            body.push(object::set_element(
+                self.backend,
+                Instruction::get(&tail),
+                Instruction::Const(index as i32),
+                value,
+            ));
+        }
+        let new_vector = self.call_runtime(
+            RuntimeFunction::NewVector,
+            vec![
+                object::box_integer(self.backend, length),
+                object::box_integer(self.backend, Instruction::Const(5)),
+                object::nil(self.backend),
+                Instruction::get(&tail),
+            ],
+        );
+        body.push(Instruction::set(&vector, new_vector));
+        for item in items.iter().skip(32) {
+            let value = self.emit_instructions(item)?;
+            let conj = self.call_runtime(
+                RuntimeFunction::VectorConj,
+                vec![Instruction::get(&vector), value],
+            );
+            body.push(Instruction::set(&vector, conj));
+        }
+        body.push(Instruction::get(&vector));
+        Ok(self.value_block(body))
+    }
+
+    /// Allocates a closure record and fills it in. The table index is tagged
+    /// like an integer, so the collector can treat every field as a value.
+    /// With wasm-gc the captured values go in an array of their own.
+    fn emit_closure(&mut self, index: usize, values: Vec<Instruction>) -> Instruction {
+        if self.backend == Backend::WasmGc {
+            let captured = if values.is_empty() {
+                Instruction::RefNull
+            } else {
+                Instruction::ArrayNewFixed(gc::VALUES.to_owned(), values)
+            };
+            let index = Instruction::Const(index as i32);
+            return Instruction::StructNew(gc::CLOSURE.to_owned(), vec![index, captured]);
         }
-        body
+        let record = self.declare_local("closure");
+        let size = Instruction::Const(4 * (values.len() as i32 + 2));
+        let mut body = vec![
+            Instruction::set(
+                &record,
+                self.call_runtime(RuntimeFunction::Allocate, vec![size]),
+            ),
+            Instruction::store(
+                Instruction::get(&record),
+                Instruction::Const(Tag::Function.header(values.len() as i32 + 1)),
+            ),
+            Instruction::store_offset(
+                Instruction::get(&record),
+                4,
+                value::box_integer(Instruction::Const(index as i32)),
+            ),
+        ];
+        for (field, value) in values.into_iter().enumerate() {
+            let offset = 4 * (field as u32 + 2);
+            body.push(Instruction::store_offset(
+                Instruction::get(&record),
+                offset,
+                value,
+            ));
+        }
+        body.push(Instruction::get(&record));
+        Instruction::block(body)
+    }
+
+    fn add_to_table(&mut self, name: &str) -> usize {
+        let table = self.table.get_or_insert_with(Vec::new);
+        table.push(name.to_owned());
+        table.len() - 1
     }
 
-    fn emit_instructions(&mut self, tree: &Node) -> Vec<String> {
-        let mut body = Vec::<String>::new();
-        match tree {
-            Node::List(list) => body.append(self.emit_function_call(list).as_mut()),
-            Node::Null => {}
-            Node::Main(details) => body.append(self.emit_main_function(details).as_mut()),
-            Node::Def(_) => {}
-            Node::Function(_) => {}
-            Node::Constant(constant) => body.append(self.emit_constant(constant).as_mut()),
-            Node::Keyword(_) => {}
-            Node::Variable(_) => {}
-            Node::Map(_) => {}
-            Node::Vector(_) => {}
+    /// Calls a closure through the table, passing the record itself, the
+    /// number of arguments and the arguments as a seq. A callee that may not
+    /// be a function is checked first.
+    fn emit_indirect_call(
+        &mut self,
+        callee: &Node,
+        args: &Vec<Node>,
+    ) -> Result<Expression, CompileError> {
+        let Expression {
+            instruction: callee,
+            kind,
+        } = self.emit_expression(callee)?;
+        let local = self.declare_local("callee");
+        let values = self.emit_arguments(args, true)?;
+        let operands = vec![
+            Instruction::get(&local),
+            Instruction::Const(args.len() as i32),
+            self.emit_seq(values),
+        ];
+        // the table has to exist even when no closure was created
+        self.table.get_or_insert_with(Vec::new);
+        let signature = Signature {
+            params: vec![
+                object::value_type(self.backend),
+                ValueType::I32,
+                object::value_type(self.backend),
+            ],
+            result: Some(object::value_type(self.backend)),
+        };
+        let index = match (kind, self.backend) {
+            (Kind::Function, Backend::Linear) => {
+                value::unbox_integer(Instruction::load_offset(Instruction::get(&local), 4))
+            }
+            (Kind::Function, Backend::WasmGc) => {
+                gc::get_field(gc::CLOSURE, 0, Instruction::get(&local))
+            }
+            _ => self.call_runtime(
+                RuntimeFunction::FunctionIndex,
+                vec![Instruction::get(&local)],
+            ),
         };
-        body
+
+        Ok(Expression::new(
+            self.value_block(vec![
+                Instruction::set(&local, callee),
+                Instruction::CallIndirect(signature, operands, Box::new(index)),
+            ]),
+            Kind::Unknown,
+        ))
     }
 
-    fn emit_main_function(&mut self, details: &MainDetails) -> Vec<String> {
-        let mut types = Vec::new();
-        for (index, _) in details.args.iter().enumerate() {
-            types.push(Types::I32param(index).to_string());
+    /// Sets a var to the value, creating it on first use. A var that is
+    /// redefined with a different kind of value loses its static kind.
+    fn emit_def(&mut self, details: &VariableInformation) -> Result<Instruction, CompileError> {
+        let name = match &details.name {
+            box Node::Variable(name) => name,
+            _ => return Err(CompileError::InvalidBinding),
+        };
+        let value = self.emit_expression(&details.value)?;
+        match self.globals.iter_mut().find(|(global, _)| global == name) {
+            Some((_, kind)) => *kind = kind.join(value.kind),
+            None => self.globals.push((name.to_owned(), value.kind)),
         }
-        let mut body = self.emit_function_body(details.body.as_ref());
-        let mut function = vec!["(func $main ".to_owned()];
-        function.append(types.as_mut());
-        function.append(body.as_mut());
-        function.push(")".to_owned());
-        function
+        Ok(Instruction::global_set(name, value.instruction))
     }
 
-    fn emit_function_body(&mut self, body: &Vec<Node>) -> Vec<String> {
+    /// Binds each name to a fresh local in turn, so later values and the body
+    /// see the earlier bindings
+    fn emit_let(&mut self, details: &LetDetails) -> Result<Expression, CompileError> {
+        self.context.environment.enter_scope();
+        let (mut instructions, _) = self.emit_bindings(&details.bindings, false)?;
+        let (mut body, kind) = self.emit_sequence(&details.body)?;
+        instructions.append(&mut body);
+        self.context.environment.exit_scope();
+
+        Ok(Expression {
+            instruction: self.value_block(instructions),
+            kind,
+        })
+    }
+
+    /// Binds each name to a fresh local in the current scope. Loop bindings
+    /// can be replaced by recur with any kind of value, so their kind is not
+    /// known.
+    fn emit_bindings(
+        &mut self,
+        bindings: &Vec<VariableInformation>,
+        rebindable: bool,
+    ) -> Result<(Vec<Instruction>, Vec<String>), CompileError> {
         let mut instructions = Vec::new();
-        for expression in body {
-            instructions.append(self.emit_instructions(expression).as_mut());
+        let mut locals = Vec::new();
+        for binding in bindings {
+            let name = match &binding.name {
+                box Node::Variable(name) => name,
+                _ => return Err(CompileError::InvalidBinding),
+            };
+            let value = self.emit_expression(&binding.value)?;
+            let local = self.declare_local(name);
+            instructions.push(Instruction::set(&local, value.instruction));
+            let kind = if rebindable {
+                Kind::Unknown
+            } else {
+                value.kind
+            };
+            self.context.environment.define(
+                name,
+                Binding {
+                    local: local.to_owned(),
+                    kind,
+                },
+            );
+            locals.push(local);
         }
-        instructions
+        Ok((instructions, locals))
+    }
+
+    /// The bindings are set once, then the body runs in a wasm loop that
+    /// recur branches back to
+    fn emit_loop(&mut self, details: &LetDetails) -> Result<Expression, CompileError> {
+        self.context.environment.enter_scope();
+        let (mut instructions, locals) = self.emit_bindings(&details.bindings, true)?;
+        let label = self.fresh_label("loop");
+        self.context.targets.push(Target {
+            label: label.to_owned(),
+            locals,
+            used: false,
+        });
+        let (body, kind) = self.emit_sequence(&details.body)?;
+        self.context.targets.pop();
+        self.context.environment.exit_scope();
+
+        instructions.push(Instruction::Loop {
+            label,
+            result: Some(object::value_type(self.backend)),
+            body,
+        });
+        Ok(Expression::new(self.value_block(instructions), kind))
+    }
+
+    fn emit_recur(&mut self, details: &RecurDetails) -> Result<Expression, CompileError> {
+        self.emit_jump("recur", &details.args)
     }
 
-    fn emit_function_call(&mut self, list: &ListDetails) -> Vec<String> {
-        if let box Node::Keyword(details) = &list.head {
-            match &details.token {
-                &Lexeme::Plus => self.emit_add_function(&list.rest),
-                &Lexeme::Minus => self.emit_subtract_function(&list.rest),
-                &Lexeme::Print => self.emit_print_function(&list.rest),
-                _ => vec![],
+    /// Rebinds the locals of the innermost loop or function and jumps back to
+    /// its start. Every new value is computed before any local changes.
+    fn emit_jump(&mut self, name: &str, args: &Vec<Node>) -> Result<Expression, CompileError> {
+        let (label, locals) = match self.context.targets.last_mut() {
+            Some(target) => {
+                target.used = true;
+                (target.label.to_owned(), target.locals.clone())
             }
+            None => {
+                return Err(CompileError::UnsupportedForm(
+                    "recur outside a loop".to_owned(),
+                ))
+            }
+        };
+        if args.len() != locals.len() {
+            return Err(CompileError::ArgumentCount(name.to_owned()));
+        }
+        // the values end up in locals, which keeps them rooted
+        let values = args
+            .iter()
+            .map(|argument| self.emit_instructions(argument))
+            .collect::<Result<Vec<Instruction>, CompileError>>()?;
+
+        let mut body = Vec::new();
+        if values.len() == 1 {
+            body.extend(
+                values
+                    .into_iter()
+                    .map(|value| Instruction::set(&locals[0], value)),
+            );
         } else {
-            vec![]
+            let mut temporaries = Vec::new();
+            for value in values {
+                let temporary = self.declare_local("next");
+                body.push(Instruction::set(&temporary, value));
+                temporaries.push(temporary);
+            }
+            for (local, temporary) in locals.iter().zip(temporaries.iter()) {
+                body.push(Instruction::set(local, Instruction::get(temporary)));
+            }
+        }
+        body.push(Instruction::Branch(label));
+        Ok(Expression::new(self.value_block(body), Kind::Never))
+    }
+
+    fn fresh_label(&mut self, prefix: &str) -> String {
+        let mut label = prefix.to_owned();
+        let mut count = 0;
+        while self.context.labels.contains(&label) {
+            count += 1;
+            label = format!("{}_{}", prefix, count);
         }
+        self.context.labels.push(label.to_owned());
+        label
     }
 
-    fn emit_export(&self) -> Vec<String> {
-        vec!["(export \"_start\" (func $main))".to_owned()]
+    /// Evaluates to a non-zero i32 exactly when the node is truthy. Only nil
+    /// and false are falsey.
+    fn emit_condition(&mut self, node: &Node) -> Result<Instruction, CompileError> {
+        let Expression { instruction, kind } = self.emit_expression(node)?;
+        Ok(match kind.truthiness() {
+            Some(truthy) => Instruction::block(vec![
+                Instruction::drop(instruction),
+                Instruction::Const(truthy as i32),
+            ]),
+            None => self.truthy(instruction),
+        })
     }
 
-    // Perhaps these functions are collapsible
-    fn emit_add_function(&mut self, args: &Vec<Node>) -> Vec<String> {
-        let mut body = vec![Opcodes::Add.to_string()];
-        for argument in args {
-            body.append(self.emit_instructions(argument).as_mut())
+    /// `and` evaluates to the first falsey operand or the last one, `or` to
+    /// the first truthy operand or the last one. Later operands are only
+    /// evaluated when needed.
+    fn emit_logical(&mut self, and: bool, args: &[Node]) -> Result<Expression, CompileError> {
+        let (first, rest) = match args.split_first() {
+            Some(split) => split,
+            // (and) is true, (or) is nil
+            None if and => return Ok(Expression::new(self.boolean(true), Kind::Boolean)),
+            None => return Ok(Expression::new(object::nil(self.backend), Kind::Nil)),
+        };
+        let first = self.emit_expression(first)?;
+        if rest.is_empty() {
+            return Ok(first);
         }
-        body.push(")".to_owned());
-        body
+        let rest = self.emit_logical(and, rest)?;
+        let kind = first.kind.join(rest.kind);
+
+        let instruction = match first.kind.truthiness() {
+            // and moves on past a truthy operand, or past a falsey one
+            Some(truthy) if truthy == and => {
+                self.value_block(vec![Instruction::drop(first.instruction), rest.instruction])
+            }
+            Some(_) => first.instruction,
+            None => {
+                let local = self.declare_local("operand");
+                let (then, otherwise) = if and {
+                    (rest.instruction, Instruction::get(&local))
+                } else {
+                    (Instruction::get(&local), rest.instruction)
+                };
+                let condition = self.truthy(Instruction::get(&local));
+                self.value_block(vec![
+                    Instruction::set(&local, first.instruction),
+                    object::choose(self.backend, condition, vec![then], vec![otherwise]),
+                ])
+            }
+        };
+        Ok(Expression::new(instruction, kind))
     }
 
-    fn emit_subtract_function(&mut self, args: &Vec<Node>) -> Vec<String> {
-        let mut body = vec![Opcodes::Subtract.to_string()];
-        for argument in args {
-            body.append(self.emit_instructions(argument).as_mut())
+    fn emit_if(&mut self, details: &IfDetails) -> Result<Expression, CompileError> {
+        let condition = self.emit_condition(&details.condition)?;
+        let then = self.emit_expression(&details.then)?;
+        let otherwise = match &details.otherwise {
+            Some(otherwise) => self.emit_expression(otherwise)?,
+            None => Expression {
+                instruction: object::nil(self.backend),
+                kind: Kind::Nil,
+            },
+        };
+
+        Ok(Expression {
+            instruction: object::choose(
+                self.backend,
+                condition,
+                vec![then.instruction],
+                vec![otherwise.instruction],
+            ),
+            kind: then.kind.join(otherwise.kind),
+        })
+    }
+
+    fn emit_when(&mut self, details: &WhenDetails) -> Result<Expression, CompileError> {
+        let condition = self.emit_condition(&details.condition)?;
+        let (body, kind) = self.emit_sequence(&details.body)?;
+
+        Ok(Expression {
+            instruction: object::choose(
+                self.backend,
+                condition,
+                body,
+                vec![object::nil(self.backend)],
+            ),
+            kind: kind.join(Kind::Nil),
+        })
+    }
+
+    fn emit_do(&mut self, body: &Vec<Node>) -> Result<Expression, CompileError> {
+        let (body, kind) = self.emit_sequence(body)?;
+
+        Ok(Expression {
+            instruction: self.value_block(body),
+            kind,
+        })
+    }
+
+    /// Nests the clauses into ifs from the last one outwards. Without a
+    /// catch-all clause at the end, falling through evaluates to nil.
+    fn emit_cond(&mut self, clauses: &Vec<CondClause>) -> Result<Expression, CompileError> {
+        let mut clauses = clauses.iter().rev().peekable();
+        let mut result = match clauses.peek() {
+            Some(CondClause {
+                test: Node::Constant(ConstantLiteral::BooleanLiteral(true)),
+                body,
+            }) => {
+                clauses.next();
+                self.emit_expression(body)?
+            }
+            _ => Expression {
+                instruction: object::nil(self.backend),
+                kind: Kind::Nil,
+            },
+        };
+        for clause in clauses {
+            let condition = self.emit_condition(&clause.test)?;
+            let body = self.emit_expression(&clause.body)?;
+            result = Expression {
+                instruction: object::choose(
+                    self.backend,
+                    condition,
+                    vec![body.instruction],
+                    vec![result.instruction],
+                ),
+                kind: body.kind.join(result.kind),
+            };
         }
-        body.push(")".to_owned());
-        body
+        Ok(result)
     }
 
-    fn emit_print_function(&mut self, args: &Vec<Node>) -> Vec<String> {
-        self.imports.push(WASIImports::FDWrite);
-        let mut body = vec![];
-        for argument in args {
-            // build io vector
-            body.push(Opcodes::Store(0, 8).to_string());
-            body.push(Opcodes::Store(4, 12).to_string());
-            body.push(
-                SysCalls::Write(
-                    Opcodes::Const(1),
-                    Opcodes::Const(0),
-                    Opcodes::Const(1),
-                    Opcodes::Const(20),
-                )
-                .to_string(),
-            );
-            body.append(self.emit_instructions(argument).as_mut());
-            body.push(Opcodes::Drop.to_string());
+    /// Adds a local to the current function. Shadowed names get a numbered
+    /// suffix so every binding keeps its own slot.
+    fn declare_local(&mut self, name: &str) -> String {
+        let value_type = object::value_type(self.backend);
+        let context = &mut self.context;
+        let taken = |context: &Context, candidate: &str| {
+            context.parameters.iter().any(|param| param == candidate)
+                || context.locals.iter().any(|local| local.name == candidate)
+        };
+        let mut local = name.to_owned();
+        let mut suffix = 0;
+        while taken(context, &local) {
+            suffix += 1;
+            local = format!("{}_{}", name, suffix);
         }
-        body
+        context.locals.push(Local {
+            name: local.to_owned(),
+            value_type,
+        });
+        local
     }
 
-    fn emit_constant(&mut self, constant: &ConstantLiteral) -> Vec<String> {
-        match constant {
-            ConstantLiteral::IntegerLiteral(integer) => self.emit_integer_constant(*integer),
-            ConstantLiteral::StringLiteral(string) => self.emit_string_bytes(string),
+    /// Adds a local the collector does not look at
+    fn declare_raw_local(&mut self, name: &str, value_type: ValueType) -> String {
+        let local = self.declare_local(name);
+        if let Some(declared) = self.context.locals.last_mut() {
+            declared.value_type = value_type;
+        }
+        self.context.raw.push(local.to_owned());
+        local
+    }
+
+    fn emit_function_call(&mut self, list: &ListDetails) -> Result<Expression, CompileError> {
+        let args = &list.rest;
+        match &list.head {
+            box Node::Keyword(details) => match &details.token {
+                &Lexeme::Plus => Ok(Expression::new(
+                    self.emit_add_function(args)?,
+                    Kind::Integer,
+                )),
+                &Lexeme::Minus => Ok(Expression::new(
+                    self.emit_subtract_function(args)?,
+                    Kind::Integer,
+                )),
+                &Lexeme::Star => Ok(Expression::new(
+                    self.emit_multiply_function(args)?,
+                    Kind::Integer,
+                )),
+                &Lexeme::Slash => Ok(Expression::new(
+                    self.emit_divide_function(args)?,
+                    Kind::Integer,
+                )),
+                &Lexeme::Print => Ok(Expression::new(self.emit_print_function(args)?, Kind::Nil)),
+                &Lexeme::Less => self.emit_comparison("<", BinaryOp::LessThan, args),
+                &Lexeme::LessEqual => self.emit_comparison("<=", BinaryOp::LessEqual, args),
+                &Lexeme::Greater => self.emit_comparison(">", BinaryOp::GreaterThan, args),
+                &Lexeme::GreaterEqual => self.emit_comparison(">=", BinaryOp::GreaterEqual, args),
+                &Lexeme::Equal | &Lexeme::DoubleEqual => self.emit_equality(args),
+                &Lexeme::And => self.emit_logical(true, args),
+                &Lexeme::Or => self.emit_logical(false, args),
+                token => Err(CompileError::UnsupportedForm(format!("{:?}", token))),
+            },
+            box Node::KeywordLiteral(name) => self.emit_keyword_lookup(name, args),
+            // locals may hold closures and shadow every function
+            box Node::Variable(name) if self.context.environment.resolve(name).is_some() => {
+                self.emit_indirect_call(&list.head, args)
+            }
+            // functions defined in the program take precedence over built ins
+            box Node::Variable(name) if self.functions.contains_key(name) => {
+                let (function, args) = self.emit_direct_call(name, args, list.position)?;
+                Ok(Expression::new(
+                    self.call_overload(&function, args),
+                    Kind::Unknown,
+                ))
+            }
+            box Node::Variable(name) if self.globals.iter().any(|(global, _)| global == name) => {
+                self.emit_indirect_call(&list.head, args)
+            }
+            box Node::Variable(name) => self.emit_builtin_call(name, args, list.position),
+            box Node::Null | box Node::Constant(_) => {
+                Err(CompileError::UnsupportedForm("call".to_owned()))
+            }
+            head => self.emit_indirect_call(head, args),
+        }
+    }
+
+    /// A call to the function being defined, from outside any loop, jumps
+    /// back to the start of its body. With tail calls enabled, calls to other
+    /// functions reuse the caller's frame.
+    fn emit_tail_call(&mut self, list: &ListDetails) -> Result<Expression, CompileError> {
+        let name = match &list.head {
+            box Node::Variable(name)
+                if self.functions.contains_key(name)
+                    && self.context.environment.resolve(name).is_none() =>
+            {
+                name
+            }
+            _ => return self.emit_function_call(list),
+        };
+        let args = &list.rest;
+        let overload = match Overload::resolve(&self.functions[name], args.len()) {
+            Some(overload) => overload,
+            None => return self.emit_function_call(list),
+        };
+        let own = match self.context.targets.as_slice() {
+            [_] => overload.function == self.context.function && !overload.variadic,
+            _ => false,
+        };
+        // main returns nothing, so it cannot replace a function returning a value
+        if own {
+            self.emit_jump(name, args)
+        } else if self.tail_calls && name != "main" {
+            let (function, args) = self.emit_direct_call(name, args, list.position)?;
+            Ok(Expression::new(
+                Instruction::ReturnCall(function, args),
+                Kind::Never,
+            ))
+        } else {
+            self.emit_function_call(list)
+        }
+    }
+
+    /// Built in functions whose names are plain symbols
+    fn emit_builtin_call(
+        &mut self,
+        name: &String,
+        args: &Vec<Node>,
+        position: Position,
+    ) -> Result<Expression, CompileError> {
+        let integer = |instruction| Ok(Expression::new(instruction, Kind::Integer));
+        match name.as_str() {
+            "inc" => integer(self.emit_step(name, BinaryOp::Add, args)?),
+            "dec" => integer(self.emit_step(name, BinaryOp::Subtract, args)?),
+            "quot" => integer(self.emit_division(name, RuntimeFunction::Quotient, args)?),
+            "rem" => integer(self.emit_division(name, RuntimeFunction::Remainder, args)?),
+            "mod" => integer(self.emit_division(name, RuntimeFunction::Modulo, args)?),
+            // rest parameters are the only seqs so far
+            "first" => self.emit_seq_access(name, RuntimeFunction::First, args),
+            "next" => self.emit_seq_access(name, RuntimeFunction::Next, args),
+            "nil?" => self.emit_type_check(name, Tag::Nil, args),
+            "boolean?" => self.emit_type_check(name, Tag::Boolean, args),
+            "integer?" => self.emit_type_check(name, Tag::Integer, args),
+            "string?" => self.emit_type_check(name, Tag::String, args),
+            "fn?" => self.emit_type_check(name, Tag::Function, args),
+            "seq?" => self.emit_type_check(name, Tag::Seq, args),
+            "vector?" => self.emit_type_check(name, Tag::Vector, args),
+            "map?" => self.emit_type_check(name, Tag::Map, args),
+            "set?" => self.emit_type_check(name, Tag::Set, args),
+            "keyword?" => self.emit_type_check(name, Tag::Keyword, args),
+            "count" => match args.as_slice() {
+                [argument] => {
+                    let value = self.emit_instructions(argument)?;
+                    let count = self.call_runtime(RuntimeFunction::Count, vec![value]);
+                    integer(object::box_integer(self.backend, count))
+                }
+                _ => Err(CompileError::ArgumentCount(name.to_owned())),
+            },
+            "nth" => self.emit_collection_call(name, RuntimeFunction::Nth, args, &[2]),
+            "get" => self.emit_collection_call(name, RuntimeFunction::Get, args, &[2, 3]),
+            "peek" => self.emit_collection_call(name, RuntimeFunction::Peek, args, &[1]),
+            "pop" => self.emit_collection_call(name, RuntimeFunction::Pop, args, &[1]),
+            "subvec" => self.emit_collection_call(name, RuntimeFunction::Subvec, args, &[2, 3]),
+            "conj" => self.emit_collection_update(name, RuntimeFunction::Conj, args, 1),
+            "assoc" => self.emit_collection_update(name, RuntimeFunction::Assoc, args, 2),
+            "dissoc" => self.emit_collection_update(name, RuntimeFunction::Dissoc, args, 1),
+            "disj" => self.emit_collection_update(name, RuntimeFunction::Disj, args, 1),
+            "keys" => self.emit_collection_call(name, RuntimeFunction::Keys, args, &[1]),
+            "vals" => self.emit_collection_call(name, RuntimeFunction::Vals, args, &[1]),
+            "contains?" => match args.as_slice() {
+                [_, _] => {
+                    let values = self.emit_arguments(args, false)?;
+                    let contains = self.call_runtime(RuntimeFunction::Contains, values);
+                    Ok(Expression::new(self.box_boolean(contains), Kind::Boolean))
+                }
+                _ => Err(CompileError::ArgumentCount(name.to_owned())),
+            },
+            // merging nothing gives nil and merging one map gives it back
+            "merge" => match args.as_slice() {
+                [] => Ok(Expression::new(object::nil(self.backend), Kind::Nil)),
+                [map] => self.emit_expression(map),
+                _ => self.emit_collection_update(name, RuntimeFunction::Merge, args, 1),
+            },
+            "update" => self.emit_update(args, position),
+            "not" => match args.as_slice() {
+                [argument] => {
+                    let condition = self.emit_condition(argument)?;
+                    Ok(Expression::new(
+                        self.box_boolean(Instruction::unary(UnaryOp::EqualZero, condition)),
+                        Kind::Boolean,
+                    ))
+                }
+                _ => Err(CompileError::ArgumentCount(name.to_owned())),
+            },
+            "not=" => {
+                let equality = self.emit_raw_equality(args)?;
+                Ok(Expression::new(
+                    self.box_boolean(Instruction::unary(UnaryOp::EqualZero, equality)),
+                    Kind::Boolean,
+                ))
+            }
+            _ => Err(CompileError::UndefinedFunction(name.to_owned())),
         }
     }
 
-    fn emit_integer_constant(&self, constant: i32) -> Vec<String> {
-        vec![Opcodes::Const(constant).to_string()]
+    /// Chooses the arity at compile time, packing any arguments past the fixed
+    /// ones into a seq for a variadic arity
+    fn emit_direct_call(
+        &mut self,
+        name: &String,
+        args: &Vec<Node>,
+        position: Position,
+    ) -> Result<(String, Vec<Instruction>), CompileError> {
+        let overload = match Overload::resolve(&self.functions[name], args.len()) {
+            Some(overload) => overload.clone(),
+            None => {
+                return Err(CompileError::ArityMismatch(
+                    position,
+                    name.to_owned(),
+                    args.len(),
+                ))
+            }
+        };
+        let mut values = self.emit_arguments(args, overload.variadic)?;
+        if overload.variadic {
+            let rest = values.split_off(overload.required);
+            let seq = self.emit_seq(rest);
+            values.push(seq);
+        }
+        Ok((overload.function, values))
     }
 
-    fn emit_string_bytes(&mut self, constant: &String) -> Vec<String> {
-        let location = Opcodes::Const(8);
-        let data = format!("{}\n", constant);
-        self.data.push(OpData {
-            location,
-            data: data.parse().unwrap(),
+    /// Compares every argument with the next one, so `(< a b c)` holds when
+    /// the arguments are strictly increasing. Each argument is evaluated
+    /// exactly once.
+    fn emit_comparison(
+        &mut self,
+        name: &str,
+        op: BinaryOp,
+        args: &Vec<Node>,
+    ) -> Result<Expression, CompileError> {
+        if args.is_empty() {
+            return Err(CompileError::ArgumentCount(name.to_owned()));
+        }
+        let args = self.emit_integer_arguments(args)?;
+        let result = self.emit_pairwise(args, ValueType::I32, |left, right| {
+            Instruction::binary(op, left, right)
         });
-        vec![]
+        Ok(Expression::new(self.box_boolean(result), Kind::Boolean))
+    }
+
+    fn emit_equality(&mut self, args: &Vec<Node>) -> Result<Expression, CompileError> {
+        let equality = self.emit_raw_equality(args)?;
+        Ok(Expression::new(self.box_boolean(equality), Kind::Boolean))
+    }
+
+    /// Values of different kinds are never equal. Integers, booleans, nil,
+    /// functions and interned keywords are compared as they are, which with
+    /// wasm-gc compares the references and integers in an i31 by value.
+    /// Anything else may be a collection or a string, and is compared by the
+    /// equal routine. Evaluates to a wasm condition.
+    fn emit_raw_equality(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        if args.is_empty() {
+            return Err(CompileError::ArgumentCount("=".to_owned()));
+        }
+        let mut kinds = Vec::new();
+        let mut instructions = Vec::new();
+        for (index, argument) in args.iter().enumerate() {
+            let expression = self.emit_expression(argument)?;
+            if expression.kind != Kind::Unknown && !kinds.contains(&expression.kind) {
+                kinds.push(expression.kind);
+            }
+            instructions.push(if index + 1 < args.len() {
+                self.root_value(expression.instruction)
+            } else {
+                expression.instruction
+            });
+        }
+        if kinds.len() > 1 {
+            let mut body: Vec<Instruction> =
+                instructions.into_iter().map(Instruction::drop).collect();
+            body.push(Instruction::Const(0));
+            return Ok(Instruction::block(body));
+        }
+        let identical = [
+            Kind::Integer,
+            Kind::Boolean,
+            Kind::Nil,
+            Kind::Function,
+            Kind::Keyword,
+        ];
+        if kinds.len() != 1 || !identical.contains(&kinds[0]) {
+            let value_type = object::value_type(self.backend);
+            self.require_runtime(RuntimeFunction::Equal);
+            return Ok(self.emit_pairwise(instructions, value_type, |left, right| {
+                Instruction::call(RuntimeFunction::Equal.name(), vec![left, right])
+            }));
+        }
+        Ok(match self.backend {
+            Backend::Linear => self.emit_pairwise(instructions, ValueType::I32, |left, right| {
+                Instruction::binary(BinaryOp::Equal, left, right)
+            }),
+            Backend::WasmGc => self.emit_pairwise(instructions, ValueType::EqRef, |left, right| {
+                Instruction::RefEq(Box::new(left), Box::new(right))
+            }),
+        })
+    }
+
+    /// Applies a comparison to each neighbouring pair of values and combines
+    /// the results. With more than two values they are stored in locals
+    /// first, as each one takes part in two comparisons.
+    fn emit_pairwise<F>(
+        &mut self,
+        mut values: Vec<Instruction>,
+        value_type: ValueType,
+        compare: F,
+    ) -> Instruction
+    where
+        F: Fn(Instruction, Instruction) -> Instruction,
+    {
+        match values.len() {
+            1 => Instruction::block(vec![
+                Instruction::drop(values.remove(0)),
+                Instruction::Const(1),
+            ]),
+            2 => {
+                let right = values.remove(1);
+                compare(values.remove(0), right)
+            }
+            _ => {
+                let mut body = Vec::new();
+                let mut locals = Vec::new();
+                for value in values {
+                    let local = self.declare_raw_local("compare", value_type);
+                    body.push(Instruction::set(&local, value));
+                    locals.push(local);
+                }
+                let result = locals
+                    .windows(2)
+                    .map(|pair| compare(Instruction::get(&pair[0]), Instruction::get(&pair[1])))
+                    .fold(None, |chain, comparison| match chain {
+                        None => Some(comparison),
+                        Some(chain) => Some(Instruction::binary(BinaryOp::And, chain, comparison)),
+                    });
+                body.extend(result);
+                Instruction::block(body)
+            }
+        }
+    }
+
+    /// Evaluates the arguments in order. A value waiting on the operand stack
+    /// is invisible to the collector, so each one is rooted before the next
+    /// argument runs. When something allocates after the last argument, that
+    /// one is rooted as well.
+    fn emit_arguments(
+        &mut self,
+        args: &Vec<Node>,
+        then_allocates: bool,
+    ) -> Result<Vec<Instruction>, CompileError> {
+        let mut values = Vec::new();
+        for (index, argument) in args.iter().enumerate() {
+            let value = self.emit_instructions(argument)?;
+            values.push(if then_allocates || index + 1 < args.len() {
+                self.root_value(value)
+            } else {
+                value
+            });
+        }
+        Ok(values)
+    }
+
+    /// Keeps a value in a local of its own, which the shadow stack mirrors.
+    /// Constants and variables need no local, and neither does anything the
+    /// engine collects.
+    fn root_value(&mut self, value: Instruction) -> Instruction {
+        if self.backend == Backend::WasmGc {
+            return value;
+        }
+        match value {
+            Instruction::Const(_) | Instruction::LocalGet(_) | Instruction::GlobalGet(_) => value,
+            value => {
+                let local = self.declare_local("argument");
+                Instruction::block(vec![
+                    Instruction::set(&local, value),
+                    Instruction::get(&local),
+                ])
+            }
+        }
     }
 
-    fn emit_memory_initializer(&self) -> String {
-        String::from("(memory 1) (export \"memory\" (memory 0))")
+    /// Evaluates the arguments to raw i32s. Those not known to be integers
+    /// are checked at run time.
+    fn emit_integer_arguments(
+        &mut self,
+        args: &Vec<Node>,
+    ) -> Result<Vec<Instruction>, CompileError> {
+        let mut values = Vec::new();
+        for argument in args {
+            let Expression { instruction, kind } = self.emit_expression(argument)?;
+            values.push(match kind {
+                Kind::Integer => object::unbox_integer(self.backend, instruction),
+                _ => self.call_runtime(RuntimeFunction::IntegerValue, vec![instruction]),
+            });
+        }
+        Ok(values)
+    }
+
+    /// Checks a raw result fits in an integer. Two integers add or subtract
+    /// to at most 32 bits, so this is enough for either.
+    fn fit_integer(&mut self, value: Instruction) -> Instruction {
+        self.call_runtime(RuntimeFunction::FitInteger, vec![value])
+    }
+
+    /// A sum outside the range of an integer fails rather than wraps
+    fn emit_add_function(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let args = self.emit_integer_arguments(args)?;
+        self.require_runtime(RuntimeFunction::FitInteger);
+        Ok(object::box_integer(
+            self.backend,
+            fold(args, Instruction::Const(0), |left, right| {
+                Instruction::call(
+                    RuntimeFunction::FitInteger.name(),
+                    vec![Instruction::binary(BinaryOp::Add, left, right)],
+                )
+            }),
+        ))
+    }
+
+    fn emit_multiply_function(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let args = self.emit_integer_arguments(args)?;
+        self.require_runtime(RuntimeFunction::Multiply);
+        Ok(object::box_integer(
+            self.backend,
+            fold(args, Instruction::Const(1), |left, right| {
+                Instruction::call(RuntimeFunction::Multiply.name(), vec![left, right])
+            }),
+        ))
+    }
+
+    /// A single argument is negated, any more are subtracted from the first
+    fn emit_subtract_function(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let mut args = self.emit_integer_arguments(args)?;
+        self.require_runtime(RuntimeFunction::FitInteger);
+        let difference = match args.len() {
+            0 => return Err(CompileError::ArgumentCount("-".to_owned())),
+            1 => self.fit_integer(Instruction::binary(
+                BinaryOp::Subtract,
+                Instruction::Const(0),
+                args.remove(0),
+            )),
+            _ => fold(args, Instruction::Const(0), |left, right| {
+                Instruction::call(
+                    RuntimeFunction::FitInteger.name(),
+                    vec![Instruction::binary(BinaryOp::Subtract, left, right)],
+                )
+            }),
+        };
+        Ok(object::box_integer(self.backend, difference))
+    }
+
+    /// There are no ratios, so division truncates like `quot`. A single
+    /// argument is divided into one.
+    fn emit_divide_function(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let mut args = self.emit_integer_arguments(args)?;
+        match args.len() {
+            0 => return Err(CompileError::ArgumentCount("/".to_owned())),
+            1 => args.insert(0, Instruction::Const(1)),
+            _ => {}
+        }
+        self.require_runtime(RuntimeFunction::Quotient);
+        Ok(object::box_integer(
+            self.backend,
+            fold(args, Instruction::Const(1), |left, right| {
+                Instruction::call(RuntimeFunction::Quotient.name(), vec![left, right])
+            }),
+        ))
+    }
+
+    /// Division routines check for a zero divisor before dividing
+    fn emit_division(
+        &mut self,
+        name: &str,
+        function: RuntimeFunction,
+        args: &Vec<Node>,
+    ) -> Result<Instruction, CompileError> {
+        if args.len() != 2 {
+            return Err(CompileError::ArgumentCount(name.to_owned()));
+        }
+        let args = self.emit_integer_arguments(args)?;
+        let quotient = self.call_runtime(function, args);
+        Ok(object::box_integer(self.backend, quotient))
+    }
+
+    fn emit_seq_access(
+        &mut self,
+        name: &str,
+        function: RuntimeFunction,
+        args: &Vec<Node>,
+    ) -> Result<Expression, CompileError> {
+        match args.as_slice() {
+            [argument] => {
+                let seq = self.emit_instructions(argument)?;
+                Ok(Expression::new(
+                    self.call_runtime(function, vec![seq]),
+                    Kind::Unknown,
+                ))
+            }
+            _ => Err(CompileError::ArgumentCount(name.to_owned())),
+        }
+    }
+
+    /// Calls a collection routine taking one of the given numbers of
+    /// arguments. A missing last argument is passed as nil.
+    fn emit_collection_call(
+        &mut self,
+        name: &str,
+        function: RuntimeFunction,
+        args: &Vec<Node>,
+        counts: &[usize],
+    ) -> Result<Expression, CompileError> {
+        if !counts.contains(&args.len()) {
+            return Err(CompileError::ArgumentCount(name.to_owned()));
+        }
+        let mut values = self.emit_arguments(args, false)?;
+        while values.len() < counts[counts.len() - 1] {
+            values.push(object::nil(self.backend));
+        }
+        Ok(Expression::new(
+            self.call_runtime(function, values),
+            Kind::Unknown,
+        ))
+    }
+
+    /// Applies a routine to the collection and each group of the following
+    /// arguments in turn, like `(conj coll a b)` or `(assoc coll k v)`. The
+    /// collection built so far stays in a local while the next group runs.
+    fn emit_collection_update(
+        &mut self,
+        name: &str,
+        function: RuntimeFunction,
+        args: &Vec<Node>,
+        group: usize,
+    ) -> Result<Expression, CompileError> {
+        let (collection, rest) = match args.split_first() {
+            Some((collection, rest)) if !rest.is_empty() && rest.len() % group == 0 => {
+                (collection, rest)
+            }
+            _ => return Err(CompileError::ArgumentCount(name.to_owned())),
+        };
+        let local = self.declare_local("collection");
+        let collection = self.emit_instructions(collection)?;
+        let mut body = vec![Instruction::set(&local, collection)];
+        for values in rest.chunks(group) {
+            let mut args = vec![Instruction::get(&local)];
+            for (index, value) in values.iter().enumerate() {
+                let value = self.emit_instructions(value)?;
+                args.push(if index + 1 < values.len() {
+                    self.root_value(value)
+                } else {
+                    value
+                });
+            }
+            let updated = self.call_runtime(function, args);
+            body.push(Instruction::set(&local, updated));
+        }
+        body.push(Instruction::get(&local));
+        Ok(Expression::new(self.value_block(body), Kind::Unknown))
+    }
+
+    /// Compares the tag of the value at run time
+    fn emit_type_check(
+        &mut self,
+        name: &str,
+        tag: Tag,
+        args: &Vec<Node>,
+    ) -> Result<Expression, CompileError> {
+        match args.as_slice() {
+            [argument] => {
+                let value = self.emit_instructions(argument)?;
+                let check = Instruction::binary(
+                    BinaryOp::Equal,
+                    self.call_runtime(RuntimeFunction::TypeOf, vec![value]),
+                    Instruction::Const(tag.code()),
+                );
+                Ok(Expression::new(self.box_boolean(check), Kind::Boolean))
+            }
+            _ => Err(CompileError::ArgumentCount(name.to_owned())),
+        }
+    }
+
+    fn emit_step(
+        &mut self,
+        name: &str,
+        op: BinaryOp,
+        args: &Vec<Node>,
+    ) -> Result<Instruction, CompileError> {
+        match self.emit_integer_arguments(args)?.as_mut_slice() {
+            [argument] => {
+                let step = Instruction::binary(
+                    op,
+                    std::mem::replace(argument, Instruction::Const(0)),
+                    Instruction::Const(1),
+                );
+                let step = self.fit_integer(step);
+                Ok(object::box_integer(self.backend, step))
+            }
+            _ => Err(CompileError::ArgumentCount(name.to_owned())),
+        }
+    }
+
+    fn emit_print_function(&mut self, args: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let mut body = vec![];
+        for (index, argument) in args.iter().enumerate() {
+            // arguments are separated by a single space
+            if index > 0 {
+                body.push(self.emit_print_literal(" "));
+            }
+            let Expression { instruction, kind } = self.emit_expression(argument)?;
+            match kind {
+                Kind::Integer => {
+                    let integer = object::unbox_integer(self.backend, instruction);
+                    body.push(self.call_runtime(RuntimeFunction::PrintInteger, vec![integer]))
+                }
+                // anything else is printed by its tag
+                Kind::Vector
+                | Kind::Map
+                | Kind::Set
+                | Kind::Keyword
+                | Kind::Unknown
+                | Kind::Never => {
+                    body.push(self.call_runtime(RuntimeFunction::PrintValue, vec![instruction]))
+                }
+                Kind::String => {
+                    let function = match self.backend {
+                        Backend::Linear => RuntimeFunction::PrintString,
+                        Backend::WasmGc => RuntimeFunction::PrintText,
+                    };
+                    body.push(self.call_runtime(function, vec![instruction]))
+                }
+                Kind::Boolean => {
+                    let then = self.emit_print_literal("true");
+                    let otherwise = self.emit_print_literal("false");
+                    body.push(Instruction::If {
+                        result: None,
+                        condition: Box::new(self.truthy(instruction)),
+                        then: vec![then],
+                        otherwise: vec![otherwise],
+                    });
+                }
+                Kind::Nil => {
+                    body.push(Instruction::drop(instruction));
+                    body.push(self.emit_print_literal("nil"));
+                }
+                Kind::Function => {
+                    body.push(Instruction::drop(instruction));
+                    body.push(self.emit_print_literal("#function"));
+                }
+            }
+        }
+        // print evaluates to nil
+        body.push(object::nil(self.backend));
+        Ok(self.value_block(body))
+    }
+
+    fn emit_print_literal(&mut self, text: &str) -> Instruction {
+        let address = Instruction::Const(self.strings.intern(text));
+        self.call_runtime(RuntimeFunction::PrintString, vec![address])
+    }
+
+    fn kind_of(&self, node: &Node) -> Kind {
+        match node {
+            Node::Constant(ConstantLiteral::IntegerLiteral(_)) => Kind::Integer,
+            Node::Constant(ConstantLiteral::StringLiteral(_)) => Kind::String,
+            Node::Constant(ConstantLiteral::BooleanLiteral(_)) => Kind::Boolean,
+            _ => Kind::Nil,
+        }
+    }
+
+    fn import(&mut self, import: WASIImports) {
+        if !self.imports.contains(&import) {
+            self.imports.push(import);
+        }
+    }
+
+    fn call_runtime(&mut self, function: RuntimeFunction, args: Vec<Instruction>) -> Instruction {
+        self.require_runtime(function);
+        Instruction::call(function.name(), args)
+    }
+
+    /// Adds a routine to the module along with everything it calls
+    fn require_runtime(&mut self, function: RuntimeFunction) {
+        if self.runtime.contains(&function) {
+            return;
+        }
+        self.runtime.push(function);
+        for import in function.imports() {
+            self.import(import);
+        }
+        for dependency in function.dependencies(self.backend) {
+            self.require_runtime(dependency);
+        }
+    }
+
+    fn emit_constant(&mut self, constant: &ConstantLiteral) -> Result<Instruction, CompileError> {
+        Ok(match constant {
+            ConstantLiteral::IntegerLiteral(integer) => self.emit_integer_constant(*integer)?,
+            ConstantLiteral::StringLiteral(string) => self.emit_string_bytes(string),
+            ConstantLiteral::BooleanLiteral(boolean) => self.boolean(*boolean),
+            ConstantLiteral::NilLiteral => object::nil(self.backend),
+        })
+    }
+
+    fn emit_integer_constant(&self, constant: i32) -> Result<Instruction, CompileError> {
+        if !(INTEGER_MIN..=INTEGER_MAX).contains(&constant) {
+            return Err(CompileError::IntegerOutOfRange(constant));
+        }
+        Ok(object::box_integer(
+            self.backend,
+            Instruction::Const(constant),
+        ))
+    }
+
+    fn emit_string_bytes(&mut self, constant: &String) -> Instruction {
+        // a string evaluates to its address in linear memory
+        let address = self.strings.intern(constant);
+        match self.backend {
+            Backend::Linear => Instruction::Const(address),
+            Backend::WasmGc => {
+                if !self.string_objects.contains(&address) {
+                    self.string_objects.push(address);
+                }
+                Instruction::global_get(&string_object(address))
+            }
+        }
+    }
+
+    /// A keyword evaluates to its interned name, so the same keyword is
+    /// always the same value
+    fn emit_keyword(&mut self, name: &str) -> Instruction {
+        let address = self.strings.intern_keyword(name);
+        match self.backend {
+            Backend::Linear => Instruction::Const(address),
+            Backend::WasmGc => {
+                if !self.keyword_objects.contains(&address) {
+                    self.keyword_objects.push(address);
+                }
+                Instruction::global_get(&keyword_object(address))
+            }
+        }
+    }
+
+    /// Assocs each entry onto an empty map in turn. The map is kept in a
+    /// local while the next entry runs, and the key while its value runs.
+    fn emit_map(&mut self, items: &Vec<MapItem>) -> Result<Instruction, CompileError> {
+        let map = self.declare_local("map");
+        let empty = self.call_runtime(
+            RuntimeFunction::NewMap,
+            vec![
+                object::box_integer(self.backend, Instruction::Const(0)),
+                object::nil(self.backend),
+            ],
+        );
+        let mut body = vec![Instruction::set(&map, empty)];
+        for item in items {
+            let key = self.emit_instructions(&item.key)?;
+            let key = self.root_value(key);
+            let value = self.emit_instructions(&item.value)?;
+            let assoc = self.call_runtime(
+                RuntimeFunction::MapAssoc,
+                vec![Instruction::get(&map), key, value],
+            );
+            body.push(Instruction::set(&map, assoc));
+        }
+        body.push(Instruction::get(&map));
+        Ok(self.value_block(body))
+    }
+
+    /// Conjs each element onto the map of an empty set, with nil for its
+    /// value, and wraps up the map at the end
+    fn emit_set(&mut self, items: &Vec<Node>) -> Result<Instruction, CompileError> {
+        let map = self.declare_local("map");
+        let empty = self.call_runtime(
+            RuntimeFunction::NewMap,
+            vec![
+                object::box_integer(self.backend, Instruction::Const(0)),
+                object::nil(self.backend),
+            ],
+        );
+        let mut body = vec![Instruction::set(&map, empty)];
+        for item in items {
+            let element = self.emit_instructions(item)?;
+            let assoc = self.call_runtime(
+                RuntimeFunction::MapAssoc,
+                vec![Instruction::get(&map), element, object::nil(self.backend)],
+            );
+            body.push(Instruction::set(&map, assoc));
+        }
+        body.push(self.call_runtime(RuntimeFunction::NewSet, vec![Instruction::get(&map)]));
+        Ok(self.value_block(body))
+    }
+
+    /// `(:key map)` looks the keyword up in the map, with an optional default
+    fn emit_keyword_lookup(
+        &mut self,
+        name: &str,
+        args: &Vec<Node>,
+    ) -> Result<Expression, CompileError> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(CompileError::ArgumentCount(format!(":{}", name)));
+        }
+        let mut values = self.emit_arguments(args, false)?;
+        values.insert(1, self.emit_keyword(name));
+        if values.len() < 3 {
+            values.push(object::nil(self.backend));
+        }
+        Ok(Expression::new(
+            self.call_runtime(RuntimeFunction::Get, values),
+            Kind::Unknown,
+        ))
+    }
+
+    /// `(update map key f args...)` calls f with the value of the key and
+    /// the args, and assocs what it returns. The call is compiled as if it
+    /// were written out, with the value bound to a name no program can use.
+    fn emit_update(
+        &mut self,
+        args: &Vec<Node>,
+        position: Position,
+    ) -> Result<Expression, CompileError> {
+        let (collection, key, function, rest) = match args.as_slice() {
+            [collection, key, function, rest @ ..] => (collection, key, function, rest),
+            _ => return Err(CompileError::ArgumentCount("update".to_owned())),
+        };
+        let collection_local = self.declare_local("collection");
+        let key_local = self.declare_local("key");
+        let current = self.declare_local("current");
+        let mut body = vec![
+            Instruction::set(&collection_local, self.emit_instructions(collection)?),
+            Instruction::set(&key_local, self.emit_instructions(key)?),
+        ];
+        let value = self.call_runtime(
+            RuntimeFunction::Get,
+            vec![
+                Instruction::get(&collection_local),
+                Instruction::get(&key_local),
+                object::nil(self.backend),
+            ],
+        );
+        body.push(Instruction::set(&current, value));
+        let name = "current#";
+        let call = ListDetails {
+            head: Box::new(function.clone()),
+            rest: [vec![Node::Variable(name.to_owned())], rest.to_vec()].concat(),
+            position,
+        };
+        self.context.environment.enter_scope();
+        self.context.environment.define(
+            name,
+            Binding {
+                local: current.to_owned(),
+                kind: Kind::Unknown,
+            },
+        );
+        let updated = self.emit_function_call(&call);
+        self.context.environment.exit_scope();
+        let assoc = self.call_runtime(
+            RuntimeFunction::Assoc,
+            vec![
+                Instruction::get(&collection_local),
+                Instruction::get(&key_local),
+                updated?.instruction,
+            ],
+        );
+        body.push(Instruction::set(&collection_local, assoc));
+        body.push(Instruction::get(&collection_local));
+        Ok(Expression::new(self.value_block(body), Kind::Unknown))
+    }
+
+    /// Calls one arity of a function defined in the program
+    fn call_overload(&self, function: &str, args: Vec<Instruction>) -> Instruction {
+        if function == "main" {
+            // main does not return anything, evaluate to nil instead
+            return self.value_block(vec![
+                Instruction::call(function, args),
+                object::nil(self.backend),
+            ]);
+        }
+        Instruction::call(function, args)
+    }
+
+    /// A block evaluating to a value
+    fn value_block(&self, body: Vec<Instruction>) -> Instruction {
+        Instruction::Block {
+            label: None,
+            result: Some(object::value_type(self.backend)),
+            body,
+        }
+    }
+
+    fn boolean(&self, boolean: bool) -> Instruction {
+        match self.backend {
+            Backend::Linear if boolean => Instruction::Const(TRUE),
+            Backend::Linear => Instruction::Const(FALSE),
+            Backend::WasmGc => gc::box_boolean(Instruction::Const(boolean as i32)),
+        }
+    }
+
+    fn box_boolean(&self, raw: Instruction) -> Instruction {
+        match self.backend {
+            Backend::Linear => value::box_boolean(raw),
+            Backend::WasmGc => gc::box_boolean(raw),
+        }
+    }
+
+    fn truthy(&mut self, value: Instruction) -> Instruction {
+        match self.backend {
+            Backend::Linear => value::truthy(value),
+            Backend::WasmGc => {
+                let truthy = gc::truthy(value);
+                if let Instruction::Call(..) = truthy {
+                    self.require_runtime(RuntimeFunction::Truthy);
+                }
+                truthy
+            }
+        }
+    }
+}
+
+/// Chains values together from the left, or gives the identity when there
+/// are none
+fn fold<F>(values: Vec<Instruction>, identity: Instruction, combine: F) -> Instruction
+where
+    F: Fn(Instruction, Instruction) -> Instruction,
+{
+    let mut values = values.into_iter();
+    match values.next() {
+        Some(first) => values.fold(first, combine),
+        None => identity,
+    }
+}
+
+/// Parameters and locals that may hold values
+fn rooted_locals(context: &Context) -> Vec<String> {
+    context
+        .parameters
+        .iter()
+        .chain(context.locals.iter().map(|local| &local.name))
+        .filter(|name| !context.raw.contains(name))
+        .cloned()
+        .collect()
+}
+
+/// Global holding the object of a string literal, with wasm-gc
+fn string_object(address: i32) -> String {
+    format!("string#{}", address)
+}
+
+fn keyword_object(address: i32) -> String {
+    format!("keyword#{}", address)
+}
+
+/// Every name referred to in the forms, which is all a function could
+/// capture from its surroundings
+fn free_variables<'a>(forms: impl Iterator<Item = &'a Node>) -> Vec<String> {
+    fn collect(node: &Node, names: &mut Vec<String>) {
+        if let Node::Variable(name) = node {
+            if !names.contains(name) {
+                names.push(name.to_owned());
+            }
+        }
+        for child in node.children() {
+            collect(child, names);
+        }
+    }
+    let mut names = Vec::new();
+    for node in forms {
+        collect(node, &mut names);
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::emitter::{Backend, CompileError, Emitter};
+    use crate::frontend::parser::Parser;
+    use crate::frontend::scanner::Position;
+    use wasmi::{
+        Error, Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryRef, Module,
+        ModuleImportResolver, ModuleInstance, RuntimeArgs, RuntimeValue, Signature, Trap, TrapKind,
+    };
+
+    fn compile(text: &str) -> Result<String, CompileError> {
+        let nodes = Parser::new(text).parse().unwrap();
+        Emitter::new().emit(nodes)
+    }
+
+    fn compile_binary(text: &str) -> Result<Vec<u8>, CompileError> {
+        let nodes = Parser::new(text).parse().unwrap();
+        Emitter::new().emit_binary(nodes)
+    }
+
+    /// The host side of the two WASI calls the module makes. Whatever is
+    /// written to any descriptor is kept, so failures can be checked too.
+    struct Wasi {
+        memory: Option<MemoryRef>,
+        output: Vec<u8>,
+        exited: bool,
+    }
+
+    const FD_WRITE: usize = 0;
+    const PROC_EXIT: usize = 1;
+
+    impl ModuleImportResolver for Wasi {
+        fn resolve_func(&self, field: &str, signature: &Signature) -> Result<FuncRef, Error> {
+            let index = match field {
+                "fd_write" => FD_WRITE,
+                "proc_exit" => PROC_EXIT,
+                _ => return Err(Error::Instantiation(field.to_owned())),
+            };
+            Ok(FuncInstance::alloc_host(signature.clone(), index))
+        }
+    }
+
+    impl Externals for Wasi {
+        fn invoke_index(
+            &mut self,
+            index: usize,
+            args: RuntimeArgs,
+        ) -> Result<Option<RuntimeValue>, Trap> {
+            if index == PROC_EXIT {
+                self.exited = true;
+                return Err(Trap::new(TrapKind::Unreachable));
+            }
+            let memory = self.memory.as_ref().unwrap();
+            let iovec: u32 = args.nth(1);
+            let start: u32 = memory.get_value(iovec).unwrap();
+            let length: u32 = memory.get_value(iovec + 4).unwrap();
+            let bytes = memory.get(start, length as usize).unwrap();
+            self.output.extend(bytes);
+            memory.set_value(args.nth::<u32>(3), length).unwrap();
+            Ok(Some(RuntimeValue::I32(0)))
+        }
+    }
+
+    /// Compiles the program for linear memory, runs it and returns what it
+    /// printed
+    fn run(text: &str) -> String {
+        execute(Emitter::new(), text).0
+    }
+
+    /// Runs the program collecting garbage on every allocation
+    fn run_stressed(text: &str) -> String {
+        execute(Emitter::new().with_gc_stress(true), text).0
+    }
+
+    /// Runs the program, returning what it printed and the pages of memory
+    /// it ended up with
+    fn execute(mut emitter: Emitter, text: &str) -> (String, usize) {
+        let nodes = Parser::new(text).parse().unwrap();
+        let binary = emitter.emit_binary(nodes).unwrap();
+        let module = Module::from_buffer(binary).unwrap();
+        let mut wasi = Wasi {
+            memory: None,
+            output: Vec::new(),
+            exited: false,
+        };
+        let imports = ImportsBuilder::new().with_resolver("wasi_unstable", &wasi);
+        let instance = ModuleInstance::new(&module, &imports)
+            .unwrap()
+            .assert_no_start();
+        let memory = instance.export_by_name("memory").unwrap();
+        wasi.memory = memory.as_memory().cloned();
+        // a failure exits, which ends the run early, but any other trap is a
+        // bug in the generated code
+        if let Err(error) = instance.invoke_export("_start", &[], &mut wasi) {
+            assert!(wasi.exited, "the program trapped: {:?}", error);
+        }
+        let pages = wasi.memory.as_ref().unwrap().current_size().0;
+        (String::from_utf8(wasi.output).unwrap(), pages)
+    }
+
+    #[test]
+    fn run_prints_what_the_program_writes() {
+        assert_eq!(run("(print 1 \"two\" :three)"), "1 two :three");
+    }
+
+    #[test]
+    fn emit_function_definition() {
+        let output = compile("(defn add [x y] (+ x y))").unwrap();
+
+        assert!(output.contains(
+            "(func $add (param $x i32) (param $y i32) (result i32)\n    (i32.or\n      (i32.shl\n        \
+             (call $fit_integer\n          (i32.add\n            (call $integer_value (local.get $x))\n            \
+             (call $integer_value (local.get $y))))"
+        ))
+    }
+
+    #[test]
+    fn emit_function_call() {
+        let output = compile("(defn id [x] x) (defn main [] (id 1))").unwrap();
+
+        // integers are shifted left past their tag bit
+        assert!(output.contains("(call $id (i32.const 3))"))
+    }
+
+    #[test]
+    fn reject_undefined_function() {
+        assert_eq!(
+            compile("(defn main [] (missing 1))"),
+            Err(CompileError::UndefinedFunction("missing".to_owned()))
+        )
+    }
+
+    #[test]
+    fn print_integers_through_runtime() {
+        let output = compile("(defn main [] (print 1) (print (+ 2 3)))").unwrap();
+
+        assert!(output.contains("(call $print_integer (i32.const 1))"));
+        assert_eq!(output.matches("(func $print_integer").count(), 1);
+        // the sum fails on overflow, which exits through the second import
+        assert_eq!(output.matches("(import").count(), 2);
+    }
+
+    #[test]
+    fn strings_get_their_own_segments() {
+        let output = compile("(defn main [] (print \"one\") (print \"two\" \"one\"))").unwrap();
+
+        assert!(output.contains("(data (i32.const 128) \"\\03\\03\\00\\00one\")"));
+        assert!(output.contains("(data (i32.const 136) \"\\03\\03\\00\\00two\")"));
+        assert!(output.contains("(data (i32.const 144) \"\\03\\01\\00\\00 \")"));
+        assert_eq!(output.matches("(data").count(), 3);
+    }
+
+    #[test]
+    fn fold_arguments_into_binary_instructions() {
+        let output = compile("(defn sum [x y] (+ x y 5))").unwrap();
+
+        assert!(output.contains(
+            "(i32.add\n            (call $fit_integer\n              (i32.add\n                \
+             (call $integer_value (local.get $x))\n                \
+             (call $integer_value (local.get $y))))\n            (i32.const 5))"
+        ))
+    }
+
+    #[test]
+    fn emit_binary_module() {
+        let output = compile_binary("(defn main [] (print 1))").unwrap();
+
+        assert_eq!(
+            output[0..8],
+            [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]
+        );
+        // the type section comes first and holds fd_write, print_integer and main
+        assert_eq!(output[8..10], [0x01, 0x10]);
+        assert_eq!(output[10], 3);
+    }
+
+    #[test]
+    fn let_bindings_become_locals() {
+        let output = compile("(defn f [x] (let [x (+ x 1) y x] (let [x 5] y)))").unwrap();
+
+        assert!(output.contains("(local $x_1 i32) (local $y i32) (local $x_2 i32)"));
+        assert!(output.contains("(i32.add (call $integer_value (local.get $x)) (i32.const 1))"));
+        assert!(output.contains("(local.set $y (local.get $x_1))"));
+        assert!(output.contains("(local.get $y)"));
+    }
+
+    #[test]
+    fn reject_bindings_out_of_scope() {
+        assert_eq!(
+            compile("(defn f [] (let [x 1] x) x)"),
+            Err(CompileError::UndefinedVariable("x".to_owned()))
+        )
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsey() {
+        let output = compile("(defn f [x] (if 0 (if x 1 2) (if nil 3)))").unwrap();
+
+        assert!(output.contains("(if (result i32)\n      (block (result i32)\n        (drop (i32.const 1))\n        (i32.const 1))"));
+        assert!(
+            output.contains("(if (result i32)\n          (i32.and (local.get $x) (i32.const -3))")
+        );
+        assert!(output.contains("(drop (i32.const 0))\n            (i32.const 0))"));
+        assert_eq!(
+            run("(print (not nil) (not false) (not 0) (not \"\") (not []) (not true))"),
+            "true true false false false false"
+        );
+        assert_eq!(run("(print (and 0 \"\" :k) (or 0 1) (or [] 1))"), ":k 0 []");
+    }
+
+    #[test]
+    fn cond_falls_through_to_else() {
+        let output = compile("(defn f [x] (cond x 1 :else 2))").unwrap();
+
+        assert!(output.contains(
+            "(if (result i32)\n      (i32.and (local.get $x) (i32.const -3))\n      \
+             (then\n        (i32.const 3))\n      (else\n        (i32.const 5))))"
+        ))
+    }
+
+    #[test]
+    fn chain_variadic_comparisons() {
+        let output = compile("(defn f [x] (< 1 x 3))").unwrap();
+
+        assert!(output.contains("(local.set $compare (i32.const 1))"));
+        assert!(output.contains("(local.set $compare_1 (call $integer_value (local.get $x)))"));
+        assert!(output.contains(
+            "(i32.and\n            (i32.lt_s (local.get $compare) (local.get $compare_1))\n            \
+             (i32.lt_s (local.get $compare_1) (local.get $compare_2)))"
+        ));
+    }
+
+    #[test]
+    fn values_of_different_kinds_are_not_equal() {
+        let output = compile("(defn f [x] (not= nil false x))").unwrap();
+
+        assert!(output.contains("(i32.eqz\n          (block (result i32)"));
+        assert!(!output.contains("i32.eq "));
+    }
+
+    #[test]
+    fn values_of_unknown_kind_are_checked_by_tag() {
+        let output = compile("(defn f [x] (print x) (integer? x))").unwrap();
+
+        assert!(output.contains("(call $print_value (local.get $x))"));
+        assert!(output.contains("(i32.eq (call $type_of (local.get $x)) (i32.const 2))"));
+        assert_eq!(
+            compile("(print 1073741824)"),
+            Err(CompileError::IntegerOutOfRange(1073741824))
+        );
+    }
+
+    #[test]
+    fn arithmetic_identities_and_negation() {
+        let output = compile("(defn f [x] (+ (+) (*) (- x) (* x 2 3)))").unwrap();
+
+        assert!(output.contains("(i32.add (i32.const 0) (i32.const 1))"));
+        assert!(output.contains("(i32.sub (i32.const 0) (call $integer_value (local.get $x)))"));
+        assert!(
+            output.contains("(call $multiply (call $integer_value (local.get $x)) (i32.const 2))")
+        );
+    }
+
+    #[test]
+    fn division_checks_for_zero() {
+        let output = compile("(defn f [x] (mod x 3))").unwrap();
+
+        assert!(
+            output.contains("(call $modulo (call $integer_value (local.get $x)) (i32.const 3))")
+        );
+        assert!(output.contains("(call $fail (i32.const 128))"));
+        assert!(output.contains("(import \"wasi_unstable\" \"proc_exit\""));
+        assert!(compile("(defn f [x] (quot x))").is_err());
+    }
+
+    #[test]
+    fn arithmetic_fails_past_the_integer_range() {
+        let overflow = "ArithmeticException: integer overflow\n";
+
+        assert_eq!(
+            run("(print (+ 1073741822 1) (- -1073741823 1))"),
+            "1073741823 -1073741824"
+        );
+        assert_eq!(
+            run("(print (inc 1073741822) (dec -1073741823))"),
+            "1073741823 -1073741824"
+        );
+        assert_eq!(
+            run("(print (* 32767 32768) (* -32768 32768))"),
+            "1073709056 -1073741824"
+        );
+        assert_eq!(run("(print (* 0 1073741823) (* 1073741823 0))"), "0 0");
+        assert_eq!(run("(print (+ 1073741823 1))"), overflow);
+        assert_eq!(run("(print (- -1073741824 1))"), overflow);
+        assert_eq!(run("(print (- -1073741824))"), overflow);
+        assert_eq!(run("(print (inc 1073741823))"), overflow);
+        assert_eq!(run("(print (dec -1073741824))"), overflow);
+        assert_eq!(run("(print (* 32768 32768))"), overflow);
+        // wraps around an i32 into a product that would fit
+        assert_eq!(run("(print (* 65536 65536))"), overflow);
+        assert_eq!(
+            run("(print (quot -1073741823 -1) (/ 1073741823 -1))"),
+            "1073741823 -1073741823"
+        );
+        assert_eq!(run("(print (quot -1073741824 -1))"), overflow);
+        assert_eq!(run("(print (/ -1073741824 -1))"), overflow);
+        assert_eq!(
+            run("(print 1) (print (+ 1073741823 1)) (print 2)"),
+            format!("1{}", overflow)
+        );
+    }
+
+    #[test]
+    fn logical_operators_return_the_deciding_operand() {
+        let output = compile("(defn f [x y] (or x y))").unwrap();
+
+        assert!(output.contains("(local.set $operand (local.get $x))"));
+        assert!(output.contains(
+            "(if (result i32)\n        (i32.and (local.get $operand) (i32.const -3))\n        \
+             (then\n          (local.get $operand))\n        (else\n          (local.get $y)))"
+        ));
+        assert_eq!(
+            run("(print (and 1 nil 2) (and 1 2) (and false 1) (or nil false 3) (or false nil))"),
+            "nil 2 false 3 nil"
+        );
+        assert_eq!(run("(print (and) (or))"), "true nil");
+    }
+
+    #[test]
+    fn logical_operators_stop_at_the_deciding_operand() {
+        let say = "(defn say [x] (print x) x)";
+
+        assert_eq!(
+            run(&format!("{} (say (and (say 1) (say nil) (say 2)))", say)),
+            "1nilnil"
+        );
+        assert_eq!(
+            run(&format!("{} (say (or (say nil) (say 3) (say 4)))", say)),
+            "nil33"
+        );
+        assert_eq!(run(&format!("{} (say (and false (say 1)))", say)), "false");
+        assert_eq!(run(&format!("{} (say (or 2 (say 1)))", say)), "2");
+    }
+
+    #[test]
+    fn def_creates_globals_set_at_start() {
+        let output = compile("(def x 1) (defn f [] (def x (inc x)) x)").unwrap();
+
+        assert!(output.contains("(global $x (mut i32) (i32.const 0))"));
+        assert!(output.contains("(func $_start\n    (global.set $x (i32.const 3)))"));
+        assert!(
+            output.contains("(i32.add (i32.shr_s (global.get $x) (i32.const 1)) (i32.const 1))")
+        );
+        assert!(output.contains("(export \"_start\" (func $_start))"));
+    }
+
+    #[test]
+    fn run_top_level_forms_in_order() {
+        let output = compile("(print 1) (defn main [] (print 2)) (print 3)").unwrap();
+        let start = &output[output.find("(func $_start").unwrap()..];
+
+        assert!(start.find("(i32.const 1)").unwrap() < start.find("(i32.const 3)").unwrap());
+        assert!(!output.contains("(call $main"));
+        assert_eq!(run("(print 1) (defn main [] (print 2)) (print 3)"), "13");
+        assert_eq!(run("(def x 1) (print x) (def x 2) (print x)"), "12");
+    }
+
+    #[test]
+    fn main_runs_only_where_it_is_called() {
+        assert_eq!(run("(defn main [] (print 1))"), "");
+        assert_eq!(
+            run("(print 1) (defn main [] (print 2)) (main) (print 3)"),
+            "123"
+        );
+        assert_eq!(run("(defn main [] (print 1)) (main) (main)"), "11");
+        assert_eq!(run(include_str!("../../test/test.clj")), "Hello world\n8");
+    }
+
+    #[test]
+    fn recur_rebinds_after_computing_every_value() {
+        let output =
+            compile("(defn f [n acc] (if (<= n 1) acc (recur (dec n) (* n acc))))").unwrap();
+
+        assert!(output.contains("(loop $recur (result i32)"));
+        assert!(output.contains(
+            "(local.set $n (local.get $next))\n            (local.set $acc (local.get $next_1))"
+        ));
+        assert!(output.contains("(br $recur)"));
+    }
+
+    #[test]
+    fn loop_binds_locals_once() {
+        let output = compile("(defn f [] (loop [i 0] (if (< i 3) (recur (inc i)) i)))").unwrap();
+
+        assert!(output.contains("(local.set $i (i32.const 1))\n      (loop $loop (result i32)"));
+        assert!(output.contains("(i32.const 1)))\n              (br $loop)"));
+        assert!(!output.contains("$recur"));
+    }
+
+    #[test]
+    fn self_tail_calls_become_loops() {
+        let output = compile("(defn f [n] (if (= n 0) 0 (f (dec n))))").unwrap();
+
+        assert!(output.contains("(loop $recur (result i32)"));
+        assert!(output.contains("(br $recur)"));
+        assert!(!output.contains("(call $f ("));
+    }
+
+    #[test]
+    fn tail_calls_use_return_call_when_enabled() {
+        let text = "(defn f [n] (g n)) (defn g [n] (loop [i n] (f i)))";
+        let nodes = Parser::new(text).parse().unwrap();
+        let output = Emitter::new().with_tail_calls(true).emit(nodes).unwrap();
+
+        assert!(output.contains("(return_call $g (local.get $n))"));
+        assert!(output.contains("(return_call $f (local.get $i))"));
+        assert!(compile(text).unwrap().contains("(call $g (local.get $n))"));
+    }
+
+    #[test]
+    fn calls_choose_an_arity_at_compile_time() {
+        let text = "(defn f ([x] x) ([x & more] (first more))) (defn g [] (f 1 2 3))";
+        let output = compile(text).unwrap();
+
+        assert!(output.contains("(func $f#1 (param $x i32)"));
+        assert!(output.contains(
+            "(call $f#rest\n      (i32.const 3)\n      \
+             (call $cons (i32.const 5) (call $cons (i32.const 7) (i32.const 0))))"
+        ));
+        assert_eq!(
+            compile("(defn f [x] x)\n(defn g [] (f))"),
+            Err(CompileError::ArityMismatch(
+                Position {
+                    line: 2,
+                    column: 14
+                },
+                "f".to_owned(),
+                0
+            ))
+        );
+    }
+
+    #[test]
+    fn closures_capture_free_variables() {
+        let output = compile("(defn adder [n] (fn [x] (+ x n)))").unwrap();
+
+        assert!(output.contains("(table 1 funcref)\n  (elem (i32.const 0) $fn#0)"));
+        assert!(output.contains("(i32.store (local.get $closure) (i32.const 516))"));
+        assert!(output.contains("(i32.store offset=4 (local.get $closure) (i32.const 1))"));
+        assert!(output.contains("(i32.store offset=8 (local.get $closure) (local.get $n))"));
+        assert!(output.contains(
+            "(func $fn#0#1 (param $closure# i32) (param $x i32) (result i32) (local $n i32)"
+        ));
+        assert!(output.contains("(local.set $n (i32.load offset=8 (local.get $closure#)))"));
+    }
+
+    #[test]
+    fn allocations_past_the_memory_grow_it() {
+        let program = "(defn build [n acc] (if (= n 0) acc (recur (dec n) (conj acc n)))) \
+                       (def v (build 5000 [])) \
+                       (print (count v) (nth v 0) (nth v 4999) \"static\")";
+        let (output, pages) = execute(Emitter::new(), program);
+
+        // the string is static data, which the heap never hands out
+        assert_eq!(output, "5000 5000 1 static");
+        assert!(pages > 2);
+    }
+
+    #[test]
+    fn freed_blocks_are_handed_out_again() {
+        // a call through a value packs its arguments into a seq, which the
+        // dispatcher frees once it has read them
+        let program = "(def f (fn [x y z] z)) \
+                       (defn spin [n] (if (= n 0) (f 1 2 \"done\") (do (f n n n) (recur (dec n))))) \
+                       (print (spin 20000))";
+        let (output, pages) = execute(Emitter::new(), program);
+
+        assert_eq!(output, "done");
+        // too little for a collection, so only the freeing keeps it this small
+        assert_eq!(pages, 2);
+    }
+
+    #[test]
+    fn garbage_is_collected_before_the_memory_grows() {
+        let program = "(defn spin [n v] \
+                         (if (= n 0) (count v) (recur (dec n) (conj [n n n n n n n n] n)))) \
+                       (print (spin 20000 []))";
+        let (output, pages) = execute(Emitter::new(), program);
+
+        assert_eq!(output, "9");
+        // the 40000 vectors made take over 3 MiB, collections keep it to
+        // the first megabyte or so
+        assert!(pages < 24);
+    }
+
+    #[test]
+    fn heap_follows_the_static_data() {
+        let output = compile("(defn adder [n] (fn [x] (+ x n)))").unwrap();
+        let long = format!("(print \"{}\")", "a".repeat(70000));
+
+        // past the 64 KiB shadow stack
+        assert!(output.contains("(memory 2)\n  (global $heap# (mut i32) (i32.const 65888))"));
+        assert!(output.contains("(memory.grow"));
+        // arguments unpacked by a dispatcher are freed straight away
+        assert!(output.contains("(call $free (local.get $spent#))"));
+        assert!(compile(&long).unwrap().contains("(memory 2)"));
+        assert!(compile(&format!("{} (fn [])", long))
+            .unwrap()
+            .contains("(memory 3)"));
+    }
+
+    #[test]
+    fn locals_holding_values_live_on_the_shadow_stack() {
+        let text = "(def v (fn [])) (defn f [x] (let [y (fn [] x)] (< 1 2 3) (f y)))";
+        let output = compile(text).unwrap();
+
+        assert!(output.contains("(local.set $frame# (call $push_frame (i32.const 3)))"));
+        assert!(output.contains("(i32.store offset=8 (local.get $frame#) (local.get $y))"));
+        assert!(output.contains("(i32.store (global.get $roots#) (global.get $v))"));
+        assert!(output.contains("(global $threshold# (mut i32) (i32.const 1048576))"));
+        let stress = Emitter::new()
+            .with_gc_stress(true)
+            .emit(Parser::new(text).parse().unwrap())
+            .unwrap();
+        assert!(stress.contains("(global $threshold# (mut i32) (i32.const 0))"));
+    }
+
+    #[test]
+    fn collecting_on_every_allocation_keeps_live_values() {
+        let programs = [
+            (
+                "(defn upto [n] (loop [i 0 v []] (if (< i n) (recur (inc i) (conj v [i])) v))) \
+                 (def v (upto 40)) (print (count v) (nth v 33) (peek (pop v)) (subvec v 38))",
+                "40 [33] [38] [[38] [39]]",
+            ),
+            (
+                "(defn build [n m] (if (= n 0) m (recur (dec n) (assoc m [n] {:n n})))) \
+                 (def m (build 40 {})) (print (count m) (get m [7]) (get (dissoc m [7]) [7]))",
+                "40 {:n 7} nil",
+            ),
+            (
+                "(defn triple [x] (fn [y] (fn [z] [x y z]))) \
+                 (def fs [((triple :a) [:b]) ((triple {:c 1}) :d)]) \
+                 (print ((nth fs 0) :e) ((nth fs 1) :f) (conj #{[1]} [2] [1]))",
+                "[:a [:b] :e] [{:c 1} :d :f] #{[1] [2]}",
+            ),
+        ];
+        for (program, printed) in programs.iter() {
+            assert_eq!(run(program), *printed);
+            assert_eq!(run_stressed(program), *printed);
+        }
+    }
+
+    #[test]
+    fn wasm_gc_values_are_references() {
+        let text = "(defn adder [n] (fn [x] (+ x n))) (print ((adder 1) 2) \"hi\")";
+        let output = Emitter::new()
+            .with_backend(Backend::WasmGc)
+            .emit(Parser::new(text).parse().unwrap())
+            .unwrap();
+
+        assert!(output.contains("(type $closure (struct (field i32) (field eqref)))"));
+        assert!(output.contains(
+            "(struct.new $closure (i32.const 0) (array.new_fixed $values 1 (local.get $n)))"
+        ));
+        assert!(output.contains("(call $cons (ref.i31 (i32.const 2)) (ref.null eq))"));
+        assert!(output.contains("(global.set $string#192 (call $new_string (i32.const 192)))"));
+        assert!(!output.contains("$allocate"));
+    }
+
+    #[test]
+    fn vector_literals_fill_a_tail() {
+        let output = compile("(defn f [x] (conj [1 x] 3 4))").unwrap();
+
+        assert!(output.contains("(local.set $tail (call $new_array (i32.const 2)))"));
+        assert!(output.contains(
+            "(call $new_vector (i32.const 5) (i32.const 11) (i32.const 0) (local.get $tail))"
+        ));
+        assert!(output.contains(
+            "(local.set $collection (call $conj (local.get $collection) (i32.const 7)))"
+        ));
+        assert!(output.contains("(func $push_tail"));
+        assert!(
+            compile_binary("(print (nth (assoc (pop [1 2 3]) 0 5) 0) (subvec [1 2] 1))").is_ok()
+        );
+        assert!(Emitter::new()
+            .with_backend(Backend::WasmGc)
+            .emit_binary(
+                Parser::new("(print (count (conj [] 1)) (peek [2]))")
+                    .parse()
+                    .unwrap()
+            )
+            .is_ok());
+    }
+
+    /// Defines `(upto n)`, a vector of the integers below n
+    const UPTO: &str =
+        "(defn upto [n] (loop [i 0 v []] (if (< i n) (recur (inc i) (conj v i)) v)))";
+
+    #[test]
+    fn read_vectors() {
+        assert_eq!(
+            run("(print [1 2 3] [] (count []) (nth [1 2 3] 1) (get [1 2 3] 2))"),
+            "[1 2 3] [] 0 2 3"
+        );
+        // get gives nil or the default where nth fails
+        assert_eq!(
+            run("(print (get [1 2] 5) (get [1 2] 5 :none) (get [1 2] -1))"),
+            "nil :none nil"
+        );
+        assert_eq!(run("(print (nth [1 2] 2))"), "IndexOutOfBoundsException\n");
+        assert_eq!(run("(print (nth [1 2] -1))"), "IndexOutOfBoundsException\n");
+    }
+
+    #[test]
+    fn count_strings_by_character() {
+        assert_eq!(
+            run("(print (count \"h\u{e9}llo\") (count \"\") (count \"\u{1f600}!\"))"),
+            "5 0 2"
+        );
+    }
+
+    #[test]
+    fn conj_moves_full_tails_into_the_trie() {
+        // 32 fit in the tail, the 33rd pushes it into the root
+        let small = format!(
+            "{} (def v (upto 33)) (print (count v) (nth v 0) (nth v 31) (nth v 32))",
+            UPTO
+        );
+        assert_eq!(run(&small), "33 0 31 32");
+        // past 32 + 1024 the root is full and gains a level
+        let large = format!(
+            "{} (def v (upto 1100)) \
+             (print (count v) (nth v 500) (nth v 1055) (nth v 1056) (nth v 1099))",
+            UPTO
+        );
+        assert_eq!(run(&large), "1100 500 1055 1056 1099");
+    }
+
+    #[test]
+    fn assoc_replaces_or_appends() {
+        let program = format!(
+            "{} (def v (upto 1100)) \
+             (print (assoc [1 2] 0 :a) (assoc [1 2] 2 3) \
+             (nth (assoc v 5 :x) 5) (nth (assoc v 1090 :y) 1090))",
+            UPTO
+        );
+        assert_eq!(run(&program), "[:a 2] [1 2 3] :x :y");
+        assert_eq!(
+            run("(print (assoc [1 2] 3 0))"),
+            "IndexOutOfBoundsException\n"
+        );
+    }
+
+    #[test]
+    fn pop_takes_the_tail_back_from_the_trie() {
+        let program = format!(
+            "{} (def v (upto 33)) (def w (pop (upto 1057))) \
+             (print (peek v) (count (pop v)) (peek (pop v)) (peek (pop (pop v))) \
+             (count w) (peek w) (nth w 1024) (peek []) (pop [1]))",
+            UPTO
+        );
+        assert_eq!(run(&program), "32 32 31 30 1056 1055 1024 nil []");
+        assert_eq!(
+            run("(print (pop []))"),
+            "IllegalStateException: Can't pop empty vector\n"
+        );
+    }
+
+    #[test]
+    fn subvec_takes_a_range() {
+        let program = format!(
+            "{} (def s (subvec (upto 1100) 10 1060)) \
+             (print (subvec [1 2 3 4] 1 3) (subvec [1 2 3 4] 2) (subvec [1 2] 2) \
+             (count s) (nth s 1049))",
+            UPTO
+        );
+        assert_eq!(run(&program), "[2 3] [3 4] [] 1050 1059");
+        assert_eq!(
+            run("(print (subvec [1 2] 1 3))"),
+            "IndexOutOfBoundsException\n"
+        );
+        assert_eq!(
+            run("(print (subvec [1 2] 2 1))"),
+            "IndexOutOfBoundsException\n"
+        );
+    }
+
+    #[test]
+    fn updated_vectors_share_with_the_original() {
+        let program = format!(
+            "{} (def a (upto 40)) (def b (conj a 99)) (def c (assoc a 3 :x)) (def d (pop a)) \
+             (print (count a) (nth a 3) (peek a) (count b) (peek b) (nth c 3) (count d))",
+            UPTO
+        );
+        assert_eq!(run(&program), "40 3 39 41 99 :x 39");
+    }
+
+    #[test]
+    fn maps_are_hash_tries() {
+        let output = compile("(defn f [m] (:a m 2) (m :b)) (print {:a 1})").unwrap();
+
+        // keywords are interned like strings, with a tag of their own
+        assert!(output.contains("(data (i32.const 128) \"\\09\\01\\00\\00a\")"));
+        assert!(output.contains("(call $get (local.get $m) (i32.const 128) (i32.const 5))"));
+        assert!(output.contains("(call $map_assoc (local.get $map) (i32.const 128) (i32.const 3))"));
+        assert!(output.contains("(elem (i32.const 0) $invoke)"));
+        assert!(compile_binary("(print (update {:n 1} :n inc) (= {:a [1]} {:a [1]}))").is_ok());
+        assert!(Emitter::new()
+            .with_backend(Backend::WasmGc)
+            .emit_binary(
+                Parser::new("(print (merge {:a 1} {:b 2}) (keys {:a 1}) (dissoc {:a 1} :a))")
+                    .parse()
+                    .unwrap()
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn look_up_map_entries() {
+        let program = "(def m {:a 1 :b 2}) \
+                       (print m (:a m) (m :b) (:c m) (:c m 9) (m :c 8) (get m :a) (count m) {})";
+        assert_eq!(run(program), "{:a 1, :b 2} 1 2 nil 9 8 1 2 {}");
+        // any value can be a key, found by equality rather than identity
+        assert_eq!(
+            run("(def m {[1 2] :v {:k 1} :m}) (print (get m [1 2]) (get m {:k 1}))"),
+            ":v :m"
+        );
+        assert_eq!(
+            run(
+                "(print (contains? {:a nil} :a) (contains? {:a 1} :b) (keys {:a 1}) (vals {:a 1}))"
+            ),
+            "true false (:a) (1)"
+        );
+    }
+
+    #[test]
+    fn maps_grow_and_shrink_through_levels() {
+        let program = "(defn build [n m] (if (= n 0) m (recur (dec n) (assoc m n (* n n))))) \
+                       (defn strip [n m] (if (= n 100) m (recur (dec n) (dissoc m n)))) \
+                       (def big (build 2000 {})) (def small (strip 2000 big)) \
+                       (print (count big) (get big 1999) \
+                       (count small) (get small 50) (get small 500))";
+        assert_eq!(run(program), "2000 3996001 100 2500 nil");
+        assert_eq!(
+            run("(print (dissoc {:a 1} :a) (dissoc {:a 1} :z))"),
+            "{} {:a 1}"
+        );
+    }
+
+    #[test]
+    fn colliding_keys_share_a_node() {
+        // strings hash like Java's, so these four all hash the same
+        let program = "(def c {\"AaAa\" 1 \"BBBB\" 2 \"AaBB\" 3 \"BBAa\" 4}) \
+                       (print (count c) (get c \"AaBB\") (get c \"BBBa\") \
+                       (count (assoc c \"BBBB\" :x)) (get (assoc c \"BBBB\" :x) \"BBBB\") \
+                       (count (dissoc c \"AaAa\")) (get (dissoc c \"AaAa\") \"BBAa\") \
+                       (dissoc (dissoc (dissoc c \"AaAa\") \"BBBB\") \"AaBB\"))";
+        assert_eq!(run(program), "4 3 nil 4 :x 3 4 {BBAa 4}");
+        assert_eq!(run("(print (dissoc {\"Aa\" 1 \"BB\" 2} \"Aa\"))"), "{BB 2}");
+    }
+
+    #[test]
+    fn merge_and_update_maps() {
+        assert_eq!(
+            run("(print (merge {:a 1} {:b 2} {:a 3}) (merge {:a 1} nil))"),
+            "{:a 3, :b 2} {:a 1}"
+        );
+        assert_eq!(
+            run("(print (update {:a 1} :a inc) (update {:a 1} :b (fn [x] x)))"),
+            "{:a 2} {:a 1, :b nil}"
+        );
+    }
+
+    #[test]
+    fn maps_equal_regardless_of_order() {
+        let program =
+            "(print (= {:a 1 :b 2} {:b 2 :a 1}) (= {:a 1} {:a 2}) (= {:a 1} {:a 1 :b 2}) \
+                       (= {\"Aa\" 1 \"BB\" 2} {\"BB\" 2 \"Aa\" 1}))";
+        assert_eq!(run(program), "true false false true");
+        // so equal maps hash the same, whatever order they were built in
+        assert_eq!(run("(print (get {{:a 1 :b 2} :x} {:b 2 :a 1}))"), ":x");
+    }
+
+    #[test]
+    fn sets_wrap_maps() {
+        let output = compile("(defn f [s] (disj s 1)) (print (#{1 2} 1))").unwrap();
+
+        assert!(output.contains("(call $map_assoc (local.get $map) (i32.const 3) (i32.const 0))"));
+        assert!(output.contains("(call $new_set (local.get $map))"));
+        assert!(output.contains("(call $disj (local.get $collection) (i32.const 3))"));
+        assert!(Emitter::new()
+            .with_backend(Backend::WasmGc)
+            .emit_binary(
+                Parser::new("(print (conj #{1} 2) (contains? #{1} 1) (= #{1} #{1}))")
+                    .parse()
+                    .unwrap()
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn sets_hold_each_element_once() {
+        assert_eq!(
+            run("(print #{1 2} #{} (conj #{1} 2) (conj #{1} 1) (count #{1 2 3}))"),
+            "#{1 2} #{} #{1 2} #{1} 3"
+        );
+        assert_eq!(
+            run("(print (disj #{\"Aa\" \"BB\"} \"Aa\") (disj #{1} 5))"),
+            "#{BB} #{1}"
+        );
+        let program = "(defn build [n s] (if (= n 0) s (recur (dec n) (conj s (mod n 100))))) \
+                       (defn strip [n s] (if (= n 0) s (recur (dec n) (disj s n)))) \
+                       (print (count (build 1000 #{})) (strip 99 (build 1000 #{})))";
+        assert_eq!(run(program), "100 #{0}");
+    }
+
+    #[test]
+    fn look_up_set_elements() {
+        assert_eq!(
+            run("(print (#{1 2} 1) (#{1 2} 3) (get #{:a} :a) (get #{:a} :b))"),
+            "1 nil :a nil"
+        );
+        assert_eq!(
+            run("(print (contains? #{1 2} 2) (contains? #{1 2} 3) (contains? #{nil} nil))"),
+            "true false true"
+        );
+        assert_eq!(
+            run("(print (= #{1 2} #{2 1}) (= #{1} #{1 2}) (get {#{1 2} :s} #{2 1}))"),
+            "true false :s"
+        );
+    }
+
+    #[test]
+    fn named_fns_loop_on_calls_to_themselves() {
+        let program = "(print ((fn down [n] (if (= n 0) :done (down (dec n)))) 100000))";
+        assert_eq!(run(program), ":done");
+    }
+
+    #[test]
+    fn closures_nested_in_closures() {
+        assert_eq!(run("(print (((fn [] (fn [] 3)))))"), "3");
+        assert_eq!(
+            run("(def h (fn [x] (fn [] x))) (def k (h 4)) (print (k))"),
+            "4"
+        );
+        assert_eq!(
+            run("(defn adder [x] (fn [y] (fn [z] (+ x y z)))) (print (((adder 1) 2) 3))"),
+            "6"
+        );
+    }
+
+    #[test]
+    fn keywords_compare_by_address() {
+        let output = compile("(defn f [x] (= x :a)) (print :a)").unwrap();
+
+        assert!(output.contains("(i32.eq (local.get $x) (i32.const 128))"));
+        assert!(!output.contains("$equal"));
+    }
+
+    #[test]
+    fn call_closures_through_the_table() {
+        let output = compile("(defn inc-all [f] (f 1)) (defn g [] (inc-all inc-all))").unwrap();
+
+        assert!(output.contains(
+            "(call_indirect (param i32 i32 i32) (result i32)\n            (local.get $callee)\n            \
+             (i32.const 1)\n            (call $cons (i32.const 3) (i32.const 0))\n            \
+             (call $function_index (local.get $callee)))"
+        ));
+        assert!(output.contains(
+            "(func $inc-all#fn (param $closure# i32) (param $count# i32) (param $arguments# i32)"
+        ));
+        assert!(compile_binary("(print (#(+ % 1) 2))").is_ok());
     }
 }
@@ -1,4 +1,56 @@
-use crate::frontend::ast::Node;
 use std::collections::HashMap;
 
-type Environment = HashMap<Node, Node>;
+/// A chain of lexical scopes. Names are looked up from the innermost scope
+/// outwards, so inner definitions shadow outer ones.
+pub struct Environment<T> {
+    scopes: Vec<HashMap<String, T>>,
+}
+
+impl<T> Environment<T> {
+    pub fn new() -> Self {
+        Environment {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Forgets everything defined since the matching `enter_scope`
+    pub fn exit_scope(&mut self) {
+        // the outermost scope always stays
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: T) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_owned(), value);
+        }
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<&T> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::environment::Environment;
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let mut environment = Environment::new();
+        environment.define("x", 1);
+        environment.enter_scope();
+        environment.define("x", 2);
+        environment.define("y", 3);
+
+        assert_eq!(environment.resolve("x"), Some(&2));
+        environment.exit_scope();
+        assert_eq!(environment.resolve("x"), Some(&1));
+        assert_eq!(environment.resolve("y"), None);
+    }
+}
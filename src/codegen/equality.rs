@@ -0,0 +1,366 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction};
+use crate::codegen::map;
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::object::{
+    binary, box_integer, byte, call, cases, choose, constant, either, field, get, increment, not,
+    string_length, unbox_integer, value_local,
+};
+use crate::codegen::runtime::{until, RuntimeFunction};
+use crate::codegen::value::{Tag, TRUE};
+
+// Two values are equal when they are the same value, strings with the same
+// text, seqs or vectors with equal elements in the same order, maps with
+// equal values for the same keys, or sets with the same elements. Keywords
+// are interned, so one only equals itself. Equal values hash the same,
+// which lets any value be a key in a map.
+
+/// Added to the hash of the text of a keyword, so it differs from the hash
+/// of a string with the same text
+const KEYWORD_SEED: i32 = 0x9e37_79b9_u32 as i32;
+
+/// Builds the routine, or None for one that is not about equality
+pub fn definition(function: RuntimeFunction, backend: Backend) -> Option<Function> {
+    let equality = Equality { backend, function };
+    Some(match function {
+        RuntimeFunction::Hash => equality.hash(),
+        RuntimeFunction::Equal => equality.equal(),
+        _ => return None,
+    })
+}
+
+struct Equality {
+    backend: Backend,
+    function: RuntimeFunction,
+}
+
+impl Equality {
+    fn value(&self, name: &str) -> Local {
+        value_local(self.backend, name)
+    }
+
+    /// Both routines evaluate to a raw i32
+    fn function(&self, params: Vec<Local>, locals: Vec<Local>, body: Vec<Instruction>) -> Function {
+        Function {
+            name: self.function.name().to_owned(),
+            params,
+            result: Some(ValueType::I32),
+            locals,
+            body,
+        }
+    }
+
+    /// The text of a string or keyword, which is a string itself
+    fn text(&self, value: Instruction, tag: &str) -> Instruction {
+        match self.backend {
+            Backend::Linear => value,
+            Backend::WasmGc => choose(
+                self.backend,
+                has_tag(tag, Tag::Keyword),
+                vec![field(self.backend, gc::KEYWORD, value.clone(), 0)],
+                vec![value],
+            ),
+        }
+    }
+
+    /// The element of a seq or vector at the index. For a seq the cursor
+    /// holds the rest of it, starting at that element.
+    fn element(&self, tag: &str, collection: &str, cursor: &str) -> Instruction {
+        choose(
+            self.backend,
+            has_tag(tag, Tag::Vector),
+            vec![call(
+                RuntimeFunction::Nth,
+                vec![get(collection), box_integer(self.backend, get("index"))],
+            )],
+            vec![call(RuntimeFunction::First, vec![get(cursor)])],
+        )
+    }
+
+    fn advance(&self, tag: &str, cursor: &str) -> Instruction {
+        Instruction::when(
+            has_tag(tag, Tag::Seq),
+            vec![Instruction::set(
+                cursor,
+                call(RuntimeFunction::Next, vec![get(cursor)]),
+            )],
+        )
+    }
+
+    /// Strings hash their bytes, and seqs and vectors their elements in
+    /// order. A map or set sums what its entries hash to, which leaves out
+    /// their order. Functions only equal themselves, so they hash by
+    /// identity.
+    fn hash(&self) -> Function {
+        let step = |value: Instruction| {
+            Instruction::set(
+                "hash",
+                binary(
+                    BinaryOp::Add,
+                    binary(BinaryOp::Multiply, get("hash"), constant(31)),
+                    value,
+                ),
+            )
+        };
+        let text = vec![
+            Instruction::set("text", self.text(get("value"), "tag")),
+            until(
+                "hashed",
+                binary(
+                    BinaryOp::GreaterEqual,
+                    get("index"),
+                    string_length(self.backend, get("text")),
+                ),
+                vec![
+                    step(byte(self.backend, get("text"), get("index"))),
+                    increment("index"),
+                ],
+            ),
+            Instruction::choose(
+                has_tag("tag", Tag::Keyword),
+                vec![binary(BinaryOp::Add, get("hash"), constant(KEYWORD_SEED))],
+                vec![get("hash")],
+            ),
+        ];
+        let elements = vec![
+            Instruction::set("hash", constant(1)),
+            Instruction::set("cursor", get("value")),
+            Instruction::set("count", call(RuntimeFunction::Count, vec![get("value")])),
+            until(
+                "hashed",
+                binary(BinaryOp::GreaterEqual, get("index"), get("count")),
+                vec![
+                    step(call(
+                        self.function,
+                        vec![self.element("tag", "value", "cursor")],
+                    )),
+                    self.advance("tag", "cursor"),
+                    increment("index"),
+                ],
+            ),
+            get("hash"),
+        ];
+        let boolean = match self.backend {
+            Backend::Linear => binary(BinaryOp::Equal, get("value"), constant(TRUE)),
+            Backend::WasmGc => Instruction::RefEq(
+                Box::new(get("value")),
+                Box::new(Instruction::global_get(gc::TRUE_GLOBAL)),
+            ),
+        };
+        let identity = match self.backend {
+            Backend::Linear => get("value"),
+            Backend::WasmGc => field(self.backend, gc::CLOSURE, get("value"), 0),
+        };
+        let mut body = vec![Instruction::set(
+            "tag",
+            call(RuntimeFunction::TypeOf, vec![get("value")]),
+        )];
+        body.extend(cases(
+            ValueType::I32,
+            vec![
+                (has_tag("tag", Tag::Nil), vec![constant(0)]),
+                (
+                    has_tag("tag", Tag::Integer),
+                    vec![unbox_integer(self.backend, get("value"))],
+                ),
+                (
+                    has_tag("tag", Tag::Boolean),
+                    vec![Instruction::choose(
+                        boolean,
+                        vec![constant(1231)],
+                        vec![constant(1237)],
+                    )],
+                ),
+                (
+                    either(has_tag("tag", Tag::String), has_tag("tag", Tag::Keyword)),
+                    text,
+                ),
+                (sequential("tag"), elements),
+                (
+                    has_tag("tag", Tag::Map),
+                    vec![call(
+                        RuntimeFunction::NodeHash,
+                        vec![map::root(self.backend, get("value"))],
+                    )],
+                ),
+                (
+                    has_tag("tag", Tag::Set),
+                    vec![call(
+                        RuntimeFunction::NodeHash,
+                        vec![map::root(
+                            self.backend,
+                            map::set_map(self.backend, get("value")),
+                        )],
+                    )],
+                ),
+            ],
+            vec![identity],
+        ));
+
+        self.function(
+            vec![self.value("value")],
+            vec![
+                Local::i32("tag"),
+                Local::i32("hash"),
+                Local::i32("index"),
+                Local::i32("count"),
+                self.value("text"),
+                self.value("cursor"),
+            ],
+            body,
+        )
+    }
+
+    fn equal(&self) -> Function {
+        let same = match self.backend {
+            Backend::Linear => binary(BinaryOp::Equal, get("left"), get("right")),
+            Backend::WasmGc => Instruction::RefEq(Box::new(get("left")), Box::new(get("right"))),
+        };
+        let text = vec![
+            Instruction::set("count", string_length(self.backend, get("left"))),
+            Instruction::set(
+                "equal",
+                binary(
+                    BinaryOp::Equal,
+                    get("count"),
+                    string_length(self.backend, get("right")),
+                ),
+            ),
+            until(
+                "compared",
+                either(
+                    binary(BinaryOp::GreaterEqual, get("index"), get("count")),
+                    not(get("equal")),
+                ),
+                vec![
+                    Instruction::set(
+                        "equal",
+                        binary(
+                            BinaryOp::Equal,
+                            byte(self.backend, get("left"), get("index")),
+                            byte(self.backend, get("right"), get("index")),
+                        ),
+                    ),
+                    increment("index"),
+                ],
+            ),
+            get("equal"),
+        ];
+        let elements = vec![
+            Instruction::set("count", call(RuntimeFunction::Count, vec![get("left")])),
+            Instruction::set(
+                "equal",
+                binary(
+                    BinaryOp::Equal,
+                    get("count"),
+                    call(RuntimeFunction::Count, vec![get("right")]),
+                ),
+            ),
+            Instruction::set("left_cursor", get("left")),
+            Instruction::set("right_cursor", get("right")),
+            until(
+                "compared",
+                either(
+                    binary(BinaryOp::GreaterEqual, get("index"), get("count")),
+                    not(get("equal")),
+                ),
+                vec![
+                    Instruction::set(
+                        "equal",
+                        call(
+                            self.function,
+                            vec![
+                                self.element("left_tag", "left", "left_cursor"),
+                                self.element("right_tag", "right", "right_cursor"),
+                            ],
+                        ),
+                    ),
+                    self.advance("left_tag", "left_cursor"),
+                    self.advance("right_tag", "right_cursor"),
+                    increment("index"),
+                ],
+            ),
+            get("equal"),
+        ];
+        let mut body = vec![
+            Instruction::set("left_tag", call(RuntimeFunction::TypeOf, vec![get("left")])),
+            Instruction::set(
+                "right_tag",
+                call(RuntimeFunction::TypeOf, vec![get("right")]),
+            ),
+        ];
+        body.extend(cases(
+            ValueType::I32,
+            vec![
+                (same, vec![constant(1)]),
+                (
+                    binary(
+                        BinaryOp::And,
+                        has_tag("left_tag", Tag::String),
+                        has_tag("right_tag", Tag::String),
+                    ),
+                    text,
+                ),
+                (
+                    binary(
+                        BinaryOp::And,
+                        sequential("left_tag"),
+                        sequential("right_tag"),
+                    ),
+                    elements,
+                ),
+                (
+                    binary(
+                        BinaryOp::And,
+                        has_tag("left_tag", Tag::Map),
+                        has_tag("right_tag", Tag::Map),
+                    ),
+                    vec![call(
+                        RuntimeFunction::MapEqual,
+                        vec![get("left"), get("right")],
+                    )],
+                ),
+                (
+                    binary(
+                        BinaryOp::And,
+                        has_tag("left_tag", Tag::Set),
+                        has_tag("right_tag", Tag::Set),
+                    ),
+                    vec![call(
+                        RuntimeFunction::MapEqual,
+                        vec![
+                            map::set_map(self.backend, get("left")),
+                            map::set_map(self.backend, get("right")),
+                        ],
+                    )],
+                ),
+            ],
+            vec![constant(0)],
+        ));
+
+        self.function(
+            vec![self.value("left"), self.value("right")],
+            vec![
+                Local::i32("left_tag"),
+                Local::i32("right_tag"),
+                Local::i32("equal"),
+                Local::i32("index"),
+                Local::i32("count"),
+                self.value("left_cursor"),
+                self.value("right_cursor"),
+            ],
+            body,
+        )
+    }
+}
+
+/// Whether the local holds the code of the tag
+fn has_tag(local: &str, tag: Tag) -> Instruction {
+    binary(BinaryOp::Equal, get(local), constant(tag.code()))
+}
+
+/// Whether the local holds the code of a seq or a vector
+fn sequential(local: &str) -> Instruction {
+    either(has_tag(local, Tag::Seq), has_tag(local, Tag::Vector))
+}
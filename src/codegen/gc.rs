@@ -0,0 +1,587 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, Width};
+use crate::codegen::map::{print_entries, print_members};
+use crate::codegen::module::{
+    Composite, Field, Function, Global, HeapType, Local, StorageType, TypeDefinition, ValueType,
+};
+use crate::codegen::object::increment;
+use crate::codegen::pool::StringPool;
+use crate::codegen::runtime::{fail_with, until, RuntimeFunction, DATA_START, FREE_LISTS, INVOKE};
+use crate::codegen::value::Tag;
+use crate::codegen::vector::print_elements;
+
+// With the wasm-gc target every value is an eqref and the engine allocates
+// and collects the objects:
+//
+//   null       nil
+//   i31        an integer, which has 31 bits like a tagged one
+//   $boolean   false or true, of which there is one instance each
+//   $string    the bytes of a string
+//   $closure   the table index of a function and the values it captured
+//   $seq       the first value of a seq and the seq of the rest
+//   $vector    the count, shift, root and tail of a vector
+//   $map       the count and root of a map, whose nodes and entries are
+//              $map_node and $entry
+//   $keyword   the name of a keyword, of which there is one instance each
+//   $set       the map of a set, whose keys are its elements
+//
+// The types are defined together in a single group, which keeps the engine
+// telling them apart even when they have the same shape. Linear memory is
+// only used to hand text to WASI.
+
+pub const BOOLEAN: &str = "boolean";
+pub const STRING: &str = "string";
+pub const VALUES: &str = "values";
+pub const CLOSURE: &str = "closure";
+pub const SEQ: &str = "seq";
+pub const VECTOR: &str = "vector";
+pub const MAP: &str = "map";
+pub const MAP_NODE: &str = "map_node";
+pub const ENTRY: &str = "entry";
+pub const KEYWORD: &str = "keyword";
+pub const SET: &str = "set";
+
+/// Globals holding the two booleans
+pub const TRUE_GLOBAL: &str = "true#";
+pub const FALSE_GLOBAL: &str = "false#";
+
+/// Strings are copied into linear memory in chunks to be printed, laid out
+/// the way print_string expects them
+const TEXT_BUFFER: i32 = FREE_LISTS;
+const TEXT_CHUNK: i32 = DATA_START - TEXT_BUFFER - 4;
+
+fn field(storage: StorageType, mutable: bool) -> Field {
+    Field { storage, mutable }
+}
+
+fn value_field() -> Field {
+    field(StorageType::Value(ValueType::EqRef), false)
+}
+
+pub fn types() -> Vec<TypeDefinition> {
+    let definition = |name: &str, composite| TypeDefinition {
+        name: name.to_owned(),
+        composite,
+    };
+    vec![
+        definition(
+            BOOLEAN,
+            Composite::Struct(vec![field(StorageType::Value(ValueType::I32), false)]),
+        ),
+        definition(STRING, Composite::Array(field(StorageType::I8, true))),
+        definition(
+            VALUES,
+            Composite::Array(field(StorageType::Value(ValueType::EqRef), true)),
+        ),
+        definition(
+            CLOSURE,
+            Composite::Struct(vec![
+                field(StorageType::Value(ValueType::I32), false),
+                value_field(),
+            ]),
+        ),
+        definition(SEQ, Composite::Struct(vec![value_field(), value_field()])),
+        definition(VECTOR, Composite::Struct(vec![value_field(); 4])),
+        definition(MAP, Composite::Struct(vec![value_field(); 2])),
+        definition(MAP_NODE, Composite::Struct(vec![value_field(); 2])),
+        definition(ENTRY, Composite::Struct(vec![value_field(); 2])),
+        definition(KEYWORD, Composite::Struct(vec![value_field()])),
+        definition(SET, Composite::Struct(vec![value_field()])),
+    ]
+}
+
+pub fn globals() -> Vec<Global> {
+    let boolean = |name: &str, raw| Global {
+        name: name.to_owned(),
+        value_type: ValueType::EqRef,
+        initial: Instruction::StructNew(BOOLEAN.to_owned(), vec![Instruction::Const(raw)]),
+    };
+    vec![boolean(TRUE_GLOBAL, 1), boolean(FALSE_GLOBAL, 0)]
+}
+
+/// Whether the value is an object of the type
+pub fn is(name: &str, value: Instruction) -> Instruction {
+    Instruction::ref_test(HeapType::Defined(name.to_owned()), value)
+}
+
+/// The value as a reference to the type, which it has to be
+pub fn cast(name: &str, value: Instruction) -> Instruction {
+    Instruction::ref_cast(HeapType::Defined(name.to_owned()), value)
+}
+
+/// Reads a field of a struct
+pub fn get_field(name: &str, index: u32, value: Instruction) -> Instruction {
+    Instruction::struct_get(name, index, cast(name, value))
+}
+
+/// Reads an element of the values array
+pub fn get_element(array: Instruction, index: Instruction) -> Instruction {
+    Instruction::ArrayGet {
+        name: VALUES.to_owned(),
+        packed: false,
+        array: Box::new(cast(VALUES, array)),
+        index: Box::new(index),
+    }
+}
+
+pub fn box_integer(raw: Instruction) -> Instruction {
+    Instruction::RefI31(Box::new(raw))
+}
+
+/// The raw i32 of a value already known to be an integer. Boxing drops the
+/// top bit, so a boxed value is unboxed even right away.
+pub fn unbox_integer(value: Instruction) -> Instruction {
+    Instruction::I31Get(Box::new(Instruction::ref_cast(HeapType::I31, value)))
+}
+
+/// Picks one of the two booleans for a wasm condition
+pub fn box_boolean(raw: Instruction) -> Instruction {
+    match raw {
+        Instruction::Const(0) => Instruction::global_get(FALSE_GLOBAL),
+        Instruction::Const(_) => Instruction::global_get(TRUE_GLOBAL),
+        raw => Instruction::If {
+            result: Some(ValueType::EqRef),
+            condition: Box::new(raw),
+            then: vec![Instruction::global_get(TRUE_GLOBAL)],
+            otherwise: vec![Instruction::global_get(FALSE_GLOBAL)],
+        },
+    }
+}
+
+/// Non-zero exactly when the value is neither null nor the false instance.
+/// A boolean that was just boxed is tested as it was, and a value read
+/// from a local is tested in place.
+pub fn truthy(value: Instruction) -> Instruction {
+    match value {
+        Instruction::If {
+            result: Some(ValueType::EqRef),
+            condition: box raw,
+            then,
+            otherwise,
+        } if then == vec![Instruction::global_get(TRUE_GLOBAL)]
+            && otherwise == vec![Instruction::global_get(FALSE_GLOBAL)] =>
+        {
+            raw
+        }
+        Instruction::LocalGet(_) | Instruction::GlobalGet(_) => test_truthy(value),
+        value => Instruction::call(RuntimeFunction::Truthy.name(), vec![value]),
+    }
+}
+
+/// Evaluates the value twice
+fn test_truthy(value: Instruction) -> Instruction {
+    Instruction::binary(
+        BinaryOp::And,
+        Instruction::unary(
+            UnaryOp::EqualZero,
+            Instruction::RefIsNull(Box::new(value.clone())),
+        ),
+        Instruction::unary(
+            UnaryOp::EqualZero,
+            Instruction::RefEq(
+                Box::new(value),
+                Box::new(Instruction::global_get(FALSE_GLOBAL)),
+            ),
+        ),
+    )
+}
+
+/// Other routines a routine built for wasm-gc calls, or None when it is
+/// the same as in linear memory
+pub fn dependencies(function: RuntimeFunction) -> Option<Vec<RuntimeFunction>> {
+    let dependencies = match function {
+        RuntimeFunction::Cons
+        | RuntimeFunction::TypeOf
+        | RuntimeFunction::Truthy
+        | RuntimeFunction::NewString
+        | RuntimeFunction::NewArray
+        | RuntimeFunction::NewVector
+        | RuntimeFunction::NewMap
+        | RuntimeFunction::NewMapNode
+        | RuntimeFunction::NewEntry
+        | RuntimeFunction::NewSet => vec![],
+        RuntimeFunction::First | RuntimeFunction::Next | RuntimeFunction::IntegerValue => {
+            vec![RuntimeFunction::Fail]
+        }
+        RuntimeFunction::FunctionIndex => vec![RuntimeFunction::Invoke, RuntimeFunction::Fail],
+        RuntimeFunction::PrintText => vec![RuntimeFunction::PrintString],
+        RuntimeFunction::PrintValue => vec![
+            RuntimeFunction::TypeOf,
+            RuntimeFunction::PrintInteger,
+            RuntimeFunction::PrintString,
+            RuntimeFunction::PrintText,
+            RuntimeFunction::ArrayFor,
+            RuntimeFunction::PrintNode,
+        ],
+        _ => return None,
+    };
+    Some(dependencies)
+}
+
+/// Builds a routine for wasm-gc, or None when the one working on linear
+/// memory does
+pub fn definition(function: RuntimeFunction, strings: &mut StringPool) -> Option<Function> {
+    let name = function.name().to_owned();
+    let value = || Instruction::get("value");
+    let (params, result, locals, body) = match function {
+        RuntimeFunction::Cons => (
+            vec![Local::eqref("first"), Local::eqref("next")],
+            Some(ValueType::EqRef),
+            vec![],
+            vec![Instruction::StructNew(
+                SEQ.to_owned(),
+                vec![Instruction::get("first"), Instruction::get("next")],
+            )],
+        ),
+        RuntimeFunction::First => seq_field(strings, 0),
+        RuntimeFunction::Next => seq_field(strings, 1),
+        RuntimeFunction::TypeOf => (
+            vec![Local::eqref("value")],
+            Some(ValueType::I32),
+            vec![],
+            vec![type_of()],
+        ),
+        RuntimeFunction::IntegerValue => {
+            let message = strings.intern("ClassCastException: value is not an integer\n");
+            (
+                vec![Local::eqref("value")],
+                Some(ValueType::I32),
+                vec![],
+                vec![
+                    Instruction::when(
+                        Instruction::unary(
+                            UnaryOp::EqualZero,
+                            Instruction::ref_test(HeapType::I31, value()),
+                        ),
+                        vec![fail_with(message)],
+                    ),
+                    unbox_integer(value()),
+                ],
+            )
+        }
+        RuntimeFunction::FunctionIndex => {
+            let message = strings.intern("ClassCastException: value is not a function\n");
+            (
+                vec![Local::eqref("value")],
+                Some(ValueType::I32),
+                vec![],
+                vec![Instruction::choose(
+                    is(CLOSURE, value()),
+                    vec![get_field(CLOSURE, 0, value())],
+                    vec![Instruction::choose(
+                        Instruction::binary(
+                            BinaryOp::Or,
+                            Instruction::binary(BinaryOp::Or, is(MAP, value()), is(SET, value())),
+                            is(KEYWORD, value()),
+                        ),
+                        vec![Instruction::global_get(INVOKE)],
+                        vec![fail_with(message), Instruction::Const(0)],
+                    )],
+                )],
+            )
+        }
+        RuntimeFunction::Truthy => (
+            vec![Local::eqref("value")],
+            Some(ValueType::I32),
+            vec![],
+            vec![test_truthy(value())],
+        ),
+        RuntimeFunction::NewString => new_string(),
+        RuntimeFunction::PrintText => print_text(),
+        RuntimeFunction::PrintValue => print_value(function, strings),
+        _ => return None,
+    };
+    Some(Function {
+        name,
+        params,
+        result,
+        locals,
+        body,
+    })
+}
+
+type Parts = (Vec<Local>, Option<ValueType>, Vec<Local>, Vec<Instruction>);
+
+/// Reads a field of a seq's first pair, or nil for the empty seq
+fn seq_field(strings: &mut StringPool, index: u32) -> Parts {
+    let message = strings.intern("ClassCastException: value is not a seq\n");
+    let seq = || Instruction::get("seq");
+    let body = vec![Instruction::If {
+        result: Some(ValueType::EqRef),
+        condition: Box::new(Instruction::RefIsNull(Box::new(seq()))),
+        then: vec![Instruction::RefNull],
+        otherwise: vec![
+            Instruction::when(
+                Instruction::unary(UnaryOp::EqualZero, is(SEQ, seq())),
+                vec![fail_with(message)],
+            ),
+            get_field(SEQ, index, seq()),
+        ],
+    }];
+    (
+        vec![Local::eqref("seq")],
+        Some(ValueType::EqRef),
+        vec![],
+        body,
+    )
+}
+
+/// The same tag codes values have in linear memory
+fn type_of() -> Instruction {
+    let value = || Instruction::get("value");
+    let cases = vec![
+        (Instruction::RefIsNull(Box::new(value())), Tag::Nil),
+        (Instruction::ref_test(HeapType::I31, value()), Tag::Integer),
+        (is(BOOLEAN, value()), Tag::Boolean),
+        (is(STRING, value()), Tag::String),
+        (is(SEQ, value()), Tag::Seq),
+        (is(VECTOR, value()), Tag::Vector),
+        (is(MAP, value()), Tag::Map),
+        (is(KEYWORD, value()), Tag::Keyword),
+        (is(MAP_NODE, value()), Tag::MapNode),
+        (is(ENTRY, value()), Tag::Entry),
+        (is(SET, value()), Tag::Set),
+    ];
+    // anything else is a closure
+    let mut code = Instruction::Const(Tag::Function.code());
+    for (condition, tag) in cases.into_iter().rev() {
+        code = Instruction::choose(condition, vec![Instruction::Const(tag.code())], vec![code]);
+    }
+    code
+}
+
+/// Copies a string from the static data into a string object
+fn new_string() -> Parts {
+    let get = Instruction::get;
+    let length = Instruction::binary(
+        BinaryOp::ShiftRightSigned,
+        Instruction::load(get("address")),
+        Instruction::Const(8),
+    );
+    let body = vec![
+        Instruction::set(
+            "string",
+            Instruction::ArrayNew {
+                name: STRING.to_owned(),
+                value: Box::new(Instruction::Const(0)),
+                length: Box::new(length),
+            },
+        ),
+        until(
+            "copied",
+            Instruction::binary(
+                BinaryOp::GreaterEqual,
+                get("index"),
+                Instruction::ArrayLen(Box::new(cast(STRING, get("string")))),
+            ),
+            vec![
+                Instruction::ArraySet {
+                    name: STRING.to_owned(),
+                    array: Box::new(cast(STRING, get("string"))),
+                    index: Box::new(get("index")),
+                    value: Box::new(Instruction::Load {
+                        width: Width::Byte,
+                        offset: 4,
+                        address: Box::new(Instruction::binary(
+                            BinaryOp::Add,
+                            get("address"),
+                            get("index"),
+                        )),
+                    }),
+                },
+                increment("index"),
+            ],
+        ),
+        get("string"),
+    ];
+    (
+        vec![Local::i32("address")],
+        Some(ValueType::EqRef),
+        vec![Local::eqref("string"), Local::i32("index")],
+        body,
+    )
+}
+
+/// Prints a string object a chunk at a time
+fn print_text() -> Parts {
+    let get = Instruction::get;
+    let flush = vec![
+        // print_string only reads the length from the header
+        Instruction::store(
+            Instruction::Const(TEXT_BUFFER),
+            Instruction::binary(BinaryOp::ShiftLeft, get("filled"), Instruction::Const(8)),
+        ),
+        Instruction::call(
+            RuntimeFunction::PrintString.name(),
+            vec![Instruction::Const(TEXT_BUFFER)],
+        ),
+        Instruction::set("filled", Instruction::Const(0)),
+    ];
+    let body = vec![
+        until(
+            "printed",
+            Instruction::binary(
+                BinaryOp::GreaterEqual,
+                get("index"),
+                Instruction::ArrayLen(Box::new(cast(STRING, get("string")))),
+            ),
+            vec![
+                Instruction::Store {
+                    width: Width::Byte,
+                    offset: TEXT_BUFFER as u32 + 4,
+                    address: Box::new(get("filled")),
+                    value: Box::new(Instruction::ArrayGet {
+                        name: STRING.to_owned(),
+                        packed: true,
+                        array: Box::new(cast(STRING, get("string"))),
+                        index: Box::new(get("index")),
+                    }),
+                },
+                increment("index"),
+                increment("filled"),
+                Instruction::when(
+                    Instruction::binary(
+                        BinaryOp::Equal,
+                        get("filled"),
+                        Instruction::Const(TEXT_CHUNK),
+                    ),
+                    flush.clone(),
+                ),
+            ],
+        ),
+        Instruction::when(get("filled"), flush),
+    ];
+    (
+        vec![Local::eqref("string")],
+        None,
+        vec![Local::i32("index"), Local::i32("filled")],
+        body,
+    )
+}
+
+/// Prints any value the way its type says it should look. Seqs print their
+/// elements in parentheses, vectors in brackets and maps in braces.
+fn print_value(function: RuntimeFunction, strings: &mut StringPool) -> Parts {
+    let mut literal = |text: &str| {
+        Instruction::call(
+            RuntimeFunction::PrintString.name(),
+            vec![Instruction::Const(strings.intern(text))],
+        )
+    };
+    let value = || Instruction::get("value");
+    let tag_is = |tag: Tag| {
+        Instruction::binary(
+            BinaryOp::Equal,
+            Instruction::get("tag"),
+            Instruction::Const(tag.code()),
+        )
+    };
+    let boolean = Instruction::If {
+        result: None,
+        condition: Box::new(Instruction::RefEq(
+            Box::new(value()),
+            Box::new(Instruction::global_get(TRUE_GLOBAL)),
+        )),
+        then: vec![literal("true")],
+        otherwise: vec![literal("false")],
+    };
+    let seq = vec![
+        literal("("),
+        Instruction::Loop {
+            label: "elements".to_owned(),
+            result: None,
+            body: vec![
+                Instruction::call(function.name(), vec![get_field(SEQ, 0, value())]),
+                Instruction::set("value", get_field(SEQ, 1, value())),
+                Instruction::when(
+                    Instruction::unary(
+                        UnaryOp::EqualZero,
+                        Instruction::RefIsNull(Box::new(value())),
+                    ),
+                    vec![literal(" "), Instruction::Branch("elements".to_owned())],
+                ),
+            ],
+        },
+        literal(")"),
+    ];
+    let vector = print_elements(Backend::WasmGc, function.name(), &mut literal);
+    let map = print_entries(Backend::WasmGc, &mut literal);
+    let set = print_members(Backend::WasmGc, &mut literal);
+    let keyword = vec![
+        literal(":"),
+        Instruction::call(
+            RuntimeFunction::PrintText.name(),
+            vec![get_field(KEYWORD, 0, value())],
+        ),
+    ];
+    let cases = vec![
+        (
+            tag_is(Tag::Integer),
+            vec![Instruction::call(
+                RuntimeFunction::PrintInteger.name(),
+                vec![unbox_integer(value())],
+            )],
+        ),
+        (
+            tag_is(Tag::String),
+            vec![Instruction::call(
+                RuntimeFunction::PrintText.name(),
+                vec![value()],
+            )],
+        ),
+        (tag_is(Tag::Nil), vec![literal("nil")]),
+        (tag_is(Tag::Boolean), vec![boolean]),
+        (tag_is(Tag::Seq), seq),
+        (tag_is(Tag::Vector), vector),
+        (tag_is(Tag::Map), map),
+        (tag_is(Tag::Keyword), keyword),
+        (tag_is(Tag::Set), set),
+    ];
+    let mut body = vec![literal("#function")];
+    for (condition, then) in cases.into_iter().rev() {
+        body = vec![Instruction::If {
+            result: None,
+            condition: Box::new(condition),
+            then,
+            otherwise: body,
+        }];
+    }
+    body.insert(
+        0,
+        Instruction::set(
+            "tag",
+            Instruction::call(RuntimeFunction::TypeOf.name(), vec![value()]),
+        ),
+    );
+    (
+        vec![Local::eqref("value")],
+        None,
+        vec![Local::i32("tag"), Local::i32("index"), Local::i32("count")],
+        body,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::gc::{box_boolean, truthy, FALSE_GLOBAL, TRUE_GLOBAL};
+    use crate::codegen::instructions::{BinaryOp, Instruction};
+
+    #[test]
+    fn booleans_are_shared_instances() {
+        let raw = Instruction::binary(
+            BinaryOp::Equal,
+            Instruction::get("x"),
+            Instruction::get("y"),
+        );
+
+        assert_eq!(
+            box_boolean(Instruction::Const(1)),
+            Instruction::global_get(TRUE_GLOBAL)
+        );
+        assert_eq!(
+            box_boolean(Instruction::Const(0)),
+            Instruction::global_get(FALSE_GLOBAL)
+        );
+        assert_eq!(truthy(box_boolean(raw.clone())), raw);
+    }
+}
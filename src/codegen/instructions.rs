@@ -1,89 +1,351 @@
-use std::fmt::{Display, Error, Formatter};
+use crate::codegen::module::{HeapType, Import, Signature, ValueType};
 
-type ReferenceNumber = usize;
-
-/// Only operations on i32 numbers are supported at the moment
-pub enum Types {
-    I32param(ReferenceNumber),
-    I32result,
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    DivideSigned,
+    DivideUnsigned,
+    RemainderSigned,
+    RemainderUnsigned,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRightSigned,
+    ShiftRightUnsigned,
+    Equal,
+    NotEqual,
+    LessThan,
+    LessEqual,
+    GreaterThan,
+    GreaterThanUnsigned,
+    GreaterEqual,
 }
 
-pub struct OpData {
-    pub location: Opcodes,
-    pub data: String,
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UnaryOp {
+    EqualZero,
+    CountLeadingZeros,
+    PopulationCount,
 }
 
-#[derive(Copy, Clone)]
-pub enum Opcodes {
-    GetLocal,        // Get a local variable from the stack
-    Add,             // Add two i32 constants
-    Subtract,        // Subtract two i32 constants
-    Load,            // Load 4 bytes as an i32 from linear memory
-    Store(i32, i32), // Store 4 bytes as an i32 into linear memory
-    Const(i32),      // Push a constant on the stack
-    Drop,
+/// Width of a memory access
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Width {
+    Word,
+    Byte,
 }
 
-pub enum WASIImports {
-    FDWrite,
+/// A typed instruction tree. Every instruction owns the instructions that
+/// produce its operands, so the tree can only describe balanced code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Const(i32),
+    LocalGet(String),
+    LocalSet(String, Box<Instruction>),
+    GlobalGet(String),
+    GlobalSet(String, Box<Instruction>),
+    Load {
+        width: Width,
+        offset: u32,
+        address: Box<Instruction>,
+    },
+    Store {
+        width: Width,
+        offset: u32,
+        address: Box<Instruction>,
+        value: Box<Instruction>,
+    },
+    Unary(UnaryOp, Box<Instruction>),
+    Binary(BinaryOp, Box<Instruction>, Box<Instruction>),
+    Call(String, Vec<Instruction>),
+    // from the tail call proposal, replaces the caller's frame with the callee's
+    ReturnCall(String, Vec<Instruction>),
+    // calls the function at the table index given by the last operand
+    CallIndirect(Signature, Vec<Instruction>, Box<Instruction>),
+    Drop(Box<Instruction>),
+    // size of the memory in pages
+    MemorySize,
+    // grows the memory by a number of pages, giving the old size or -1
+    MemoryGrow(Box<Instruction>),
+    Block {
+        label: Option<String>,
+        result: Option<ValueType>,
+        body: Vec<Instruction>,
+    },
+    Loop {
+        label: String,
+        result: Option<ValueType>,
+        body: Vec<Instruction>,
+    },
+    If {
+        result: Option<ValueType>,
+        condition: Box<Instruction>,
+        then: Vec<Instruction>,
+        otherwise: Vec<Instruction>,
+    },
+    Branch(String),
+    BranchIf(String, Box<Instruction>),
+    // the rest are from the garbage collection proposal. Types of structs
+    // and arrays are referred to by name.
+    RefNull,
+    RefIsNull(Box<Instruction>),
+    RefEq(Box<Instruction>, Box<Instruction>),
+    // packs an i32 into a reference, dropping the top bit
+    RefI31(Box<Instruction>),
+    // unpacks a reference made by RefI31, sign extending it
+    I31Get(Box<Instruction>),
+    // whether a reference is a non null reference to the heap type
+    RefTest(HeapType, Box<Instruction>),
+    // traps unless the reference could pass RefTest
+    RefCast(HeapType, Box<Instruction>),
+    StructNew(String, Vec<Instruction>),
+    StructGet(String, u32, Box<Instruction>),
+    // an array of a length filled with a value
+    ArrayNew {
+        name: String,
+        value: Box<Instruction>,
+        length: Box<Instruction>,
+    },
+    ArrayNewFixed(String, Vec<Instruction>),
+    ArrayGet {
+        name: String,
+        // packed elements are read zero extended
+        packed: bool,
+        array: Box<Instruction>,
+        index: Box<Instruction>,
+    },
+    ArraySet {
+        name: String,
+        array: Box<Instruction>,
+        index: Box<Instruction>,
+        value: Box<Instruction>,
+    },
+    ArrayLen(Box<Instruction>),
 }
 
-pub enum SysCalls {
-    Write(Opcodes, Opcodes, Opcodes, Opcodes),
-}
+impl Instruction {
+    pub fn get(name: &str) -> Self {
+        Instruction::LocalGet(name.to_owned())
+    }
 
-impl Display for Types {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        match self {
-            Types::I32param(name) => write!(f, "(param $p{:?} i32)", name),
-            Types::I32result => write!(f, "(result i32)"),
+    pub fn set(name: &str, value: Instruction) -> Self {
+        Instruction::LocalSet(name.to_owned(), Box::new(value))
+    }
+
+    pub fn unary(op: UnaryOp, value: Instruction) -> Self {
+        Instruction::Unary(op, Box::new(value))
+    }
+
+    pub fn global_get(name: &str) -> Self {
+        Instruction::GlobalGet(name.to_owned())
+    }
+
+    pub fn global_set(name: &str, value: Instruction) -> Self {
+        Instruction::GlobalSet(name.to_owned(), Box::new(value))
+    }
+
+    pub fn binary(op: BinaryOp, left: Instruction, right: Instruction) -> Self {
+        Instruction::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    pub fn call(name: &str, args: Vec<Instruction>) -> Self {
+        Instruction::Call(name.to_owned(), args)
+    }
+
+    pub fn drop(value: Instruction) -> Self {
+        Instruction::Drop(Box::new(value))
+    }
+
+    pub fn load(address: Instruction) -> Self {
+        Instruction::Load {
+            width: Width::Word,
+            offset: 0,
+            address: Box::new(address),
+        }
+    }
+
+    pub fn store(address: Instruction, value: Instruction) -> Self {
+        Instruction::Store {
+            width: Width::Word,
+            offset: 0,
+            address: Box::new(address),
+            value: Box::new(value),
         }
     }
-}
 
-impl Display for OpData {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "(data {} {:?})", self.location, self.data)
+    pub fn load_offset(address: Instruction, offset: u32) -> Self {
+        Instruction::Load {
+            width: Width::Word,
+            offset,
+            address: Box::new(address),
+        }
+    }
+
+    pub fn store_offset(address: Instruction, offset: u32, value: Instruction) -> Self {
+        Instruction::Store {
+            width: Width::Word,
+            offset,
+            address: Box::new(address),
+            value: Box::new(value),
+        }
     }
-}
 
-impl Display for Opcodes {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+    pub fn load8(address: Instruction) -> Self {
+        Instruction::Load {
+            width: Width::Byte,
+            offset: 0,
+            address: Box::new(address),
+        }
+    }
+
+    pub fn store8(address: Instruction, value: Instruction) -> Self {
+        Instruction::Store {
+            width: Width::Byte,
+            offset: 0,
+            address: Box::new(address),
+            value: Box::new(value),
+        }
+    }
+
+    pub fn struct_get(name: &str, field: u32, reference: Instruction) -> Self {
+        Instruction::StructGet(name.to_owned(), field, Box::new(reference))
+    }
+
+    pub fn ref_cast(heap_type: HeapType, reference: Instruction) -> Self {
+        Instruction::RefCast(heap_type, Box::new(reference))
+    }
+
+    pub fn ref_test(heap_type: HeapType, reference: Instruction) -> Self {
+        Instruction::RefTest(heap_type, Box::new(reference))
+    }
+
+    /// The instructions producing the operands, in evaluation order
+    pub fn operands_mut(&mut self) -> Vec<&mut Instruction> {
         match self {
-            Opcodes::GetLocal => write!(f, "(get_local)"),
-            Opcodes::Add => write!(f, "(i32.add"),
-            Opcodes::Subtract => write!(f, "(i32.sub"),
-            Opcodes::Load => write!(f, "(i32.load32_s)"),
-            Opcodes::Store(address, value) => write!(
-                f,
-                "(i32.store {} {})",
-                Opcodes::Const(*address),
-                Opcodes::Const(*value)
-            ),
-            Opcodes::Const(constant) => write!(f, "(i32.const {:?})", constant),
-            Opcodes::Drop => write!(f, "drop"),
+            Instruction::LocalSet(_, value)
+            | Instruction::GlobalSet(_, value)
+            | Instruction::Unary(_, value)
+            | Instruction::Drop(value)
+            | Instruction::MemoryGrow(value)
+            | Instruction::BranchIf(_, value)
+            | Instruction::Load { address: value, .. }
+            | Instruction::RefIsNull(value)
+            | Instruction::RefI31(value)
+            | Instruction::I31Get(value)
+            | Instruction::RefTest(_, value)
+            | Instruction::RefCast(_, value)
+            | Instruction::StructGet(_, _, value)
+            | Instruction::ArrayLen(value) => vec![value],
+            Instruction::Store { address, value, .. } => vec![address, value],
+            Instruction::Binary(_, left, right) | Instruction::RefEq(left, right) => {
+                vec![left, right]
+            }
+            Instruction::Call(_, args)
+            | Instruction::ReturnCall(_, args)
+            | Instruction::StructNew(_, args)
+            | Instruction::ArrayNewFixed(_, args) => args.iter_mut().collect(),
+            Instruction::ArrayNew { value, length, .. } => vec![value, length],
+            Instruction::ArrayGet { array, index, .. } => vec![array, index],
+            Instruction::ArraySet {
+                array,
+                index,
+                value,
+                ..
+            } => vec![array, index, value],
+            Instruction::CallIndirect(_, args, index) => {
+                let mut operands: Vec<&mut Instruction> = args.iter_mut().collect();
+                operands.push(index);
+                operands
+            }
+            Instruction::If { condition, .. } => vec![condition],
+            Instruction::Const(_)
+            | Instruction::LocalGet(_)
+            | Instruction::GlobalGet(_)
+            | Instruction::MemorySize
+            | Instruction::RefNull
+            | Instruction::Block { .. }
+            | Instruction::Loop { .. }
+            | Instruction::Branch(_) => vec![],
         }
     }
-}
 
-impl Display for SysCalls {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+    /// The sequences of instructions nested inside blocks, loops and ifs
+    pub fn bodies_mut(&mut self) -> Vec<&mut Vec<Instruction>> {
         match self {
-            SysCalls::Write(file_descriptor, iov_ptr, iov_len, num_written) => write!(
-                f,
-                "(call $fd_write {} {} {} {})",
-                file_descriptor, iov_ptr, iov_len, num_written
-            ),
+            Instruction::Block { body, .. } | Instruction::Loop { body, .. } => vec![body],
+            Instruction::If {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+            _ => vec![],
+        }
+    }
+
+    /// A block evaluating to the last of its instructions
+    pub fn block(body: Vec<Instruction>) -> Self {
+        Instruction::Block {
+            label: None,
+            result: Some(ValueType::I32),
+            body,
+        }
+    }
+
+    /// An if choosing between two raw i32s
+    pub fn choose(
+        condition: Instruction,
+        then: Vec<Instruction>,
+        otherwise: Vec<Instruction>,
+    ) -> Self {
+        Instruction::If {
+            result: Some(ValueType::I32),
+            condition: Box::new(condition),
+            then,
+            otherwise,
+        }
+    }
+
+    /// An if without an else branch, evaluating to nothing
+    pub fn when(condition: Instruction, then: Vec<Instruction>) -> Self {
+        Instruction::If {
+            result: None,
+            condition: Box::new(condition),
+            then,
+            otherwise: vec![],
         }
     }
 }
 
-impl Display for WASIImports {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+#[derive(Clone, Copy, PartialEq)]
+pub enum WASIImports {
+    FDWrite,
+    ProcExit,
+}
+
+impl WASIImports {
+    pub fn name(&self) -> &'static str {
         match self {
-            WASIImports::FDWrite =>
-                write!(f, "(import \"wasi_unstable\" \"fd_write\" (func $fd_write (param i32 i32 i32 i32) {}))",
-                       Types::I32result),
+            WASIImports::FDWrite => "fd_write",
+            WASIImports::ProcExit => "proc_exit",
+        }
+    }
+
+    pub fn import(&self) -> Import {
+        let signature = match self {
+            WASIImports::FDWrite => Signature {
+                params: vec![ValueType::I32; 4],
+                result: Some(ValueType::I32),
+            },
+            WASIImports::ProcExit => Signature {
+                params: vec![ValueType::I32],
+                result: None,
+            },
+        };
+        Import {
+            module: "wasi_unstable".to_owned(),
+            name: self.name().to_owned(),
+            signature,
         }
     }
 }
@@ -0,0 +1,1201 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp};
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::object::{
+    add, binary, box_integer, call, cases, choose, constant, either, element, field, get,
+    increment, is_nil, length, new_record, nil, not, set_element, tag_is, unbox_integer,
+    value_local, value_type,
+};
+use crate::codegen::pool::StringPool;
+use crate::codegen::runtime::{fail_with, until, RuntimeFunction};
+use crate::codegen::value::Tag;
+
+// A map is a record of its count and the root of a hash array mapped trie,
+// which is nil while the map is empty. Each node of the trie takes 4 more
+// bits of the hash of a key, and keeps a bitmap of its 16 slots that are in
+// use along with an array of just those slots, in order. A slot holds an
+// entry of a key and its value, or the node below when more keys share it.
+// Keys whose whole hashes are the same end up in a collision node, which
+// has no bitmap and keeps its entries in an array that is searched in turn.
+// Nodes and entries never change, so an update copies the nodes on the way
+// to the key and shares the rest.
+//
+// A set is a record of a map whose keys are its elements, each with a value
+// of nil. An entry then hashes the same as its key, so a set hashes to the
+// sum of its elements.
+
+const COUNT: u32 = 0;
+const ROOT: u32 = 1;
+
+const BITMAP: u32 = 0;
+const ARRAY: u32 = 1;
+
+const KEY: u32 = 0;
+const VALUE: u32 = 1;
+
+const BITS: i32 = 4;
+const WIDTH: i32 = 1 << BITS;
+/// The shift of the nodes below the last one the hash reaches
+const COLLISION: i32 = 32;
+
+/// What node_seq collects from each entry, besides its key or value
+const ENTRIES: i32 = 2;
+
+/// The count of a map, as a raw i32
+pub fn count(backend: Backend, map: Instruction) -> Instruction {
+    unbox_integer(backend, field(backend, gc::MAP, map, COUNT))
+}
+
+/// The root node of a map, nil when it is empty
+pub fn root(backend: Backend, map: Instruction) -> Instruction {
+    field(backend, gc::MAP, map, ROOT)
+}
+
+/// The map of a set
+pub fn set_map(backend: Backend, set: Instruction) -> Instruction {
+    field(backend, gc::SET, set, 0)
+}
+
+/// Prints the map in the local `value` between braces, passing each key
+/// and value to the printing routine
+pub fn print_entries(
+    backend: Backend,
+    mut literal: impl FnMut(&str) -> Instruction,
+) -> Vec<Instruction> {
+    vec![
+        literal("{"),
+        Instruction::drop(call(
+            RuntimeFunction::PrintNode,
+            vec![root(backend, get("value")), constant(0), constant(ENTRIES)],
+        )),
+        literal("}"),
+    ]
+}
+
+/// Prints the set in the local `value` between `#{` and `}`, passing each
+/// element to the printing routine
+pub fn print_members(
+    backend: Backend,
+    mut literal: impl FnMut(&str) -> Instruction,
+) -> Vec<Instruction> {
+    vec![
+        literal("#{"),
+        Instruction::drop(call(
+            RuntimeFunction::PrintNode,
+            vec![
+                root(backend, set_map(backend, get("value"))),
+                constant(0),
+                constant(KEY as i32),
+            ],
+        )),
+        literal("}"),
+    ]
+}
+
+/// The bit of the slot the hash goes in, in a node at the shift
+fn bit(hash: Instruction, shift: Instruction) -> Instruction {
+    binary(
+        BinaryOp::ShiftLeft,
+        constant(1),
+        binary(
+            BinaryOp::And,
+            binary(BinaryOp::ShiftRightUnsigned, hash, shift),
+            constant(WIDTH - 1),
+        ),
+    )
+}
+
+/// Where the slot of the bit is in the array of a node, which is the number
+/// of slots in use before it
+fn position(bitmap: Instruction, bit: Instruction) -> Instruction {
+    Instruction::unary(
+        UnaryOp::PopulationCount,
+        binary(BinaryOp::And, bitmap, add(bit, -1)),
+    )
+}
+
+/// Builds the map routine, or None for one that is not about maps
+pub fn definition(
+    function: RuntimeFunction,
+    strings: &mut StringPool,
+    backend: Backend,
+) -> Option<Function> {
+    let maps = Maps { backend, function };
+    Some(match function {
+        RuntimeFunction::NewMap => maps.new_record(Tag::Map, gc::MAP, &["count", "root"]),
+        RuntimeFunction::NewMapNode => {
+            maps.new_record(Tag::MapNode, gc::MAP_NODE, &["bitmap", "array"])
+        }
+        RuntimeFunction::NewEntry => maps.new_record(Tag::Entry, gc::ENTRY, &["key", "value"]),
+        RuntimeFunction::EntryIndex => maps.entry_index(),
+        RuntimeFunction::FindEntry => maps.find_entry(),
+        RuntimeFunction::MapGet => maps.map_get(),
+        RuntimeFunction::MapAssoc => maps.map_assoc(),
+        RuntimeFunction::NodeAssoc => maps.node_assoc(),
+        RuntimeFunction::MapDissoc => maps.map_dissoc(),
+        RuntimeFunction::NodeDissoc => maps.node_dissoc(),
+        RuntimeFunction::NodeSeq => maps.node_seq(),
+        RuntimeFunction::NodeHash => maps.node_hash(),
+        RuntimeFunction::MapEqual => maps.map_equal(),
+        RuntimeFunction::NodeContained => maps.node_contained(),
+        RuntimeFunction::PrintNode => maps.print_node(strings),
+        RuntimeFunction::Contains => maps.contains(strings),
+        RuntimeFunction::Dissoc => maps.dissoc(strings),
+        RuntimeFunction::Keys => maps.entry_seq(strings, KEY as i32),
+        RuntimeFunction::Vals => maps.entry_seq(strings, VALUE as i32),
+        RuntimeFunction::Merge => maps.merge(strings),
+        RuntimeFunction::NewSet => maps.new_record(Tag::Set, gc::SET, &["map"]),
+        RuntimeFunction::SetGet => maps.set_get(),
+        RuntimeFunction::Disj => maps.disj(strings),
+        RuntimeFunction::Invoke => maps.invoke(strings),
+        _ => return None,
+    })
+}
+
+struct Maps {
+    backend: Backend,
+    function: RuntimeFunction,
+}
+
+impl Maps {
+    fn value(&self, name: &str) -> Local {
+        value_local(self.backend, name)
+    }
+
+    fn function(&self, params: Vec<Local>, locals: Vec<Local>, body: Vec<Instruction>) -> Function {
+        self.function_of(params, value_type(self.backend), locals, body)
+    }
+
+    fn function_of(
+        &self,
+        params: Vec<Local>,
+        result: ValueType,
+        locals: Vec<Local>,
+        body: Vec<Instruction>,
+    ) -> Function {
+        Function {
+            name: self.function.name().to_owned(),
+            params,
+            result: Some(result),
+            locals,
+            body,
+        }
+    }
+
+    /// A block evaluating to the value the body leaves
+    fn block(&self, body: Vec<Instruction>) -> Instruction {
+        Instruction::Block {
+            label: None,
+            result: Some(value_type(self.backend)),
+            body,
+        }
+    }
+
+    fn nil(&self) -> Instruction {
+        nil(self.backend)
+    }
+
+    fn is_nil(&self, value: Instruction) -> Instruction {
+        is_nil(self.backend, value)
+    }
+
+    fn key(&self, entry: Instruction) -> Instruction {
+        field(self.backend, gc::ENTRY, entry, KEY)
+    }
+
+    fn entry_value(&self, entry: Instruction) -> Instruction {
+        field(self.backend, gc::ENTRY, entry, VALUE)
+    }
+
+    fn bitmap(&self, node: Instruction) -> Instruction {
+        unbox_integer(
+            self.backend,
+            field(self.backend, gc::MAP_NODE, node, BITMAP),
+        )
+    }
+
+    fn array(&self, node: Instruction) -> Instruction {
+        field(self.backend, gc::MAP_NODE, node, ARRAY)
+    }
+
+    fn element(&self, array: Instruction, index: Instruction) -> Instruction {
+        element(self.backend, array, index)
+    }
+
+    fn new_map(&self, count: Instruction, root: Instruction) -> Instruction {
+        call(
+            RuntimeFunction::NewMap,
+            vec![box_integer(self.backend, count), root],
+        )
+    }
+
+    fn new_node(&self, bitmap: Instruction, array: Instruction) -> Instruction {
+        call(
+            RuntimeFunction::NewMapNode,
+            vec![box_integer(self.backend, bitmap), array],
+        )
+    }
+
+    fn hash(&self, key: Instruction) -> Instruction {
+        call(RuntimeFunction::Hash, vec![key])
+    }
+
+    fn equal(&self, left: Instruction, right: Instruction) -> Instruction {
+        call(RuntimeFunction::Equal, vec![left, right])
+    }
+
+    fn is_entry(&self, item: Instruction) -> Instruction {
+        tag_is(item, Tag::Entry)
+    }
+
+    /// Fails unless the local holds a map
+    fn check(&self, name: &str, message: i32) -> Instruction {
+        Instruction::when(not(tag_is(get(name), Tag::Map)), vec![fail_with(message)])
+    }
+
+    /// Runs the body for each item in the array of the node in the local
+    /// `node`, with the item in the local `item`, while the condition does
+    /// not hold. Nothing runs for a nil node.
+    fn each_item(&self, stop: Instruction, mut body: Vec<Instruction>) -> Instruction {
+        body.insert(
+            0,
+            Instruction::set("item", self.element(get("array"), get("index"))),
+        );
+        body.push(increment("index"));
+        Instruction::when(
+            not(self.is_nil(get("node"))),
+            vec![
+                Instruction::set("array", self.array(get("node"))),
+                until(
+                    "visited",
+                    either(
+                        binary(
+                            BinaryOp::GreaterEqual,
+                            get("index"),
+                            length(self.backend, get("array")),
+                        ),
+                        stop,
+                    ),
+                    body,
+                ),
+            ],
+        )
+    }
+
+    fn new_record(&self, tag: Tag, record: &str, fields: &[&str]) -> Function {
+        let locals = match self.backend {
+            Backend::Linear => vec![Local::i32("object")],
+            Backend::WasmGc => vec![],
+        };
+        self.function(
+            fields.iter().map(|name| self.value(name)).collect(),
+            locals,
+            new_record(self.backend, tag, record, fields),
+        )
+    }
+
+    /// The index of the entry with the key in an array of entries, or -1
+    fn entry_index(&self) -> Function {
+        let body = vec![
+            Instruction::set("found", constant(-1)),
+            until(
+                "searched",
+                either(
+                    binary(
+                        BinaryOp::GreaterEqual,
+                        get("index"),
+                        length(self.backend, get("array")),
+                    ),
+                    binary(BinaryOp::GreaterEqual, get("found"), constant(0)),
+                ),
+                vec![
+                    Instruction::when(
+                        self.equal(
+                            self.key(self.element(get("array"), get("index"))),
+                            get("key"),
+                        ),
+                        vec![Instruction::set("found", get("index"))],
+                    ),
+                    increment("index"),
+                ],
+            ),
+            get("found"),
+        ];
+        self.function_of(
+            vec![self.value("array"), self.value("key")],
+            ValueType::I32,
+            vec![Local::i32("found"), Local::i32("index")],
+            body,
+        )
+    }
+
+    /// Follows the hash of the key down from the root of a map. Evaluates
+    /// to the entry of the key, or nil when there is none.
+    fn find_entry(&self) -> Function {
+        let collision = vec![
+            Instruction::set(
+                "index",
+                call(
+                    RuntimeFunction::EntryIndex,
+                    vec![self.array(get("node")), get("key")],
+                ),
+            ),
+            Instruction::when(
+                binary(BinaryOp::GreaterEqual, get("index"), constant(0)),
+                vec![Instruction::set(
+                    "entry",
+                    self.element(self.array(get("node")), get("index")),
+                )],
+            ),
+            Instruction::set("node", self.nil()),
+        ];
+        let found = vec![
+            Instruction::set(
+                "item",
+                self.element(self.array(get("node")), position(get("bitmap"), get("bit"))),
+            ),
+            Instruction::If {
+                result: None,
+                condition: Box::new(self.is_entry(get("item"))),
+                then: vec![
+                    Instruction::when(
+                        self.equal(self.key(get("item")), get("key")),
+                        vec![Instruction::set("entry", get("item"))],
+                    ),
+                    Instruction::set("node", self.nil()),
+                ],
+                otherwise: vec![
+                    Instruction::set("node", get("item")),
+                    Instruction::set("shift", add(get("shift"), BITS)),
+                ],
+            },
+        ];
+        let branch = vec![
+            Instruction::set("bitmap", self.bitmap(get("node"))),
+            Instruction::set("bit", bit(get("hash"), get("shift"))),
+            Instruction::If {
+                result: None,
+                condition: Box::new(binary(BinaryOp::And, get("bitmap"), get("bit"))),
+                then: found,
+                otherwise: vec![Instruction::set("node", self.nil())],
+            },
+        ];
+        let body = vec![
+            Instruction::set("node", root(self.backend, get("map"))),
+            Instruction::set("hash", self.hash(get("key"))),
+            until(
+                "found",
+                self.is_nil(get("node")),
+                vec![Instruction::If {
+                    result: None,
+                    condition: Box::new(binary(
+                        BinaryOp::GreaterEqual,
+                        get("shift"),
+                        constant(COLLISION),
+                    )),
+                    then: collision,
+                    otherwise: branch,
+                }],
+            ),
+            get("entry"),
+        ];
+        self.function(
+            vec![self.value("map"), self.value("key")],
+            vec![
+                self.value("node"),
+                self.value("item"),
+                self.value("entry"),
+                Local::i32("hash"),
+                Local::i32("shift"),
+                Local::i32("bitmap"),
+                Local::i32("bit"),
+                Local::i32("index"),
+            ],
+            body,
+        )
+    }
+
+    /// The value of the key in a map, or the default when it has none
+    fn map_get(&self) -> Function {
+        let body = vec![
+            Instruction::set(
+                "entry",
+                call(RuntimeFunction::FindEntry, vec![get("map"), get("key")]),
+            ),
+            choose(
+                self.backend,
+                self.is_nil(get("entry")),
+                vec![get("default")],
+                vec![self.entry_value(get("entry"))],
+            ),
+        ];
+        self.function(
+            vec![self.value("map"), self.value("key"), self.value("default")],
+            vec![self.value("entry")],
+            body,
+        )
+    }
+
+    /// A new map with the key set to the value, counting one more when the
+    /// key is new
+    fn map_assoc(&self) -> Function {
+        let body = vec![
+            Instruction::set("count", count(self.backend, get("map"))),
+            Instruction::when(
+                self.is_nil(call(
+                    RuntimeFunction::FindEntry,
+                    vec![get("map"), get("key")],
+                )),
+                vec![increment("count")],
+            ),
+            Instruction::set(
+                "entry",
+                call(RuntimeFunction::NewEntry, vec![get("key"), get("value")]),
+            ),
+            Instruction::set(
+                "root",
+                call(
+                    RuntimeFunction::NodeAssoc,
+                    vec![
+                        root(self.backend, get("map")),
+                        constant(0),
+                        self.hash(get("key")),
+                        get("entry"),
+                    ],
+                ),
+            ),
+            self.new_map(get("count"), get("root")),
+        ];
+        self.function(
+            vec![self.value("map"), self.value("key"), self.value("value")],
+            vec![Local::i32("count"), self.value("entry"), self.value("root")],
+            body,
+        )
+    }
+
+    /// A copy of the node, which may be nil, with the entry in place of any
+    /// entry of the same key
+    fn node_assoc(&self) -> Function {
+        let below = add(get("shift"), BITS);
+        let leaf = vec![
+            Instruction::set("copy", call(RuntimeFunction::NewArray, vec![constant(1)])),
+            set_element(self.backend, get("copy"), constant(0), get("entry")),
+            self.new_node(bit(get("hash"), get("shift")), get("copy")),
+        ];
+        let collision = vec![
+            Instruction::set(
+                "index",
+                call(
+                    RuntimeFunction::EntryIndex,
+                    vec![get("array"), self.key(get("entry"))],
+                ),
+            ),
+            choose(
+                self.backend,
+                binary(BinaryOp::LessThan, get("index"), constant(0)),
+                vec![self.new_node(
+                    constant(0),
+                    call(
+                        RuntimeFunction::ArrayInsert,
+                        vec![
+                            get("array"),
+                            length(self.backend, get("array")),
+                            get("entry"),
+                        ],
+                    ),
+                )],
+                self.replace("index", get("entry")),
+            ),
+        ];
+        // two keys sharing a slot move down to a node of their own
+        let child = cases(
+            value_type(self.backend),
+            vec![
+                (
+                    not(self.is_entry(get("item"))),
+                    vec![call(
+                        self.function,
+                        vec![get("item"), below.clone(), get("hash"), get("entry")],
+                    )],
+                ),
+                (
+                    self.equal(self.key(get("item")), self.key(get("entry"))),
+                    vec![get("entry")],
+                ),
+            ],
+            vec![call(
+                self.function,
+                vec![
+                    call(
+                        self.function,
+                        vec![
+                            self.nil(),
+                            below.clone(),
+                            self.hash(self.key(get("item"))),
+                            get("item"),
+                        ],
+                    ),
+                    below,
+                    get("hash"),
+                    get("entry"),
+                ],
+            )],
+        );
+        let mut taken = vec![
+            Instruction::set("item", self.element(get("array"), get("position"))),
+            Instruction::set("item", self.block(child)),
+        ];
+        taken.extend(self.replace("position", get("item")));
+        let branch = vec![
+            Instruction::set("bitmap", self.bitmap(get("node"))),
+            Instruction::set("bit", bit(get("hash"), get("shift"))),
+            Instruction::set("position", position(get("bitmap"), get("bit"))),
+            choose(
+                self.backend,
+                binary(BinaryOp::And, get("bitmap"), get("bit")),
+                taken,
+                vec![self.new_node(
+                    binary(BinaryOp::Or, get("bitmap"), get("bit")),
+                    call(
+                        RuntimeFunction::ArrayInsert,
+                        vec![get("array"), get("position"), get("entry")],
+                    ),
+                )],
+            ),
+        ];
+        let mut body = vec![Instruction::when(
+            not(self.is_nil(get("node"))),
+            vec![Instruction::set("array", self.array(get("node")))],
+        )];
+        body.extend(cases(
+            value_type(self.backend),
+            vec![
+                (self.is_nil(get("node")), leaf),
+                (
+                    binary(BinaryOp::GreaterEqual, get("shift"), constant(COLLISION)),
+                    collision,
+                ),
+            ],
+            branch,
+        ));
+        self.function(
+            vec![
+                self.value("node"),
+                Local::i32("shift"),
+                Local::i32("hash"),
+                self.value("entry"),
+            ],
+            vec![
+                self.value("array"),
+                self.value("item"),
+                self.value("copy"),
+                Local::i32("bitmap"),
+                Local::i32("bit"),
+                Local::i32("position"),
+                Local::i32("index"),
+            ],
+            body,
+        )
+    }
+
+    /// A copy of the node in the local `node` with the item at an index of
+    /// its array replaced
+    fn replace(&self, index: &str, item: Instruction) -> Vec<Instruction> {
+        vec![
+            Instruction::set(
+                "copy",
+                call(
+                    RuntimeFunction::CopyArray,
+                    vec![get("array"), length(self.backend, get("array"))],
+                ),
+            ),
+            set_element(self.backend, get("copy"), get(index), item),
+            self.new_node(self.bitmap(get("node")), get("copy")),
+        ]
+    }
+
+    /// A new map without the key, or the same map when it has no such key
+    fn map_dissoc(&self) -> Function {
+        let body = vec![choose(
+            self.backend,
+            self.is_nil(call(
+                RuntimeFunction::FindEntry,
+                vec![get("map"), get("key")],
+            )),
+            vec![get("map")],
+            vec![self.new_map(
+                add(count(self.backend, get("map")), -1),
+                call(
+                    RuntimeFunction::NodeDissoc,
+                    vec![
+                        root(self.backend, get("map")),
+                        constant(0),
+                        self.hash(get("key")),
+                        get("key"),
+                    ],
+                ),
+            )],
+        )];
+        self.function(vec![self.value("map"), self.value("key")], vec![], body)
+    }
+
+    /// A copy of the node without the key, which has to be in it. Evaluates
+    /// to nil when nothing would be left in the node.
+    fn node_dissoc(&self) -> Function {
+        let collision = vec![
+            Instruction::set(
+                "index",
+                call(RuntimeFunction::EntryIndex, vec![get("array"), get("key")]),
+            ),
+            choose(
+                self.backend,
+                binary(
+                    BinaryOp::Equal,
+                    length(self.backend, get("array")),
+                    constant(1),
+                ),
+                vec![self.nil()],
+                vec![self.new_node(
+                    constant(0),
+                    call(
+                        RuntimeFunction::ArrayRemove,
+                        vec![get("array"), get("index")],
+                    ),
+                )],
+            ),
+        ];
+        let mut branch = vec![
+            Instruction::set("bitmap", self.bitmap(get("node"))),
+            Instruction::set("bit", bit(get("hash"), get("shift"))),
+            Instruction::set("position", position(get("bitmap"), get("bit"))),
+            Instruction::set("item", self.element(get("array"), get("position"))),
+            Instruction::when(
+                not(self.is_entry(get("item"))),
+                vec![Instruction::set(
+                    "child",
+                    call(
+                        self.function,
+                        vec![
+                            get("item"),
+                            add(get("shift"), BITS),
+                            get("hash"),
+                            get("key"),
+                        ],
+                    ),
+                )],
+            ),
+        ];
+        branch.extend(cases(
+            value_type(self.backend),
+            vec![
+                (
+                    not(self.is_nil(get("child"))),
+                    self.replace("position", get("child")),
+                ),
+                (
+                    binary(BinaryOp::Equal, get("bitmap"), get("bit")),
+                    vec![self.nil()],
+                ),
+            ],
+            vec![self.new_node(
+                binary(BinaryOp::Xor, get("bitmap"), get("bit")),
+                call(
+                    RuntimeFunction::ArrayRemove,
+                    vec![get("array"), get("position")],
+                ),
+            )],
+        ));
+        let mut body = vec![Instruction::set("array", self.array(get("node")))];
+        body.extend(cases(
+            value_type(self.backend),
+            vec![(
+                binary(BinaryOp::GreaterEqual, get("shift"), constant(COLLISION)),
+                collision,
+            )],
+            branch,
+        ));
+        self.function(
+            vec![
+                self.value("node"),
+                Local::i32("shift"),
+                Local::i32("hash"),
+                self.value("key"),
+            ],
+            vec![
+                self.value("array"),
+                self.value("item"),
+                self.value("child"),
+                self.value("copy"),
+                Local::i32("bitmap"),
+                Local::i32("bit"),
+                Local::i32("position"),
+                Local::i32("index"),
+            ],
+            body,
+        )
+    }
+
+    /// Conses the keys, the values or the entries of the node, which may be
+    /// nil, onto the seq
+    fn node_seq(&self) -> Function {
+        let part = cases(
+            value_type(self.backend),
+            vec![
+                (
+                    binary(BinaryOp::Equal, get("part"), constant(ENTRIES)),
+                    vec![get("item")],
+                ),
+                (
+                    binary(BinaryOp::Equal, get("part"), constant(KEY as i32)),
+                    vec![self.key(get("item"))],
+                ),
+            ],
+            vec![self.entry_value(get("item"))],
+        );
+        let body = vec![
+            self.each_item(
+                constant(0),
+                vec![Instruction::set(
+                    "seq",
+                    choose(
+                        self.backend,
+                        self.is_entry(get("item")),
+                        vec![call(
+                            RuntimeFunction::Cons,
+                            vec![self.block(part), get("seq")],
+                        )],
+                        vec![call(
+                            self.function,
+                            vec![get("item"), get("seq"), get("part")],
+                        )],
+                    ),
+                )],
+            ),
+            get("seq"),
+        ];
+        self.function(
+            vec![self.value("node"), self.value("seq"), Local::i32("part")],
+            vec![self.value("array"), self.value("item"), Local::i32("index")],
+            body,
+        )
+    }
+
+    /// The sum of the hashes of the entries below the node, which may be
+    /// nil. An entry hashes its key and value together.
+    fn node_hash(&self) -> Function {
+        let body = vec![
+            self.each_item(
+                constant(0),
+                vec![Instruction::set(
+                    "hash",
+                    binary(
+                        BinaryOp::Add,
+                        get("hash"),
+                        Instruction::choose(
+                            self.is_entry(get("item")),
+                            vec![binary(
+                                BinaryOp::Xor,
+                                self.hash(self.key(get("item"))),
+                                self.hash(self.entry_value(get("item"))),
+                            )],
+                            vec![call(self.function, vec![get("item")])],
+                        ),
+                    ),
+                )],
+            ),
+            get("hash"),
+        ];
+        self.function_of(
+            vec![self.value("node")],
+            ValueType::I32,
+            vec![
+                self.value("array"),
+                self.value("item"),
+                Local::i32("index"),
+                Local::i32("hash"),
+            ],
+            body,
+        )
+    }
+
+    /// Maps of the same count are equal when each entry of one has an equal
+    /// value in the other
+    fn map_equal(&self) -> Function {
+        let body = vec![Instruction::choose(
+            binary(
+                BinaryOp::Equal,
+                count(self.backend, get("left")),
+                count(self.backend, get("right")),
+            ),
+            vec![call(
+                RuntimeFunction::NodeContained,
+                vec![root(self.backend, get("left")), get("right")],
+            )],
+            vec![constant(0)],
+        )];
+        self.function_of(
+            vec![self.value("left"), self.value("right")],
+            ValueType::I32,
+            vec![],
+            body,
+        )
+    }
+
+    /// Whether the map has an equal value for the key of every entry below
+    /// the node, which may be nil
+    fn node_contained(&self) -> Function {
+        let entry = vec![
+            Instruction::set(
+                "found",
+                call(
+                    RuntimeFunction::FindEntry,
+                    vec![get("map"), self.key(get("item"))],
+                ),
+            ),
+            Instruction::choose(
+                self.is_nil(get("found")),
+                vec![constant(0)],
+                vec![self.equal(
+                    self.entry_value(get("found")),
+                    self.entry_value(get("item")),
+                )],
+            ),
+        ];
+        let body = vec![
+            Instruction::set("contained", constant(1)),
+            self.each_item(
+                not(get("contained")),
+                vec![Instruction::set(
+                    "contained",
+                    Instruction::choose(
+                        self.is_entry(get("item")),
+                        entry,
+                        vec![call(self.function, vec![get("item"), get("map")])],
+                    ),
+                )],
+            ),
+            get("contained"),
+        ];
+        self.function_of(
+            vec![self.value("node"), self.value("map")],
+            ValueType::I32,
+            vec![
+                self.value("array"),
+                self.value("item"),
+                self.value("found"),
+                Local::i32("index"),
+                Local::i32("contained"),
+            ],
+            body,
+        )
+    }
+
+    /// Prints the entries below the node, which may be nil, starting with a
+    /// separator when asked to. Evaluates to whether the next entry needs
+    /// one. Only the keys are printed when the part is KEY, as for a set.
+    fn print_node(&self, strings: &mut StringPool) -> Function {
+        let mut literal = |text: &str| {
+            call(
+                RuntimeFunction::PrintString,
+                vec![constant(strings.intern(text))],
+            )
+        };
+        let print = |value: Instruction| call(RuntimeFunction::PrintValue, vec![value]);
+        let body = vec![
+            self.each_item(
+                constant(0),
+                vec![Instruction::If {
+                    result: None,
+                    condition: Box::new(self.is_entry(get("item"))),
+                    then: vec![
+                        Instruction::If {
+                            result: None,
+                            condition: Box::new(binary(
+                                BinaryOp::Equal,
+                                get("part"),
+                                constant(KEY as i32),
+                            )),
+                            then: vec![
+                                Instruction::when(get("separate"), vec![literal(" ")]),
+                                print(self.key(get("item"))),
+                            ],
+                            otherwise: vec![
+                                Instruction::when(get("separate"), vec![literal(", ")]),
+                                print(self.key(get("item"))),
+                                literal(" "),
+                                print(self.entry_value(get("item"))),
+                            ],
+                        },
+                        Instruction::set("separate", constant(1)),
+                    ],
+                    otherwise: vec![Instruction::set(
+                        "separate",
+                        call(
+                            self.function,
+                            vec![get("item"), get("separate"), get("part")],
+                        ),
+                    )],
+                }],
+            ),
+            get("separate"),
+        ];
+        self.function_of(
+            vec![
+                self.value("node"),
+                Local::i32("separate"),
+                Local::i32("part"),
+            ],
+            ValueType::I32,
+            vec![self.value("array"), self.value("item"), Local::i32("index")],
+            body,
+        )
+    }
+
+    /// Whether a map has the key, a set has the element, or a vector or
+    /// string has an element at the index
+    fn contains(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("IllegalArgumentException: contains? not supported on this type\n");
+        let body = cases(
+            ValueType::I32,
+            vec![
+                (self.is_nil(get("collection")), vec![constant(0)]),
+                (
+                    tag_is(get("collection"), Tag::Map),
+                    vec![not(self.is_nil(call(
+                        RuntimeFunction::FindEntry,
+                        vec![get("collection"), get("key")],
+                    )))],
+                ),
+                (
+                    tag_is(get("collection"), Tag::Set),
+                    vec![not(self.is_nil(call(
+                        RuntimeFunction::FindEntry,
+                        vec![set_map(self.backend, get("collection")), get("key")],
+                    )))],
+                ),
+                (
+                    either(
+                        tag_is(get("collection"), Tag::Vector),
+                        tag_is(get("collection"), Tag::String),
+                    ),
+                    vec![Instruction::choose(
+                        tag_is(get("key"), Tag::Integer),
+                        vec![binary(
+                            BinaryOp::GreaterThanUnsigned,
+                            call(RuntimeFunction::Count, vec![get("collection")]),
+                            unbox_integer(self.backend, get("key")),
+                        )],
+                        vec![constant(0)],
+                    )],
+                ),
+            ],
+            vec![fail_with(message), constant(0)],
+        );
+        self.function_of(
+            vec![self.value("collection"), self.value("key")],
+            ValueType::I32,
+            vec![],
+            body,
+        )
+    }
+
+    /// A map without the key. Taking a key out of nil leaves nil.
+    fn dissoc(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ClassCastException: value is not a map\n");
+        let body = cases(
+            value_type(self.backend),
+            vec![(self.is_nil(get("collection")), vec![self.nil()])],
+            vec![
+                self.check("collection", message),
+                call(
+                    RuntimeFunction::MapDissoc,
+                    vec![get("collection"), get("key")],
+                ),
+            ],
+        );
+        self.function(
+            vec![self.value("collection"), self.value("key")],
+            vec![],
+            body,
+        )
+    }
+
+    /// A seq of the keys or the values of a map, nil when it is empty
+    fn entry_seq(&self, strings: &mut StringPool, part: i32) -> Function {
+        let message = strings.intern("ClassCastException: value is not a map\n");
+        let body = cases(
+            value_type(self.backend),
+            vec![(self.is_nil(get("collection")), vec![self.nil()])],
+            vec![
+                self.check("collection", message),
+                call(
+                    RuntimeFunction::NodeSeq,
+                    vec![
+                        root(self.backend, get("collection")),
+                        self.nil(),
+                        constant(part),
+                    ],
+                ),
+            ],
+        );
+        self.function(vec![self.value("collection")], vec![], body)
+    }
+
+    /// The element of a set equal to the key, or the default when it has
+    /// none
+    fn set_get(&self) -> Function {
+        let body = vec![
+            Instruction::set(
+                "entry",
+                call(
+                    RuntimeFunction::FindEntry,
+                    vec![set_map(self.backend, get("set")), get("key")],
+                ),
+            ),
+            choose(
+                self.backend,
+                self.is_nil(get("entry")),
+                vec![get("default")],
+                vec![self.key(get("entry"))],
+            ),
+        ];
+        self.function(
+            vec![self.value("set"), self.value("key"), self.value("default")],
+            vec![self.value("entry")],
+            body,
+        )
+    }
+
+    /// A set without the element. Taking an element out of nil leaves nil.
+    fn disj(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ClassCastException: value is not a set\n");
+        let body = cases(
+            value_type(self.backend),
+            vec![(self.is_nil(get("set")), vec![self.nil()])],
+            vec![
+                Instruction::when(not(tag_is(get("set"), Tag::Set)), vec![fail_with(message)]),
+                call(
+                    RuntimeFunction::NewSet,
+                    vec![call(
+                        RuntimeFunction::MapDissoc,
+                        vec![set_map(self.backend, get("set")), get("key")],
+                    )],
+                ),
+            ],
+        );
+        self.function(vec![self.value("set"), self.value("key")], vec![], body)
+    }
+
+    /// The map with every entry of the other one assoced on. Either can be
+    /// nil.
+    fn merge(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ClassCastException: value is not a map\n");
+        let merged = vec![
+            self.check("map", message),
+            self.check("other", message),
+            Instruction::set("result", get("map")),
+            Instruction::set(
+                "entries",
+                call(
+                    RuntimeFunction::NodeSeq,
+                    vec![
+                        root(self.backend, get("other")),
+                        self.nil(),
+                        constant(ENTRIES),
+                    ],
+                ),
+            ),
+            until(
+                "merged",
+                self.is_nil(get("entries")),
+                vec![
+                    Instruction::set("entry", call(RuntimeFunction::First, vec![get("entries")])),
+                    Instruction::set(
+                        "result",
+                        call(
+                            RuntimeFunction::MapAssoc,
+                            vec![
+                                get("result"),
+                                self.key(get("entry")),
+                                self.entry_value(get("entry")),
+                            ],
+                        ),
+                    ),
+                    Instruction::set("entries", call(RuntimeFunction::Next, vec![get("entries")])),
+                ],
+            ),
+            get("result"),
+        ];
+        let body = cases(
+            value_type(self.backend),
+            vec![
+                (self.is_nil(get("other")), vec![get("map")]),
+                (self.is_nil(get("map")), vec![get("other")]),
+            ],
+            merged,
+        );
+        self.function(
+            vec![self.value("map"), self.value("other")],
+            vec![
+                self.value("result"),
+                self.value("entries"),
+                self.value("entry"),
+            ],
+            body,
+        )
+    }
+
+    /// Called through the table for a map, set or keyword used as a
+    /// function. A map or set looks up its argument and a keyword looks
+    /// itself up in its argument, each with an optional default.
+    fn invoke(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ArityException: Wrong number of args passed to a map\n");
+        let lookup = |collection: &str, key: &str| {
+            call(
+                RuntimeFunction::Get,
+                vec![get(collection), get(key), get("default")],
+            )
+        };
+        let body = vec![
+            Instruction::when(
+                binary(
+                    BinaryOp::GreaterThanUnsigned,
+                    add(get("count"), -1),
+                    constant(1),
+                ),
+                vec![fail_with(message)],
+            ),
+            Instruction::set(
+                "argument",
+                call(RuntimeFunction::First, vec![get("arguments")]),
+            ),
+            Instruction::when(
+                binary(BinaryOp::Equal, get("count"), constant(2)),
+                vec![Instruction::set(
+                    "default",
+                    call(
+                        RuntimeFunction::First,
+                        vec![call(RuntimeFunction::Next, vec![get("arguments")])],
+                    ),
+                )],
+            ),
+            choose(
+                self.backend,
+                tag_is(get("callee"), Tag::Keyword),
+                vec![lookup("argument", "callee")],
+                vec![lookup("callee", "argument")],
+            ),
+        ];
+        self.function(
+            vec![
+                self.value("callee"),
+                Local::i32("count"),
+                self.value("arguments"),
+            ],
+            vec![self.value("argument"), self.value("default")],
+            body,
+        )
+    }
+}
@@ -1,3 +1,16 @@
+mod binary;
 pub mod emitter;
 mod environment;
+mod equality;
+mod gc;
 mod instructions;
+mod map;
+mod module;
+mod object;
+mod pool;
+mod runtime;
+mod shadow;
+mod validate;
+mod value;
+mod vector;
+mod wat;
@@ -0,0 +1,192 @@
+use crate::codegen::instructions::Instruction;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ValueType {
+    I32,
+    // nullable reference to anything that can be compared by identity, from
+    // the garbage collection proposal
+    EqRef,
+}
+
+/// What a reference is tested against or cast to
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeapType {
+    I31,
+    // a struct or array type defined in the module
+    Defined(String),
+}
+
+/// How a field of a struct or an element of an array is kept
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StorageType {
+    // packed, read back zero extended to an i32
+    I8,
+    Value(ValueType),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Field {
+    pub storage: StorageType,
+    pub mutable: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Composite {
+    Struct(Vec<Field>),
+    Array(Field),
+}
+
+/// A type of object the engine allocates and collects
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeDefinition {
+    pub name: String,
+    pub composite: Composite,
+}
+
+/// Parameter and result types of a function
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub params: Vec<ValueType>,
+    pub result: Option<ValueType>,
+}
+
+/// A named parameter or local variable
+#[derive(Clone, Debug, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub value_type: ValueType,
+}
+
+/// A function provided by the host
+#[derive(Debug, PartialEq)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub signature: Signature,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Local>,
+    pub result: Option<ValueType>,
+    pub locals: Vec<Local>,
+    pub body: Vec<Instruction>,
+}
+
+/// A mutable module level variable, set to a constant expression when the
+/// module is instantiated
+#[derive(Debug, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub value_type: ValueType,
+    pub initial: Instruction,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Export {
+    Function(String, String),
+    Memory(String),
+}
+
+/// Bytes placed in linear memory when the module is instantiated
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataSegment {
+    pub offset: i32,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything that makes up a module. Functions, locals and labels are
+/// referred to by name, the back ends turn them into indices.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    // struct and array types, which come before the function types
+    pub types: Vec<TypeDefinition>,
+    pub imports: Vec<Import>,
+    pub memory_pages: u32,
+    pub globals: Vec<Global>,
+    // functions that are called through call_indirect, by their index
+    pub table: Option<Vec<String>>,
+    pub functions: Vec<Function>,
+    pub exports: Vec<Export>,
+    pub data: Vec<DataSegment>,
+}
+
+impl Local {
+    pub fn i32(name: &str) -> Self {
+        Local {
+            name: name.to_owned(),
+            value_type: ValueType::I32,
+        }
+    }
+
+    pub fn eqref(name: &str) -> Self {
+        Local {
+            name: name.to_owned(),
+            value_type: ValueType::EqRef,
+        }
+    }
+}
+
+impl Field {
+    /// The type the field holds once read
+    pub fn value_type(&self) -> ValueType {
+        match self.storage {
+            StorageType::I8 => ValueType::I32,
+            StorageType::Value(value_type) => value_type,
+        }
+    }
+}
+
+impl TypeDefinition {
+    /// Fields of a struct, or the single element field of an array
+    pub fn fields(&self) -> Vec<Field> {
+        match &self.composite {
+            Composite::Struct(fields) => fields.clone(),
+            Composite::Array(element) => vec![*element],
+        }
+    }
+}
+
+impl Function {
+    pub fn signature(&self) -> Signature {
+        Signature {
+            params: self.params.iter().map(|param| param.value_type).collect(),
+            result: self.result,
+        }
+    }
+
+    /// Parameters followed by locals, in index order
+    pub fn variables(&self) -> impl Iterator<Item = &Local> {
+        self.params.iter().chain(self.locals.iter())
+    }
+}
+
+impl Module {
+    /// Index of a struct or array type in the type index space
+    pub fn type_index(&self, name: &str) -> Option<usize> {
+        self.types
+            .iter()
+            .position(|definition| definition.name == name)
+    }
+
+    /// Signature of an imported or defined function
+    pub fn signature_of(&self, name: &str) -> Option<Signature> {
+        match self.imports.iter().find(|import| import.name == name) {
+            Some(import) => Some(import.signature.clone()),
+            None => self
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| function.signature()),
+        }
+    }
+
+    /// Imported functions come first in the function index space
+    pub fn function_names(&self) -> impl Iterator<Item = &String> {
+        self.imports
+            .iter()
+            .map(|import| &import.name)
+            .chain(self.functions.iter().map(|function| &function.name))
+    }
+}
@@ -0,0 +1,430 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, Width};
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::runtime::{until, RuntimeFunction};
+use crate::codegen::value::{self, Tag, NIL};
+
+// Objects the runtime builds for collections are records of a fixed number
+// of value fields, or arrays of any number of values. In linear memory
+// either kind is a header word followed by the values. With wasm-gc a record
+// is a struct of eqrefs and an array is a $values array. Routines written
+// with these helpers work the same with both backends.
+
+pub fn value_type(backend: Backend) -> ValueType {
+    match backend {
+        Backend::Linear => ValueType::I32,
+        Backend::WasmGc => ValueType::EqRef,
+    }
+}
+
+/// A local or parameter holding a value
+pub fn value_local(backend: Backend, name: &str) -> Local {
+    Local {
+        name: name.to_owned(),
+        value_type: value_type(backend),
+    }
+}
+
+pub fn nil(backend: Backend) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::Const(NIL),
+        Backend::WasmGc => Instruction::RefNull,
+    }
+}
+
+/// An if choosing between two values
+pub fn choose(
+    backend: Backend,
+    condition: Instruction,
+    then: Vec<Instruction>,
+    otherwise: Vec<Instruction>,
+) -> Instruction {
+    Instruction::If {
+        result: Some(value_type(backend)),
+        condition: Box::new(condition),
+        then,
+        otherwise,
+    }
+}
+
+pub fn is_nil(backend: Backend, value: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::unary(UnaryOp::EqualZero, value),
+        Backend::WasmGc => Instruction::RefIsNull(Box::new(value)),
+    }
+}
+
+pub fn box_integer(backend: Backend, raw: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => value::box_integer(raw),
+        Backend::WasmGc => gc::box_integer(raw),
+    }
+}
+
+pub fn unbox_integer(backend: Backend, value: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => value::unbox_integer(value),
+        Backend::WasmGc => gc::unbox_integer(value),
+    }
+}
+
+/// The byte length of a string
+pub fn string_length(backend: Backend, string: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => header_length(string),
+        Backend::WasmGc => Instruction::ArrayLen(Box::new(gc::cast(gc::STRING, string))),
+    }
+}
+
+/// A byte of a string, read unsigned
+pub fn byte(backend: Backend, string: Instruction, index: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::Load {
+            width: Width::Byte,
+            offset: 4,
+            address: Box::new(binary(BinaryOp::Add, string, index)),
+        },
+        Backend::WasmGc => Instruction::ArrayGet {
+            name: gc::STRING.to_owned(),
+            packed: true,
+            array: Box::new(gc::cast(gc::STRING, string)),
+            index: Box::new(index),
+        },
+    }
+}
+
+fn header_length(object: Instruction) -> Instruction {
+    Instruction::binary(
+        BinaryOp::ShiftRightSigned,
+        Instruction::load(object),
+        Instruction::Const(8),
+    )
+}
+
+/// Reads a field of a record, which with wasm-gc is a struct of the type
+pub fn field(backend: Backend, record: &str, object: Instruction, index: u32) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::load_offset(object, 4 * (index + 1)),
+        Backend::WasmGc => gc::get_field(record, index, object),
+    }
+}
+
+/// Allocates a record holding the values of the locals. Leaves the record
+/// on the stack, using a local named `object` in linear memory.
+pub fn new_record(backend: Backend, tag: Tag, record: &str, fields: &[&str]) -> Vec<Instruction> {
+    let values = fields.iter().map(|field| Instruction::get(field));
+    match backend {
+        Backend::Linear => {
+            let object = || Instruction::get("object");
+            let size = Instruction::Const(4 * (fields.len() as i32 + 1));
+            let mut body = vec![
+                Instruction::set(
+                    "object",
+                    Instruction::call(RuntimeFunction::Allocate.name(), vec![size]),
+                ),
+                Instruction::store(
+                    object(),
+                    Instruction::Const(tag.header(fields.len() as i32)),
+                ),
+            ];
+            for (index, value) in values.enumerate() {
+                body.push(Instruction::store_offset(
+                    object(),
+                    4 * (index as u32 + 1),
+                    value,
+                ));
+            }
+            body.push(object());
+            body
+        }
+        Backend::WasmGc => vec![Instruction::StructNew(record.to_owned(), values.collect())],
+    }
+}
+
+/// Address of an element of an array in linear memory, less the header
+fn element_address(array: Instruction, index: Instruction) -> Instruction {
+    Instruction::binary(
+        BinaryOp::Add,
+        array,
+        Instruction::binary(BinaryOp::ShiftLeft, index, Instruction::Const(2)),
+    )
+}
+
+pub fn element(backend: Backend, array: Instruction, index: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::load_offset(element_address(array, index), 4),
+        Backend::WasmGc => gc::get_element(array, index),
+    }
+}
+
+pub fn set_element(
+    backend: Backend,
+    array: Instruction,
+    index: Instruction,
+    value: Instruction,
+) -> Instruction {
+    match backend {
+        Backend::Linear => Instruction::store_offset(element_address(array, index), 4, value),
+        Backend::WasmGc => Instruction::ArraySet {
+            name: gc::VALUES.to_owned(),
+            array: Box::new(gc::cast(gc::VALUES, array)),
+            index: Box::new(index),
+            value: Box::new(value),
+        },
+    }
+}
+
+/// The number of values in an array
+pub fn length(backend: Backend, array: Instruction) -> Instruction {
+    match backend {
+        Backend::Linear => header_length(array),
+        Backend::WasmGc => Instruction::ArrayLen(Box::new(gc::cast(gc::VALUES, array))),
+    }
+}
+
+/// An array of the length filled with nil. In linear memory the values are
+/// cleared before anything else can allocate, as the collector reads them.
+pub fn new_array(function: RuntimeFunction, backend: Backend) -> Function {
+    let get = Instruction::get;
+    let body = match backend {
+        Backend::Linear => vec![
+            Instruction::set(
+                "array",
+                Instruction::call(
+                    RuntimeFunction::Allocate.name(),
+                    vec![Instruction::binary(
+                        BinaryOp::ShiftLeft,
+                        Instruction::binary(BinaryOp::Add, get("length"), Instruction::Const(1)),
+                        Instruction::Const(2),
+                    )],
+                ),
+            ),
+            Instruction::store(
+                get("array"),
+                Instruction::binary(
+                    BinaryOp::Or,
+                    Instruction::binary(BinaryOp::ShiftLeft, get("length"), Instruction::Const(8)),
+                    Instruction::Const(Tag::Array.code()),
+                ),
+            ),
+            until(
+                "cleared",
+                Instruction::binary(BinaryOp::GreaterEqual, get("index"), get("length")),
+                vec![
+                    set_element(backend, get("array"), get("index"), nil(backend)),
+                    increment("index"),
+                ],
+            ),
+            get("array"),
+        ],
+        Backend::WasmGc => vec![Instruction::ArrayNew {
+            name: gc::VALUES.to_owned(),
+            value: Box::new(Instruction::RefNull),
+            length: Box::new(get("length")),
+        }],
+    };
+
+    Function {
+        name: function.name().to_owned(),
+        params: vec![Local::i32("length")],
+        result: Some(value_type(backend)),
+        locals: match backend {
+            Backend::Linear => vec![Local::i32("array"), Local::i32("index")],
+            Backend::WasmGc => vec![],
+        },
+        body,
+    }
+}
+
+/// A new array of the length, starting with as many values of the source as
+/// fit. A nil source counts as empty.
+pub fn copy_array(function: RuntimeFunction, backend: Backend) -> Function {
+    let get = Instruction::get;
+    let body = vec![
+        Instruction::set(
+            "array",
+            Instruction::call(RuntimeFunction::NewArray.name(), vec![get("length")]),
+        ),
+        Instruction::when(
+            Instruction::unary(UnaryOp::EqualZero, is_nil(backend, get("source"))),
+            vec![
+                Instruction::set("copied", length(backend, get("source"))),
+                Instruction::when(
+                    Instruction::binary(BinaryOp::GreaterThan, get("copied"), get("length")),
+                    vec![Instruction::set("copied", get("length"))],
+                ),
+            ],
+        ),
+        until(
+            "filled",
+            Instruction::binary(BinaryOp::GreaterEqual, get("index"), get("copied")),
+            vec![
+                set_element(
+                    backend,
+                    get("array"),
+                    get("index"),
+                    element(backend, get("source"), get("index")),
+                ),
+                increment("index"),
+            ],
+        ),
+        get("array"),
+    ];
+
+    Function {
+        name: function.name().to_owned(),
+        params: vec![value_local(backend, "source"), Local::i32("length")],
+        result: Some(value_type(backend)),
+        locals: vec![
+            value_local(backend, "array"),
+            Local::i32("copied"),
+            Local::i32("index"),
+        ],
+        body,
+    }
+}
+
+/// A copy of the array one longer, with the value at the index and the
+/// values from there on moved up
+pub fn array_insert(function: RuntimeFunction, backend: Backend) -> Function {
+    let moved = binary(BinaryOp::GreaterEqual, get("position"), get("index"));
+    let body = vec![
+        Instruction::set("length", length(backend, get("array"))),
+        Instruction::set(
+            "copy",
+            call(RuntimeFunction::NewArray, vec![add(get("length"), 1)]),
+        ),
+        until(
+            "copied",
+            binary(BinaryOp::GreaterEqual, get("position"), get("length")),
+            vec![
+                set_element(
+                    backend,
+                    get("copy"),
+                    binary(BinaryOp::Add, get("position"), moved),
+                    element(backend, get("array"), get("position")),
+                ),
+                increment("position"),
+            ],
+        ),
+        set_element(backend, get("copy"), get("index"), get("value")),
+        get("copy"),
+    ];
+
+    Function {
+        name: function.name().to_owned(),
+        params: vec![
+            value_local(backend, "array"),
+            Local::i32("index"),
+            value_local(backend, "value"),
+        ],
+        result: Some(value_type(backend)),
+        locals: vec![
+            Local::i32("length"),
+            value_local(backend, "copy"),
+            Local::i32("position"),
+        ],
+        body,
+    }
+}
+
+/// A copy of the array one shorter, without the value at the index
+pub fn array_remove(function: RuntimeFunction, backend: Backend) -> Function {
+    let moved = binary(BinaryOp::GreaterEqual, get("position"), get("index"));
+    let body = vec![
+        Instruction::set("length", add(length(backend, get("array")), -1)),
+        Instruction::set("copy", call(RuntimeFunction::NewArray, vec![get("length")])),
+        until(
+            "copied",
+            binary(BinaryOp::GreaterEqual, get("position"), get("length")),
+            vec![
+                set_element(
+                    backend,
+                    get("copy"),
+                    get("position"),
+                    element(
+                        backend,
+                        get("array"),
+                        binary(BinaryOp::Add, get("position"), moved),
+                    ),
+                ),
+                increment("position"),
+            ],
+        ),
+        get("copy"),
+    ];
+
+    Function {
+        name: function.name().to_owned(),
+        params: vec![value_local(backend, "array"), Local::i32("index")],
+        result: Some(value_type(backend)),
+        locals: vec![
+            Local::i32("length"),
+            value_local(backend, "copy"),
+            Local::i32("position"),
+        ],
+        body,
+    }
+}
+
+pub fn increment(name: &str) -> Instruction {
+    Instruction::set(
+        name,
+        Instruction::binary(BinaryOp::Add, Instruction::get(name), Instruction::Const(1)),
+    )
+}
+
+pub fn get(name: &str) -> Instruction {
+    Instruction::get(name)
+}
+
+pub fn constant(value: i32) -> Instruction {
+    Instruction::Const(value)
+}
+
+pub fn binary(op: BinaryOp, left: Instruction, right: Instruction) -> Instruction {
+    Instruction::binary(op, left, right)
+}
+
+pub fn add(value: Instruction, amount: i32) -> Instruction {
+    binary(BinaryOp::Add, value, constant(amount))
+}
+
+pub fn call(function: RuntimeFunction, args: Vec<Instruction>) -> Instruction {
+    Instruction::call(function.name(), args)
+}
+
+pub fn not(condition: Instruction) -> Instruction {
+    Instruction::unary(UnaryOp::EqualZero, condition)
+}
+
+pub fn either(left: Instruction, right: Instruction) -> Instruction {
+    binary(BinaryOp::Or, left, right)
+}
+
+pub fn tag_is(value: Instruction, tag: Tag) -> Instruction {
+    binary(
+        BinaryOp::Equal,
+        call(RuntimeFunction::TypeOf, vec![value]),
+        constant(tag.code()),
+    )
+}
+
+/// Nested ifs trying each case in turn, evaluating to a value of the type
+pub fn cases(
+    result: ValueType,
+    cases: Vec<(Instruction, Vec<Instruction>)>,
+    otherwise: Vec<Instruction>,
+) -> Vec<Instruction> {
+    let mut body = otherwise;
+    for (condition, then) in cases.into_iter().rev() {
+        body = vec![Instruction::If {
+            result: Some(result),
+            condition: Box::new(condition),
+            then,
+            otherwise: body,
+        }];
+    }
+    body
+}
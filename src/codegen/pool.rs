@@ -0,0 +1,93 @@
+use crate::codegen::module::DataSegment;
+use crate::codegen::runtime::DATA_START;
+use crate::codegen::value::Tag;
+use std::collections::HashMap;
+
+/// Places every string literal in its own data segment. Each string is stored
+/// as a header word holding its byte length followed by its UTF-8 bytes, and
+/// the address of the header is what the string evaluates to. Keywords are
+/// laid out the same way with a tag of their own, and interned in a table of
+/// their own, so a keyword is the same address wherever it appears.
+pub struct StringPool {
+    addresses: HashMap<String, i32>,
+    keywords: HashMap<String, i32>,
+    segments: Vec<DataSegment>,
+    next_address: i32,
+}
+
+impl StringPool {
+    pub fn new() -> Self {
+        StringPool {
+            addresses: HashMap::new(),
+            keywords: HashMap::new(),
+            segments: Vec::new(),
+            next_address: DATA_START,
+        }
+    }
+
+    /// Returns the address of the string, adding it to the pool the first time it is seen
+    pub fn intern(&mut self, string: &str) -> i32 {
+        if let Some(address) = self.addresses.get(string) {
+            return *address;
+        }
+        let address = self.place(Tag::String, string);
+        self.addresses.insert(string.to_owned(), address);
+        address
+    }
+
+    /// Returns the address of the keyword with the name, without its colon
+    pub fn intern_keyword(&mut self, name: &str) -> i32 {
+        if let Some(address) = self.keywords.get(name) {
+            return *address;
+        }
+        let address = self.place(Tag::Keyword, name);
+        self.keywords.insert(name.to_owned(), address);
+        address
+    }
+
+    fn place(&mut self, tag: Tag, text: &str) -> i32 {
+        let address = self.next_address;
+        let mut data = tag.header(text.len() as i32).to_le_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        // keep every header word aligned
+        self.next_address += (data.len() as i32 + 3) & !3;
+
+        self.segments.push(DataSegment {
+            offset: address,
+            bytes: data,
+        });
+        address
+    }
+
+    /// The first address after the pooled strings
+    pub fn end(&self) -> i32 {
+        self.next_address
+    }
+
+    pub fn segments(&self) -> &Vec<DataSegment> {
+        &self.segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::pool::StringPool;
+    use crate::codegen::runtime::DATA_START;
+
+    #[test]
+    fn intern_strings() {
+        let mut pool = StringPool::new();
+
+        let hello = pool.intern("hello");
+        let smile = pool.intern("😀");
+
+        assert_eq!(hello, DATA_START);
+        // 4 bytes of header and 5 bytes of text, rounded up
+        assert_eq!(smile, DATA_START + 12);
+        assert_eq!(pool.intern("hello"), hello);
+        assert_eq!(
+            pool.segments()[1].bytes,
+            vec![3, 4, 0, 0, 0xf0, 0x9f, 0x98, 0x80]
+        );
+    }
+}
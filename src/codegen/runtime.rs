@@ -0,0 +1,1541 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::equality;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, WASIImports};
+use crate::codegen::map;
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::object;
+use crate::codegen::pool::StringPool;
+use crate::codegen::value::{unbox_integer, Tag, TRUE};
+use crate::codegen::vector;
+
+/// Address of the io vector handed to fd_write
+pub const IOVEC_ADDRESS: i32 = 0;
+/// Address fd_write stores the number of bytes written into
+pub const WRITTEN_ADDRESS: i32 = 8;
+/// Integers are converted to text backwards from this address
+pub const INTEGER_BUFFER_END: i32 = 32;
+/// Heads of the free lists, a word for each size class
+pub const FREE_LISTS: i32 = 32;
+/// Blocks of size class n take up 8 << n bytes, their header included
+pub const SIZE_CLASSES: i32 = 24;
+/// Start of the static data segments, which the heap follows
+pub const DATA_START: i32 = FREE_LISTS + 4 * SIZE_CLASSES;
+
+/// Global holding the address of the first byte the heap has never handed out
+pub const HEAP: &str = "heap#";
+/// Global holding the address of the first block on the heap
+pub const HEAP_START: &str = "heap_start#";
+/// Set in the header of a block while it is on a free list
+pub const FREE_BLOCK: i32 = 0x100;
+/// Set in the header of a block found reachable during a collection
+pub const MARKED_BLOCK: i32 = 0x200;
+
+/// Global holding the address of the root slots of the vars, which the
+/// shadow stack follows
+pub const ROOTS: &str = "roots#";
+/// Global holding the top of the shadow stack
+pub const SHADOW: &str = "shadow#";
+/// Bytes set aside for the shadow stack, between the roots and the heap
+pub const SHADOW_STACK_SIZE: i32 = 0x10000;
+/// Globals counting the bytes allocated since the last collection, and how
+/// many bytes the next collection waits for
+pub const ALLOCATED: &str = "allocated#";
+pub const THRESHOLD: &str = "threshold#";
+/// Bytes allocated between collections, unless every allocation collects
+pub const COLLECT_AFTER: i32 = 0x100000;
+
+/// Bytes in a page of memory
+pub const PAGE_SIZE: i32 = 0x10000;
+
+/// Global holding the table index of invoke, which stands in for the
+/// function of a map, set or keyword that is called
+pub const INVOKE: &str = "invoke#";
+
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+/// Support routines emitted into the module at most once, the first time
+/// the generated code needs them
+#[derive(Clone, Copy, PartialEq)]
+pub enum RuntimeFunction {
+    PrintInteger,
+    PrintString,
+    Fail,
+    Quotient,
+    Remainder,
+    Modulo,
+    FitInteger,
+    Multiply,
+    Allocate,
+    Free,
+    Collect,
+    Mark,
+    PushFrame,
+    Cons,
+    First,
+    Next,
+    TypeOf,
+    IntegerValue,
+    FunctionIndex,
+    PrintValue,
+    NewArray,
+    CopyArray,
+    NewVector,
+    ArrayFor,
+    Count,
+    Nth,
+    Get,
+    Conj,
+    Assoc,
+    Peek,
+    Pop,
+    Subvec,
+    VectorConj,
+    VectorAssoc,
+    VectorPop,
+    PushTail,
+    NewPath,
+    DoAssoc,
+    PopTail,
+    ArrayInsert,
+    ArrayRemove,
+    Hash,
+    Equal,
+    NewMap,
+    NewMapNode,
+    NewEntry,
+    EntryIndex,
+    FindEntry,
+    MapGet,
+    MapAssoc,
+    NodeAssoc,
+    MapDissoc,
+    NodeDissoc,
+    NodeSeq,
+    NodeHash,
+    MapEqual,
+    NodeContained,
+    PrintNode,
+    Contains,
+    Dissoc,
+    Keys,
+    Vals,
+    Merge,
+    NewSet,
+    SetGet,
+    Disj,
+    Invoke,
+    // only needed with wasm-gc
+    Truthy,
+    PrintText,
+    NewString,
+}
+
+/// Writes whatever the io vector currently points at to a file descriptor
+fn write_iovec(descriptor: i32) -> Instruction {
+    Instruction::drop(Instruction::call(
+        WASIImports::FDWrite.name(),
+        vec![
+            Instruction::Const(descriptor),
+            Instruction::Const(IOVEC_ADDRESS),
+            Instruction::Const(1),
+            Instruction::Const(WRITTEN_ADDRESS),
+        ],
+    ))
+}
+
+pub fn fail_with(message: i32) -> Instruction {
+    Instruction::call(
+        RuntimeFunction::Fail.name(),
+        vec![Instruction::Const(message)],
+    )
+}
+
+/// The byte length kept in the header of a string
+fn string_length(string: Instruction) -> Instruction {
+    Instruction::binary(
+        BinaryOp::ShiftRightSigned,
+        Instruction::load(string),
+        Instruction::Const(8),
+    )
+}
+
+/// Runs the body over and over until the condition holds when checked at
+/// the start
+pub fn until(label: &str, condition: Instruction, mut body: Vec<Instruction>) -> Instruction {
+    let repeat = format!("{}_loop", label);
+    body.insert(
+        0,
+        Instruction::BranchIf(label.to_owned(), Box::new(condition)),
+    );
+    body.push(Instruction::Branch(repeat.to_owned()));
+    Instruction::Block {
+        label: Some(label.to_owned()),
+        result: None,
+        body: vec![Instruction::Loop {
+            label: repeat,
+            result: None,
+            body,
+        }],
+    }
+}
+
+/// The bits of a word that are set in the mask
+fn flag(word: Instruction, mask: i32) -> Instruction {
+    Instruction::binary(BinaryOp::And, word, Instruction::Const(mask))
+}
+
+/// Bytes taken up by a block of a size class
+fn block_size(class: Instruction) -> Instruction {
+    Instruction::binary(BinaryOp::ShiftLeft, Instruction::Const(8), class)
+}
+
+/// Address of the head of a size class's free list
+fn free_list(class: Instruction) -> Instruction {
+    Instruction::binary(
+        BinaryOp::Add,
+        Instruction::Const(FREE_LISTS),
+        Instruction::binary(BinaryOp::ShiftLeft, class, Instruction::Const(2)),
+    )
+}
+
+fn subtract(left: Instruction, right: Instruction) -> Instruction {
+    Instruction::binary(BinaryOp::Subtract, left, right)
+}
+
+impl RuntimeFunction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuntimeFunction::PrintInteger => "print_integer",
+            RuntimeFunction::PrintString => "print_string",
+            RuntimeFunction::Fail => "fail",
+            RuntimeFunction::Quotient => "quotient",
+            RuntimeFunction::Remainder => "remainder",
+            RuntimeFunction::Modulo => "modulo",
+            RuntimeFunction::FitInteger => "fit_integer",
+            RuntimeFunction::Multiply => "multiply",
+            RuntimeFunction::Allocate => "allocate",
+            RuntimeFunction::Free => "free",
+            RuntimeFunction::Collect => "collect",
+            RuntimeFunction::Mark => "mark",
+            RuntimeFunction::PushFrame => "push_frame",
+            RuntimeFunction::Cons => "cons",
+            RuntimeFunction::First => "first",
+            RuntimeFunction::Next => "next",
+            RuntimeFunction::TypeOf => "type_of",
+            RuntimeFunction::IntegerValue => "integer_value",
+            RuntimeFunction::FunctionIndex => "function_index",
+            RuntimeFunction::PrintValue => "print_value",
+            RuntimeFunction::NewArray => "new_array",
+            RuntimeFunction::CopyArray => "copy_array",
+            RuntimeFunction::NewVector => "new_vector",
+            RuntimeFunction::ArrayFor => "array_for",
+            RuntimeFunction::Count => "count",
+            RuntimeFunction::Nth => "nth",
+            RuntimeFunction::Get => "get",
+            RuntimeFunction::Conj => "conj",
+            RuntimeFunction::Assoc => "assoc",
+            RuntimeFunction::Peek => "peek",
+            RuntimeFunction::Pop => "pop",
+            RuntimeFunction::Subvec => "subvec",
+            RuntimeFunction::VectorConj => "vector_conj",
+            RuntimeFunction::VectorAssoc => "vector_assoc",
+            RuntimeFunction::VectorPop => "vector_pop",
+            RuntimeFunction::PushTail => "push_tail",
+            RuntimeFunction::NewPath => "new_path",
+            RuntimeFunction::DoAssoc => "do_assoc",
+            RuntimeFunction::PopTail => "pop_tail",
+            RuntimeFunction::ArrayInsert => "array_insert",
+            RuntimeFunction::ArrayRemove => "array_remove",
+            RuntimeFunction::Hash => "hash",
+            RuntimeFunction::Equal => "equal",
+            RuntimeFunction::NewMap => "new_map",
+            RuntimeFunction::NewMapNode => "new_map_node",
+            RuntimeFunction::NewEntry => "new_entry",
+            RuntimeFunction::EntryIndex => "entry_index",
+            RuntimeFunction::FindEntry => "find_entry",
+            RuntimeFunction::MapGet => "map_get",
+            RuntimeFunction::MapAssoc => "map_assoc",
+            RuntimeFunction::NodeAssoc => "node_assoc",
+            RuntimeFunction::MapDissoc => "map_dissoc",
+            RuntimeFunction::NodeDissoc => "node_dissoc",
+            RuntimeFunction::NodeSeq => "node_seq",
+            RuntimeFunction::NodeHash => "node_hash",
+            RuntimeFunction::MapEqual => "map_equal",
+            RuntimeFunction::NodeContained => "node_contained",
+            RuntimeFunction::PrintNode => "print_node",
+            RuntimeFunction::Contains => "contains",
+            RuntimeFunction::Dissoc => "dissoc",
+            RuntimeFunction::Keys => "keys",
+            RuntimeFunction::Vals => "vals",
+            RuntimeFunction::Merge => "merge",
+            RuntimeFunction::NewSet => "new_set",
+            RuntimeFunction::SetGet => "set_get",
+            RuntimeFunction::Disj => "disj",
+            RuntimeFunction::Invoke => "invoke",
+            RuntimeFunction::Truthy => "truthy",
+            RuntimeFunction::PrintText => "print_text",
+            RuntimeFunction::NewString => "new_string",
+        }
+    }
+
+    /// Host functions the routine calls
+    pub fn imports(&self) -> Vec<WASIImports> {
+        match self {
+            RuntimeFunction::PrintInteger | RuntimeFunction::PrintString => {
+                vec![WASIImports::FDWrite]
+            }
+            RuntimeFunction::Fail => vec![WASIImports::FDWrite, WASIImports::ProcExit],
+            _ => vec![],
+        }
+    }
+
+    /// Other routines the routine calls
+    pub fn dependencies(&self, backend: Backend) -> Vec<RuntimeFunction> {
+        if backend == Backend::WasmGc {
+            if let Some(dependencies) = gc::dependencies(*self) {
+                return dependencies;
+            }
+        }
+        match self {
+            RuntimeFunction::Quotient => vec![RuntimeFunction::Fail, RuntimeFunction::FitInteger],
+            RuntimeFunction::Remainder | RuntimeFunction::Modulo | RuntimeFunction::FitInteger => {
+                vec![RuntimeFunction::Fail]
+            }
+            RuntimeFunction::Multiply => vec![RuntimeFunction::FitInteger, RuntimeFunction::Fail],
+            RuntimeFunction::Allocate => vec![RuntimeFunction::Fail, RuntimeFunction::Collect],
+            RuntimeFunction::Collect => vec![RuntimeFunction::Mark, RuntimeFunction::Free],
+            RuntimeFunction::PushFrame => vec![RuntimeFunction::Fail],
+            RuntimeFunction::Cons => vec![RuntimeFunction::Allocate],
+            RuntimeFunction::IntegerValue => vec![RuntimeFunction::Fail],
+            RuntimeFunction::FunctionIndex => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::Invoke,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::PrintValue => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::PrintInteger,
+                RuntimeFunction::PrintString,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::PrintNode,
+            ],
+            RuntimeFunction::NewArray
+            | RuntimeFunction::NewVector
+            | RuntimeFunction::NewMap
+            | RuntimeFunction::NewMapNode
+            | RuntimeFunction::NewEntry
+            | RuntimeFunction::NewSet => vec![RuntimeFunction::Allocate],
+            _ => self.collection_dependencies(),
+        }
+    }
+
+    /// Parameters holding values while the routine allocates, which have to
+    /// be on the shadow stack in case a collection runs
+    pub fn roots(&self) -> Vec<&'static str> {
+        match self {
+            RuntimeFunction::Cons => vec!["first", "next"],
+            RuntimeFunction::CopyArray => vec!["source"],
+            RuntimeFunction::NewVector => vec!["root", "tail"],
+            RuntimeFunction::Subvec => vec!["vector", "result"],
+            RuntimeFunction::VectorConj => vec![
+                "vector", "value", "root", "tail", "path", "new_root", "new_tail",
+            ],
+            RuntimeFunction::VectorAssoc => {
+                vec!["vector", "value", "root", "tail", "new_tail", "new_root"]
+            }
+            RuntimeFunction::VectorPop => vec!["vector", "root", "tail", "new_tail", "new_root"],
+            RuntimeFunction::PushTail => vec!["parent", "tail", "child"],
+            RuntimeFunction::NewPath => vec!["node", "child"],
+            RuntimeFunction::DoAssoc => vec!["node", "value", "child"],
+            RuntimeFunction::PopTail => vec!["node", "child"],
+            RuntimeFunction::Assoc => vec!["key", "value"],
+            RuntimeFunction::ArrayInsert => vec!["array", "value"],
+            RuntimeFunction::ArrayRemove => vec!["array"],
+            RuntimeFunction::NewMap => vec!["root"],
+            RuntimeFunction::NewMapNode => vec!["array"],
+            RuntimeFunction::NewEntry => vec!["key", "value"],
+            RuntimeFunction::MapAssoc => vec!["map", "key", "value", "entry", "root"],
+            RuntimeFunction::NodeAssoc => vec!["node", "entry", "array", "item", "copy"],
+            RuntimeFunction::MapDissoc => vec!["map", "key"],
+            RuntimeFunction::NodeDissoc => vec!["node", "key", "array", "child", "copy"],
+            RuntimeFunction::NodeSeq => vec!["node", "seq", "array"],
+            RuntimeFunction::Merge => vec!["map", "other", "result", "entries"],
+            RuntimeFunction::NewSet => vec!["map"],
+            _ => vec![],
+        }
+    }
+
+    /// Routines working on collections call the same routines with either
+    /// backend
+    fn collection_dependencies(&self) -> Vec<RuntimeFunction> {
+        match self {
+            RuntimeFunction::CopyArray => vec![RuntimeFunction::NewArray],
+            RuntimeFunction::ArrayFor => vec![],
+            RuntimeFunction::Count => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::Next,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Nth => vec![
+                RuntimeFunction::IntegerValue,
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Get => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::MapGet,
+                RuntimeFunction::SetGet,
+            ],
+            RuntimeFunction::Conj => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::VectorConj,
+                RuntimeFunction::Cons,
+                RuntimeFunction::Nth,
+                RuntimeFunction::MapAssoc,
+                RuntimeFunction::NewSet,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Assoc => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::VectorAssoc,
+                RuntimeFunction::NewMap,
+                RuntimeFunction::MapAssoc,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Peek => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::First,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Pop => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::Next,
+                RuntimeFunction::VectorPop,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Subvec => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::IntegerValue,
+                RuntimeFunction::NewVector,
+                RuntimeFunction::NewArray,
+                RuntimeFunction::VectorConj,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::VectorConj => vec![
+                RuntimeFunction::CopyArray,
+                RuntimeFunction::NewArray,
+                RuntimeFunction::NewVector,
+                RuntimeFunction::PushTail,
+                RuntimeFunction::NewPath,
+            ],
+            RuntimeFunction::VectorAssoc => vec![
+                RuntimeFunction::IntegerValue,
+                RuntimeFunction::VectorConj,
+                RuntimeFunction::CopyArray,
+                RuntimeFunction::NewVector,
+                RuntimeFunction::DoAssoc,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::VectorPop => vec![
+                RuntimeFunction::NewVector,
+                RuntimeFunction::NewArray,
+                RuntimeFunction::CopyArray,
+                RuntimeFunction::ArrayFor,
+                RuntimeFunction::PopTail,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::PushTail => vec![RuntimeFunction::NewPath, RuntimeFunction::CopyArray],
+            RuntimeFunction::NewPath => vec![RuntimeFunction::NewArray],
+            RuntimeFunction::DoAssoc | RuntimeFunction::PopTail => {
+                vec![RuntimeFunction::CopyArray]
+            }
+            RuntimeFunction::ArrayInsert | RuntimeFunction::ArrayRemove => {
+                vec![RuntimeFunction::NewArray]
+            }
+            RuntimeFunction::Hash => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::Count,
+                RuntimeFunction::Nth,
+                RuntimeFunction::First,
+                RuntimeFunction::Next,
+                RuntimeFunction::NodeHash,
+            ],
+            RuntimeFunction::Equal => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::Count,
+                RuntimeFunction::Nth,
+                RuntimeFunction::First,
+                RuntimeFunction::Next,
+                RuntimeFunction::MapEqual,
+            ],
+            RuntimeFunction::EntryIndex => vec![RuntimeFunction::Equal],
+            RuntimeFunction::FindEntry => vec![
+                RuntimeFunction::Hash,
+                RuntimeFunction::Equal,
+                RuntimeFunction::EntryIndex,
+            ],
+            RuntimeFunction::MapGet => vec![RuntimeFunction::FindEntry],
+            RuntimeFunction::MapAssoc => vec![
+                RuntimeFunction::FindEntry,
+                RuntimeFunction::NewEntry,
+                RuntimeFunction::NodeAssoc,
+                RuntimeFunction::NewMap,
+            ],
+            RuntimeFunction::NodeAssoc => vec![
+                RuntimeFunction::NewArray,
+                RuntimeFunction::CopyArray,
+                RuntimeFunction::ArrayInsert,
+                RuntimeFunction::NewMapNode,
+                RuntimeFunction::EntryIndex,
+                RuntimeFunction::Equal,
+                RuntimeFunction::Hash,
+                RuntimeFunction::TypeOf,
+            ],
+            RuntimeFunction::MapDissoc => vec![
+                RuntimeFunction::FindEntry,
+                RuntimeFunction::NodeDissoc,
+                RuntimeFunction::NewMap,
+            ],
+            RuntimeFunction::NodeDissoc => vec![
+                RuntimeFunction::EntryIndex,
+                RuntimeFunction::ArrayRemove,
+                RuntimeFunction::CopyArray,
+                RuntimeFunction::NewMapNode,
+                RuntimeFunction::TypeOf,
+            ],
+            RuntimeFunction::NodeSeq => vec![RuntimeFunction::TypeOf, RuntimeFunction::Cons],
+            RuntimeFunction::NodeHash => vec![RuntimeFunction::TypeOf, RuntimeFunction::Hash],
+            RuntimeFunction::MapEqual => vec![RuntimeFunction::NodeContained],
+            RuntimeFunction::NodeContained => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::FindEntry,
+                RuntimeFunction::Equal,
+            ],
+            RuntimeFunction::PrintNode => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::PrintString,
+                RuntimeFunction::PrintValue,
+            ],
+            RuntimeFunction::Contains => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::FindEntry,
+                RuntimeFunction::Count,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Dissoc => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::MapDissoc,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Keys | RuntimeFunction::Vals => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::NodeSeq,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Merge => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::NodeSeq,
+                RuntimeFunction::First,
+                RuntimeFunction::Next,
+                RuntimeFunction::MapAssoc,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::SetGet => vec![RuntimeFunction::FindEntry],
+            RuntimeFunction::Disj => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::MapDissoc,
+                RuntimeFunction::NewSet,
+                RuntimeFunction::Fail,
+            ],
+            RuntimeFunction::Invoke => vec![
+                RuntimeFunction::TypeOf,
+                RuntimeFunction::First,
+                RuntimeFunction::Next,
+                RuntimeFunction::Get,
+                RuntimeFunction::Fail,
+            ],
+            _ => vec![],
+        }
+    }
+
+    /// Builds the routine, placing any text it prints in the string pool
+    pub fn definition(&self, strings: &mut StringPool, backend: Backend) -> Function {
+        if backend == Backend::WasmGc {
+            if let Some(function) = gc::definition(*self, strings) {
+                return function;
+            }
+        }
+        match self {
+            RuntimeFunction::PrintInteger => self.print_integer(),
+            RuntimeFunction::PrintString => self.print_string(),
+            RuntimeFunction::Fail => self.fail(),
+            // the one quotient past an integer is the least one divided by -1
+            RuntimeFunction::Quotient => self.checked_division(
+                strings,
+                vec![],
+                vec![Instruction::call(
+                    RuntimeFunction::FitInteger.name(),
+                    vec![Instruction::binary(
+                        BinaryOp::DivideSigned,
+                        Instruction::get("dividend"),
+                        Instruction::get("divisor"),
+                    )],
+                )],
+            ),
+            RuntimeFunction::Remainder => self.checked_division(
+                strings,
+                vec![],
+                vec![Instruction::binary(
+                    BinaryOp::RemainderSigned,
+                    Instruction::get("dividend"),
+                    Instruction::get("divisor"),
+                )],
+            ),
+            RuntimeFunction::Modulo => {
+                self.checked_division(strings, vec![Local::i32("remainder")], modulo())
+            }
+            RuntimeFunction::FitInteger => self.fit_integer(strings),
+            RuntimeFunction::Multiply => self.multiply(strings),
+            RuntimeFunction::Allocate => self.allocate(strings),
+            RuntimeFunction::Free => self.free(),
+            RuntimeFunction::Collect => self.collect(),
+            RuntimeFunction::Mark => self.mark(),
+            RuntimeFunction::PushFrame => self.push_frame(strings),
+            RuntimeFunction::Cons => self.cons(),
+            RuntimeFunction::First => self.seq_field(4),
+            RuntimeFunction::Next => self.seq_field(8),
+            RuntimeFunction::TypeOf => self.type_of(),
+            RuntimeFunction::IntegerValue => self.integer_value(strings),
+            RuntimeFunction::FunctionIndex => self.function_index(strings),
+            RuntimeFunction::PrintValue => self.print_value(strings),
+            RuntimeFunction::NewArray => object::new_array(*self, backend),
+            RuntimeFunction::CopyArray => object::copy_array(*self, backend),
+            RuntimeFunction::ArrayInsert => object::array_insert(*self, backend),
+            RuntimeFunction::ArrayRemove => object::array_remove(*self, backend),
+            RuntimeFunction::Truthy | RuntimeFunction::PrintText | RuntimeFunction::NewString => {
+                unreachable!("{} is only built for wasm-gc", self.name())
+            }
+            _ => equality::definition(*self, backend)
+                .or_else(|| map::definition(*self, strings, backend))
+                .or_else(|| vector::definition(*self, strings, backend))
+                .unwrap_or_else(|| unreachable!("{} has no definition", self.name())),
+        }
+    }
+
+    /// A seq is nil when empty, otherwise a pair of its first value and the
+    /// seq of the rest
+    fn cons(&self) -> Function {
+        let body = vec![
+            Instruction::set(
+                "cell",
+                Instruction::call(
+                    RuntimeFunction::Allocate.name(),
+                    vec![Instruction::Const(12)],
+                ),
+            ),
+            Instruction::store(
+                Instruction::get("cell"),
+                Instruction::Const(Tag::Seq.header(2)),
+            ),
+            Instruction::store_offset(Instruction::get("cell"), 4, Instruction::get("first")),
+            Instruction::store_offset(Instruction::get("cell"), 8, Instruction::get("next")),
+            Instruction::get("cell"),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("first"), Local::i32("next")],
+            result: Some(ValueType::I32),
+            locals: vec![Local::i32("cell")],
+            body,
+        }
+    }
+
+    /// Reads half of a seq's first pair, or nil for the empty seq
+    fn seq_field(&self, offset: u32) -> Function {
+        let body = vec![Instruction::choose(
+            Instruction::get("seq"),
+            vec![Instruction::load_offset(Instruction::get("seq"), offset)],
+            vec![Instruction::Const(0)],
+        )];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("seq")],
+            result: Some(ValueType::I32),
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// The tag code of any value, read from the header of objects
+    fn type_of(&self) -> Function {
+        let bit = |mask| {
+            Instruction::binary(
+                BinaryOp::And,
+                Instruction::get("value"),
+                Instruction::Const(mask),
+            )
+        };
+        let body = vec![Instruction::choose(
+            bit(1),
+            vec![Instruction::Const(Tag::Integer.code())],
+            vec![Instruction::choose(
+                Instruction::unary(UnaryOp::EqualZero, Instruction::get("value")),
+                vec![Instruction::Const(Tag::Nil.code())],
+                vec![Instruction::choose(
+                    bit(2),
+                    vec![Instruction::Const(Tag::Boolean.code())],
+                    vec![Instruction::load8(Instruction::get("value"))],
+                )],
+            )],
+        )];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: Some(ValueType::I32),
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// The raw i32 of an integer, failing for any other value
+    fn integer_value(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ClassCastException: value is not an integer\n");
+        let body = vec![
+            Instruction::when(
+                Instruction::unary(
+                    UnaryOp::EqualZero,
+                    Instruction::binary(
+                        BinaryOp::And,
+                        Instruction::get("value"),
+                        Instruction::Const(1),
+                    ),
+                ),
+                vec![fail_with(message)],
+            ),
+            Instruction::binary(
+                BinaryOp::ShiftRightSigned,
+                Instruction::get("value"),
+                Instruction::Const(1),
+            ),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: Some(ValueType::I32),
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// The table index of a closure, failing for any other value. Maps, sets
+    /// and keywords are called through invoke.
+    fn function_index(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ClassCastException: value is not a function\n");
+        let tag_is = |tag: Tag| {
+            Instruction::binary(
+                BinaryOp::Equal,
+                Instruction::get("tag"),
+                Instruction::Const(tag.code()),
+            )
+        };
+        let body = vec![
+            Instruction::set(
+                "tag",
+                Instruction::call(
+                    RuntimeFunction::TypeOf.name(),
+                    vec![Instruction::get("value")],
+                ),
+            ),
+            Instruction::choose(
+                tag_is(Tag::Function),
+                vec![unbox_integer(Instruction::load_offset(
+                    Instruction::get("value"),
+                    4,
+                ))],
+                vec![Instruction::choose(
+                    Instruction::binary(
+                        BinaryOp::Or,
+                        Instruction::binary(BinaryOp::Or, tag_is(Tag::Map), tag_is(Tag::Set)),
+                        tag_is(Tag::Keyword),
+                    ),
+                    vec![Instruction::global_get(INVOKE)],
+                    vec![fail_with(message), Instruction::Const(0)],
+                )],
+            ),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: Some(ValueType::I32),
+            locals: vec![Local::i32("tag")],
+            body,
+        }
+    }
+
+    /// Prints any value the way its tag says it should look. Seqs print
+    /// their elements in parentheses, vectors in brackets, maps in braces
+    /// and sets in braces after a hash.
+    fn print_value(&self, strings: &mut StringPool) -> Function {
+        let mut literal = |text: &str| {
+            Instruction::call(
+                RuntimeFunction::PrintString.name(),
+                vec![Instruction::Const(strings.intern(text))],
+            )
+        };
+        let tag_is = |tag: Tag| {
+            Instruction::binary(
+                BinaryOp::Equal,
+                Instruction::get("tag"),
+                Instruction::Const(tag.code()),
+            )
+        };
+        let boolean = Instruction::If {
+            result: None,
+            condition: Box::new(Instruction::binary(
+                BinaryOp::Equal,
+                Instruction::get("value"),
+                Instruction::Const(TRUE),
+            )),
+            then: vec![literal("true")],
+            otherwise: vec![literal("false")],
+        };
+        let seq = vec![
+            literal("("),
+            Instruction::Loop {
+                label: "elements".to_owned(),
+                result: None,
+                body: vec![
+                    Instruction::call(
+                        self.name(),
+                        vec![Instruction::load_offset(Instruction::get("value"), 4)],
+                    ),
+                    Instruction::set(
+                        "value",
+                        Instruction::load_offset(Instruction::get("value"), 8),
+                    ),
+                    Instruction::when(
+                        Instruction::get("value"),
+                        vec![literal(" "), Instruction::Branch("elements".to_owned())],
+                    ),
+                ],
+            },
+            literal(")"),
+        ];
+        let vector = vector::print_elements(Backend::Linear, self.name(), &mut literal);
+        let map = map::print_entries(Backend::Linear, &mut literal);
+        let set = map::print_members(Backend::Linear, &mut literal);
+        let keyword = vec![
+            literal(":"),
+            Instruction::call(
+                RuntimeFunction::PrintString.name(),
+                vec![Instruction::get("value")],
+            ),
+        ];
+        let cases = vec![
+            (
+                tag_is(Tag::Integer),
+                vec![Instruction::call(
+                    RuntimeFunction::PrintInteger.name(),
+                    vec![Instruction::binary(
+                        BinaryOp::ShiftRightSigned,
+                        Instruction::get("value"),
+                        Instruction::Const(1),
+                    )],
+                )],
+            ),
+            (
+                tag_is(Tag::String),
+                vec![Instruction::call(
+                    RuntimeFunction::PrintString.name(),
+                    vec![Instruction::get("value")],
+                )],
+            ),
+            (tag_is(Tag::Nil), vec![literal("nil")]),
+            (tag_is(Tag::Boolean), vec![boolean]),
+            (tag_is(Tag::Seq), seq),
+            (tag_is(Tag::Vector), vector),
+            (tag_is(Tag::Map), map),
+            (tag_is(Tag::Keyword), keyword),
+            (tag_is(Tag::Set), set),
+        ];
+        // anything else is a function
+        let mut body = vec![literal("#function")];
+        for (condition, then) in cases.into_iter().rev() {
+            body = vec![Instruction::If {
+                result: None,
+                condition: Box::new(condition),
+                then,
+                otherwise: body,
+            }];
+        }
+        body.insert(
+            0,
+            Instruction::set(
+                "tag",
+                Instruction::call(
+                    RuntimeFunction::TypeOf.name(),
+                    vec![Instruction::get("value")],
+                ),
+            ),
+        );
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: None,
+            locals: vec![Local::i32("tag"), Local::i32("index"), Local::i32("count")],
+            body,
+        }
+    }
+
+    /// Hands out a block of the smallest size class that fits, from its free
+    /// list when there is one and from the end of the heap otherwise. The
+    /// memory grows when the heap runs past it. Evaluates to the address
+    /// after the header, the contents are not cleared.
+    fn allocate(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("OutOfMemoryError: Cannot allocate memory\n");
+        let get = Instruction::get;
+        let memory_end = || {
+            Instruction::binary(
+                BinaryOp::ShiftLeft,
+                Instruction::MemorySize,
+                Instruction::Const(16),
+            )
+        };
+        // rounding up, the pages needed to reach the end of the block
+        let pages = Instruction::binary(
+            BinaryOp::ShiftRightUnsigned,
+            Instruction::binary(BinaryOp::Add, get("end"), Instruction::Const(PAGE_SIZE - 1)),
+            Instruction::Const(16),
+        );
+        let grow = Instruction::MemoryGrow(Box::new(subtract(pages, Instruction::MemorySize)));
+        let body = vec![
+            Instruction::when(
+                Instruction::binary(
+                    BinaryOp::GreaterEqual,
+                    Instruction::global_get(ALLOCATED),
+                    Instruction::global_get(THRESHOLD),
+                ),
+                vec![
+                    Instruction::call(RuntimeFunction::Collect.name(), vec![]),
+                    Instruction::global_set(ALLOCATED, Instruction::Const(0)),
+                ],
+            ),
+            // the block has to hold the size and its header
+            Instruction::set(
+                "class",
+                subtract(
+                    Instruction::Const(29),
+                    Instruction::unary(
+                        UnaryOp::CountLeadingZeros,
+                        Instruction::binary(BinaryOp::Add, get("size"), Instruction::Const(3)),
+                    ),
+                ),
+            ),
+            Instruction::when(
+                Instruction::binary(BinaryOp::LessThan, get("class"), Instruction::Const(0)),
+                vec![Instruction::set("class", Instruction::Const(0))],
+            ),
+            Instruction::when(
+                Instruction::binary(
+                    BinaryOp::GreaterEqual,
+                    get("class"),
+                    Instruction::Const(SIZE_CLASSES),
+                ),
+                vec![fail_with(message)],
+            ),
+            Instruction::global_set(
+                ALLOCATED,
+                Instruction::binary(
+                    BinaryOp::Add,
+                    Instruction::global_get(ALLOCATED),
+                    block_size(get("class")),
+                ),
+            ),
+            Instruction::set("head", free_list(get("class"))),
+            Instruction::set("block", Instruction::load(get("head"))),
+            Instruction::If {
+                result: None,
+                condition: Box::new(get("block")),
+                then: vec![Instruction::store(
+                    get("head"),
+                    Instruction::load_offset(get("block"), 4),
+                )],
+                otherwise: vec![
+                    Instruction::set("block", Instruction::global_get(HEAP)),
+                    Instruction::set(
+                        "end",
+                        Instruction::binary(BinaryOp::Add, get("block"), block_size(get("class"))),
+                    ),
+                    Instruction::when(
+                        Instruction::binary(
+                            BinaryOp::GreaterThanUnsigned,
+                            get("end"),
+                            memory_end(),
+                        ),
+                        vec![Instruction::when(
+                            Instruction::binary(BinaryOp::Equal, grow, Instruction::Const(-1)),
+                            vec![fail_with(message)],
+                        )],
+                    ),
+                    Instruction::global_set(HEAP, get("end")),
+                ],
+            },
+            Instruction::store(get("block"), get("class")),
+            Instruction::binary(BinaryOp::Add, get("block"), Instruction::Const(4)),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("size")],
+            result: Some(ValueType::I32),
+            locals: vec![
+                Local::i32("class"),
+                Local::i32("head"),
+                Local::i32("block"),
+                Local::i32("end"),
+            ],
+            body,
+        }
+    }
+
+    /// Marks every block reachable from the roots, then frees every block on
+    /// the heap that was not reached
+    fn collect(&self) -> Function {
+        let get = Instruction::get;
+        let add = |name: &str, amount: Instruction| {
+            Instruction::set(name, Instruction::binary(BinaryOp::Add, get(name), amount))
+        };
+        let mark_roots = until(
+            "marked",
+            Instruction::binary(
+                BinaryOp::GreaterEqual,
+                get("slot"),
+                Instruction::global_get(SHADOW),
+            ),
+            vec![
+                Instruction::call(
+                    RuntimeFunction::Mark.name(),
+                    vec![Instruction::load(get("slot"))],
+                ),
+                add("slot", Instruction::Const(4)),
+            ],
+        );
+        let sweep = until(
+            "swept",
+            Instruction::binary(
+                BinaryOp::GreaterEqual,
+                get("block"),
+                Instruction::global_get(HEAP),
+            ),
+            vec![
+                Instruction::set("header", Instruction::load(get("block"))),
+                // blocks already on a free list stay there
+                Instruction::when(
+                    Instruction::unary(UnaryOp::EqualZero, flag(get("header"), FREE_BLOCK)),
+                    vec![Instruction::If {
+                        result: None,
+                        condition: Box::new(flag(get("header"), MARKED_BLOCK)),
+                        then: vec![Instruction::store(
+                            get("block"),
+                            Instruction::binary(
+                                BinaryOp::Xor,
+                                get("header"),
+                                Instruction::Const(MARKED_BLOCK),
+                            ),
+                        )],
+                        otherwise: vec![Instruction::call(
+                            RuntimeFunction::Free.name(),
+                            vec![Instruction::binary(
+                                BinaryOp::Add,
+                                get("block"),
+                                Instruction::Const(4),
+                            )],
+                        )],
+                    }],
+                ),
+                add("block", block_size(flag(get("header"), 0xff))),
+            ],
+        );
+        let body = vec![
+            Instruction::set("slot", Instruction::global_get(ROOTS)),
+            mark_roots,
+            Instruction::set("block", Instruction::global_get(HEAP_START)),
+            sweep,
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![],
+            result: None,
+            locals: vec![
+                Local::i32("slot"),
+                Local::i32("block"),
+                Local::i32("header"),
+            ],
+            body,
+        }
+    }
+
+    /// Marks the block of a value and everything it refers to. Every object
+    /// but a string holds as many values after its header as the length in
+    /// the header says. The last one is followed by looping, so long seqs do
+    /// not recurse deeply.
+    fn mark(&self) -> Function {
+        let get = Instruction::get;
+        let done = |condition| Instruction::BranchIf("done".to_owned(), Box::new(condition));
+        let fields = until(
+            "traced",
+            Instruction::binary(BinaryOp::GreaterEqual, get("field"), get("last")),
+            vec![
+                Instruction::call(self.name(), vec![Instruction::load(get("field"))]),
+                Instruction::set(
+                    "field",
+                    Instruction::binary(BinaryOp::Add, get("field"), Instruction::Const(4)),
+                ),
+            ],
+        );
+        let object = vec![
+            // integers, booleans and nil are not objects
+            done(flag(get("value"), 3)),
+            Instruction::set("block", subtract(get("value"), Instruction::Const(4))),
+            // neither is anything outside the heap, like nil or static strings
+            done(Instruction::binary(
+                BinaryOp::LessThan,
+                get("block"),
+                Instruction::global_get(HEAP_START),
+            )),
+            done(Instruction::binary(
+                BinaryOp::GreaterEqual,
+                get("block"),
+                Instruction::global_get(HEAP),
+            )),
+            Instruction::set("header", Instruction::load(get("block"))),
+            done(flag(get("header"), FREE_BLOCK | MARKED_BLOCK)),
+            Instruction::store(
+                get("block"),
+                Instruction::binary(
+                    BinaryOp::Or,
+                    get("header"),
+                    Instruction::Const(MARKED_BLOCK),
+                ),
+            ),
+            done(Instruction::binary(
+                BinaryOp::Equal,
+                Instruction::load8(get("value")),
+                Instruction::Const(Tag::String.code()),
+            )),
+            Instruction::set(
+                "last",
+                Instruction::binary(
+                    BinaryOp::Add,
+                    get("value"),
+                    Instruction::binary(
+                        BinaryOp::ShiftRightSigned,
+                        Instruction::load(get("value")),
+                        Instruction::Const(6),
+                    ),
+                ),
+            ),
+            done(Instruction::binary(
+                BinaryOp::Equal,
+                get("last"),
+                get("value"),
+            )),
+            Instruction::set(
+                "field",
+                Instruction::binary(BinaryOp::Add, get("value"), Instruction::Const(4)),
+            ),
+            fields,
+            Instruction::set("value", Instruction::load(get("last"))),
+            Instruction::Branch("object".to_owned()),
+        ];
+        let body = vec![Instruction::Block {
+            label: Some("done".to_owned()),
+            result: None,
+            body: vec![Instruction::Loop {
+                label: "object".to_owned(),
+                result: None,
+                body: object,
+            }],
+        }];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: None,
+            locals: vec![
+                Local::i32("block"),
+                Local::i32("header"),
+                Local::i32("field"),
+                Local::i32("last"),
+            ],
+            body,
+        }
+    }
+
+    /// Takes a number of slots off the shadow stack and clears them.
+    /// Evaluates to the address of the first one.
+    fn push_frame(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("StackOverflowError\n");
+        let get = Instruction::get;
+        let body = vec![
+            Instruction::set("frame", Instruction::global_get(SHADOW)),
+            Instruction::set(
+                "end",
+                Instruction::binary(
+                    BinaryOp::Add,
+                    get("frame"),
+                    Instruction::binary(BinaryOp::ShiftLeft, get("slots"), Instruction::Const(2)),
+                ),
+            ),
+            // the shadow stack ends where the heap starts
+            Instruction::when(
+                Instruction::binary(
+                    BinaryOp::GreaterThan,
+                    get("end"),
+                    Instruction::global_get(HEAP_START),
+                ),
+                vec![fail_with(message)],
+            ),
+            Instruction::global_set(SHADOW, get("end")),
+            Instruction::set("slot", get("frame")),
+            until(
+                "cleared",
+                Instruction::binary(BinaryOp::GreaterEqual, get("slot"), get("end")),
+                vec![
+                    Instruction::store(get("slot"), Instruction::Const(0)),
+                    Instruction::set(
+                        "slot",
+                        Instruction::binary(BinaryOp::Add, get("slot"), Instruction::Const(4)),
+                    ),
+                ],
+            ),
+            get("frame"),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("slots")],
+            result: Some(ValueType::I32),
+            locals: vec![Local::i32("frame"), Local::i32("end"), Local::i32("slot")],
+            body,
+        }
+    }
+
+    /// Puts an allocated block back on the free list of its size class
+    fn free(&self) -> Function {
+        let get = Instruction::get;
+        let body = vec![
+            Instruction::set("block", subtract(get("address"), Instruction::Const(4))),
+            Instruction::set(
+                "head",
+                free_list(Instruction::binary(
+                    BinaryOp::And,
+                    Instruction::load(get("block")),
+                    Instruction::Const(0xff),
+                )),
+            ),
+            Instruction::store(
+                get("block"),
+                Instruction::binary(
+                    BinaryOp::Or,
+                    Instruction::load(get("block")),
+                    Instruction::Const(FREE_BLOCK),
+                ),
+            ),
+            Instruction::store_offset(get("block"), 4, Instruction::load(get("head"))),
+            Instruction::store(get("head"), get("block")),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("address")],
+            result: None,
+            locals: vec![Local::i32("block"), Local::i32("head")],
+            body,
+        }
+    }
+
+    /// Writes the decimal digits of the parameter backwards into the integer
+    /// buffer, then prints the part of the buffer that was used
+    fn print_integer(&self) -> Function {
+        let negative = || {
+            Instruction::binary(
+                BinaryOp::LessThan,
+                Instruction::get("value"),
+                Instruction::Const(0),
+            )
+        };
+        let step_back = || {
+            Instruction::set(
+                "position",
+                subtract(Instruction::get("position"), Instruction::Const(1)),
+            )
+        };
+        let body = vec![
+            Instruction::set("position", Instruction::Const(INTEGER_BUFFER_END)),
+            Instruction::set("magnitude", Instruction::get("value")),
+            Instruction::when(
+                negative(),
+                vec![Instruction::set(
+                    "magnitude",
+                    subtract(Instruction::Const(0), Instruction::get("value")),
+                )],
+            ),
+            Instruction::Loop {
+                label: "digits".to_owned(),
+                result: None,
+                body: vec![
+                    step_back(),
+                    Instruction::store8(
+                        Instruction::get("position"),
+                        Instruction::binary(
+                            BinaryOp::Add,
+                            Instruction::Const('0' as i32),
+                            Instruction::binary(
+                                BinaryOp::RemainderUnsigned,
+                                Instruction::get("magnitude"),
+                                Instruction::Const(10),
+                            ),
+                        ),
+                    ),
+                    Instruction::set(
+                        "magnitude",
+                        Instruction::binary(
+                            BinaryOp::DivideUnsigned,
+                            Instruction::get("magnitude"),
+                            Instruction::Const(10),
+                        ),
+                    ),
+                    Instruction::BranchIf(
+                        "digits".to_owned(),
+                        Box::new(Instruction::get("magnitude")),
+                    ),
+                ],
+            },
+            Instruction::when(
+                negative(),
+                vec![
+                    step_back(),
+                    Instruction::store8(
+                        Instruction::get("position"),
+                        Instruction::Const('-' as i32),
+                    ),
+                ],
+            ),
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS),
+                Instruction::get("position"),
+            ),
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS + 4),
+                subtract(
+                    Instruction::Const(INTEGER_BUFFER_END),
+                    Instruction::get("position"),
+                ),
+            ),
+            write_iovec(STDOUT),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: None,
+            locals: vec![Local::i32("position"), Local::i32("magnitude")],
+            body,
+        }
+    }
+
+    /// Prints a string laid out as its header followed by its bytes
+    fn print_string(&self) -> Function {
+        let body = vec![
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS),
+                Instruction::binary(
+                    BinaryOp::Add,
+                    Instruction::get("string"),
+                    Instruction::Const(4),
+                ),
+            ),
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS + 4),
+                string_length(Instruction::get("string")),
+            ),
+            write_iovec(STDOUT),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("string")],
+            result: None,
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// Prints a message to stderr and exits with a non-zero status
+    fn fail(&self) -> Function {
+        let body = vec![
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS),
+                Instruction::binary(
+                    BinaryOp::Add,
+                    Instruction::get("message"),
+                    Instruction::Const(4),
+                ),
+            ),
+            Instruction::store(
+                Instruction::Const(IOVEC_ADDRESS + 4),
+                string_length(Instruction::get("message")),
+            ),
+            write_iovec(STDERR),
+            Instruction::call(WASIImports::ProcExit.name(), vec![Instruction::Const(1)]),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("message")],
+            result: None,
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// Gives back a raw i32 that fits in an integer, and fails on any other
+    /// instead of letting it wrap when it is boxed
+    fn fit_integer(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ArithmeticException: integer overflow\n");
+        let value = || Instruction::get("value");
+        // boxing drops the top bit, which has to match the sign
+        let boxed = Instruction::binary(
+            BinaryOp::ShiftRightSigned,
+            Instruction::binary(BinaryOp::ShiftLeft, value(), Instruction::Const(1)),
+            Instruction::Const(1),
+        );
+        let body = vec![
+            Instruction::when(
+                Instruction::binary(BinaryOp::NotEqual, boxed, value()),
+                vec![fail_with(message)],
+            ),
+            value(),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("value")],
+            result: Some(ValueType::I32),
+            locals: vec![],
+            body,
+        }
+    }
+
+    /// Multiplies two raw integers. The product can wrap around an i32 as
+    /// well, which dividing it back by one factor finds out.
+    fn multiply(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("ArithmeticException: integer overflow\n");
+        let get = Instruction::get;
+        let wrapped = Instruction::binary(
+            BinaryOp::NotEqual,
+            Instruction::binary(BinaryOp::DivideSigned, get("product"), get("left")),
+            get("right"),
+        );
+        let body = vec![
+            Instruction::set(
+                "product",
+                Instruction::binary(BinaryOp::Multiply, get("left"), get("right")),
+            ),
+            // dividing by a zero factor would trap
+            Instruction::when(
+                get("left"),
+                vec![Instruction::when(wrapped, vec![fail_with(message)])],
+            ),
+            Instruction::call(RuntimeFunction::FitInteger.name(), vec![get("product")]),
+        ];
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("left"), Local::i32("right")],
+            result: Some(ValueType::I32),
+            locals: vec![Local::i32("product")],
+            body,
+        }
+    }
+
+    /// Fails on a zero divisor instead of letting the engine trap
+    fn checked_division(
+        &self,
+        strings: &mut StringPool,
+        locals: Vec<Local>,
+        result: Vec<Instruction>,
+    ) -> Function {
+        let message = strings.intern("ArithmeticException: Divide by zero\n");
+        let mut body = vec![Instruction::when(
+            Instruction::unary(UnaryOp::EqualZero, Instruction::get("divisor")),
+            vec![fail_with(message)],
+        )];
+        body.extend(result);
+
+        Function {
+            name: self.name().to_owned(),
+            params: vec![Local::i32("dividend"), Local::i32("divisor")],
+            result: Some(ValueType::I32),
+            locals,
+            body,
+        }
+    }
+}
+
+/// The remainder rounded towards negative infinity, so it takes the sign of
+/// the divisor
+fn modulo() -> Vec<Instruction> {
+    // a non-zero remainder whose sign differs from the divisor is moved over
+    let differing_signs = Instruction::binary(
+        BinaryOp::LessThan,
+        Instruction::binary(
+            BinaryOp::Xor,
+            Instruction::get("remainder"),
+            Instruction::get("divisor"),
+        ),
+        Instruction::Const(0),
+    );
+    let adjust = Instruction::binary(
+        BinaryOp::And,
+        Instruction::binary(
+            BinaryOp::NotEqual,
+            Instruction::get("remainder"),
+            Instruction::Const(0),
+        ),
+        differing_signs,
+    );
+    vec![
+        Instruction::set(
+            "remainder",
+            Instruction::binary(
+                BinaryOp::RemainderSigned,
+                Instruction::get("dividend"),
+                Instruction::get("divisor"),
+            ),
+        ),
+        Instruction::choose(
+            adjust,
+            vec![Instruction::binary(
+                BinaryOp::Add,
+                Instruction::get("remainder"),
+                Instruction::get("divisor"),
+            )],
+            vec![Instruction::get("remainder")],
+        ),
+    ]
+}
@@ -0,0 +1,205 @@
+use crate::codegen::instructions::Instruction;
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::runtime::{RuntimeFunction, ROOTS, SHADOW};
+
+// The collector cannot see the wasm stack, so every local that may hold a
+// value is mirrored into a frame on the shadow stack in linear memory. A
+// function takes a frame on entry, stores each rooted local into its slot
+// whenever the local is set, and gives the frame back on the way out. Vars
+// get a slot of their own in front of the shadow stack.
+
+/// Local holding the address of the function's frame
+pub const FRAME: &str = "frame#";
+/// Local holding the result while the frame is given back
+const RESULT: &str = "result#";
+
+struct Frame<'a> {
+    rooted: &'a Vec<String>,
+    vars: &'a Vec<String>,
+    result: Option<ValueType>,
+    // temporaries holding the arguments of tail calls
+    tail: Vec<String>,
+    // whether a tail call gives the frame back
+    left: bool,
+}
+
+/// Rewrites the function to keep its rooted locals, and every var it sets,
+/// on the shadow stack
+pub fn root_locals(function: &mut Function, rooted: &Vec<String>, vars: &Vec<String>) {
+    let mut frame = Frame {
+        rooted,
+        vars,
+        result: function.result,
+        tail: vec![],
+        left: false,
+    };
+    frame.rewrite(&mut function.body);
+
+    let slots = rooted.len() + frame.tail.len();
+    // nothing to keep, only the vars it sets had to be mirrored
+    if slots == 0 && !frame.left {
+        return;
+    }
+    let mut body = vec![Instruction::set(
+        FRAME,
+        Instruction::call(
+            RuntimeFunction::PushFrame.name(),
+            vec![Instruction::Const(slots as i32)],
+        ),
+    )];
+    for param in &function.params {
+        body.extend(frame.mirror(&param.name));
+    }
+    let inner = std::mem::take(&mut function.body);
+    let pop = Instruction::global_set(SHADOW, Instruction::get(FRAME));
+    match function.result {
+        Some(result) => {
+            body.push(Instruction::set(
+                RESULT,
+                Instruction::Block {
+                    label: None,
+                    result: Some(result),
+                    body: inner,
+                },
+            ));
+            body.push(pop);
+            body.push(Instruction::get(RESULT));
+            function.locals.push(Local::i32(RESULT));
+        }
+        None => {
+            body.push(Instruction::Block {
+                label: None,
+                result: None,
+                body: inner,
+            });
+            body.push(pop);
+        }
+    }
+    function.body = body;
+    function.locals.push(Local::i32(FRAME));
+    function
+        .locals
+        .extend(frame.tail.iter().map(|name| Local::i32(name)));
+}
+
+impl<'a> Frame<'a> {
+    /// Slot of a rooted local, the tail call temporaries come last
+    fn slot(&self, name: &str) -> Option<usize> {
+        match self.rooted.iter().position(|local| local == name) {
+            Some(slot) => Some(slot),
+            None => self
+                .tail
+                .iter()
+                .position(|local| local == name)
+                .map(|slot| self.rooted.len() + slot),
+        }
+    }
+
+    /// Stores the local into its slot, if it has one
+    fn mirror(&self, name: &str) -> Option<Instruction> {
+        let slot = self.slot(name)?;
+        Some(Instruction::store_offset(
+            Instruction::get(FRAME),
+            4 * slot as u32,
+            Instruction::get(name),
+        ))
+    }
+
+    /// Stores the var into its slot in front of the shadow stack
+    fn mirror_var(&self, name: &str) -> Option<Instruction> {
+        let slot = self.vars.iter().position(|var| var == name)?;
+        Some(Instruction::store_offset(
+            Instruction::global_get(ROOTS),
+            4 * slot as u32,
+            Instruction::global_get(name),
+        ))
+    }
+
+    fn rewrite(&mut self, body: &mut Vec<Instruction>) {
+        let mut index = 0;
+        while index < body.len() {
+            self.visit(&mut body[index]);
+            let store = match &body[index] {
+                Instruction::LocalSet(name, _) => self.mirror(name),
+                Instruction::GlobalSet(name, _) => self.mirror_var(name),
+                _ => None,
+            };
+            if let Some(store) = store {
+                index += 1;
+                body.insert(index, store);
+            }
+            index += 1;
+        }
+    }
+
+    fn visit(&mut self, instruction: &mut Instruction) {
+        if let Instruction::ReturnCall(name, args) = instruction {
+            *instruction = self.leave_before(name, std::mem::take(args));
+            return;
+        }
+        for operand in instruction.operands_mut() {
+            self.visit(operand);
+        }
+        for body in instruction.bodies_mut() {
+            self.rewrite(body);
+        }
+    }
+
+    /// The callee of a tail call takes over the frame, so the arguments are
+    /// computed while it is still there and the frame is given back just
+    /// before the call
+    fn leave_before(&mut self, name: &str, args: Vec<Instruction>) -> Instruction {
+        self.left = true;
+        let mut body = Vec::new();
+        let mut temporaries = Vec::new();
+        for (index, arg) in args.into_iter().enumerate() {
+            let temporary = format!("tail#{}", index);
+            if index == self.tail.len() {
+                self.tail.push(temporary.to_owned());
+            }
+            body.push(Instruction::set(&temporary, arg));
+            temporaries.push(Instruction::get(&temporary));
+        }
+        self.rewrite(&mut body);
+        body.push(Instruction::global_set(SHADOW, Instruction::get(FRAME)));
+        body.push(Instruction::ReturnCall(name.to_owned(), temporaries));
+        Instruction::Block {
+            label: None,
+            result: self.result,
+            body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::instructions::Instruction;
+    use crate::codegen::module::{Function, Local, ValueType};
+    use crate::codegen::shadow::{root_locals, FRAME};
+
+    #[test]
+    fn mirror_rooted_locals_into_the_frame() {
+        let mut function = Function {
+            name: "f".to_owned(),
+            params: vec![Local::i32("x")],
+            result: Some(ValueType::I32),
+            locals: vec![Local::i32("raw")],
+            body: vec![
+                Instruction::set("x", Instruction::Const(1)),
+                Instruction::set("raw", Instruction::Const(2)),
+                Instruction::get("x"),
+            ],
+        };
+        root_locals(&mut function, &vec!["x".to_owned()], &vec![]);
+
+        let store = Instruction::store_offset(Instruction::get(FRAME), 0, Instruction::get("x"));
+        let body = match &function.body[2] {
+            Instruction::LocalSet(_, box Instruction::Block { body, .. }) => body,
+            other => panic!("unexpected {:?}", other),
+        };
+        // once on entry and once after the set, but never for the raw local
+        assert_eq!(function.body[1], store);
+        assert_eq!(body[1], store);
+        assert_eq!(body.len(), 4);
+    }
+}
@@ -0,0 +1,495 @@
+use crate::codegen::instructions::Instruction;
+use crate::codegen::module::{Field, Function, Module, ValueType};
+use std::collections::{HashMap, HashSet};
+
+/// Problems with a module that the engine would reject. The function the
+/// problem was found in comes first.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    UnknownLocal(String, String),
+    UnknownGlobal(String, String),
+    UnknownFunction(String, String),
+    UnknownLabel(String, String),
+    UnknownType(String, String),
+    ArgumentCount(String, String),
+    TypeMismatch(String),
+    MissingTable(String),
+    // a name given to more than one function, or put in the table twice
+    DuplicateFunction(String),
+    DuplicateTableEntry(String),
+}
+
+/// What evaluating an instruction leaves on the stack
+#[derive(Debug, PartialEq)]
+enum Shape {
+    Nothing,
+    Value(ValueType),
+    // control never gets past an unconditional branch, so it fits anywhere
+    Unreachable,
+}
+
+struct Checker<'a> {
+    module: &'a Module,
+    function: &'a Function,
+    locals: HashMap<&'a str, ValueType>,
+    // enclosing labels and the type a branch to them carries, innermost last
+    labels: Vec<(Option<&'a str>, Option<ValueType>)>,
+}
+
+/// The first name that comes up more than once
+fn repeated<'a>(mut names: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    let mut seen = HashSet::new();
+    names.find(|name| !seen.insert(*name))
+}
+
+/// Checks that every function in the module is well typed, and that no two
+/// functions or table entries share a name
+pub fn validate(module: &Module) -> Result<(), ValidationError> {
+    if let Some(name) = repeated(module.function_names()) {
+        return Err(ValidationError::DuplicateFunction(name.to_owned()));
+    }
+    if let Some(name) = repeated(module.table.iter().flatten()) {
+        return Err(ValidationError::DuplicateTableEntry(name.to_owned()));
+    }
+    for name in module.table.iter().flatten() {
+        if module.signature_of(name).is_none() {
+            return Err(ValidationError::UnknownFunction(
+                "table".to_owned(),
+                name.to_owned(),
+            ));
+        }
+    }
+    for function in &module.functions {
+        let mut checker = Checker {
+            module,
+            function,
+            locals: function
+                .variables()
+                .map(|local| (local.name.as_str(), local.value_type))
+                .collect(),
+            labels: vec![],
+        };
+        checker.check_sequence(&function.body, function.result)?;
+    }
+    Ok(())
+}
+
+impl<'a> Checker<'a> {
+    fn global(&self, name: &str) -> Option<ValueType> {
+        self.module
+            .globals
+            .iter()
+            .find(|global| global.name == name)
+            .map(|global| global.value_type)
+    }
+
+    /// Checks the arguments against the callee's signature and gives its result
+    fn check_call(
+        &mut self,
+        name: &str,
+        args: &'a Vec<Instruction>,
+    ) -> Result<Option<ValueType>, ValidationError> {
+        let signature = match self.module.signature_of(name) {
+            Some(signature) => signature,
+            None => {
+                return Err(ValidationError::UnknownFunction(
+                    self.function.name.to_owned(),
+                    name.to_owned(),
+                ))
+            }
+        };
+        if signature.params.len() != args.len() {
+            return Err(ValidationError::ArgumentCount(
+                self.function.name.to_owned(),
+                name.to_owned(),
+            ));
+        }
+        for (arg, param) in args.iter().zip(signature.params.iter()) {
+            self.expect(arg, *param)?;
+        }
+        Ok(signature.result)
+    }
+
+    /// Fields of a struct type, or the element of an array type
+    fn fields(&self, name: &str) -> Result<Vec<Field>, ValidationError> {
+        match self.module.type_index(name) {
+            Some(index) => Ok(self.module.types[index].fields()),
+            None => Err(ValidationError::UnknownType(
+                self.function.name.to_owned(),
+                name.to_owned(),
+            )),
+        }
+    }
+
+    fn element(&self, name: &str) -> Result<ValueType, ValidationError> {
+        Ok(self.fields(name)?[0].value_type())
+    }
+
+    fn mismatch(&self) -> ValidationError {
+        ValidationError::TypeMismatch(self.function.name.to_owned())
+    }
+
+    fn expect(
+        &mut self,
+        instruction: &'a Instruction,
+        value_type: ValueType,
+    ) -> Result<(), ValidationError> {
+        match self.check(instruction)? {
+            Shape::Value(actual) if actual == value_type => Ok(()),
+            Shape::Unreachable => Ok(()),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    /// Every instruction but the last must leave nothing behind, the last one
+    /// must produce the expected result
+    fn check_sequence(
+        &mut self,
+        body: &'a Vec<Instruction>,
+        result: Option<ValueType>,
+    ) -> Result<(), ValidationError> {
+        let mut last = Shape::Nothing;
+        for (index, instruction) in body.iter().enumerate() {
+            last = self.check(instruction)?;
+            if let Shape::Value(_) = last {
+                if index + 1 < body.len() {
+                    return Err(self.mismatch());
+                }
+            }
+        }
+        match (result, last) {
+            (_, Shape::Unreachable) => Ok(()),
+            (None, Shape::Nothing) => Ok(()),
+            (Some(expected), Shape::Value(actual)) if expected == actual => Ok(()),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    fn check_block(
+        &mut self,
+        label: Option<&'a str>,
+        branch: Option<ValueType>,
+        body: &'a Vec<Instruction>,
+        result: Option<ValueType>,
+    ) -> Result<Shape, ValidationError> {
+        self.labels.push((label, branch));
+        self.check_sequence(body, result)?;
+        self.labels.pop();
+        Ok(result.map_or(Shape::Nothing, Shape::Value))
+    }
+
+    /// Branches carry no value in this IR, so the label must not expect one
+    fn check_branch(&self, label: &str) -> Result<(), ValidationError> {
+        match self
+            .labels
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == Some(label))
+        {
+            Some((_, None)) => Ok(()),
+            Some(_) => Err(self.mismatch()),
+            None => Err(ValidationError::UnknownLabel(
+                self.function.name.to_owned(),
+                label.to_owned(),
+            )),
+        }
+    }
+
+    fn check(&mut self, instruction: &'a Instruction) -> Result<Shape, ValidationError> {
+        match instruction {
+            Instruction::Const(_) => Ok(Shape::Value(ValueType::I32)),
+            Instruction::LocalGet(name) => match self.locals.get(name.as_str()) {
+                Some(value_type) => Ok(Shape::Value(*value_type)),
+                None => Err(ValidationError::UnknownLocal(
+                    self.function.name.to_owned(),
+                    name.to_owned(),
+                )),
+            },
+            Instruction::LocalSet(name, value) => match self.locals.get(name.as_str()) {
+                Some(value_type) => {
+                    let value_type = *value_type;
+                    self.expect(value, value_type)?;
+                    Ok(Shape::Nothing)
+                }
+                None => Err(ValidationError::UnknownLocal(
+                    self.function.name.to_owned(),
+                    name.to_owned(),
+                )),
+            },
+            Instruction::GlobalGet(name) => match self.global(name) {
+                Some(value_type) => Ok(Shape::Value(value_type)),
+                None => Err(ValidationError::UnknownGlobal(
+                    self.function.name.to_owned(),
+                    name.to_owned(),
+                )),
+            },
+            Instruction::GlobalSet(name, value) => match self.global(name) {
+                Some(value_type) => {
+                    self.expect(value, value_type)?;
+                    Ok(Shape::Nothing)
+                }
+                None => Err(ValidationError::UnknownGlobal(
+                    self.function.name.to_owned(),
+                    name.to_owned(),
+                )),
+            },
+            Instruction::Load { address, .. } => {
+                self.expect(address, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::Store { address, value, .. } => {
+                self.expect(address, ValueType::I32)?;
+                self.expect(value, ValueType::I32)?;
+                Ok(Shape::Nothing)
+            }
+            Instruction::Unary(_, value) => {
+                self.expect(value, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::Binary(_, left, right) => {
+                self.expect(left, ValueType::I32)?;
+                self.expect(right, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::Call(name, args) => {
+                let result = self.check_call(name, args)?;
+                Ok(result.map_or(Shape::Nothing, Shape::Value))
+            }
+            Instruction::CallIndirect(signature, args, index) => {
+                if self.module.table.is_none() {
+                    return Err(ValidationError::MissingTable(self.function.name.to_owned()));
+                }
+                if signature.params.len() != args.len() {
+                    return Err(ValidationError::ArgumentCount(
+                        self.function.name.to_owned(),
+                        "call_indirect".to_owned(),
+                    ));
+                }
+                for (arg, param) in args.iter().zip(signature.params.iter()) {
+                    self.expect(arg, *param)?;
+                }
+                self.expect(index, ValueType::I32)?;
+                Ok(signature.result.map_or(Shape::Nothing, Shape::Value))
+            }
+            // the callee's result becomes the caller's, so they have to agree
+            Instruction::ReturnCall(name, args) => {
+                if self.check_call(name, args)? != self.function.result {
+                    return Err(self.mismatch());
+                }
+                Ok(Shape::Unreachable)
+            }
+            Instruction::MemorySize => Ok(Shape::Value(ValueType::I32)),
+            Instruction::MemoryGrow(pages) => {
+                self.expect(pages, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::Drop(value) => match self.check(value)? {
+                Shape::Value(_) | Shape::Unreachable => Ok(Shape::Nothing),
+                Shape::Nothing => Err(self.mismatch()),
+            },
+            Instruction::Block {
+                label,
+                result,
+                body,
+            } => self.check_block(label.as_deref(), *result, body, *result),
+            // branching to a loop starts it again, so no value is carried
+            Instruction::Loop {
+                label,
+                result,
+                body,
+            } => self.check_block(Some(label.as_str()), None, body, *result),
+            Instruction::If {
+                result,
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.expect(condition, ValueType::I32)?;
+                // an if producing a value needs both branches
+                if result.is_some() && otherwise.is_empty() {
+                    return Err(self.mismatch());
+                }
+                self.check_block(None, *result, then, *result)?;
+                if !otherwise.is_empty() {
+                    self.check_block(None, *result, otherwise, *result)?;
+                }
+                Ok(result.map_or(Shape::Nothing, Shape::Value))
+            }
+            Instruction::Branch(label) => {
+                self.check_branch(label)?;
+                Ok(Shape::Unreachable)
+            }
+            Instruction::BranchIf(label, condition) => {
+                self.expect(condition, ValueType::I32)?;
+                self.check_branch(label)?;
+                Ok(Shape::Nothing)
+            }
+            Instruction::RefNull => Ok(Shape::Value(ValueType::EqRef)),
+            Instruction::RefIsNull(value) | Instruction::RefTest(_, value) => {
+                self.expect(value, ValueType::EqRef)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::RefEq(left, right) => {
+                self.expect(left, ValueType::EqRef)?;
+                self.expect(right, ValueType::EqRef)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            Instruction::RefI31(value) => {
+                self.expect(value, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::EqRef))
+            }
+            Instruction::I31Get(value) => {
+                self.expect(value, ValueType::EqRef)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+            // a cast narrows the type, which is still an eqref as far as
+            // anything here is concerned
+            Instruction::RefCast(_, value) => {
+                self.expect(value, ValueType::EqRef)?;
+                Ok(Shape::Value(ValueType::EqRef))
+            }
+            Instruction::StructNew(name, values) => {
+                let fields = self.fields(name)?;
+                if fields.len() != values.len() {
+                    return Err(ValidationError::ArgumentCount(
+                        self.function.name.to_owned(),
+                        name.to_owned(),
+                    ));
+                }
+                for (value, field) in values.iter().zip(fields.iter()) {
+                    self.expect(value, field.value_type())?;
+                }
+                Ok(Shape::Value(ValueType::EqRef))
+            }
+            Instruction::StructGet(name, field, value) => {
+                let fields = self.fields(name)?;
+                self.expect(value, ValueType::EqRef)?;
+                match fields.get(*field as usize) {
+                    Some(field) => Ok(Shape::Value(field.value_type())),
+                    None => Err(self.mismatch()),
+                }
+            }
+            Instruction::ArrayNew {
+                name,
+                value,
+                length,
+            } => {
+                let element = self.element(name)?;
+                self.expect(value, element)?;
+                self.expect(length, ValueType::I32)?;
+                Ok(Shape::Value(ValueType::EqRef))
+            }
+            Instruction::ArrayNewFixed(name, values) => {
+                let element = self.element(name)?;
+                for value in values {
+                    self.expect(value, element)?;
+                }
+                Ok(Shape::Value(ValueType::EqRef))
+            }
+            Instruction::ArrayGet {
+                name, array, index, ..
+            } => {
+                let element = self.element(name)?;
+                self.expect(array, ValueType::EqRef)?;
+                self.expect(index, ValueType::I32)?;
+                Ok(Shape::Value(element))
+            }
+            Instruction::ArraySet {
+                name,
+                array,
+                index,
+                value,
+            } => {
+                let element = self.element(name)?;
+                self.expect(array, ValueType::EqRef)?;
+                self.expect(index, ValueType::I32)?;
+                self.expect(value, element)?;
+                Ok(Shape::Nothing)
+            }
+            Instruction::ArrayLen(array) => {
+                self.expect(array, ValueType::EqRef)?;
+                Ok(Shape::Value(ValueType::I32))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::instructions::{BinaryOp, Instruction};
+    use crate::codegen::module::{Function, Local, Module, ValueType};
+    use crate::codegen::validate::{validate, ValidationError};
+
+    fn module(body: Vec<Instruction>) -> Module {
+        Module {
+            types: vec![],
+            imports: vec![],
+            memory_pages: 1,
+            globals: vec![],
+            table: None,
+            functions: vec![Function {
+                name: "test".to_owned(),
+                params: vec![Local::i32("x")],
+                result: Some(ValueType::I32),
+                locals: vec![],
+                body,
+            }],
+            exports: vec![],
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn accept_well_typed_function() {
+        let body = vec![Instruction::binary(
+            BinaryOp::Add,
+            Instruction::get("x"),
+            Instruction::Const(1),
+        )];
+
+        assert_eq!(validate(&module(body)), Ok(()))
+    }
+
+    #[test]
+    fn reject_values_left_on_the_stack() {
+        let body = vec![Instruction::Const(1), Instruction::Const(2)];
+
+        assert_eq!(
+            validate(&module(body)),
+            Err(ValidationError::TypeMismatch("test".to_owned()))
+        )
+    }
+
+    #[test]
+    fn reject_unknown_locals() {
+        let body = vec![Instruction::get("y")];
+
+        assert_eq!(
+            validate(&module(body)),
+            Err(ValidationError::UnknownLocal(
+                "test".to_owned(),
+                "y".to_owned()
+            ))
+        )
+    }
+
+    #[test]
+    fn reject_repeated_names() {
+        let mut repeated = module(vec![Instruction::get("x")]);
+        let twin = module(vec![Instruction::get("x")]).functions.remove(0);
+        repeated.functions.push(twin);
+
+        assert_eq!(
+            validate(&repeated),
+            Err(ValidationError::DuplicateFunction("test".to_owned()))
+        );
+
+        let mut tabled = module(vec![Instruction::get("x")]);
+        tabled.table = Some(vec!["test".to_owned(), "test".to_owned()]);
+
+        assert_eq!(
+            validate(&tabled),
+            Err(ValidationError::DuplicateTableEntry("test".to_owned()))
+        )
+    }
+}
@@ -0,0 +1,143 @@
+use crate::codegen::instructions::{BinaryOp, Instruction};
+
+// Every value is a single i32. The low bits tell what it holds:
+//
+//   ...1   an integer, shifted left by one
+//   ..10   false or true
+//   ..00   nil when zero, otherwise the address of an object on the heap or
+//          in the static data, which starts with a header word
+//
+// The header keeps the tag of the object in its low byte and a length, for
+// strings and keywords their byte length, in the rest.
+
+pub const NIL: i32 = 0;
+pub const FALSE: i32 = 2;
+pub const TRUE: i32 = 6;
+
+/// Largest and smallest integers that fit in a value
+pub const INTEGER_MAX: i32 = i32::MAX >> 1;
+pub const INTEGER_MIN: i32 = i32::MIN >> 1;
+
+/// What a value is, as told apart at run time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tag {
+    Nil,
+    Boolean,
+    Integer,
+    String,
+    Function,
+    Seq,
+    Vector,
+    // the nodes and tails of vectors, which are never values themselves
+    Array,
+    Map,
+    Keyword,
+    Set,
+    // the nodes of maps and the entries in them, which are not values either
+    MapNode,
+    Entry,
+}
+
+impl Tag {
+    pub fn code(self) -> i32 {
+        match self {
+            Tag::Nil => 0,
+            Tag::Boolean => 1,
+            Tag::Integer => 2,
+            Tag::String => 3,
+            Tag::Function => 4,
+            Tag::Seq => 5,
+            Tag::Vector => 6,
+            Tag::Array => 7,
+            Tag::Map => 8,
+            Tag::Keyword => 9,
+            Tag::MapNode => 10,
+            Tag::Entry => 11,
+            Tag::Set => 12,
+        }
+    }
+
+    /// The first word of an object with the tag
+    pub fn header(self, length: i32) -> i32 {
+        length << 8 | self.code()
+    }
+}
+
+/// Tags a raw i32 as an integer. Constants are tagged at compile time.
+pub fn box_integer(raw: Instruction) -> Instruction {
+    match raw {
+        Instruction::Const(value) => Instruction::Const(value << 1 | 1),
+        raw => Instruction::binary(
+            BinaryOp::Or,
+            Instruction::binary(BinaryOp::ShiftLeft, raw, Instruction::Const(1)),
+            Instruction::Const(1),
+        ),
+    }
+}
+
+/// The raw i32 of a value already known to be an integer
+pub fn unbox_integer(value: Instruction) -> Instruction {
+    match value {
+        Instruction::Const(value) => Instruction::Const(value >> 1),
+        value => Instruction::binary(BinaryOp::ShiftRightSigned, value, Instruction::Const(1)),
+    }
+}
+
+/// Turns a wasm condition, zero or one, into false or true
+pub fn box_boolean(raw: Instruction) -> Instruction {
+    match raw {
+        Instruction::Const(0) => Instruction::Const(FALSE),
+        Instruction::Const(_) => Instruction::Const(TRUE),
+        raw => Instruction::binary(
+            BinaryOp::Or,
+            Instruction::binary(BinaryOp::ShiftLeft, raw, Instruction::Const(2)),
+            Instruction::Const(FALSE),
+        ),
+    }
+}
+
+/// Non-zero exactly when the value is truthy. Only nil and false have no
+/// bits set besides the one marking false. A boolean that was just boxed
+/// is tested as it was.
+pub fn truthy(value: Instruction) -> Instruction {
+    match value {
+        Instruction::Binary(
+            BinaryOp::Or,
+            box Instruction::Binary(BinaryOp::ShiftLeft, box raw, box Instruction::Const(2)),
+            box Instruction::Const(FALSE),
+        ) => raw,
+        value => Instruction::binary(BinaryOp::And, value, Instruction::Const(!FALSE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::instructions::BinaryOp;
+    use crate::codegen::instructions::Instruction;
+    use crate::codegen::value::{box_boolean, box_integer, truthy, unbox_integer, FALSE, TRUE};
+
+    #[test]
+    fn tag_constants_at_compile_time() {
+        assert_eq!(box_integer(Instruction::Const(-3)), Instruction::Const(-5));
+        assert_eq!(
+            unbox_integer(Instruction::Const(-5)),
+            Instruction::Const(-3)
+        );
+        assert_eq!(box_boolean(Instruction::Const(1)), Instruction::Const(TRUE));
+        assert_eq!(
+            box_boolean(Instruction::Const(0)),
+            Instruction::Const(FALSE)
+        );
+    }
+
+    #[test]
+    fn test_booleans_without_boxing() {
+        let raw = Instruction::binary(
+            BinaryOp::Equal,
+            Instruction::get("x"),
+            Instruction::get("y"),
+        );
+
+        assert_eq!(truthy(box_boolean(raw.clone())), raw);
+    }
+}
@@ -0,0 +1,1039 @@
+use crate::codegen::emitter::Backend;
+use crate::codegen::gc;
+use crate::codegen::instructions::{BinaryOp, Instruction};
+use crate::codegen::map;
+use crate::codegen::module::{Function, Local, ValueType};
+use crate::codegen::object::{
+    add, binary, box_integer, byte, call, cases, choose, constant, either, element, field, get,
+    increment, is_nil, length, new_record, nil, not, set_element, string_length, tag_is,
+    unbox_integer, value_local, value_type,
+};
+use crate::codegen::pool::StringPool;
+use crate::codegen::runtime::{fail_with, until, RuntimeFunction};
+use crate::codegen::value::Tag;
+
+// A vector is a record of its count, the shift of its trie, the root of the
+// trie and its tail. The trie holds every element before the tail in nodes
+// of 32, with each level below the root taking 5 more bits of the index. The
+// tail holds the last 1 to 32 elements, so most conjs only copy the tail.
+// Nodes and tails are never changed once filled in, which lets vectors
+// share them. The root is nil while the trie is empty.
+
+const COUNT: u32 = 0;
+const SHIFT: u32 = 1;
+const ROOT: u32 = 2;
+const TAIL: u32 = 3;
+
+const BITS: i32 = 5;
+const WIDTH: i32 = 1 << BITS;
+
+/// Whether the index is below the count, taking negative indexes as huge
+fn in_bounds(index: Instruction, count: Instruction) -> Instruction {
+    binary(BinaryOp::GreaterThanUnsigned, count, index)
+}
+
+/// The index within a node at a level of the trie
+fn slot(index: Instruction, level: Instruction) -> Instruction {
+    binary(
+        BinaryOp::And,
+        binary(BinaryOp::ShiftRightUnsigned, index, level),
+        constant(WIDTH - 1),
+    )
+}
+
+/// Index of the first element in the tail
+fn tail_offset(count: Instruction) -> Instruction {
+    Instruction::choose(
+        binary(BinaryOp::LessThan, count.clone(), constant(WIDTH)),
+        vec![constant(0)],
+        vec![binary(
+            BinaryOp::ShiftLeft,
+            binary(BinaryOp::ShiftRightUnsigned, add(count, -1), constant(BITS)),
+            constant(BITS),
+        )],
+    )
+}
+
+/// Builds the vector routine, or None for one that is not about vectors
+pub fn definition(
+    function: RuntimeFunction,
+    strings: &mut StringPool,
+    backend: Backend,
+) -> Option<Function> {
+    let vectors = Vectors { backend, function };
+    Some(match function {
+        RuntimeFunction::NewVector => vectors.new_vector_record(),
+        RuntimeFunction::ArrayFor => vectors.array_for(),
+        RuntimeFunction::Count => vectors.count(strings),
+        RuntimeFunction::Nth => vectors.nth_routine(strings),
+        RuntimeFunction::Get => vectors.get(),
+        RuntimeFunction::Conj => vectors.conj(strings),
+        RuntimeFunction::Assoc => vectors.assoc(strings),
+        RuntimeFunction::Peek => vectors.peek(strings),
+        RuntimeFunction::Pop => vectors.pop(strings),
+        RuntimeFunction::Subvec => vectors.subvec(strings),
+        RuntimeFunction::VectorConj => vectors.vector_conj(),
+        RuntimeFunction::VectorAssoc => vectors.vector_assoc(strings),
+        RuntimeFunction::VectorPop => vectors.vector_pop(strings),
+        RuntimeFunction::PushTail => vectors.push_tail(),
+        RuntimeFunction::NewPath => vectors.new_path(),
+        RuntimeFunction::DoAssoc => vectors.do_assoc(),
+        RuntimeFunction::PopTail => vectors.pop_tail(),
+        _ => return None,
+    })
+}
+
+/// Prints the vector in the local `value` between brackets, passing each
+/// element to the printing routine. Uses the locals `index` and `count`.
+pub fn print_elements(
+    backend: Backend,
+    print: &str,
+    mut literal: impl FnMut(&str) -> Instruction,
+) -> Vec<Instruction> {
+    vec![
+        literal("["),
+        Instruction::set(
+            "count",
+            unbox_integer(backend, field(backend, gc::VECTOR, get("value"), COUNT)),
+        ),
+        until(
+            "printed",
+            binary(BinaryOp::GreaterEqual, get("index"), get("count")),
+            vec![
+                Instruction::when(get("index"), vec![literal(" ")]),
+                Instruction::call(
+                    print,
+                    vec![element(
+                        backend,
+                        call(RuntimeFunction::ArrayFor, vec![get("value"), get("index")]),
+                        slot(get("index"), constant(0)),
+                    )],
+                ),
+                increment("index"),
+            ],
+        ),
+        literal("]"),
+    ]
+}
+
+struct Vectors {
+    backend: Backend,
+    function: RuntimeFunction,
+}
+
+impl Vectors {
+    fn value(&self, name: &str) -> Local {
+        value_local(self.backend, name)
+    }
+
+    fn value_type(&self) -> ValueType {
+        value_type(self.backend)
+    }
+
+    fn function(&self, params: Vec<Local>, locals: Vec<Local>, body: Vec<Instruction>) -> Function {
+        Function {
+            name: self.function.name().to_owned(),
+            params,
+            result: Some(self.value_type()),
+            locals,
+            body,
+        }
+    }
+
+    fn nil(&self) -> Instruction {
+        nil(self.backend)
+    }
+
+    fn is_nil(&self, value: Instruction) -> Instruction {
+        is_nil(self.backend, value)
+    }
+
+    fn field(&self, vector: Instruction, index: u32) -> Instruction {
+        field(self.backend, gc::VECTOR, vector, index)
+    }
+
+    fn integer_field(&self, vector: Instruction, index: u32) -> Instruction {
+        unbox_integer(self.backend, self.field(vector, index))
+    }
+
+    fn element(&self, array: Instruction, index: Instruction) -> Instruction {
+        element(self.backend, array, index)
+    }
+
+    fn set_element(
+        &self,
+        array: Instruction,
+        index: Instruction,
+        value: Instruction,
+    ) -> Instruction {
+        set_element(self.backend, array, index, value)
+    }
+
+    fn length(&self, array: Instruction) -> Instruction {
+        length(self.backend, array)
+    }
+
+    /// The element at an index that is known to be in bounds
+    fn nth(&self, vector: Instruction, index: Instruction) -> Instruction {
+        self.element(
+            call(RuntimeFunction::ArrayFor, vec![vector, index.clone()]),
+            slot(index, constant(0)),
+        )
+    }
+
+    fn copy(&self, array: Instruction, length: Instruction) -> Instruction {
+        call(RuntimeFunction::CopyArray, vec![array, length])
+    }
+
+    fn new_array(&self, length: i32) -> Instruction {
+        call(RuntimeFunction::NewArray, vec![constant(length)])
+    }
+
+    fn new_vector(
+        &self,
+        count: Instruction,
+        shift: Instruction,
+        root: Instruction,
+        tail: Instruction,
+    ) -> Instruction {
+        call(
+            RuntimeFunction::NewVector,
+            vec![
+                box_integer(self.backend, count),
+                box_integer(self.backend, shift),
+                root,
+                tail,
+            ],
+        )
+    }
+
+    fn empty(&self) -> Instruction {
+        self.new_vector(constant(0), constant(BITS), self.nil(), self.new_array(0))
+    }
+
+    /// Fails unless the local holds a vector
+    fn check(&self, name: &str, message: i32) -> Instruction {
+        Instruction::when(
+            not(tag_is(get(name), Tag::Vector)),
+            vec![fail_with(message)],
+        )
+    }
+
+    /// Whether the local holds nil or a seq
+    fn is_seq(&self, name: &str) -> Instruction {
+        either(self.is_nil(get(name)), tag_is(get(name), Tag::Seq))
+    }
+
+    /// Reads the fields of the vector in the local `vector` into the locals
+    /// `count`, `shift`, `root` and `tail`
+    fn unpack(&self) -> Vec<Instruction> {
+        vec![
+            Instruction::set("count", self.integer_field(get("vector"), COUNT)),
+            Instruction::set("shift", self.integer_field(get("vector"), SHIFT)),
+            Instruction::set("root", self.field(get("vector"), ROOT)),
+            Instruction::set("tail", self.field(get("vector"), TAIL)),
+        ]
+    }
+
+    fn unpacked(&self) -> Vec<Local> {
+        vec![
+            Local::i32("count"),
+            Local::i32("shift"),
+            self.value("root"),
+            self.value("tail"),
+        ]
+    }
+
+    fn new_vector_record(&self) -> Function {
+        let fields = ["count", "shift", "root", "tail"];
+        let locals = match self.backend {
+            Backend::Linear => vec![Local::i32("object")],
+            Backend::WasmGc => vec![],
+        };
+        self.function(
+            fields.iter().map(|name| self.value(name)).collect(),
+            locals,
+            new_record(self.backend, Tag::Vector, gc::VECTOR, &fields),
+        )
+    }
+
+    /// The tail or the leaf of the trie holding an index that is in bounds
+    fn array_for(&self) -> Function {
+        let walk = vec![
+            Instruction::set("node", self.field(get("vector"), ROOT)),
+            Instruction::set("level", self.integer_field(get("vector"), SHIFT)),
+            until(
+                "found",
+                binary(BinaryOp::LessEqual, get("level"), constant(0)),
+                vec![
+                    Instruction::set(
+                        "node",
+                        self.element(get("node"), slot(get("index"), get("level"))),
+                    ),
+                    Instruction::set("level", add(get("level"), -BITS)),
+                ],
+            ),
+            get("node"),
+        ];
+        let body = vec![choose(
+            self.backend,
+            binary(
+                BinaryOp::GreaterEqual,
+                get("index"),
+                tail_offset(self.integer_field(get("vector"), COUNT)),
+            ),
+            vec![self.field(get("vector"), TAIL)],
+            walk,
+        )];
+        self.function(
+            vec![self.value("vector"), Local::i32("index")],
+            vec![self.value("node"), Local::i32("level")],
+            body,
+        )
+    }
+
+    /// The number of elements in nil, a string, a seq, a vector, a map or a
+    /// set. Those of a string are its characters, so the UTF-8 bytes that
+    /// continue one are left out.
+    fn count(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("UnsupportedOperationException: count not supported on this type\n");
+        let tag_is = |tag: Tag| binary(BinaryOp::Equal, get("tag"), constant(tag.code()));
+        let seq = vec![
+            Instruction::when(not(tag_is(Tag::Seq)), vec![fail_with(message)]),
+            until(
+                "counted",
+                self.is_nil(get("value")),
+                vec![
+                    increment("counted"),
+                    Instruction::set("value", call(RuntimeFunction::Next, vec![get("value")])),
+                ],
+            ),
+            get("counted"),
+        ];
+        let starts_character = binary(
+            BinaryOp::NotEqual,
+            binary(
+                BinaryOp::And,
+                byte(self.backend, get("value"), get("index")),
+                constant(0xc0),
+            ),
+            constant(0x80),
+        );
+        let text = vec![
+            Instruction::set("length", string_length(self.backend, get("value"))),
+            until(
+                "characters",
+                binary(BinaryOp::GreaterEqual, get("index"), get("length")),
+                vec![
+                    Instruction::set(
+                        "counted",
+                        binary(BinaryOp::Add, get("counted"), starts_character),
+                    ),
+                    increment("index"),
+                ],
+            ),
+            get("counted"),
+        ];
+        let mut body = vec![Instruction::set(
+            "tag",
+            call(RuntimeFunction::TypeOf, vec![get("value")]),
+        )];
+        body.extend(cases(
+            ValueType::I32,
+            vec![
+                (tag_is(Tag::Nil), vec![constant(0)]),
+                (
+                    tag_is(Tag::Vector),
+                    vec![self.integer_field(get("value"), COUNT)],
+                ),
+                (tag_is(Tag::String), text),
+                (
+                    tag_is(Tag::Map),
+                    vec![map::count(self.backend, get("value"))],
+                ),
+                (
+                    tag_is(Tag::Set),
+                    vec![map::count(
+                        self.backend,
+                        map::set_map(self.backend, get("value")),
+                    )],
+                ),
+            ],
+            seq,
+        ));
+
+        Function {
+            name: self.function.name().to_owned(),
+            params: vec![self.value("value")],
+            result: Some(ValueType::I32),
+            locals: vec![
+                Local::i32("tag"),
+                Local::i32("counted"),
+                Local::i32("index"),
+                Local::i32("length"),
+            ],
+            body,
+        }
+    }
+
+    /// The element of a vector at an index, failing when it is out of bounds
+    fn nth_routine(&self, strings: &mut StringPool) -> Function {
+        let unsupported =
+            strings.intern("UnsupportedOperationException: nth not supported on this type\n");
+        let bounds = strings.intern("IndexOutOfBoundsException\n");
+        let body = vec![
+            Instruction::set(
+                "raw",
+                call(RuntimeFunction::IntegerValue, vec![get("index")]),
+            ),
+            self.check("vector", unsupported),
+            Instruction::when(
+                not(in_bounds(
+                    get("raw"),
+                    self.integer_field(get("vector"), COUNT),
+                )),
+                vec![fail_with(bounds)],
+            ),
+            self.nth(get("vector"), get("raw")),
+        ];
+        self.function(
+            vec![self.value("vector"), self.value("index")],
+            vec![Local::i32("raw")],
+            body,
+        )
+    }
+
+    /// The value of a key in a map, the element of a set equal to the key,
+    /// or the element of a vector at an index. Evaluates to the default
+    /// when there is none.
+    fn get(&self) -> Function {
+        let found = vec![
+            Instruction::set("raw", unbox_integer(self.backend, get("key"))),
+            choose(
+                self.backend,
+                in_bounds(get("raw"), self.integer_field(get("collection"), COUNT)),
+                vec![self.nth(get("collection"), get("raw"))],
+                vec![get("default")],
+            ),
+        ];
+        let body = cases(
+            self.value_type(),
+            vec![
+                (
+                    tag_is(get("collection"), Tag::Map),
+                    vec![call(
+                        RuntimeFunction::MapGet,
+                        vec![get("collection"), get("key"), get("default")],
+                    )],
+                ),
+                (
+                    tag_is(get("collection"), Tag::Set),
+                    vec![call(
+                        RuntimeFunction::SetGet,
+                        vec![get("collection"), get("key"), get("default")],
+                    )],
+                ),
+                (
+                    binary(
+                        BinaryOp::And,
+                        tag_is(get("collection"), Tag::Vector),
+                        tag_is(get("key"), Tag::Integer),
+                    ),
+                    found,
+                ),
+            ],
+            vec![get("default")],
+        );
+        self.function(
+            vec![
+                self.value("collection"),
+                self.value("key"),
+                self.value("default"),
+            ],
+            vec![Local::i32("raw")],
+            body,
+        )
+    }
+
+    /// Adds to the end of a vector, or to the front of a seq. A map takes a
+    /// vector of a key and its value, and a set keeps a value it lacks.
+    fn conj(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("UnsupportedOperationException: conj not supported on this type\n");
+        let pair = |index: i32| {
+            call(
+                RuntimeFunction::Nth,
+                vec![get("value"), box_integer(self.backend, constant(index))],
+            )
+        };
+        let body = cases(
+            self.value_type(),
+            vec![
+                (
+                    tag_is(get("collection"), Tag::Map),
+                    vec![call(
+                        RuntimeFunction::MapAssoc,
+                        vec![get("collection"), pair(0), pair(1)],
+                    )],
+                ),
+                (
+                    tag_is(get("collection"), Tag::Set),
+                    vec![call(
+                        RuntimeFunction::NewSet,
+                        vec![call(
+                            RuntimeFunction::MapAssoc,
+                            vec![
+                                map::set_map(self.backend, get("collection")),
+                                get("value"),
+                                self.nil(),
+                            ],
+                        )],
+                    )],
+                ),
+                (
+                    tag_is(get("collection"), Tag::Vector),
+                    vec![call(
+                        RuntimeFunction::VectorConj,
+                        vec![get("collection"), get("value")],
+                    )],
+                ),
+                (
+                    self.is_seq("collection"),
+                    vec![call(
+                        RuntimeFunction::Cons,
+                        vec![get("value"), get("collection")],
+                    )],
+                ),
+            ],
+            vec![fail_with(message), self.nil()],
+        );
+        self.function(
+            vec![self.value("collection"), self.value("value")],
+            vec![],
+            body,
+        )
+    }
+
+    /// Sets the key of a map or the index of a vector. Associng onto nil
+    /// starts a new map.
+    fn assoc(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("UnsupportedOperationException: assoc not supported on this type\n");
+        let body = cases(
+            self.value_type(),
+            vec![
+                (
+                    tag_is(get("collection"), Tag::Map),
+                    vec![call(
+                        RuntimeFunction::MapAssoc,
+                        vec![get("collection"), get("key"), get("value")],
+                    )],
+                ),
+                (
+                    self.is_nil(get("collection")),
+                    vec![call(
+                        RuntimeFunction::MapAssoc,
+                        vec![
+                            call(
+                                RuntimeFunction::NewMap,
+                                vec![box_integer(self.backend, constant(0)), self.nil()],
+                            ),
+                            get("key"),
+                            get("value"),
+                        ],
+                    )],
+                ),
+            ],
+            vec![
+                self.check("collection", message),
+                call(
+                    RuntimeFunction::VectorAssoc,
+                    vec![get("collection"), get("key"), get("value")],
+                ),
+            ],
+        );
+        self.function(
+            vec![
+                self.value("collection"),
+                self.value("key"),
+                self.value("value"),
+            ],
+            vec![],
+            body,
+        )
+    }
+
+    /// The last element of a vector, or the first of a seq
+    fn peek(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("UnsupportedOperationException: peek not supported on this type\n");
+        let vector = vec![
+            self.check("collection", message),
+            Instruction::set(
+                "last",
+                add(self.integer_field(get("collection"), COUNT), -1),
+            ),
+            choose(
+                self.backend,
+                binary(BinaryOp::LessThan, get("last"), constant(0)),
+                vec![self.nil()],
+                vec![self.nth(get("collection"), get("last"))],
+            ),
+        ];
+        let body = cases(
+            self.value_type(),
+            vec![(
+                self.is_seq("collection"),
+                vec![call(RuntimeFunction::First, vec![get("collection")])],
+            )],
+            vector,
+        );
+        self.function(
+            vec![self.value("collection")],
+            vec![Local::i32("last")],
+            body,
+        )
+    }
+
+    /// A vector without its last element, or a seq without its first
+    fn pop(&self, strings: &mut StringPool) -> Function {
+        let message =
+            strings.intern("UnsupportedOperationException: pop not supported on this type\n");
+        let body = cases(
+            self.value_type(),
+            vec![(
+                self.is_seq("collection"),
+                vec![call(RuntimeFunction::Next, vec![get("collection")])],
+            )],
+            vec![
+                self.check("collection", message),
+                call(RuntimeFunction::VectorPop, vec![get("collection")]),
+            ],
+        );
+        self.function(vec![self.value("collection")], vec![], body)
+    }
+
+    /// A new vector of the elements from start up to end, or up to the count
+    /// when end is nil
+    fn subvec(&self, strings: &mut StringPool) -> Function {
+        let unsupported = strings.intern("ClassCastException: value is not a vector\n");
+        let bounds = strings.intern("IndexOutOfBoundsException\n");
+        let body = vec![
+            self.check("vector", unsupported),
+            Instruction::set(
+                "index",
+                call(RuntimeFunction::IntegerValue, vec![get("start")]),
+            ),
+            Instruction::set("count", self.integer_field(get("vector"), COUNT)),
+            Instruction::set("last", get("count")),
+            Instruction::when(
+                not(self.is_nil(get("end"))),
+                vec![Instruction::set(
+                    "last",
+                    call(RuntimeFunction::IntegerValue, vec![get("end")]),
+                )],
+            ),
+            Instruction::when(
+                either(
+                    binary(BinaryOp::LessThan, get("index"), constant(0)),
+                    either(
+                        binary(BinaryOp::LessThan, get("last"), get("index")),
+                        binary(BinaryOp::GreaterThan, get("last"), get("count")),
+                    ),
+                ),
+                vec![fail_with(bounds)],
+            ),
+            Instruction::set("result", self.empty()),
+            until(
+                "copied",
+                binary(BinaryOp::GreaterEqual, get("index"), get("last")),
+                vec![
+                    Instruction::set(
+                        "result",
+                        call(
+                            RuntimeFunction::VectorConj,
+                            vec![get("result"), self.nth(get("vector"), get("index"))],
+                        ),
+                    ),
+                    increment("index"),
+                ],
+            ),
+            get("result"),
+        ];
+        self.function(
+            vec![self.value("vector"), self.value("start"), self.value("end")],
+            vec![
+                Local::i32("index"),
+                Local::i32("count"),
+                Local::i32("last"),
+                self.value("result"),
+            ],
+            body,
+        )
+    }
+
+    /// Appends to the tail while it has room. A full tail is pushed into the
+    /// trie first, which grows a level when the root is full too.
+    fn vector_conj(&self) -> Function {
+        let appended = vec![
+            Instruction::set(
+                "new_tail",
+                self.copy(get("tail"), add(self.length(get("tail")), 1)),
+            ),
+            self.set_element(get("new_tail"), self.length(get("tail")), get("value")),
+            self.new_vector(
+                add(get("count"), 1),
+                get("shift"),
+                get("root"),
+                get("new_tail"),
+            ),
+        ];
+        let pushed = vec![
+            Instruction::If {
+                result: None,
+                condition: Box::new(binary(
+                    BinaryOp::GreaterThanUnsigned,
+                    binary(BinaryOp::ShiftRightUnsigned, get("count"), constant(BITS)),
+                    binary(BinaryOp::ShiftLeft, constant(1), get("shift")),
+                )),
+                then: vec![
+                    Instruction::set(
+                        "path",
+                        call(RuntimeFunction::NewPath, vec![get("shift"), get("tail")]),
+                    ),
+                    Instruction::set("new_root", self.new_array(WIDTH)),
+                    self.set_element(get("new_root"), constant(0), get("root")),
+                    self.set_element(get("new_root"), constant(1), get("path")),
+                    Instruction::set("shift", add(get("shift"), BITS)),
+                ],
+                otherwise: vec![Instruction::set(
+                    "new_root",
+                    call(
+                        RuntimeFunction::PushTail,
+                        vec![get("count"), get("shift"), get("root"), get("tail")],
+                    ),
+                )],
+            },
+            Instruction::set("new_tail", self.new_array(1)),
+            self.set_element(get("new_tail"), constant(0), get("value")),
+            self.new_vector(
+                add(get("count"), 1),
+                get("shift"),
+                get("new_root"),
+                get("new_tail"),
+            ),
+        ];
+        let mut body = self.unpack();
+        body.push(choose(
+            self.backend,
+            binary(
+                BinaryOp::LessThan,
+                binary(BinaryOp::Subtract, get("count"), tail_offset(get("count"))),
+                constant(WIDTH),
+            ),
+            appended,
+            pushed,
+        ));
+
+        let mut locals = self.unpacked();
+        locals.extend(vec![
+            self.value("path"),
+            self.value("new_root"),
+            self.value("new_tail"),
+        ]);
+        self.function(
+            vec![self.value("vector"), self.value("value")],
+            locals,
+            body,
+        )
+    }
+
+    /// Copies the nodes from the parent down to where the full tail goes,
+    /// and puts the tail there
+    fn push_tail(&self) -> Function {
+        let below = add(get("level"), -BITS);
+        let body = vec![
+            Instruction::set("slot", slot(add(get("count"), -1), get("level"))),
+            Instruction::set(
+                "child",
+                choose(
+                    self.backend,
+                    binary(BinaryOp::Equal, get("level"), constant(BITS)),
+                    vec![get("tail")],
+                    vec![
+                        Instruction::set("child", self.element(get("parent"), get("slot"))),
+                        choose(
+                            self.backend,
+                            self.is_nil(get("child")),
+                            vec![call(
+                                RuntimeFunction::NewPath,
+                                vec![below.clone(), get("tail")],
+                            )],
+                            vec![call(
+                                self.function,
+                                vec![get("count"), below, get("child"), get("tail")],
+                            )],
+                        ),
+                    ],
+                ),
+            ),
+            Instruction::set("copy", self.copy(get("parent"), constant(WIDTH))),
+            self.set_element(get("copy"), get("slot"), get("child")),
+            get("copy"),
+        ];
+        self.function(
+            vec![
+                Local::i32("count"),
+                Local::i32("level"),
+                self.value("parent"),
+                self.value("tail"),
+            ],
+            vec![Local::i32("slot"), self.value("child"), self.value("copy")],
+            body,
+        )
+    }
+
+    /// A chain of nodes from a level down to the node, each holding only the
+    /// next
+    fn new_path(&self) -> Function {
+        let body = vec![choose(
+            self.backend,
+            not(get("level")),
+            vec![get("node")],
+            vec![
+                Instruction::set(
+                    "child",
+                    call(self.function, vec![add(get("level"), -BITS), get("node")]),
+                ),
+                Instruction::set("path", self.new_array(WIDTH)),
+                self.set_element(get("path"), constant(0), get("child")),
+                get("path"),
+            ],
+        )];
+        self.function(
+            vec![Local::i32("level"), self.value("node")],
+            vec![self.value("child"), self.value("path")],
+            body,
+        )
+    }
+
+    /// Replaces an element, or appends when the index is the count
+    fn vector_assoc(&self, strings: &mut StringPool) -> Function {
+        let bounds = strings.intern("IndexOutOfBoundsException\n");
+        let in_tail = vec![
+            Instruction::set("new_tail", self.copy(get("tail"), self.length(get("tail")))),
+            self.set_element(
+                get("new_tail"),
+                slot(get("index"), constant(0)),
+                get("value"),
+            ),
+            self.new_vector(get("count"), get("shift"), get("root"), get("new_tail")),
+        ];
+        let in_trie = vec![
+            Instruction::set(
+                "new_root",
+                call(
+                    RuntimeFunction::DoAssoc,
+                    vec![get("shift"), get("root"), get("index"), get("value")],
+                ),
+            ),
+            self.new_vector(get("count"), get("shift"), get("new_root"), get("tail")),
+        ];
+        let mut body = vec![Instruction::set(
+            "index",
+            call(RuntimeFunction::IntegerValue, vec![get("key")]),
+        )];
+        body.extend(self.unpack());
+        body.extend(cases(
+            self.value_type(),
+            vec![
+                (
+                    binary(BinaryOp::Equal, get("index"), get("count")),
+                    vec![call(
+                        RuntimeFunction::VectorConj,
+                        vec![get("vector"), get("value")],
+                    )],
+                ),
+                (
+                    not(in_bounds(get("index"), get("count"))),
+                    vec![fail_with(bounds), self.nil()],
+                ),
+                (
+                    binary(
+                        BinaryOp::GreaterEqual,
+                        get("index"),
+                        tail_offset(get("count")),
+                    ),
+                    in_tail,
+                ),
+            ],
+            in_trie,
+        ));
+
+        let mut locals = self.unpacked();
+        locals.extend(vec![
+            Local::i32("index"),
+            self.value("new_tail"),
+            self.value("new_root"),
+        ]);
+        self.function(
+            vec![self.value("vector"), self.value("key"), self.value("value")],
+            locals,
+            body,
+        )
+    }
+
+    /// Copies the nodes from the node down to the index, with the element
+    /// replaced in the leaf
+    fn do_assoc(&self) -> Function {
+        let body = vec![
+            Instruction::set("slot", slot(get("index"), get("level"))),
+            Instruction::set(
+                "child",
+                choose(
+                    self.backend,
+                    get("level"),
+                    vec![call(
+                        self.function,
+                        vec![
+                            add(get("level"), -BITS),
+                            self.element(get("node"), get("slot")),
+                            get("index"),
+                            get("value"),
+                        ],
+                    )],
+                    vec![get("value")],
+                ),
+            ),
+            Instruction::set("copy", self.copy(get("node"), constant(WIDTH))),
+            self.set_element(get("copy"), get("slot"), get("child")),
+            get("copy"),
+        ];
+        self.function(
+            vec![
+                Local::i32("level"),
+                self.value("node"),
+                Local::i32("index"),
+                self.value("value"),
+            ],
+            vec![Local::i32("slot"), self.value("child"), self.value("copy")],
+            body,
+        )
+    }
+
+    /// Takes off the last element. Once the tail would be empty, the last
+    /// leaf of the trie becomes the tail instead, and a root left with a
+    /// single child is replaced by that child.
+    fn vector_pop(&self, strings: &mut StringPool) -> Function {
+        let message = strings.intern("IllegalStateException: Can't pop empty vector\n");
+        let shortened = vec![
+            Instruction::set(
+                "new_tail",
+                self.copy(get("tail"), add(self.length(get("tail")), -1)),
+            ),
+            self.new_vector(
+                add(get("count"), -1),
+                get("shift"),
+                get("root"),
+                get("new_tail"),
+            ),
+        ];
+        let from_trie = vec![
+            Instruction::set(
+                "new_tail",
+                call(
+                    RuntimeFunction::ArrayFor,
+                    vec![get("vector"), add(get("count"), -2)],
+                ),
+            ),
+            Instruction::set(
+                "new_root",
+                call(
+                    RuntimeFunction::PopTail,
+                    vec![get("count"), get("shift"), get("root")],
+                ),
+            ),
+            Instruction::when(
+                binary(BinaryOp::GreaterThan, get("shift"), constant(BITS)),
+                vec![Instruction::when(
+                    self.is_nil(self.element(get("new_root"), constant(1))),
+                    vec![
+                        Instruction::set("new_root", self.element(get("new_root"), constant(0))),
+                        Instruction::set("shift", add(get("shift"), -BITS)),
+                    ],
+                )],
+            ),
+            self.new_vector(
+                add(get("count"), -1),
+                get("shift"),
+                get("new_root"),
+                get("new_tail"),
+            ),
+        ];
+        let mut body = self.unpack();
+        body.push(Instruction::when(
+            not(get("count")),
+            vec![fail_with(message)],
+        ));
+        body.extend(cases(
+            self.value_type(),
+            vec![
+                (
+                    binary(BinaryOp::Equal, get("count"), constant(1)),
+                    vec![self.empty()],
+                ),
+                (
+                    binary(
+                        BinaryOp::GreaterThan,
+                        binary(BinaryOp::Subtract, get("count"), tail_offset(get("count"))),
+                        constant(1),
+                    ),
+                    shortened,
+                ),
+            ],
+            from_trie,
+        ));
+
+        let mut locals = self.unpacked();
+        locals.extend(vec![self.value("new_tail"), self.value("new_root")]);
+        self.function(vec![self.value("vector")], locals, body)
+    }
+
+    /// Copies the nodes from the node down to the last leaf, leaving the leaf
+    /// out. Evaluates to nil when nothing would be left in the node.
+    fn pop_tail(&self) -> Function {
+        let body = vec![
+            Instruction::set("slot", slot(add(get("count"), -2), get("level"))),
+            Instruction::when(
+                binary(BinaryOp::GreaterThan, get("level"), constant(BITS)),
+                vec![Instruction::set(
+                    "child",
+                    call(
+                        self.function,
+                        vec![
+                            get("count"),
+                            add(get("level"), -BITS),
+                            self.element(get("node"), get("slot")),
+                        ],
+                    ),
+                )],
+            ),
+            choose(
+                self.backend,
+                binary(BinaryOp::And, self.is_nil(get("child")), not(get("slot"))),
+                vec![self.nil()],
+                vec![
+                    Instruction::set("copy", self.copy(get("node"), constant(WIDTH))),
+                    self.set_element(get("copy"), get("slot"), get("child")),
+                    get("copy"),
+                ],
+            ),
+        ];
+        self.function(
+            vec![Local::i32("count"), Local::i32("level"), self.value("node")],
+            vec![Local::i32("slot"), self.value("child"), self.value("copy")],
+            body,
+        )
+    }
+}
@@ -0,0 +1,412 @@
+use crate::codegen::instructions::{BinaryOp, Instruction, UnaryOp, Width};
+use crate::codegen::module::{
+    Composite, DataSegment, Export, Field, Function, Global, HeapType, Import, Module, Signature,
+    StorageType, TypeDefinition, ValueType,
+};
+
+/// Prints a module in the folded WebAssembly text format
+pub fn print(module: &Module) -> String {
+    let mut fields = vec![];
+    if !module.types.is_empty() {
+        let types: Vec<String> = module.types.iter().map(print_type).collect();
+        fields.push(format!("(rec\n  {})", types.join("\n  ")));
+    }
+    for import in &module.imports {
+        fields.push(print_import(import));
+    }
+    fields.push(format!("(memory {})", module.memory_pages));
+    for global in &module.globals {
+        fields.push(print_global(global));
+    }
+    if let Some(table) = &module.table {
+        fields.push(format!("(table {} funcref)", table.len()));
+        if !table.is_empty() {
+            let names: Vec<String> = table.iter().map(|name| format!("${}", name)).collect();
+            fields.push(format!("(elem (i32.const 0) {})", names.join(" ")));
+        }
+    }
+    for segment in &module.data {
+        fields.push(print_data(segment));
+    }
+    for function in &module.functions {
+        fields.push(print_function(function));
+    }
+    for export in &module.exports {
+        fields.push(print_export(export));
+    }
+
+    let mut text = "(module".to_owned();
+    for field in fields {
+        text.push_str("\n  ");
+        text.push_str(&field.replace("\n", "\n  "));
+    }
+    text.push(')');
+    text
+}
+
+fn value_type(value_type: &ValueType) -> &'static str {
+    match value_type {
+        ValueType::I32 => "i32",
+        ValueType::EqRef => "eqref",
+    }
+}
+
+fn heap_type(heap_type: &HeapType) -> String {
+    match heap_type {
+        HeapType::I31 => "i31".to_owned(),
+        HeapType::Defined(name) => format!("${}", name),
+    }
+}
+
+fn print_field(field: &Field) -> String {
+    let storage = match &field.storage {
+        StorageType::I8 => "i8",
+        StorageType::Value(stored) => value_type(stored),
+    };
+    match field.mutable {
+        true => format!("(mut {})", storage),
+        false => storage.to_owned(),
+    }
+}
+
+fn print_type(definition: &TypeDefinition) -> String {
+    let composite = match &definition.composite {
+        Composite::Struct(fields) => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|field| format!(" (field {})", print_field(field)))
+                .collect();
+            format!("(struct{})", fields.concat())
+        }
+        Composite::Array(element) => format!("(array {})", print_field(element)),
+    };
+    format!("(type ${} {})", definition.name, composite)
+}
+
+fn result(result: &Option<ValueType>) -> String {
+    match result {
+        Some(value) => format!(" (result {})", value_type(value)),
+        None => String::new(),
+    }
+}
+
+fn print_signature(signature: &Signature) -> String {
+    let mut text = String::new();
+    if !signature.params.is_empty() {
+        let params: Vec<&str> = signature.params.iter().map(value_type).collect();
+        text.push_str(&format!(" (param {})", params.join(" ")));
+    }
+    text + &result(&signature.result)
+}
+
+fn print_import(import: &Import) -> String {
+    format!(
+        "(import \"{}\" \"{}\" (func ${}{}))",
+        import.module,
+        import.name,
+        import.name,
+        print_signature(&import.signature)
+    )
+}
+
+fn print_global(global: &Global) -> String {
+    format!(
+        "(global ${} (mut {}) {})",
+        global.name,
+        value_type(&global.value_type),
+        print_instruction(&global.initial)
+    )
+}
+
+fn print_data(segment: &DataSegment) -> String {
+    let mut text = format!("(data (i32.const {}) \"", segment.offset);
+    for byte in &segment.bytes {
+        // anything that is not plain printable ascii is written as a hex escape
+        match byte {
+            b'"' | b'\\' => text.push_str(&format!("\\{:02x}", byte)),
+            0x20..=0x7e => text.push(*byte as char),
+            _ => text.push_str(&format!("\\{:02x}", byte)),
+        }
+    }
+    text + "\")"
+}
+
+fn print_export(export: &Export) -> String {
+    match export {
+        Export::Function(name, function) => {
+            format!("(export \"{}\" (func ${}))", name, function)
+        }
+        Export::Memory(name) => format!("(export \"{}\" (memory 0))", name),
+    }
+}
+
+fn print_function(function: &Function) -> String {
+    let mut text = format!("(func ${}", function.name);
+    for param in &function.params {
+        text.push_str(&format!(
+            " (param ${} {})",
+            param.name,
+            value_type(&param.value_type)
+        ));
+    }
+    text.push_str(&result(&function.result));
+    for local in &function.locals {
+        text.push_str(&format!(
+            " (local ${} {})",
+            local.name,
+            value_type(&local.value_type)
+        ));
+    }
+    text + &print_body(&function.body) + ")"
+}
+
+fn print_body(body: &Vec<Instruction>) -> String {
+    body.iter()
+        .map(|instruction| {
+            format!(
+                "\n  {}",
+                print_instruction(instruction).replace("\n", "\n  ")
+            )
+        })
+        .collect()
+}
+
+fn binary_name(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "i32.add",
+        BinaryOp::Subtract => "i32.sub",
+        BinaryOp::Multiply => "i32.mul",
+        BinaryOp::DivideSigned => "i32.div_s",
+        BinaryOp::DivideUnsigned => "i32.div_u",
+        BinaryOp::RemainderSigned => "i32.rem_s",
+        BinaryOp::RemainderUnsigned => "i32.rem_u",
+        BinaryOp::And => "i32.and",
+        BinaryOp::Or => "i32.or",
+        BinaryOp::Xor => "i32.xor",
+        BinaryOp::ShiftLeft => "i32.shl",
+        BinaryOp::ShiftRightSigned => "i32.shr_s",
+        BinaryOp::ShiftRightUnsigned => "i32.shr_u",
+        BinaryOp::Equal => "i32.eq",
+        BinaryOp::NotEqual => "i32.ne",
+        BinaryOp::LessThan => "i32.lt_s",
+        BinaryOp::LessEqual => "i32.le_s",
+        BinaryOp::GreaterThan => "i32.gt_s",
+        BinaryOp::GreaterThanUnsigned => "i32.gt_u",
+        BinaryOp::GreaterEqual => "i32.ge_s",
+    }
+}
+
+fn unary_name(op: &UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::EqualZero => "i32.eqz",
+        UnaryOp::CountLeadingZeros => "i32.clz",
+        UnaryOp::PopulationCount => "i32.popcnt",
+    }
+}
+
+fn memory_name(prefix: &str, width: &Width, offset: u32) -> String {
+    let suffix = match (prefix, width) {
+        (_, Width::Word) => "",
+        ("load", Width::Byte) => "8_u",
+        (_, Width::Byte) => "8",
+    };
+    match offset {
+        0 => format!("i32.{}{}", prefix, suffix),
+        _ => format!("i32.{}{} offset={}", prefix, suffix, offset),
+    }
+}
+
+/// Short operations are written on one line, anything containing a block
+/// gets its operands on separate indented lines
+fn fold(name: String, operands: Vec<&Instruction>) -> String {
+    let printed: Vec<String> = operands.into_iter().map(print_instruction).collect();
+    let inline = printed.iter().all(|operand| !operand.contains('\n'))
+        && printed.iter().map(|operand| operand.len()).sum::<usize>() < 60;
+    if inline {
+        let mut text = format!("({}", name);
+        for operand in printed {
+            text.push(' ');
+            text.push_str(&operand);
+        }
+        text + ")"
+    } else {
+        let mut text = format!("({}", name);
+        for operand in printed {
+            text.push_str("\n  ");
+            text.push_str(&operand.replace("\n", "\n  "));
+        }
+        text + ")"
+    }
+}
+
+fn print_instruction(instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Const(value) => format!("(i32.const {})", value),
+        Instruction::LocalGet(name) => format!("(local.get ${})", name),
+        Instruction::LocalSet(name, value) => fold(format!("local.set ${}", name), vec![value]),
+        Instruction::GlobalGet(name) => format!("(global.get ${})", name),
+        Instruction::GlobalSet(name, value) => fold(format!("global.set ${}", name), vec![value]),
+        Instruction::Load {
+            width,
+            offset,
+            address,
+        } => fold(memory_name("load", width, *offset), vec![address]),
+        Instruction::Store {
+            width,
+            offset,
+            address,
+            value,
+        } => fold(memory_name("store", width, *offset), vec![address, value]),
+        Instruction::Unary(op, value) => fold(unary_name(op).to_owned(), vec![value]),
+        Instruction::Binary(op, left, right) => fold(binary_name(op).to_owned(), vec![left, right]),
+        Instruction::Call(name, args) => fold(format!("call ${}", name), args.iter().collect()),
+        Instruction::ReturnCall(name, args) => {
+            fold(format!("return_call ${}", name), args.iter().collect())
+        }
+        Instruction::CallIndirect(signature, args, index) => {
+            let mut operands: Vec<&Instruction> = args.iter().collect();
+            operands.push(index);
+            fold(
+                format!("call_indirect{}", print_signature(signature)),
+                operands,
+            )
+        }
+        Instruction::Drop(value) => fold("drop".to_owned(), vec![value]),
+        Instruction::MemorySize => "(memory.size)".to_owned(),
+        Instruction::MemoryGrow(pages) => fold("memory.grow".to_owned(), vec![pages]),
+        Instruction::Block {
+            label,
+            result: block_result,
+            body,
+        } => {
+            let label = match label {
+                Some(label) => format!(" ${}", label),
+                None => String::new(),
+            };
+            format!(
+                "(block{}{}{})",
+                label,
+                result(block_result),
+                print_body(body)
+            )
+        }
+        Instruction::Loop {
+            label,
+            result: loop_result,
+            body,
+        } => {
+            format!(
+                "(loop ${}{}{})",
+                label,
+                result(loop_result),
+                print_body(body)
+            )
+        }
+        Instruction::If {
+            result: if_result,
+            condition,
+            then,
+            otherwise,
+        } => {
+            let mut text = format!("(if{}", result(if_result));
+            text.push_str(&format!(
+                "\n  {}",
+                print_instruction(condition).replace("\n", "\n  ")
+            ));
+            text.push_str(&format!(
+                "\n  (then{})",
+                print_body(then).replace("\n", "\n  ")
+            ));
+            if !otherwise.is_empty() {
+                text.push_str(&format!(
+                    "\n  (else{})",
+                    print_body(otherwise).replace("\n", "\n  ")
+                ));
+            }
+            text + ")"
+        }
+        Instruction::Branch(label) => format!("(br ${})", label),
+        Instruction::BranchIf(label, condition) => {
+            fold(format!("br_if ${}", label), vec![condition])
+        }
+        Instruction::RefNull => "(ref.null eq)".to_owned(),
+        Instruction::RefIsNull(value) => fold("ref.is_null".to_owned(), vec![value]),
+        Instruction::RefEq(left, right) => fold("ref.eq".to_owned(), vec![left, right]),
+        Instruction::RefI31(value) => fold("ref.i31".to_owned(), vec![value]),
+        Instruction::I31Get(value) => fold("i31.get_s".to_owned(), vec![value]),
+        Instruction::RefTest(tested, value) => {
+            fold(format!("ref.test (ref {})", heap_type(tested)), vec![value])
+        }
+        Instruction::RefCast(cast, value) => {
+            fold(format!("ref.cast (ref {})", heap_type(cast)), vec![value])
+        }
+        Instruction::StructNew(name, fields) => {
+            fold(format!("struct.new ${}", name), fields.iter().collect())
+        }
+        Instruction::StructGet(name, field, value) => {
+            fold(format!("struct.get ${} {}", name, field), vec![value])
+        }
+        Instruction::ArrayNew {
+            name,
+            value,
+            length,
+        } => fold(format!("array.new ${}", name), vec![value, length]),
+        Instruction::ArrayNewFixed(name, elements) => fold(
+            format!("array.new_fixed ${} {}", name, elements.len()),
+            elements.iter().collect(),
+        ),
+        Instruction::ArrayGet {
+            name,
+            packed,
+            array,
+            index,
+        } => {
+            let suffix = if *packed { "_u" } else { "" };
+            fold(format!("array.get{} ${}", suffix, name), vec![array, index])
+        }
+        Instruction::ArraySet {
+            name,
+            array,
+            index,
+            value,
+        } => fold(format!("array.set ${}", name), vec![array, index, value]),
+        Instruction::ArrayLen(value) => fold("array.len".to_owned(), vec![value]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::instructions::{BinaryOp, Instruction};
+    use crate::codegen::module::{Export, Function, Local, Module, ValueType};
+    use crate::codegen::wat::print;
+
+    #[test]
+    fn print_folded_function() {
+        let module = Module {
+            types: vec![],
+            imports: vec![],
+            memory_pages: 1,
+            globals: vec![],
+            table: None,
+            functions: vec![Function {
+                name: "inc".to_owned(),
+                params: vec![Local::i32("x")],
+                result: Some(ValueType::I32),
+                locals: vec![],
+                body: vec![Instruction::binary(
+                    BinaryOp::Add,
+                    Instruction::get("x"),
+                    Instruction::Const(1),
+                )],
+            }],
+            exports: vec![Export::Function("inc".to_owned(), "inc".to_owned())],
+            data: vec![],
+        };
+
+        assert_eq!(
+            print(&module),
+            "(module\n  (memory 1)\n  (func $inc (param $x i32) (result i32)\n    \
+             (i32.add (local.get $x) (i32.const 1)))\n  (export \"inc\" (func $inc)))"
+        )
+    }
+}
@@ -1,59 +1,194 @@
-use crate::frontend::scanner::Lexeme;
+use crate::frontend::scanner::{Lexeme, Position};
 
 type VariableName = String;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ConstantLiteral {
     IntegerLiteral(i32),
     StringLiteral(String),
+    BooleanLiteral(bool),
+    NilLiteral,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct KeywordDetails {
     pub token: Lexeme,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ListDetails {
     pub head: Box<Node>,
     pub rest: Vec<Node>,
+    // where the head of the list is
+    pub position: Position,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct FunctionDetails {
-    pub name: Box<Node>,
+/// One parameter list of a function and the body it runs
+#[derive(Debug, PartialEq, Clone)]
+pub struct Arity {
     pub args: Vec<Node>,
+    // bound to a seq of the arguments past the fixed ones, nil when there are none
+    pub rest: Option<Box<Node>>,
     pub body: Vec<Node>,
 }
 
-#[derive(Debug, PartialEq)]
+impl Arity {
+    fn children(&self) -> impl Iterator<Item = &Node> {
+        self.args
+            .iter()
+            .chain(self.rest.as_deref())
+            .chain(&self.body)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionDetails {
+    pub name: Box<Node>,
+    pub arities: Vec<Arity>,
+}
+
+/// A function value. The name, when there is one, refers to the function
+/// itself inside its body.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FnDetails {
+    pub name: Option<VariableName>,
+    pub arities: Vec<Arity>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct MainDetails {
     pub args: Vec<Node>,
     pub body: Vec<Node>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct VariableInformation {
     pub name: Box<Node>,
     pub value: Box<Node>,
 }
 
-#[derive(Debug, PartialEq)]
+/// Bindings are evaluated in order, each one can see the ones before it
+#[derive(Debug, PartialEq, Clone)]
+pub struct LetDetails {
+    pub bindings: Vec<VariableInformation>,
+    pub body: Vec<Node>,
+}
+
+/// The else branch is optional and evaluates to nil when missing
+#[derive(Debug, PartialEq, Clone)]
+pub struct IfDetails {
+    pub condition: Box<Node>,
+    pub then: Box<Node>,
+    pub otherwise: Option<Box<Node>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct WhenDetails {
+    pub condition: Box<Node>,
+    pub body: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CondClause {
+    pub test: Node,
+    pub body: Node,
+}
+
+/// Jumps back to the enclosing loop or function with new values for its
+/// bindings
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecurDetails {
+    pub position: Position,
+    pub args: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct MapItem {
-    pub key: String,
+    pub key: Node,
     pub value: Node,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Node {
     Null,
     Main(MainDetails),
     Def(VariableInformation),
     Function(FunctionDetails),
+    Fn(FnDetails),
+    Let(LetDetails),
+    Loop(LetDetails),
+    Recur(RecurDetails),
+    If(IfDetails),
+    When(WhenDetails),
+    Do(Vec<Node>),
+    Cond(Vec<CondClause>),
     Constant(ConstantLiteral),
     Keyword(KeywordDetails),
+    // a keyword like :name, without its colon
+    KeywordLiteral(String),
     Variable(VariableName),
     Map(Vec<MapItem>),
     Vector(Vec<Node>),
+    Set(Vec<Node>),
     List(ListDetails),
+    // a call whose value the enclosing function returns directly
+    TailCall(ListDetails),
+}
+
+impl Node {
+    /// The forms directly nested in this one
+    pub fn children(&self) -> Vec<&Node> {
+        let mut children = Vec::new();
+        match self {
+            Node::Main(details) => children.extend(details.args.iter().chain(&details.body)),
+            Node::Function(details) => {
+                for arity in &details.arities {
+                    children.extend(arity.children());
+                }
+            }
+            Node::Fn(details) => {
+                for arity in &details.arities {
+                    children.extend(arity.children());
+                }
+            }
+            Node::Def(details) => children.extend(vec![&*details.name, &*details.value]),
+            Node::Let(details) | Node::Loop(details) => {
+                for binding in &details.bindings {
+                    children.extend(vec![&*binding.name, &*binding.value]);
+                }
+                children.extend(&details.body);
+            }
+            Node::Recur(details) => children.extend(&details.args),
+            Node::If(details) => {
+                children.extend(vec![&*details.condition, &*details.then]);
+                children.extend(details.otherwise.as_deref());
+            }
+            Node::When(details) => {
+                children.push(&*details.condition);
+                children.extend(&details.body);
+            }
+            Node::Do(body) | Node::Vector(body) | Node::Set(body) => children.extend(body),
+            Node::Cond(clauses) => {
+                for clause in clauses {
+                    children.extend(vec![&clause.test, &clause.body]);
+                }
+            }
+            Node::Map(items) => {
+                for item in items {
+                    children.push(&item.key);
+                    children.push(&item.value);
+                }
+            }
+            Node::List(details) | Node::TailCall(details) => {
+                children.push(&*details.head);
+                children.extend(&details.rest);
+            }
+            Node::Null
+            | Node::Constant(_)
+            | Node::Keyword(_)
+            | Node::KeywordLiteral(_)
+            | Node::Variable(_) => {}
+        }
+        children
+    }
 }
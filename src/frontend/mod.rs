@@ -1,3 +1,4 @@
 pub mod ast;
 pub(crate) mod parser;
 pub(crate) mod scanner;
+pub(crate) mod tail;
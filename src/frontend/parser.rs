@@ -1,9 +1,12 @@
 use super::scanner::{scan_into_peekable, Lexeme, Token};
 use crate::frontend::ast::Node::Constant;
 use crate::frontend::ast::{
-    ConstantLiteral, FunctionDetails, KeywordDetails, ListDetails, MainDetails, MapItem, Node,
+    Arity, CondClause, ConstantLiteral, FnDetails, FunctionDetails, IfDetails, KeywordDetails,
+    LetDetails, ListDetails, MainDetails, MapItem, Node, RecurDetails, VariableInformation,
+    WhenDetails,
 };
 use crate::frontend::scanner::{Position, ScanError};
+use crate::frontend::tail::{check_recur, loop_self_calls, mark_tail_calls};
 use std::iter::Peekable;
 use std::option::NoneError;
 use std::vec::IntoIter;
@@ -16,6 +19,10 @@ pub enum ParseError {
     UnexpectedEndOfFile,
     UnexpectedToken(Position, Lexeme),
     InvalidFunctionName(Position, Lexeme),
+    InvalidBindings(Position),
+    MalformedForm(Position, Lexeme),
+    RecurNotInTailPosition(Position),
+    DuplicateKey(Position),
 }
 
 impl From<NoneError> for ParseError {
@@ -45,6 +52,8 @@ impl Parser {
         while (tokens.peek()?).lexeme != Lexeme::EOF {
             nodes.push(self.parse_token_stream(&mut tokens)?)
         }
+        check_recur(&nodes)?;
+        mark_tail_calls(&mut nodes);
         Ok(nodes)
     }
 
@@ -56,12 +65,20 @@ impl Parser {
             } => self.parse_list(tokens),
             Token {
                 lexeme: Lexeme::LeftBrace,
-                ..
-            } => self.parse_map(tokens),
+                position,
+            } => self.parse_map(position, tokens),
             Token {
                 lexeme: Lexeme::LeftBracket,
                 ..
             } => self.parse_vector(tokens),
+            Token {
+                lexeme: Lexeme::HashParen,
+                ..
+            } => self.parse_anonymous_function(tokens),
+            Token {
+                lexeme: Lexeme::HashBrace,
+                ..
+            } => self.parse_set(tokens),
             random => Err(ParseError::UnexpectedToken(random.position, random.lexeme)),
         };
     }
@@ -72,16 +89,69 @@ impl Parser {
                 lexeme: Lexeme::Defn,
                 ..
             }) => self.parse_function_definition(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Def,
+                ..
+            }) => self.parse_def(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Let,
+                ..
+            }) => self.parse_let(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Loop,
+                ..
+            }) => self.parse_loop(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Recur,
+                ..
+            }) => {
+                let recur_token = token_stream.next()?;
+                Ok(Node::Recur(RecurDetails {
+                    position: recur_token.position,
+                    args: self.parse_body(token_stream)?,
+                }))
+            }
+            Some(Token {
+                lexeme: Lexeme::If, ..
+            }) => self.parse_if(token_stream),
+            Some(Token {
+                lexeme: Lexeme::When,
+                ..
+            }) => self.parse_when(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Do, ..
+            }) => {
+                token_stream.next();
+                Ok(Node::Do(self.parse_body(token_stream)?))
+            }
+            Some(Token {
+                lexeme: Lexeme::Cond,
+                ..
+            }) => self.parse_cond(token_stream),
+            Some(Token {
+                lexeme: Lexeme::Fn, ..
+            }) => self.parse_fn(token_stream),
             _ => self.parse_seq_list(token_stream),
         }
     }
 
+    /// Parses any form, starting with its first token
+    fn parse_form(&self, token: Token, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        match token.lexeme {
+            Lexeme::LeftParen => self.parse_list(token_stream),
+            Lexeme::LeftBracket => self.parse_vector(token_stream),
+            Lexeme::LeftBrace => self.parse_map(token.position, token_stream),
+            Lexeme::HashParen => self.parse_anonymous_function(token_stream),
+            Lexeme::HashBrace => self.parse_set(token_stream),
+            _ => self.parse_item(token),
+        }
+    }
+
     fn parse_function_definition(
         &self,
         token_stream: &mut TokenStream,
     ) -> Result<Node, ParseError> {
-        // dump the defn token
-        token_stream.next();
+        let defn_token = token_stream.next()?;
         let name_token = token_stream.next()?;
         let name = match &name_token {
             Token {
@@ -100,63 +170,316 @@ impl Parser {
             }
         };
 
-        let next_element = match token_stream.next()? {
+        let mut arities = self.parse_arities(&defn_token, token_stream)?;
+
+        match name {
+            Node::Main(..) => match (arities.pop(), arities.is_empty()) {
+                (
+                    Some(Arity {
+                        args,
+                        rest: None,
+                        body,
+                    }),
+                    true,
+                ) => Ok(Node::Main(MainDetails { args, body })),
+                _ => Err(ParseError::MalformedForm(
+                    defn_token.position,
+                    defn_token.lexeme,
+                )),
+            },
+            _ => Ok(Node::Function(FunctionDetails {
+                name: Box::new(name),
+                arities,
+            })),
+        }
+    }
+
+    /// Parses either a single `[params] body` or one `([params] body)` list per
+    /// arity, up to the closing parenthesis of the whole form. Like Clojure,
+    /// arities must differ in their number of fixed parameters and only one of
+    /// them can take the rest.
+    fn parse_arities(
+        &self,
+        form: &Token,
+        token_stream: &mut TokenStream,
+    ) -> Result<Vec<Arity>, ParseError> {
+        let malformed = || ParseError::MalformedForm(form.position, form.lexeme.clone());
+        let mut arities = vec![];
+        match token_stream.next()?.lexeme {
+            Lexeme::LeftBracket => arities.push(self.parse_arity(form, token_stream)?),
+            Lexeme::LeftParen => loop {
+                match token_stream.next()?.lexeme {
+                    Lexeme::LeftBracket => arities.push(self.parse_arity(form, token_stream)?),
+                    _ => return Err(malformed()),
+                }
+                match token_stream.next()?.lexeme {
+                    Lexeme::LeftParen => continue,
+                    Lexeme::RightParen => break,
+                    _ => return Err(malformed()),
+                }
+            },
+            _ => return Err(malformed()),
+        }
+
+        let variadic: Vec<&Arity> = arities
+            .iter()
+            .filter(|arity| arity.rest.is_some())
+            .collect();
+        let fixed: Vec<usize> = arities
+            .iter()
+            .filter(|arity| arity.rest.is_none())
+            .map(|arity| arity.args.len())
+            .collect();
+        let distinct = fixed
+            .iter()
+            .enumerate()
+            .all(|(index, count)| !fixed[..index].contains(count));
+        let rest_last = match variadic.as_slice() {
+            [] => true,
+            [arity] => fixed.iter().all(|count| *count <= arity.args.len()),
+            _ => false,
+        };
+        if !distinct || !rest_last {
+            return Err(malformed());
+        }
+        Ok(arities)
+    }
+
+    /// Parses the parameters, whose opening bracket was already read, and the
+    /// body after them
+    fn parse_arity(
+        &self,
+        form: &Token,
+        token_stream: &mut TokenStream,
+    ) -> Result<Arity, ParseError> {
+        let mut args = match self.parse_vector(token_stream)? {
+            Node::Vector(args) => args,
+            _ => vec![],
+        };
+        let marker = args
+            .iter()
+            .position(|arg| *arg == Node::Variable("&".to_owned()));
+        let rest = match marker {
+            Some(index) if index + 2 == args.len() => {
+                let rest = args.pop().map(Box::new);
+                args.pop();
+                rest
+            }
+            Some(_) => {
+                return Err(ParseError::MalformedForm(
+                    form.position,
+                    form.lexeme.clone(),
+                ))
+            }
+            None => None,
+        };
+        let body = self.parse_body(token_stream)?;
+
+        Ok(Arity { args, rest, body })
+    }
+
+    fn parse_def(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let def_token = token_stream.next()?;
+        let mut forms = self.parse_body(token_stream)?.into_iter();
+        match (forms.next(), forms.next(), forms.next()) {
+            (Some(name @ Node::Variable(_)), Some(value), None) => {
+                Ok(Node::Def(VariableInformation {
+                    name: Box::new(name),
+                    value: Box::new(value),
+                }))
+            }
+            _ => Err(ParseError::MalformedForm(
+                def_token.position,
+                def_token.lexeme,
+            )),
+        }
+    }
+
+    fn parse_fn(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let fn_token = token_stream.next()?;
+        let name = match token_stream.peek() {
+            Some(Token {
+                lexeme: Lexeme::Identifier(name),
+                ..
+            }) => Some(name.to_owned()),
+            _ => None,
+        };
+        if name.is_some() {
+            token_stream.next();
+        }
+        let mut arities = self.parse_arities(&fn_token, token_stream)?;
+        if let Some(name) = &name {
+            loop_self_calls(name, &mut arities);
+        }
+
+        Ok(Node::Fn(FnDetails { name, arities }))
+    }
+
+    /// `#(...)` is a function whose body is the list itself, taking as many
+    /// parameters as the highest `%n` it mentions, and the rest as `%&`
+    fn parse_anonymous_function(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let body = self.parse_list(token_stream)?;
+        let count = anonymous_arity(&body);
+        let rest = Node::Variable("%&".to_owned());
+        let variadic = mentions(&body, &rest);
+
+        Ok(Node::Fn(FnDetails {
+            name: None,
+            arities: vec![Arity {
+                args: (1..=count)
+                    .map(|index| Node::Variable(format!("%{}", index)))
+                    .collect(),
+                rest: if variadic { Some(Box::new(rest)) } else { None },
+                body: vec![body],
+            }],
+        }))
+    }
+
+    fn parse_let(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let let_token = token_stream.next()?;
+        let bindings = self.parse_bindings(let_token.position, token_stream)?;
+        let body = self.parse_body(token_stream)?;
+
+        Ok(Node::Let(LetDetails { bindings, body }))
+    }
+
+    fn parse_if(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let if_token = token_stream.next()?;
+        let mut forms = self.parse_body(token_stream)?.into_iter();
+        match (forms.next(), forms.next(), forms.next(), forms.next()) {
+            (Some(condition), Some(then), otherwise, None) => Ok(Node::If(IfDetails {
+                condition: Box::new(condition),
+                then: Box::new(then),
+                otherwise: otherwise.map(Box::new),
+            })),
+            _ => Err(ParseError::MalformedForm(
+                if_token.position,
+                if_token.lexeme,
+            )),
+        }
+    }
+
+    fn parse_when(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let when_token = token_stream.next()?;
+        let mut forms = self.parse_body(token_stream)?;
+        if forms.is_empty() {
+            return Err(ParseError::MalformedForm(
+                when_token.position,
+                when_token.lexeme,
+            ));
+        }
+        let condition = forms.remove(0);
+
+        Ok(Node::When(WhenDetails {
+            condition: Box::new(condition),
+            body: forms,
+        }))
+    }
+
+    fn parse_cond(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let cond_token = token_stream.next()?;
+        let mut clauses = Vec::new();
+        loop {
+            let token = token_stream.next()?;
+            let test = match token.lexeme {
+                Lexeme::RightParen => break,
+                // a keyword such as :else is always truthy
+                Lexeme::MapKey(_) => Node::Constant(ConstantLiteral::BooleanLiteral(true)),
+                _ => self.parse_form(token, token_stream)?,
+            };
+            let body = match token_stream.next()? {
+                Token {
+                    lexeme: Lexeme::RightParen,
+                    ..
+                } => {
+                    return Err(ParseError::MalformedForm(
+                        cond_token.position,
+                        cond_token.lexeme,
+                    ))
+                }
+                token => self.parse_form(token, token_stream)?,
+            };
+            clauses.push(CondClause { test, body });
+        }
+
+        Ok(Node::Cond(clauses))
+    }
+
+    fn parse_loop(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let loop_token = token_stream.next()?;
+        let bindings = self.parse_bindings(loop_token.position, token_stream)?;
+        let body = self.parse_body(token_stream)?;
+
+        Ok(Node::Loop(LetDetails { bindings, body }))
+    }
+
+    /// Parses a binding vector of alternating names and values
+    fn parse_bindings(
+        &self,
+        position: Position,
+        token_stream: &mut TokenStream,
+    ) -> Result<Vec<VariableInformation>, ParseError> {
+        let items = match token_stream.next()? {
             Token {
                 lexeme: Lexeme::LeftBracket,
                 ..
             } => self.parse_vector(token_stream)?,
             token => return Err(ParseError::UnexpectedToken(token.position, token.lexeme)),
         };
-
-        let args = match next_element {
-            Node::Vector(arguments) => arguments,
-            _ => vec![],
+        let items = match items {
+            Node::Vector(items) if items.len() % 2 == 0 => items,
+            _ => return Err(ParseError::InvalidBindings(position)),
         };
 
-        let body = self.parse_function_body(token_stream)?;
-
-        match name {
-            Node::Main(..) => Ok(Node::Main(MainDetails { args, body })),
-            _ => Ok(Node::Function(FunctionDetails {
-                name: Box::new(name),
-                args,
-                body,
-            })),
+        let mut bindings = Vec::new();
+        let mut items = items.into_iter();
+        while let (Some(name), Some(value)) = (items.next(), items.next()) {
+            match name {
+                Node::Variable(_) => bindings.push(VariableInformation {
+                    name: Box::new(name),
+                    value: Box::new(value),
+                }),
+                _ => return Err(ParseError::InvalidBindings(position)),
+            }
         }
+
+        Ok(bindings)
     }
 
-    fn parse_function_body(&self, token_stream: &mut TokenStream) -> Result<Vec<Node>, ParseError> {
+    fn parse_body(&self, token_stream: &mut TokenStream) -> Result<Vec<Node>, ParseError> {
         let mut body = Vec::<Node>::new();
-        while let Some(token) = token_stream.peek() {
-            if token.lexeme == Lexeme::LeftParen {
-                // move to function body
-                token_stream.next();
-                body.push(self.parse_seq_list(token_stream)?);
-            } else {
-                break;
+        loop {
+            let token = token_stream.next()?;
+            match token.lexeme {
+                // the body ends with the closing parenthesis of the enclosing form
+                Lexeme::RightParen => break,
+                _ => body.push(self.parse_form(token, token_stream)?),
             }
         }
-        // skip trailing right parenthesis
-        token_stream.next();
 
         Ok(body)
     }
 
+    /// Parses a call. There is nothing to call in an empty list, so `()` and
+    /// `#()` are malformed.
     fn parse_seq_list(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let position = token_stream.peek()?.position;
         let mut list = Vec::<Node>::new();
         while let Some(token) = token_stream.next() {
             if token.lexeme == Lexeme::RightParen {
                 break;
-            } else if token.lexeme == Lexeme::LeftParen {
-                list.push(self.parse_seq_list(token_stream)?)
             } else {
-                list.push(self.parse_item(token)?);
+                list.push(self.parse_form(token, token_stream)?);
             }
         }
+        if list.is_empty() {
+            return Err(ParseError::MalformedForm(position, Lexeme::RightParen));
+        }
         let top = list.remove(0);
         Ok(Node::List(ListDetails {
             head: Box::from(top),
             rest: list,
+            position,
         }))
     }
 
@@ -167,40 +490,64 @@ impl Parser {
             if token.lexeme == Lexeme::RightBracket {
                 break;
             } else {
-                list.push(self.parse_item(token)?);
+                list.push(self.parse_form(token, token_stream)?);
             }
         }
 
         Ok(Node::Vector(list))
     }
 
-    fn parse_map(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+    /// Reads the forms between the braces in pairs of key and value. As in
+    /// Clojure, there must be a value for every key and no key may be read
+    /// twice.
+    fn parse_map(
+        &self,
+        position: Position,
+        token_stream: &mut TokenStream,
+    ) -> Result<Node, ParseError> {
+        let mut forms = Vec::<(Position, Node)>::new();
+        loop {
+            let token = token_stream.next()?;
+            if token.lexeme == Lexeme::RightBrace {
+                break;
+            }
+            forms.push((token.position, self.parse_form(token, token_stream)?));
+        }
+        if forms.len() % 2 == 1 {
+            return Err(ParseError::MalformedForm(position, Lexeme::LeftBrace));
+        }
+
         let mut map_items = Vec::<MapItem>::new();
-        while let Some(token) = token_stream.next() {
-            match token.lexeme {
-                Lexeme::MapKey(name) => {
-                    let item = match token_stream.next() {
-                        Some(value) => MapItem {
-                            key: name,
-                            value: self.parse_item(value)?,
-                        },
-                        None => return Err(ParseError::UnexpectedEndOfFile),
-                    };
-                    map_items.push(item);
-                }
-                Lexeme::RightBrace => break,
-                _ => {
-                    map_items.push(MapItem {
-                        key: String::from(""),
-                        value: self.parse_item(token)?,
-                    });
-                }
+        let mut pairs = forms.into_iter();
+        while let (Some((position, key)), Some((_, value))) = (pairs.next(), pairs.next()) {
+            if map_items.iter().any(|item| item.key == key) {
+                return Err(ParseError::DuplicateKey(position));
             }
+            map_items.push(MapItem { key, value });
         }
 
         Ok(Node::Map(map_items))
     }
 
+    /// Reads the elements of a set, none of which may be read twice
+    fn parse_set(&self, token_stream: &mut TokenStream) -> Result<Node, ParseError> {
+        let mut elements = Vec::<Node>::new();
+        loop {
+            let token = token_stream.next()?;
+            if token.lexeme == Lexeme::RightBrace {
+                break;
+            }
+            let position = token.position;
+            let element = self.parse_form(token, token_stream)?;
+            if elements.contains(&element) {
+                return Err(ParseError::DuplicateKey(position));
+            }
+            elements.push(element);
+        }
+
+        Ok(Node::Set(elements))
+    }
+
     fn parse_item(&self, item: Token) -> Result<Node, ParseError> {
         return match item.lexeme {
             Lexeme::NumberLiteral(number) => {
@@ -209,9 +556,25 @@ impl Parser {
             Lexeme::StringLiteral(string) => {
                 Ok(Node::Constant(ConstantLiteral::StringLiteral(string)))
             }
-            Lexeme::Plus | Lexeme::Minus | Lexeme::And | Lexeme::Or | Lexeme::Print => {
-                Ok(Node::Keyword(KeywordDetails { token: item.lexeme }))
-            }
+            Lexeme::True => Ok(Node::Constant(ConstantLiteral::BooleanLiteral(true))),
+            Lexeme::False => Ok(Node::Constant(ConstantLiteral::BooleanLiteral(false))),
+            Lexeme::Nil => Ok(Node::Constant(ConstantLiteral::NilLiteral)),
+            Lexeme::Plus
+            | Lexeme::Minus
+            | Lexeme::Star
+            | Lexeme::Slash
+            | Lexeme::And
+            | Lexeme::Or
+            | Lexeme::Print
+            | Lexeme::Less
+            | Lexeme::LessEqual
+            | Lexeme::Greater
+            | Lexeme::GreaterEqual
+            | Lexeme::Equal
+            | Lexeme::DoubleEqual => Ok(Node::Keyword(KeywordDetails { token: item.lexeme })),
+            Lexeme::MapKey(name) => Ok(Node::KeywordLiteral(name)),
+            // a bare % is the first parameter of an anonymous function
+            Lexeme::Identifier(name) if name == "%" => Ok(Node::Variable("%1".to_owned())),
             Lexeme::Identifier(name) => Ok(Node::Variable(name)),
             Lexeme::Main => Ok(Node::Variable("main".to_owned())),
             _ => Ok(Node::Null),
@@ -226,13 +589,34 @@ impl Parser {
     }
 }
 
+fn anonymous_arity(node: &Node) -> usize {
+    let own = match node {
+        Node::Variable(name) if name.starts_with('%') => name[1..].parse().unwrap_or(0),
+        _ => 0,
+    };
+    node.children()
+        .into_iter()
+        .map(anonymous_arity)
+        .fold(own, usize::max)
+}
+
+fn mentions(node: &Node, form: &Node) -> bool {
+    node == form
+        || node
+            .children()
+            .into_iter()
+            .any(|child| mentions(child, form))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::frontend::ast::{
-        ConstantLiteral, FunctionDetails, KeywordDetails, ListDetails, MapItem, Node,
+        Arity, CondClause, ConstantLiteral, FnDetails, FunctionDetails, IfDetails, KeywordDetails,
+        LetDetails, ListDetails, MapItem, Node, VariableInformation,
     };
+    use crate::frontend::parser::ParseError;
     use crate::frontend::parser::Parser;
-    use crate::frontend::scanner::Lexeme;
+    use crate::frontend::scanner::{Lexeme, Position};
 
     #[test]
     fn parse_list() {
@@ -247,6 +631,7 @@ mod tests {
                 Node::Constant(ConstantLiteral::IntegerLiteral(1 as i32)),
                 Node::Constant(ConstantLiteral::IntegerLiteral(2 as i32)),
             ],
+            position: Position { line: 1, column: 3 },
         });
         let nodes = parser.parse().unwrap();
 
@@ -272,8 +657,10 @@ mod tests {
                         Node::Constant(ConstantLiteral::IntegerLiteral(2 as i32)),
                         Node::Constant(ConstantLiteral::IntegerLiteral(3 as i32)),
                     ],
+                    position: Position { line: 1, column: 8 },
                 }),
             ],
+            position: Position { line: 1, column: 3 },
         });
 
         let nodes = parser.parse().unwrap();
@@ -287,11 +674,11 @@ mod tests {
 
         let tree = Node::Map(vec![
             MapItem {
-                key: "guten".to_string(),
+                key: Node::KeywordLiteral("guten".to_string()),
                 value: Node::Constant(ConstantLiteral::IntegerLiteral(1 as i32)),
             },
             MapItem {
-                key: "tag".to_string(),
+                key: Node::KeywordLiteral("tag".to_string()),
                 value: Node::Constant(ConstantLiteral::IntegerLiteral(2 as i32)),
             },
         ]);
@@ -301,6 +688,79 @@ mod tests {
         assert_eq!(nodes[0], tree)
     }
 
+    #[test]
+    fn parse_map_with_any_keys() {
+        let text = "{1 \"one\" \"a\" 2}".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::Map(vec![
+            MapItem {
+                key: Node::Constant(ConstantLiteral::IntegerLiteral(1 as i32)),
+                value: Node::Constant(ConstantLiteral::StringLiteral("one".to_string())),
+            },
+            MapItem {
+                key: Node::Constant(ConstantLiteral::StringLiteral("a".to_string())),
+                value: Node::Constant(ConstantLiteral::IntegerLiteral(2 as i32)),
+            },
+        ]);
+
+        let nodes = parser.parse().unwrap();
+
+        assert_eq!(nodes[0], tree)
+    }
+
+    #[test]
+    fn reject_malformed_maps() {
+        assert_eq!(
+            Parser::new("{:a 1 :b}").parse(),
+            Err(ParseError::MalformedForm(
+                Position { line: 1, column: 2 },
+                Lexeme::LeftBrace
+            ))
+        );
+        assert_eq!(
+            Parser::new("{:a 1 :a 2}").parse(),
+            Err(ParseError::DuplicateKey(Position { line: 1, column: 9 }))
+        );
+    }
+
+    #[test]
+    fn parse_set() {
+        let text = "#{1 :a}".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::Set(vec![
+            Node::Constant(ConstantLiteral::IntegerLiteral(1 as i32)),
+            Node::KeywordLiteral("a".to_string()),
+        ]);
+
+        let nodes = parser.parse().unwrap();
+
+        assert_eq!(nodes[0], tree);
+        assert_eq!(
+            Parser::new("#{1 2 1}").parse(),
+            Err(ParseError::DuplicateKey(Position { line: 1, column: 7 }))
+        );
+    }
+
+    #[test]
+    fn parse_keywords_anywhere() {
+        let text = "(get m :a)".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::List(ListDetails {
+            head: Box::from(Node::Variable("get".to_string())),
+            rest: vec![
+                Node::Variable("m".to_string()),
+                Node::KeywordLiteral("a".to_string()),
+            ],
+            position: Position { line: 1, column: 5 },
+        });
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(nodes[0], tree)
+    }
+
     #[test]
     fn parse_vector() {
         let text = "[1 2]".to_string();
@@ -323,22 +783,220 @@ mod tests {
 
         let tree = Node::Function(FunctionDetails {
             name: Box::new(Node::Variable("add".to_owned())),
-            args: vec![
-                Node::Variable("x".to_owned()),
-                Node::Variable("y".to_owned()),
+            arities: vec![Arity {
+                args: vec![
+                    Node::Variable("x".to_owned()),
+                    Node::Variable("y".to_owned()),
+                ],
+                rest: None,
+                body: vec![Node::List(ListDetails {
+                    head: Box::from(Node::Keyword(KeywordDetails {
+                        token: Lexeme::Plus,
+                    })),
+                    rest: vec![
+                        Node::Variable("x".to_owned()),
+                        Node::Variable("y".to_owned()),
+                    ],
+                    position: Position {
+                        line: 1,
+                        column: 19,
+                    },
+                })],
+            }],
+        });
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(nodes[0], tree)
+    }
+
+    #[test]
+    fn parse_function_returning_argument() {
+        let text = "(defn id [x] x)".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::Function(FunctionDetails {
+            name: Box::new(Node::Variable("id".to_owned())),
+            arities: vec![Arity {
+                args: vec![Node::Variable("x".to_owned())],
+                rest: None,
+                body: vec![Node::Variable("x".to_owned())],
+            }],
+        });
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(nodes[0], tree)
+    }
+
+    #[test]
+    fn parse_let() {
+        let text = "(let [x 1 y x] y)".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::Let(LetDetails {
+            bindings: vec![
+                VariableInformation {
+                    name: Box::new(Node::Variable("x".to_owned())),
+                    value: Box::new(Node::Constant(ConstantLiteral::IntegerLiteral(1))),
+                },
+                VariableInformation {
+                    name: Box::new(Node::Variable("y".to_owned())),
+                    value: Box::new(Node::Variable("x".to_owned())),
+                },
             ],
-            body: vec![Node::List(ListDetails {
+            body: vec![Node::Variable("y".to_owned())],
+        });
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(nodes[0], tree)
+    }
+
+    #[test]
+    fn reject_odd_bindings() {
+        let text = "(let [x 1 y] y)".to_string();
+        let parser = Parser::new(&text);
+
+        assert_eq!(
+            parser.parse(),
+            Err(ParseError::InvalidBindings(Position { line: 1, column: 5 }))
+        )
+    }
+
+    #[test]
+    fn parse_if_and_cond() {
+        let text = "(if true 1) (cond nil 2 :else 3)".to_string();
+        let parser = Parser::new(&text);
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(
+            nodes[0],
+            Node::If(IfDetails {
+                condition: Box::new(Node::Constant(ConstantLiteral::BooleanLiteral(true))),
+                then: Box::new(Node::Constant(ConstantLiteral::IntegerLiteral(1))),
+                otherwise: None,
+            })
+        );
+        assert_eq!(
+            nodes[1],
+            Node::Cond(vec![
+                CondClause {
+                    test: Node::Constant(ConstantLiteral::NilLiteral),
+                    body: Node::Constant(ConstantLiteral::IntegerLiteral(2)),
+                },
+                CondClause {
+                    test: Node::Constant(ConstantLiteral::BooleanLiteral(true)),
+                    body: Node::Constant(ConstantLiteral::IntegerLiteral(3)),
+                },
+            ])
+        )
+    }
+
+    #[test]
+    fn parse_def() {
+        let text = "(def answer 42)".to_string();
+        let parser = Parser::new(&text);
+
+        let tree = Node::Def(VariableInformation {
+            name: Box::new(Node::Variable("answer".to_owned())),
+            value: Box::new(Node::Constant(ConstantLiteral::IntegerLiteral(42))),
+        });
+
+        let nodes = parser.parse().unwrap();
+        assert_eq!(nodes[0], tree)
+    }
+
+    #[test]
+    fn parse_anonymous_functions() {
+        let parser = Parser::new("(fn twice [x] (+ x x)) #(+ % %2)");
+        let plus = |left: &str, right: &str, column| {
+            Node::List(ListDetails {
                 head: Box::from(Node::Keyword(KeywordDetails {
                     token: Lexeme::Plus,
                 })),
                 rest: vec![
-                    Node::Variable("x".to_owned()),
-                    Node::Variable("y".to_owned()),
+                    Node::Variable(left.to_owned()),
+                    Node::Variable(right.to_owned()),
                 ],
-            })],
-        });
+                position: Position { line: 1, column },
+            })
+        };
 
         let nodes = parser.parse().unwrap();
-        assert_eq!(nodes[0], tree)
+
+        assert_eq!(
+            nodes[0],
+            Node::Fn(FnDetails {
+                name: Some("twice".to_owned()),
+                arities: vec![Arity {
+                    args: vec![Node::Variable("x".to_owned())],
+                    rest: None,
+                    body: vec![plus("x", "x", 17)],
+                }],
+            })
+        );
+        assert_eq!(
+            nodes[1],
+            Node::Fn(FnDetails {
+                name: None,
+                arities: vec![Arity {
+                    args: vec![
+                        Node::Variable("%1".to_owned()),
+                        Node::Variable("%2".to_owned()),
+                    ],
+                    rest: None,
+                    body: vec![plus("%1", "%2", 26)],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn reject_empty_calls() {
+        assert_eq!(
+            Parser::new("(print #())").parse(),
+            Err(ParseError::MalformedForm(
+                Position {
+                    line: 1,
+                    column: 10
+                },
+                Lexeme::RightParen
+            ))
+        );
+        assert!(Parser::new("()").parse().is_err());
+    }
+
+    #[test]
+    fn parse_multiple_arities() {
+        let text = "(defn f ([x] x) ([x & more] more)) (fn [& xs] xs)";
+        let nodes = Parser::new(text).parse().unwrap();
+
+        let arities = match &nodes[0] {
+            Node::Function(details) => &details.arities,
+            _ => panic!("expected a function"),
+        };
+        assert_eq!(arities.len(), 2);
+        assert_eq!(
+            arities[1].rest,
+            Some(Box::new(Node::Variable("more".to_owned())))
+        );
+        assert_eq!(arities[1].args.len(), 1);
+        assert_eq!(arities[0].rest, None);
+        match &nodes[1] {
+            Node::Fn(details) => assert!(details.arities[0].args.is_empty()),
+            _ => panic!("expected a fn"),
+        }
+    }
+
+    #[test]
+    fn reject_clashing_arities() {
+        let error = |text: &str| Parser::new(text).parse().unwrap_err();
+
+        assert_eq!(
+            error("(defn f ([x] 1) ([y] 2))"),
+            ParseError::MalformedForm(Position { line: 1, column: 6 }, Lexeme::Defn)
+        );
+        assert!(Parser::new("(defn f ([x y] 1) ([x & ys] 2))")
+            .parse()
+            .is_err());
+        assert!(Parser::new("(defn f [x &] 1)").parse().is_err());
     }
 }
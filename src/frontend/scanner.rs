@@ -28,6 +28,10 @@ pub enum Lexeme {
     GreaterEqual,
     Less,
     LessEqual,
+    // `#(`, which opens an anonymous function literal
+    HashParen,
+    // `#{`, which opens a set literal
+    HashBrace,
 
     Identifier(String),
     StringLiteral(String),
@@ -40,10 +44,17 @@ pub enum Lexeme {
     Cond,
     Def,
     Defn,
+    Do,
+    Fn,
+    If,
+    Let,
+    Loop,
     Nil,
     Or,
     Print,
+    Recur,
     True,
+    When,
     Main,
 
     Comment,
@@ -102,6 +113,15 @@ fn is_alpha(c: char) -> bool {
     return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_' || c == '-';
 }
 
+/// Characters that may appear in a symbol after its first letter, as in
+/// `not=` or `empty?`
+fn is_symbol_char(c: char) -> bool {
+    match c {
+        '?' | '!' | '*' | '=' | '<' | '>' | '&' => true,
+        _ => false,
+    }
+}
+
 fn check_keyword(
     input_string: &String,
     index: usize,
@@ -118,6 +138,9 @@ fn check_keyword(
 #[derive(Debug, PartialEq)]
 pub enum ScanError {
     UnknownCharacter(Position, String),
+    UnterminatedString(Position),
+    /// a number that is not a 32-bit integer
+    InvalidNumber(Position, String),
 }
 
 impl fmt::Display for ScanError {
@@ -126,6 +149,12 @@ impl fmt::Display for ScanError {
             ScanError::UnknownCharacter(ref pos, ref string) => {
                 write!(f, "unknown character {:?} at {:?}", pos, string)
             }
+            ScanError::UnterminatedString(ref pos) => {
+                write!(f, "unterminated string starting at {:?}", pos)
+            }
+            ScanError::InvalidNumber(ref pos, ref number) => {
+                write!(f, "invalid number {} at {:?}", number, pos)
+            }
         }
     }
 }
@@ -178,7 +207,19 @@ impl<'a> Scanner<'a> {
             }
             Some(',') => self.make_token(Lexeme::Comma),
             Some('.') => self.make_token(Lexeme::Dot),
-            Some('-') => self.make_token(Lexeme::Minus),
+            Some('-') => {
+                // a minus directly followed by a digit starts a negative number
+                let negative = match self.source.peek() {
+                    Some(&c) => is_digit(c),
+                    None => false,
+                };
+                self.source.reset_peek();
+                if negative {
+                    self.make_digit()
+                } else {
+                    self.make_token(Lexeme::Minus)
+                }
+            }
             Some('+') => self.make_token(Lexeme::Plus),
             Some('*') => self.make_token(Lexeme::Star),
             Some('!') => {
@@ -210,6 +251,11 @@ impl<'a> Scanner<'a> {
                 }
             }
             Some('/') => self.make_token(Lexeme::Slash),
+            Some('#') if self.peek_match('(') => self.make_token(Lexeme::HashParen),
+            Some('#') if self.peek_match('{') => self.make_token(Lexeme::HashBrace),
+            // the parameters of an anonymous function literal, and the marker
+            // before a rest parameter
+            Some('%') | Some('&') => self.make_identifier(),
             Some('"') => self.make_string(),
             Some(c) if is_whitespace(c) => self.make_token(Lexeme::Whitespace),
             Some(c) if is_digit(c) => self.make_digit(),
@@ -240,6 +286,8 @@ impl<'a> Scanner<'a> {
             self.source.next();
             return true;
         }
+        // leave the next peek looking at the same character
+        self.source.reset_peek();
         false
     }
 
@@ -252,20 +300,28 @@ impl<'a> Scanner<'a> {
     }
 
     fn make_string(&mut self) -> Result<Token, ScanError> {
-        // remove the starting '"'
-        self.current_string.pop();
+        let start = self.current_position;
+        let mut string = String::new();
         loop {
-            self.advance();
-            if let Some('"') = self.source.peek() {
-                break;
+            match self.advance() {
+                Some('"') => break,
+                Some('\\') => match self.advance() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    // covers \" and \\
+                    Some(ch) => string.push(ch),
+                    None => return Err(ScanError::UnterminatedString(start)),
+                },
+                Some(ch) => string.push(ch),
+                None => return Err(ScanError::UnterminatedString(start)),
             }
         }
-        // skip the trailing '"'
-        self.source.next();
-        self.make_token(Lexeme::StringLiteral(String::from(&self.current_string)))
+        self.make_token(Lexeme::StringLiteral(string))
     }
 
     fn make_digit(&mut self) -> Result<Token, ScanError> {
+        let start = self.current_position;
         let mut decimal_count = 1;
         loop {
             match self.source.peek() {
@@ -286,7 +342,13 @@ impl<'a> Scanner<'a> {
             }
         }
 
-        self.make_token(Lexeme::NumberLiteral(self.current_string.parse().unwrap()))
+        match self.current_string.parse() {
+            Ok(number) => self.make_token(Lexeme::NumberLiteral(number)),
+            Err(_) => Err(ScanError::InvalidNumber(
+                start,
+                self.current_string.to_owned(),
+            )),
+        }
     }
 
     fn make_identifier(&mut self) -> Result<Token, ScanError> {
@@ -307,7 +369,7 @@ impl<'a> Scanner<'a> {
     fn scan_word(&mut self) {
         loop {
             match self.source.peek() {
-                Some(&ch) if is_alpha(ch) || is_digit(ch) => {
+                Some(&ch) if is_alpha(ch) || is_digit(ch) || is_symbol_char(ch) => {
                     self.advance();
                 }
                 _ => break,
@@ -322,22 +384,32 @@ impl<'a> Scanner<'a> {
             'f' if self.current_string.len() > 1 => match current_chars.peek().unwrap() {
                 'a' => check_keyword(&self.current_string, 2, "lse".into(), Lexeme::False),
                 'o' => check_keyword(&self.current_string, 2, "r".into(), Lexeme::For),
+                'n' => check_keyword(&self.current_string, 2, "".into(), Lexeme::Fn),
                 _ => Lexeme::Identifier(String::from(&self.current_string)),
             },
             'c' => check_keyword(&self.current_string, 1, "ond".into(), Lexeme::Cond),
             'd' if self.current_string.len() > 1 => match current_chars.peek().unwrap() {
-                'e' if self.current_string.len() > 2 => match current_chars.peek().unwrap() {
+                'e' if self.current_string.len() > 3 => match current_chars.peek().unwrap() {
                     'f' => check_keyword(&self.current_string, 3, "n".into(), Lexeme::Defn),
                     _ => Lexeme::Identifier(String::from(&self.current_string)),
                 },
                 'e' => check_keyword(&self.current_string, 2, "f".into(), Lexeme::Def),
+                'o' => check_keyword(&self.current_string, 2, "".into(), Lexeme::Do),
+                _ => Lexeme::Identifier(String::from(&self.current_string)),
+            },
+            'i' => check_keyword(&self.current_string, 1, "f".into(), Lexeme::If),
+            'l' if self.current_string.len() > 1 => match current_chars.peek().unwrap() {
+                'e' => check_keyword(&self.current_string, 2, "t".into(), Lexeme::Let),
+                'o' => check_keyword(&self.current_string, 2, "op".into(), Lexeme::Loop),
                 _ => Lexeme::Identifier(String::from(&self.current_string)),
             },
             'm' => check_keyword(&self.current_string, 1, "ain".into(), Lexeme::Main),
             'n' => check_keyword(&self.current_string, 1, "il".into(), Lexeme::Nil),
             'o' => check_keyword(&self.current_string, 1, "r".into(), Lexeme::Or),
             'p' => check_keyword(&self.current_string, 1, "rint".into(), Lexeme::Print),
+            'r' => check_keyword(&self.current_string, 1, "ecur".into(), Lexeme::Recur),
             't' => check_keyword(&self.current_string, 1, "rue".into(), Lexeme::True),
+            'w' => check_keyword(&self.current_string, 1, "hen".into(), Lexeme::When),
             _ => Lexeme::Identifier(String::from(&self.current_string)),
         }
     }
@@ -381,8 +453,10 @@ pub fn scan_into_peekable(source: String) -> Result<Peekable<IntoIter<Token>>, S
 
 #[cfg(test)]
 mod tests {
-    use crate::frontend::scanner::Lexeme::NumberLiteral;
-    use crate::frontend::scanner::Scanner;
+    use crate::frontend::scanner::Lexeme::{
+        Identifier, LessEqual, MapKey, Minus, NumberLiteral, StringLiteral,
+    };
+    use crate::frontend::scanner::{Position, ScanError, Scanner};
 
     #[test]
     fn parse_numbers() {
@@ -394,4 +468,84 @@ mod tests {
             scanner.scan_token().unwrap().lexeme
         )
     }
+
+    #[test]
+    fn parse_string_escapes() {
+        let text = r#""say \"hi\"\n" """#.to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(
+            StringLiteral("say \"hi\"\n".to_owned()),
+            scanner.scan_token().unwrap().lexeme
+        );
+        scanner.scan_token().unwrap();
+        assert_eq!(
+            StringLiteral("".to_owned()),
+            scanner.scan_token().unwrap().lexeme
+        )
+    }
+
+    #[test]
+    fn parse_single_letter_keywords() {
+        let text = ":a 1".to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(MapKey("a".to_owned()), scanner.scan_token().unwrap().lexeme)
+    }
+
+    #[test]
+    fn reject_unterminated_string() {
+        let text = "\"open".to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(
+            Err(ScanError::UnterminatedString(Position {
+                line: 1,
+                column: 2
+            })),
+            scanner.scan_token()
+        )
+    }
+
+    #[test]
+    fn reject_numbers_past_i32() {
+        let text = "99999999999".to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(
+            Err(ScanError::InvalidNumber(
+                Position { line: 1, column: 2 },
+                "99999999999".to_owned()
+            )),
+            scanner.scan_token()
+        )
+    }
+
+    #[test]
+    fn parse_symbols_with_punctuation() {
+        let text = "not= <= empty?".to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(
+            Identifier("not=".to_owned()),
+            scanner.scan_token().unwrap().lexeme
+        );
+        scanner.scan_token().unwrap();
+        assert_eq!(LessEqual, scanner.scan_token().unwrap().lexeme);
+        scanner.scan_token().unwrap();
+        assert_eq!(
+            Identifier("empty?".to_owned()),
+            scanner.scan_token().unwrap().lexeme
+        )
+    }
+
+    #[test]
+    fn parse_negative_numbers() {
+        let text = "-12 - 3".to_string();
+        let mut scanner = Scanner::new(&text);
+
+        assert_eq!(NumberLiteral(-12), scanner.scan_token().unwrap().lexeme);
+        scanner.scan_token().unwrap();
+        assert_eq!(Minus, scanner.scan_token().unwrap().lexeme);
+    }
 }
@@ -0,0 +1,286 @@
+use crate::frontend::ast::{Arity, LetDetails, ListDetails, Node, RecurDetails};
+use crate::frontend::parser::ParseError;
+use crate::frontend::scanner::Lexeme;
+
+/// Checks that every recur is in tail position of a loop or function body,
+/// as it compiles to a jump back to the start of that body
+pub fn check_recur(nodes: &Vec<Node>) -> Result<(), ParseError> {
+    for node in nodes {
+        walk(node, false)?;
+    }
+    Ok(())
+}
+
+/// Walks a body where only the last form can be in tail position
+fn walk_body(body: &Vec<Node>, tail: bool) -> Result<(), ParseError> {
+    let last = body.len().saturating_sub(1);
+    for (index, node) in body.iter().enumerate() {
+        walk(node, tail && index == last)?;
+    }
+    Ok(())
+}
+
+fn walk_bindings(details: &LetDetails) -> Result<(), ParseError> {
+    for binding in &details.bindings {
+        walk(&binding.value, false)?;
+    }
+    Ok(())
+}
+
+fn walk_arities(arities: &Vec<Arity>) -> Result<(), ParseError> {
+    for arity in arities {
+        walk_body(&arity.body, true)?;
+    }
+    Ok(())
+}
+
+fn walk(node: &Node, tail: bool) -> Result<(), ParseError> {
+    match node {
+        Node::Recur(details) => {
+            if !tail {
+                return Err(ParseError::RecurNotInTailPosition(details.position));
+            }
+            walk_body(&details.args, false)
+        }
+        // function and loop bodies are where recur jumps back to
+        Node::Function(details) => walk_arities(&details.arities),
+        Node::Main(details) => walk_body(&details.body, true),
+        Node::Fn(details) => walk_arities(&details.arities),
+        Node::Loop(details) => {
+            walk_bindings(details)?;
+            walk_body(&details.body, true)
+        }
+        Node::Let(details) => {
+            walk_bindings(details)?;
+            walk_body(&details.body, tail)
+        }
+        Node::If(details) => {
+            walk(&details.condition, false)?;
+            walk(&details.then, tail)?;
+            match &details.otherwise {
+                Some(otherwise) => walk(otherwise, tail),
+                None => Ok(()),
+            }
+        }
+        Node::When(details) => {
+            walk(&details.condition, false)?;
+            walk_body(&details.body, tail)
+        }
+        Node::Do(body) => walk_body(body, tail),
+        Node::Cond(clauses) => {
+            for clause in clauses {
+                walk(&clause.test, false)?;
+                walk(&clause.body, tail)?;
+            }
+            Ok(())
+        }
+        Node::List(ListDetails {
+            head: box Node::Keyword(details),
+            rest,
+            ..
+        }) if details.token == Lexeme::And || details.token == Lexeme::Or => {
+            // the last operand of and/or is what the whole form evaluates to
+            walk_body(rest, tail)
+        }
+        Node::List(details) | Node::TailCall(details) => {
+            walk(&details.head, false)?;
+            walk_body(&details.rest, false)
+        }
+        Node::Def(details) => walk(&details.value, false),
+        Node::Vector(items) | Node::Set(items) => walk_body(items, false),
+        Node::Map(items) => {
+            for item in items {
+                walk(&item.key, false)?;
+                walk(&item.value, false)?;
+            }
+            Ok(())
+        }
+        Node::Null
+        | Node::Constant(_)
+        | Node::Keyword(_)
+        | Node::KeywordLiteral(_)
+        | Node::Variable(_) => Ok(()),
+    }
+}
+
+/// Marks the calls whose value a function returns directly, so they can be
+/// compiled without growing the call stack
+pub fn mark_tail_calls(nodes: &mut Vec<Node>) {
+    for node in nodes {
+        if let Node::Function(details) = node {
+            for arity in &mut details.arities {
+                mark_body(&mut arity.body);
+            }
+        }
+    }
+}
+
+fn mark_body(body: &mut Vec<Node>) {
+    if let Some(last) = body.last_mut() {
+        mark(last);
+    }
+}
+
+fn mark(node: &mut Node) {
+    match node {
+        // only calls to named functions, the keyword operators are inlined
+        Node::List(ListDetails {
+            head: box Node::Variable(_),
+            ..
+        }) => {
+            if let Node::List(details) = std::mem::replace(node, Node::Null) {
+                *node = Node::TailCall(details);
+            }
+        }
+        Node::List(ListDetails {
+            head: box Node::Keyword(details),
+            rest,
+            ..
+        }) if details.token == Lexeme::And || details.token == Lexeme::Or => mark_body(rest),
+        Node::Let(details) | Node::Loop(details) => mark_body(&mut details.body),
+        Node::If(details) => {
+            mark(&mut details.then);
+            if let Some(otherwise) = &mut details.otherwise {
+                mark(otherwise);
+            }
+        }
+        Node::When(details) => mark_body(&mut details.body),
+        Node::Do(body) => mark_body(body),
+        Node::Cond(clauses) => {
+            for clause in clauses {
+                mark(&mut clause.body);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Turns the calls a named fn makes to itself in tail position into recurs,
+/// so they loop rather than grow the call stack. Only arities without rest
+/// parameters are rewritten, as recur would pass the rest as a seq, and only
+/// calls with as many arguments as the arity takes.
+pub fn loop_self_calls(name: &str, arities: &mut Vec<Arity>) {
+    for arity in arities {
+        let shadowed = arity.args.iter().any(|arg| binds(arg, name));
+        if arity.rest.is_none() && !shadowed {
+            loop_body(&mut arity.body, name, arity.args.len());
+        }
+    }
+}
+
+fn binds(node: &Node, name: &str) -> bool {
+    match node {
+        Node::Variable(variable) => variable == name,
+        _ => false,
+    }
+}
+
+fn loop_body(body: &mut Vec<Node>, name: &str, count: usize) {
+    if let Some(last) = body.last_mut() {
+        loop_call(last, name, count);
+    }
+}
+
+fn loop_call(node: &mut Node, name: &str, count: usize) {
+    match node {
+        Node::List(ListDetails {
+            head: box Node::Variable(head),
+            rest,
+            ..
+        }) if head == name && rest.len() == count => {
+            if let Node::List(details) = std::mem::replace(node, Node::Null) {
+                *node = Node::Recur(RecurDetails {
+                    position: details.position,
+                    args: details.rest,
+                });
+            }
+        }
+        Node::List(ListDetails {
+            head: box Node::Keyword(details),
+            rest,
+            ..
+        }) if details.token == Lexeme::And || details.token == Lexeme::Or => {
+            loop_body(rest, name, count)
+        }
+        Node::Let(details) => {
+            let bindings = &details.bindings;
+            if !bindings.iter().any(|binding| binds(&binding.name, name)) {
+                loop_body(&mut details.body, name, count);
+            }
+        }
+        Node::If(details) => {
+            loop_call(&mut details.then, name, count);
+            if let Some(otherwise) = &mut details.otherwise {
+                loop_call(otherwise, name, count);
+            }
+        }
+        Node::When(details) => loop_body(&mut details.body, name, count),
+        Node::Do(body) => loop_body(body, name, count),
+        Node::Cond(clauses) => {
+            for clause in clauses {
+                loop_call(&mut clause.body, name, count);
+            }
+        }
+        // not into a loop, where a recur would jump back to the loop instead
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frontend::ast::Node;
+    use crate::frontend::parser::{ParseError, Parser};
+    use crate::frontend::scanner::Position;
+
+    #[test]
+    fn accept_recur_in_tail_position() {
+        let text = "(loop [i 0] (if (< i 3) (do (print i) (recur (inc i))) i))";
+
+        assert!(Parser::new(text).parse().is_ok())
+    }
+
+    #[test]
+    fn reject_recur_outside_tail_position() {
+        let text = "(loop [i 0]\n  (+ 1 (recur i)))";
+
+        assert_eq!(
+            Parser::new(text).parse(),
+            Err(ParseError::RecurNotInTailPosition(Position {
+                line: 2,
+                column: 14
+            }))
+        )
+    }
+
+    #[test]
+    fn mark_only_calls_in_tail_position() {
+        let text = "(defn f [n] (if (= n 0) (g n) (do (g n) (f (dec n)))))";
+        let body = match Parser::new(text).parse().unwrap().pop() {
+            Some(Node::Function(mut details)) => details.arities.remove(0).body,
+            _ => panic!("expected a function"),
+        };
+        let output = format!("{:?}", body);
+
+        assert_eq!(output.matches("TailCall").count(), 2);
+        assert!(output.contains("Do([List"));
+    }
+
+    #[test]
+    fn loop_only_calls_a_named_fn_makes_to_itself() {
+        let recurs = |text: &str| {
+            let nodes = Parser::new(text).parse().unwrap();
+            format!("{:?}", nodes).matches("Recur(").count()
+        };
+
+        assert_eq!(recurs("(fn f [n] (if (= n 0) (f) (f (dec n))))"), 1);
+        assert_eq!(
+            recurs("(fn f ([n] (f n 0)) ([n acc] (and n (f (dec n) acc))))"),
+            1
+        );
+        assert_eq!(recurs("(fn f [n] (loop [i n] (f i)))"), 0);
+        assert_eq!(recurs("(fn f [n] (let [f inc] (f n)))"), 0);
+        assert_eq!(recurs("(fn f [f] (f 1))"), 0);
+        assert_eq!(recurs("(fn f [& xs] (f 1))"), 0);
+        assert_eq!(recurs("(fn [n] (f n))"), 0);
+    }
+}
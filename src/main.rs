@@ -4,7 +4,7 @@
 mod codegen;
 mod frontend;
 
-use codegen::emitter::Emitter;
+use codegen::emitter::{Backend, CompileError, Emitter};
 use frontend::parser::{ParseError, Parser};
 use std::env;
 use std::fs::File;
@@ -13,7 +13,10 @@ use std::io::{BufReader, Read, Write};
 #[derive(Debug)]
 enum AppError {
     Parse(ParseError),
+    Compile(CompileError),
     Io(std::io::Error),
+    // a command line the compiler does not understand
+    Usage(String),
 }
 
 impl From<std::io::Error> for AppError {
@@ -28,6 +31,31 @@ impl From<ParseError> for AppError {
     }
 }
 
+impl From<CompileError> for AppError {
+    fn from(err: CompileError) -> Self {
+        AppError::Compile(err)
+    }
+}
+
+/// The backend named after `--target`, linear memory when there is none
+fn target(args: &[String]) -> Result<Backend, AppError> {
+    let index = match args.iter().position(|arg| arg == "--target") {
+        Some(index) => index,
+        None => return Ok(Backend::Linear),
+    };
+    match args.get(index + 1).map(String::as_str) {
+        Some("linear") => Ok(Backend::Linear),
+        Some("wasm-gc") => Ok(Backend::WasmGc),
+        Some(other) => Err(AppError::Usage(format!(
+            "unknown target {:?}, expected one of: linear, wasm-gc",
+            other
+        ))),
+        None => Err(AppError::Usage(
+            "missing target after --target, expected one of: linear, wasm-gc".to_owned(),
+        )),
+    }
+}
+
 fn main() -> Result<(), AppError> {
     let args: Vec<String> = env::args().collect();
     let file = File::open(args[1].to_owned())?;
@@ -37,10 +65,51 @@ fn main() -> Result<(), AppError> {
     let parser = Parser::new(&contents);
 
     let tree = parser.parse()?;
-    let mut emitter = Emitter::new();
-    let content = emitter.emit(tree);
+    // return_call needs an engine with the tail call proposal
+    let tail_calls = args.iter().any(|arg| arg == "--tail-calls");
+    // collecting on every allocation turns rooting bugs into crashes
+    let gc_stress = args.iter().any(|arg| arg == "--gc-stress");
+    // with wasm-gc the engine manages the objects, which it has to support
+    let backend = target(&args)?;
+    let mut emitter = Emitter::new()
+        .with_tail_calls(tail_calls)
+        .with_gc_stress(gc_stress)
+        .with_backend(backend);
 
-    let mut out = File::create("main.wat")?;
-    out.write_all(content.as_bytes())?;
+    // the text format is only written when asked for
+    if args.iter().any(|arg| arg == "--wat") {
+        let content = emitter.emit(tree)?;
+        let mut out = File::create("main.wat")?;
+        out.write_all(content.as_bytes())?;
+    } else {
+        let content = emitter.emit_binary(tree)?;
+        let mut out = File::create("main.wasm")?;
+        out.write_all(&content)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::emitter::Backend;
+    use crate::target;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn choose_target() {
+        assert_eq!(target(&args("wasl a.clj")).ok(), Some(Backend::Linear));
+        assert_eq!(
+            target(&args("wasl a.clj --target linear")).ok(),
+            Some(Backend::Linear)
+        );
+        assert_eq!(
+            target(&args("wasl --target wasm-gc a.clj")).ok(),
+            Some(Backend::WasmGc)
+        );
+        assert!(target(&args("wasl a.clj --target foo")).is_err());
+        assert!(target(&args("wasl a.clj --target")).is_err());
+    }
+}